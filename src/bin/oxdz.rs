@@ -1,3 +1,4 @@
+extern crate byteorder;
 extern crate memmap;
 extern crate oxdz;
 extern crate riff_wave;
@@ -6,7 +7,9 @@ extern crate getopts;
 use std::env;
 use std::error::Error;
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use byteorder::{LittleEndian, WriteBytesExt};
 use getopts::Options;
 use memmap::Mmap;
 use oxdz::{format, module, player, FrameInfo};
@@ -18,6 +21,18 @@ fn main() {
     let mut opts = Options::new();
 
     opts.optflag("h", "help", "display usage information and exit");
+    opts.optopt("i", "interpolator", "interpolator to use when mixing (nearest, linear, cubic, sinc)", "NAME");
+    opts.optopt("r", "rate", "output sampling rate in Hz (default 44100)", "RATE");
+    opts.optopt("l", "layout", "output channel layout (mono, stereo, reversed)", "LAYOUT");
+    opts.optopt("b", "bits", "output sample format (16, 32 or float)", "BITS");
+    opts.optopt("g", "gain", "master gain (default 1.0)", "GAIN");
+    opts.optopt("", "limiter", "output limiter (off, clip, soft)", "LIMITER");
+    opts.optflag("", "float", "use the floating-point mixer");
+    opts.optflag("", "stems", "also write each channel to out_NN.wav");
+    opts.optopt("s", "start", "start replay at the given time in seconds", "TIME");
+    opts.optopt("t", "timing", "tick timing (bpm, cia, vblank)", "TIMING");
+    opts.optflag("", "ntsc", "use the NTSC Amiga clock for CIA and vblank timing");
+    opts.optopt("", "amiga", "emulate the Amiga audio hardware (a500, a1200)", "MODEL");
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -27,30 +42,162 @@ fn main() {
         }
     };
 
-    if matches.opt_present("h") ||  matches.free.len() < 1 {
+    if matches.opt_present("h") ||  matches.free.is_empty() {
         let brief = format!("Usage: {} [options] filename", args[0]);
         print!("{}", opts.usage(&brief));
         return;
     }
 
-    match run(&matches.free[0]) {
+    let interp = match matches.opt_str("i") {
+        Some(name) => match player::Interpolator::from_name(&name) {
+            Some(val) => val,
+            None      => {
+                println!("Error: invalid interpolator {}", name);
+                return;
+            }
+        },
+        None       => player::Interpolator::Linear,
+    };
+
+    let rate = match matches.opt_str("r") {
+        Some(val) => match val.parse::<usize>() {
+            Ok(v)  => v,
+            Err(_) => {
+                println!("Error: invalid sampling rate {}", val);
+                return;
+            }
+        },
+        None      => 44100,
+    };
+
+    let layout = match matches.opt_str("l").as_deref() {
+        Some("mono")     => player::Layout::Mono,
+        Some("stereo")   => player::Layout::Stereo,
+        Some("reversed") => player::Layout::ReversedStereo,
+        None             => player::Layout::Stereo,
+        Some(val)        => {
+            println!("Error: invalid layout {}", val);
+            return;
+        }
+    };
+
+    let format = match matches.opt_str("b").as_deref() {
+        Some("16") | None => player::SampleFormat::I16,
+        Some("32")        => player::SampleFormat::I32,
+        Some("float")     => player::SampleFormat::F32,
+        Some(val)         => {
+            println!("Error: invalid sample size {}", val);
+            return;
+        }
+    };
+
+    let gain = match matches.opt_str("g") {
+        Some(val) => match val.parse::<f32>() {
+            Ok(v)  => v,
+            Err(_) => {
+                println!("Error: invalid gain {}", val);
+                return;
+            }
+        },
+        None      => 1.0,
+    };
+
+    let limiter = match matches.opt_str("limiter").as_deref() {
+        Some("clip") | None => player::Limiter::Clip,
+        Some("off")         => player::Limiter::Off,
+        Some("soft")        => player::Limiter::SoftKnee,
+        Some(val)           => {
+            println!("Error: invalid limiter {}", val);
+            return;
+        }
+    };
+
+    let float_mix = matches.opt_present("float");
+
+    let start = match matches.opt_str("s") {
+        Some(val) => match val.parse::<f64>() {
+            Ok(v) if v >= 0.0 => (v * 1000.0) as u32,
+            _                 => {
+                println!("Error: invalid start time {}", val);
+                return;
+            }
+        },
+        None      => 0,
+    };
+
+    let stems = matches.opt_present("stems");
+
+    let timing = match matches.opt_str("t").as_deref() {
+        Some("bpm")    => Some(player::Timing::Bpm),
+        Some("cia")    => Some(player::Timing::Cia),
+        Some("vblank") => Some(player::Timing::Vblank),
+        None           => None,
+        Some(val)      => {
+            println!("Error: invalid timing {}", val);
+            return;
+        }
+    };
+
+    let clock = if matches.opt_present("ntsc") { player::Clock::Ntsc } else { player::Clock::Pal };
+
+    let paula = match matches.opt_str("amiga").as_deref() {
+        Some("a500")  => player::Paula::A500,
+        Some("a1200") => player::Paula::A1200,
+        None          => player::Paula::Off,
+        Some(val)     => {
+            println!("Error: invalid Amiga model {}", val);
+            return;
+        }
+    };
+
+    let options = RunOptions {
+        interp,
+        rate,
+        layout,
+        format,
+        float_mix,
+        gain,
+        limiter,
+        start,
+        stems,
+        timing,
+        clock,
+        paula,
+    };
+
+    match run(&matches.free[0], &options) {
         Ok(_)  => {},
         Err(e) => println!("Error: {}", e),
     }
 }
 
-fn run(name: &String) -> Result<(), Box<Error>> {
-    let file = try!(File::open(name));
+// Replay options set in the command line
+struct RunOptions {
+    interp   : player::Interpolator,
+    rate     : usize,
+    layout   : player::Layout,
+    format   : player::SampleFormat,
+    float_mix: bool,
+    gain     : f32,
+    limiter  : player::Limiter,
+    start    : u32,                     // start time in milliseconds
+    stems    : bool,
+    timing   : Option<player::Timing>,  // None to use the player timing
+    clock    : player::Clock,
+    paula    : player::Paula,
+}
+
+fn run(name: &str, opts: &RunOptions) -> Result<(), Box<dyn Error>> {
+    let rate = opts.rate;
+    let file = File::open(name)?;
     let mmap = unsafe { Mmap::map(&file).expect("failed to map the file") };
 
-    let module = try!(format::load(&mmap[..]));
+    let module = Arc::new(format::load(&mmap[..])?);
     println!("Title: {}", module.title());
 
     println!("Instruments:");
-    let mut i = 0;
-    for ins in module.instruments() {
-        println!("{:3}: {:30}", i + 1, ins);
-        i += 1;
+    for (i, ins) in module.instruments().iter().enumerate() {
+        println!("{:3}: {:30}", i + 1, ins.name);
     }
 
     println!("Samples:");
@@ -67,7 +214,16 @@ fn run(name: &String) -> Result<(), Box<Error>> {
         println!("{:5} {:40} {:?}", info.id, info.name, info.accepts);
     }
 
-    let mut player = player::Player::find_player(&module, module.player)?;
+    let mut player = player::Player::find_player_with_rate(module.clone(), module.player, rate)?;
+    player.set_interpolator(opts.interp).set_layout(opts.layout).set_format(opts.format);
+    player.set_float_mix(opts.float_mix).set_master_gain(opts.gain).set_limiter(opts.limiter);
+    if let Some(timing) = opts.timing {
+        player.set_timing(timing);
+    }
+    player.set_clock(opts.clock).set_paula(opts.paula);
+
+    let duration = player.scan().duration();
+    println!("Duration: {}:{:02}.{:03}", duration / 60000, (duration / 1000) % 60, duration % 1000);
 
     println!("Length: {}", module.len());
     println!("Patterns: {}", module.patterns());
@@ -77,25 +233,131 @@ fn run(name: &String) -> Result<(), Box<Error>> {
 
     let mut frame_info = FrameInfo::new();
 
-    let file = try!(File::create("out.wav"));
-    let writer = BufWriter::new(file);
-    let mut wave_writer = try!(WaveWriter::new(2, 44100, 16, writer));
+    let mut wave_writer = Output::create("out.wav", opts.format, opts.layout.channels() as u16, rate as u32)?;
 
-    player.start();
+    let mut stem_writers = Vec::new();
+    if opts.stems {
+        player.set_stems(player::Stems::Channel);
+        for i in 0..player.num_stems() {
+            let file = File::create(format!("out_{:02}.wav", i + 1))?;
+            stem_writers.push(WaveWriter::new(2, rate as u32, 16, BufWriter::new(file))?);
+        }
+    }
+
+    if opts.start > 0 {
+        player.seek_time(opts.start);
+    } else {
+        player.start();
+    }
     for _ in 0..1000 {
-        let buffer = player.info(&mut frame_info).play_frame().buffer();
+        player.info(&mut frame_info).play_frame();
+        for (i, w) in stem_writers.iter_mut().enumerate() {
+            for s in player.stem_buffer(i) {
+                w.write_sample_i16((s.clamp(-1.0, 1.0) * 32767.0) as i16)?;
+            }
+        }
         print!("info pos:{} row:{} frame:{} speed:{} tempo:{}    \r", frame_info.pos, frame_info.row, frame_info.frame, frame_info.speed, frame_info.tempo);
-        for s in buffer {
-            try!(wave_writer.write_sample_i16(*s));
+        wave_writer.write_frame(&player)?;
+
+        // Keep the last frame played
+        if player.end() {
+            break;
         }
     }
     println!();
+    if player.overs() > 0 {
+        println!("Clipped samples: {}", player.overs());
+    }
 
-    try!(wave_writer.sync_header());
+    wave_writer.sync_header()?;
+    for w in &mut stem_writers {
+        w.sync_header()?;
+    }
 
     Ok(())
 }
 
+// Wave file of integer or float samples. The riff-wave writer only writes
+// integer PCM, so float files are written here.
+enum Output {
+    Pcm(WaveWriter<BufWriter<File>>),
+    Float(FloatWaveWriter<BufWriter<File>>),
+}
+
+impl Output {
+    fn create(name: &str, format: player::SampleFormat, channels: u16, rate: u32) -> Result<Self, Box<dyn Error>> {
+        let writer = BufWriter::new(File::create(name)?);
+        Ok(match format {
+            player::SampleFormat::I16 => Output::Pcm(WaveWriter::new(channels, rate, 16, writer)?),
+            player::SampleFormat::I32 => Output::Pcm(WaveWriter::new(channels, rate, 32, writer)?),
+            player::SampleFormat::F32 => Output::Float(FloatWaveWriter::new(channels, rate, writer)?),
+        })
+    }
+
+    fn write_frame(&mut self, player: &player::Player) -> Result<(), Box<dyn Error>> {
+        match *self {
+            Output::Pcm(ref mut w) => if w.pcm_format.bits_per_sample == 32 {
+                for s in player.buffer_i32() {
+                    w.write_sample_i32(*s)?;
+                }
+            } else {
+                for s in player.buffer() {
+                    w.write_sample_i16(*s)?;
+                }
+            },
+            Output::Float(ref mut w) => for s in player.buffer_f32() {
+                w.write_sample(*s)?;
+            },
+        }
+        Ok(())
+    }
+
+    fn sync_header(&mut self) -> io::Result<()> {
+        match *self {
+            Output::Pcm(ref mut w)   => w.sync_header(),
+            Output::Float(ref mut w) => w.sync_header(),
+        }
+    }
+}
+
+struct FloatWaveWriter<W: Write + Seek> {
+    writer: W,
+    size  : u32,  // data size in bytes
+}
+
+impl<W: Write + Seek> FloatWaveWriter<W> {
+    fn new(channels: u16, rate: u32, writer: W) -> io::Result<Self> {
+        let mut w = FloatWaveWriter { writer, size: 0 };
+        w.writer.write_all(b"RIFF")?;
+        w.writer.write_u32::<LittleEndian>(36)?;
+        w.writer.write_all(b"WAVEfmt ")?;
+        w.writer.write_u32::<LittleEndian>(16)?;
+        w.writer.write_u16::<LittleEndian>(3)?;                  // IEEE float
+        w.writer.write_u16::<LittleEndian>(channels)?;
+        w.writer.write_u32::<LittleEndian>(rate)?;
+        w.writer.write_u32::<LittleEndian>(rate * channels as u32 * 4)?;
+        w.writer.write_u16::<LittleEndian>(channels * 4)?;
+        w.writer.write_u16::<LittleEndian>(32)?;
+        w.writer.write_all(b"data")?;
+        w.writer.write_u32::<LittleEndian>(0)?;
+        Ok(w)
+    }
+
+    fn write_sample(&mut self, smp: f32) -> io::Result<()> {
+        self.size += 4;
+        self.writer.write_f32::<LittleEndian>(smp)
+    }
+
+    fn sync_header(&mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_u32::<LittleEndian>(36 + self.size)?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_u32::<LittleEndian>(self.size)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()
+    }
+}
+
 fn show_pattern(module: &module::Module, num: usize) {
     println!("Pattern {}:", num);
     for r in 0..module.rows(num) {
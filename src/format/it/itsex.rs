@@ -0,0 +1,199 @@
+// Impulse Tracker sample decompression, based on the public domain IT214
+// decompressor by Jeffrey Lim and the itsex implementation used in libxmp.
+//
+// Compressed samples are stored in blocks of 0x8000 samples (0x4000 for
+// 16-bit samples). Each block starts with its compressed size and the bit
+// reader restarts at the beginning of each block. IT2.15 compression adds a
+// second integration pass.
+
+use util::BinaryRead;
+use ::*;
+
+struct BitReader<'a> {
+    b     : &'a [u8],
+    pos   : usize,
+    bitbuf: u32,
+    bitnum: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(b: &'a [u8]) -> Self {
+        BitReader {
+            b,
+            pos   : 0,
+            bitbuf: 0,
+            bitnum: 0,
+        }
+    }
+
+    // Read n bits, least significant bit first
+    fn read_bits(&mut self, n: usize) -> u32 {
+        if n == 0 || n > 32 {
+            return 0;
+        }
+
+        let mut val = 0_u32;
+        for _ in 0..n {
+            if self.bitnum == 0 {
+                self.bitbuf = if self.pos < self.b.len() { self.b[self.pos] as u32 } else { 0 };
+                self.pos += 1;
+                self.bitnum = 8;
+            }
+            val >>= 1;
+            val |= self.bitbuf << 31;
+            self.bitbuf >>= 1;
+            self.bitnum -= 1;
+        }
+        val >> (32 - n)
+    }
+}
+
+// Return the compressed data of the block starting at the given offset
+// and the offset of the next block
+fn read_block(b: &[u8], ofs: usize) -> Result<(&[u8], usize), Error> {
+    let size = b.read16l(ofs)? as usize;
+    let start = ofs + 2;
+    let end = (start + size).min(b.len());
+    Ok((&b[start.min(end)..end], start + size))
+}
+
+pub fn decompress8(b: &[u8], len: usize, it215: bool) -> Result<Vec<u8>, Error> {
+    let mut dst = Vec::<u8>::with_capacity(len);
+    let mut ofs = 0;
+
+    while dst.len() < len {
+        let (block, next) = read_block(b, ofs)?;
+        ofs = next;
+
+        let mut r = BitReader::new(block);
+        let count = (len - dst.len()).min(0x8000);
+        let mut width = 9_usize;
+        let mut temp = 0_u8;
+        let mut temp2 = 0_u8;
+        let mut pos = 0;
+
+        while pos < count {
+            let bits = r.read_bits(width) as u16;
+
+            if width == 0 || width >= 10 {
+                // Invalid width, skip sample
+                dst.push(0);
+                pos += 1;
+                continue;
+            } else if width < 7 {
+                // Method 1: a single bit set marks a width change
+                if bits == 1 << (width - 1) {
+                    let val = r.read_bits(3) as usize + 1;
+                    width = if val < width { val } else { val + 1 };
+                    continue;
+                }
+            } else if width < 9 {
+                // Method 2: values in a small range mark a width change
+                let border = (0xff >> (9 - width)) + 4;
+                if bits > border - 8 && bits <= border {
+                    let val = (bits - (border - 8)) as usize;
+                    width = if val < width { val } else { val + 1 };
+                    continue;
+                }
+            } else if bits >= 256 {
+                // Method 3: the 9th bit marks a width change
+                width = (bits + 1) as usize & 0xff;
+                continue;
+            }
+
+            // Sign extend and integrate
+            let val = if width < 8 {
+                let shift = 8 - width;
+                ((bits << shift) as i8 >> shift) as u8
+            } else {
+                bits as u8
+            };
+
+            temp = temp.wrapping_add(val);
+            temp2 = temp2.wrapping_add(temp);
+            dst.push(if it215 { temp2 } else { temp });
+            pos += 1;
+        }
+    }
+
+    Ok(dst)
+}
+
+pub fn decompress16(b: &[u8], len: usize, it215: bool) -> Result<Vec<i16>, Error> {
+    let mut dst = Vec::<i16>::with_capacity(len);
+    let mut ofs = 0;
+
+    while dst.len() < len {
+        let (block, next) = read_block(b, ofs)?;
+        ofs = next;
+
+        let mut r = BitReader::new(block);
+        let count = (len - dst.len()).min(0x4000);
+        let mut width = 17_usize;
+        let mut temp = 0_i16;
+        let mut temp2 = 0_i16;
+        let mut pos = 0;
+
+        while pos < count {
+            let bits = r.read_bits(width);
+
+            if width == 0 || width >= 18 {
+                dst.push(0);
+                pos += 1;
+                continue;
+            } else if width < 7 {
+                if bits == 1 << (width - 1) {
+                    let val = r.read_bits(4) as usize + 1;
+                    width = if val < width { val } else { val + 1 };
+                    continue;
+                }
+            } else if width < 17 {
+                let border = (0xffff >> (17 - width)) + 8;
+                if bits > border - 16 && bits <= border {
+                    let val = (bits - (border - 16)) as usize;
+                    width = if val < width { val } else { val + 1 };
+                    continue;
+                }
+            } else if bits >= 0x10000 {
+                width = (bits + 1) as usize & 0xff;
+                continue;
+            }
+
+            let val = if width < 16 {
+                let shift = 16 - width;
+                (bits << shift) as i16 >> shift
+            } else {
+                bits as i16
+            };
+
+            temp = temp.wrapping_add(val);
+            temp2 = temp2.wrapping_add(temp);
+            dst.push(if it215 { temp2 } else { temp });
+            pos += 1;
+        }
+    }
+
+    Ok(dst)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_bits() {
+        let mut r = BitReader::new(&[0xa5, 0x0f]);
+        assert_eq!(r.read_bits(4), 0x5);
+        assert_eq!(r.read_bits(8), 0xfa);
+        assert_eq!(r.read_bits(4), 0x0);
+    }
+
+    #[test]
+    fn test_decompress8() {
+        // 9-bit deltas 1, 1, -2 and 16, least significant bit first
+        let b = [0x05, 0x00, 0x01, 0x02, 0xf8, 0x83, 0x00];
+        assert_eq!(decompress8(&b, 4, false).unwrap(), vec![0x01, 0x02, 0x00, 0x10]);
+        assert_eq!(decompress8(&b, 4, true).unwrap(), vec![0x01, 0x03, 0x03, 0x13]);
+    }
+}
@@ -0,0 +1,390 @@
+use format::Loader;
+use format::it::{ItData, ItPattern, ItInstrument, ItSample};
+use format::it::itsex;
+use module::{Module, Sample};
+use module::sample::SampleType;
+use module::instrument::{Envelope, EnvelopePoint, NoteAction, DuplicateCheck};
+use util::BinaryRead;
+use ::*;
+
+/// Impulse Tracker module loader
+pub struct ItLoader;
+
+impl ItLoader {
+    fn load_envelope(&self, b: &[u8], ofs: usize) -> Result<(Envelope, u8), Error> {
+        let mut env = Envelope::new();
+
+        let flg = b.read8(ofs)?;
+        let num = b.read8(ofs + 1)? as usize;
+        env.enabled = flg & 0x01 != 0;
+        env.has_loop = flg & 0x02 != 0;
+        env.has_sustain = flg & 0x04 != 0;
+        env.loop_start = b.read8(ofs + 2)? as usize;
+        env.loop_end = b.read8(ofs + 3)? as usize;
+        env.sus_start = b.read8(ofs + 4)? as usize;
+        env.sus_end = b.read8(ofs + 5)? as usize;
+
+        for i in 0..num.min(25) {
+            env.points.push(EnvelopePoint{
+                x: b.read16l(ofs + 7 + i * 3)? as usize,
+                y: b.read8i(ofs + 6 + i * 3)? as isize,
+            });
+        }
+
+        if env.points.is_empty() {
+            env.enabled = false;
+        }
+
+        Ok((env, flg))
+    }
+
+    fn load_instrument(&self, b: &[u8], ofs: usize, i: usize, cmwt: u16) -> Result<ItInstrument, Error> {
+        let mut ins = ItInstrument::new();
+        ins.num = i + 1;
+
+        if b.read32b(ofs)? != 0x494d5049 {  // IMPI
+            return Err(Error::Load("invalid instrument header"));
+        }
+
+        ins.filename = b.read_string(ofs + 4, 12)?;
+        ins.name = b.read_string(ofs + 32, 26)?;
+
+        for (k, key) in ins.keyboard.iter_mut().enumerate() {
+            *key = (b.read8(ofs + 64 + k * 2)?, b.read8(ofs + 65 + k * 2)?);
+        }
+
+        // Instruments saved by versions older than 2.0 use the old format
+        if cmwt < 0x200 {
+            let flags = b.read8(ofs + 17)?;
+            ins.fadeout = b.read16l(ofs + 24)? as usize * 2;
+            ins.nna = note_action(b.read8(ofs + 26)?);
+            if b.read8(ofs + 27)? != 0 {
+                ins.dct = DuplicateCheck::Note;
+            }
+
+            let env = &mut ins.vol_env;
+            env.enabled = flags & 0x01 != 0;
+            env.has_loop = flags & 0x02 != 0;
+            env.has_sustain = flags & 0x04 != 0;
+            env.loop_start = b.read8(ofs + 18)? as usize;
+            env.loop_end = b.read8(ofs + 19)? as usize;
+            env.sus_start = b.read8(ofs + 20)? as usize;
+            env.sus_end = b.read8(ofs + 21)? as usize;
+
+            for n in 0..25 {
+                let x = b.read8(ofs + 504 + n * 2)?;
+                if x == 0xff {
+                    break;
+                }
+                env.points.push(EnvelopePoint{ x: x as usize, y: b.read8(ofs + 505 + n * 2)? as isize });
+            }
+
+            if env.points.is_empty() {
+                env.enabled = false;
+            }
+
+            return Ok(ins)
+        }
+
+        ins.nna = note_action(b.read8(ofs + 17)?);
+        ins.dct = match b.read8(ofs + 18)? {
+            1 => DuplicateCheck::Note,
+            2 => DuplicateCheck::Sample,
+            3 => DuplicateCheck::Instrument,
+            _ => DuplicateCheck::Off,
+        };
+        ins.dca = match b.read8(ofs + 19)? {
+            1 => NoteAction::Off,
+            2 => NoteAction::Fade,
+            _ => NoteAction::Cut,
+        };
+        ins.fadeout = b.read16l(ofs + 20)? as usize;
+        ins.pps = b.read8i(ofs + 22)?;
+        ins.ppc = b.read8(ofs + 23)?;
+        ins.global_vol = b.read8(ofs + 24)?.min(128);
+        let dfp = b.read8(ofs + 25)?;
+        if dfp & 0x80 == 0 {
+            ins.default_pan = Some(dfp.min(64));
+        }
+        ins.rand_vol = b.read8(ofs + 26)?;
+        ins.rand_pan = b.read8(ofs + 27)?;
+
+        let ifc = b.read8(ofs + 58)?;
+        if ifc & 0x80 != 0 {
+            ins.cutoff = Some(ifc & 0x7f);
+        }
+        let ifr = b.read8(ofs + 59)?;
+        if ifr & 0x80 != 0 {
+            ins.resonance = Some(ifr & 0x7f);
+        }
+
+        ins.vol_env = self.load_envelope(b, ofs + 304)?.0;
+        ins.pan_env = self.load_envelope(b, ofs + 386)?.0;
+        let (env, flg) = self.load_envelope(b, ofs + 468)?;
+        ins.pitch_env = env;
+        ins.filter_env = flg & 0x80 != 0;
+
+        Ok(ins)
+    }
+
+    fn load_sample(&self, b: &[u8], ofs: usize, i: usize) -> Result<(ItSample, Sample), Error> {
+        let mut sub = ItSample::new();
+        let mut smp = Sample::new();
+
+        sub.num = i + 1;
+        smp.num = i + 1;
+
+        if b.read32b(ofs)? != 0x494d5053 {  // IMPS
+            return Err(Error::Load("invalid sample header"));
+        }
+
+        sub.filename = b.read_string(ofs + 4, 12)?;
+        sub.global_vol = b.read8(ofs + 17)?.min(64);
+        sub.flags = b.read8(ofs + 18)?;
+        sub.volume = b.read8(ofs + 19)?.min(64);
+        sub.name = b.read_string(ofs + 20, 26)?;
+        sub.cvt = b.read8(ofs + 46)?;
+        let dfp = b.read8(ofs + 47)?;
+        if dfp & 0x80 != 0 {
+            sub.default_pan = Some((dfp & 0x7f).min(64));
+        }
+        smp.size = b.read32l(ofs + 48)? as usize;
+        smp.loop_start = b.read32l(ofs + 52)? as usize;
+        smp.loop_end = b.read32l(ofs + 56)? as usize;
+        sub.c5speed = b.read32l(ofs + 60)?;
+        smp.sloop_start = b.read32l(ofs + 64)? as usize;
+        smp.sloop_end = b.read32l(ofs + 68)? as usize;
+        let ptr = b.read32l(ofs + 72)? as usize;
+        sub.vib_speed = b.read8(ofs + 76)?;
+        sub.vib_depth = b.read8(ofs + 77)?;
+        sub.vib_rate = b.read8(ofs + 78)?;
+        sub.vib_type = b.read8(ofs + 79)?;
+
+        smp.name = sub.name.to_owned();
+        smp.rate = sub.c5speed as f64;
+
+        // Sample without data
+        if sub.flags & 0x01 == 0 || smp.size == 0 || ptr >= b.len() {
+            smp.size = 0;
+            return Ok((sub, smp))
+        }
+
+        // Stereo samples store the left channel first, we play only that
+        let is_16bit = sub.flags & 0x02 != 0;
+        let signed = sub.cvt & 0x01 != 0;
+
+        let mut data = if sub.flags & 0x08 != 0 {
+            let it215 = sub.cvt & 0x04 != 0;
+            let src = b.slice(ptr, b.len() - ptr)?;
+            if is_16bit {
+                let mut v = Vec::<u8>::with_capacity(smp.size * 2);
+                for x in itsex::decompress16(src, smp.size, it215)? {
+                    v.push(x as u8);
+                    v.push((x >> 8) as u8);
+                }
+                v
+            } else {
+                itsex::decompress8(src, smp.size, it215)?
+            }
+        } else {
+            let bytes = if is_16bit { smp.size * 2 } else { smp.size };
+
+            // Allow truncated sample data in the last sample
+            let avail = b.len() - ptr;
+            let bytes = if bytes > avail { avail & !(is_16bit as usize) } else { bytes };
+            let mut data = b.slice(ptr, bytes)?.to_vec();
+
+            if !signed {
+                if is_16bit {
+                    data.iter_mut().skip(1).step_by(2).for_each(|x| *x ^= 0x80);
+                } else {
+                    data.iter_mut().for_each(|x| *x ^= 0x80);
+                }
+            }
+            data
+        };
+
+        if is_16bit {
+            smp.size = data.len() / 2;
+            data.truncate(smp.size * 2);
+            smp.sample_type = SampleType::Sample16;
+        } else {
+            smp.size = data.len();
+            smp.sample_type = SampleType::Sample8;
+        }
+
+        if smp.loop_end > smp.size {
+            smp.loop_end = smp.size;
+        }
+        smp.has_loop = sub.flags & 0x10 != 0 && smp.loop_start < smp.loop_end;
+        smp.loop_bidir = smp.has_loop && sub.flags & 0x40 != 0;
+
+        if smp.sloop_end > smp.size {
+            smp.sloop_end = smp.size;
+        }
+        smp.has_sloop = sub.flags & 0x20 != 0 && smp.sloop_start < smp.sloop_end;
+        smp.sloop_bidir = smp.has_sloop && sub.flags & 0x80 != 0;
+
+        if smp.size > 0 {
+            smp.store(&data);
+        } else {
+            smp.sample_type = SampleType::Empty;
+        }
+
+        Ok((sub, smp))
+    }
+
+    fn load_pattern(&self, b: &[u8], ofs: usize) -> Result<ItPattern, Error> {
+        // Empty patterns have 64 rows and no data
+        if ofs == 0 {
+            return Ok(ItPattern::from_packed(64, &[]))
+        }
+
+        let size = b.read16l(ofs)? as usize;
+        let rows = b.read16l(ofs + 2)? as usize;
+
+        if rows == 0 || rows > 200 {
+            return Err(Error::Load("invalid number of rows"));
+        }
+
+        let start = ofs + 8;
+        let size = if start + size > b.len() { b.len().saturating_sub(start) } else { size };
+
+        Ok(ItPattern::from_packed(rows, b.slice(start, size)?))
+    }
+}
+
+fn note_action(val: u8) -> NoteAction {
+    match val {
+        1 => NoteAction::Continue,
+        2 => NoteAction::Off,
+        3 => NoteAction::Fade,
+        _ => NoteAction::Cut,
+    }
+}
+
+impl Loader for ItLoader {
+    fn name(&self) -> &'static str {
+        "Impulse Tracker IT"
+    }
+
+    fn probe(&self, b: &[u8]) -> Result<(), Error> {
+        if b.len() < 192 {
+            return Err(Error::Format("file too short"));
+        }
+
+        if b.read32b(0)? == 0x494d504d {  // IMPM
+            Ok(())
+        } else {
+            Err(Error::Format("bad magic"))
+        }
+    }
+
+    fn load(self: Box<Self>, b: &[u8]) -> Result<Module, Error> {
+        let song_name = b.read_string(4, 26)?;
+        let ord_num = b.read16l(32)? as usize;
+        let ins_num = b.read16l(34)? as usize;
+        let smp_num = b.read16l(36)? as usize;
+        let pat_num = b.read16l(38)? as usize;
+        let cwt = b.read16l(40)?;
+        let cmwt = b.read16l(42)?;
+        let flags = b.read16l(44)?;
+        let special = b.read16l(46)?;
+        let global_vol = b.read8(48)?.min(128);
+        let mix_vol = b.read8(49)?;
+        let initial_speed = b.read8(50)?;
+        let initial_tempo = b.read8(51)?;
+        let sep = b.read8(52)?;
+
+        if ord_num > 256 || ins_num > 99 || smp_num > 99 || pat_num > 200 {
+            return Err(Error::Load("invalid module header"));
+        }
+
+        let mut chn_pan = [0_u8; 64];
+        chn_pan.copy_from_slice(b.slice(64, 64)?);
+        let mut chn_vol = [0_u8; 64];
+        chn_vol.copy_from_slice(b.slice(128, 64)?);
+
+        // Load orders, skipping marker patterns
+        let orders = b.slice(192, ord_num)?.iter().filter(|&&x| x != 254).cloned().collect::<Vec<u8>>();
+
+        let mut ofs = 192 + ord_num;
+        let mut ins_pp = Vec::<usize>::new();
+        for _ in 0..ins_num {
+            ins_pp.push(b.read32l(ofs)? as usize);
+            ofs += 4;
+        }
+
+        let mut smp_pp = Vec::<usize>::new();
+        for _ in 0..smp_num {
+            smp_pp.push(b.read32l(ofs)? as usize);
+            ofs += 4;
+        }
+
+        let mut pat_pp = Vec::<usize>::new();
+        for _ in 0..pat_num {
+            pat_pp.push(b.read32l(ofs)? as usize);
+            ofs += 4;
+        }
+
+        // Load instruments
+        let mut instruments = Vec::<ItInstrument>::new();
+        for (i, &pp) in ins_pp.iter().enumerate() {
+            let ins = self.load_instrument(b, pp, i, cmwt)?;
+            instruments.push(ins);
+        }
+
+        // Load samples
+        let mut sample_info = Vec::<ItSample>::new();
+        let mut samples = Vec::<Sample>::new();
+        for (i, &pp) in smp_pp.iter().enumerate() {
+            let (sub, smp) = self.load_sample(b, pp, i)?;
+            sample_info.push(sub);
+            samples.push(smp);
+        }
+
+        // Load patterns
+        let mut patterns = Vec::<ItPattern>::new();
+        for &pp in &pat_pp {
+            let pat = self.load_pattern(b, pp)?;
+            patterns.push(pat);
+        }
+
+        // Number of channels is the last channel used in patterns
+        let channels = patterns.iter().map(|x| x.channels_used()).max().unwrap_or(0).max(1);
+
+        let data = ItData{
+            song_name,
+            ord_num,
+            ins_num,
+            smp_num,
+            pat_num,
+            cwt,
+            cmwt,
+            flags,
+            special,
+            global_vol,
+            mix_vol,
+            initial_speed,
+            initial_tempo,
+            sep,
+            channels,
+            chn_pan,
+            chn_vol,
+            orders,
+            instruments,
+            sample_info,
+            patterns,
+            samples,
+        };
+
+        let m = Module {
+            format     : "it",
+            description: "Impulse Tracker IT",
+            player     : "it",
+            data       : Box::new(data),
+        };
+
+        Ok(m)
+    }
+}
@@ -0,0 +1,421 @@
+
+pub mod load;
+mod itsex;
+
+pub use self::load::*;
+
+use std::any::Any;
+use std::fmt;
+use module::{ModuleData, Event, Instrument, Sample};
+use module::event::{KEY_OFF, KEY_CUT, KEY_FADE};
+use module::instrument::{Envelope, NoteAction, DuplicateCheck};
+use util::NOTES;
+
+pub const IT_NOTE_OFF: u8 = 255;
+pub const IT_NOTE_CUT: u8 = 254;
+
+
+pub struct ItData {
+    pub song_name    : String,
+    pub ord_num      : usize,
+    pub ins_num      : usize,
+    pub smp_num      : usize,
+    pub pat_num      : usize,
+    pub cwt          : u16,
+    pub cmwt         : u16,
+    pub flags        : u16,
+    pub special      : u16,
+    pub global_vol   : u8,
+    pub mix_vol      : u8,
+    pub initial_speed: u8,
+    pub initial_tempo: u8,
+    pub sep          : u8,
+    pub channels     : usize,
+    pub chn_pan      : [u8; 64],
+    pub chn_vol      : [u8; 64],
+    pub orders       : Vec<u8>,
+    pub instruments  : Vec<ItInstrument>,
+    pub sample_info  : Vec<ItSample>,
+    pub patterns     : Vec<ItPattern>,
+    pub samples      : Vec<Sample>,
+}
+
+impl ItData {
+    pub fn stereo(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    /// Check if notes are played through instruments or directly from samples.
+    pub fn use_instruments(&self) -> bool {
+        self.flags & 0x04 != 0 && self.ins_num > 0
+    }
+
+    pub fn linear_slides(&self) -> bool {
+        self.flags & 0x08 != 0
+    }
+
+    pub fn old_effects(&self) -> bool {
+        self.flags & 0x10 != 0
+    }
+
+    /// Tone portamento doesn't share effect memory with portamento up and down.
+    pub fn compatible_gxx(&self) -> bool {
+        self.flags & 0x20 != 0
+    }
+}
+
+impl ModuleData for ItData {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn title(&self) -> &str {
+        &self.song_name
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn patterns(&self) -> usize {
+        self.pat_num
+    }
+
+    fn len(&self) -> usize {
+        self.orders.iter().position(|&x| x == 255).unwrap_or(self.orders.len())
+    }
+
+    fn pattern_in_position(&self, pos: usize) -> Option<usize> {
+        if pos >= self.orders.len() {
+            None
+        } else {
+            Some(self.orders[pos] as usize)
+        }
+    }
+
+    fn next_position(&self, _pos: usize) -> usize {
+        0
+    }
+
+    fn prev_position(&self, _pos: usize) -> usize {
+        0
+    }
+
+    fn instruments(&self) -> Vec<Instrument> {
+        if !self.use_instruments() {
+            return self.sample_info.iter().enumerate().map(|(i, x)| {
+                let smp = if self.samples[i].size > 0 { Some(i) } else { None };
+                Instrument::with_sample(i + 1, &x.name, x.volume as usize, smp)
+            }).collect::<Vec<Instrument>>()
+        }
+
+        self.instruments.iter().map(|x| {
+            let mut ins = Instrument::new();
+            ins.num = x.num;
+            ins.name = x.name.to_owned();
+            ins.volume = x.global_vol as usize / 2;
+            for (k, &(_, s)) in x.keyboard.iter().enumerate() {
+                if s != 0 && (s as usize) <= self.samples.len() {
+                    ins.keymap[k] = Some(s as usize - 1);
+                }
+            }
+            ins.vol_env = x.vol_env.clone();
+            ins.pan_env = x.pan_env.clone();
+            ins.pitch_env = x.pitch_env.clone();
+            ins.fade_out = x.fadeout;
+            ins
+        }).collect::<Vec<Instrument>>()
+    }
+
+    fn event(&self, num: usize, row: usize, chn: usize) -> Option<Event> {
+        if num >= self.pat_num || row >= self.rows(num) || chn >= self.channels {
+           None
+        } else {
+           let e = self.patterns[num].event(row, chn);
+           Some(Event{
+               note: match e.note {
+                   None              => 0,
+                   Some(IT_NOTE_OFF) => KEY_OFF,
+                   Some(IT_NOTE_CUT) => KEY_CUT,
+                   Some(n) if n > 119 => KEY_FADE,
+                   Some(n)           => n,
+               },
+               ins : e.ins,
+               vol : match e.volpan { Some(v) => v + 1, None => 0 },
+               fxt : e.cmd,
+               fxp : e.info,
+           })
+        }
+    }
+
+    fn rows(&self, pat: usize) -> usize {
+        if pat >= self.pat_num {
+            0
+        } else {
+            self.patterns[pat].rows
+        }
+    }
+
+    fn samples(&self) -> &Vec<Sample> {
+        &self.samples
+    }
+}
+
+
+/// ItInstrument defines the Impulse Tracker instrument parameters.
+#[derive(Debug)]
+pub struct ItInstrument {
+    pub num        : usize,
+    pub name       : String,
+    pub filename   : String,
+    pub nna        : NoteAction,
+    pub dct        : DuplicateCheck,
+    pub dca        : NoteAction,
+    pub fadeout    : usize,
+    pub pps        : i8,              // pitch-pan separation
+    pub ppc        : u8,              // pitch-pan center
+    pub global_vol : u8,              // 0-128
+    pub default_pan: Option<u8>,      // 0-64
+    pub rand_vol   : u8,              // random volume variation (%)
+    pub rand_pan   : u8,              // random panning variation
+    pub cutoff     : Option<u8>,      // initial filter cutoff
+    pub resonance  : Option<u8>,      // initial filter resonance
+    pub keyboard   : [(u8, u8); 120], // note and sample for each key
+    pub vol_env    : Envelope,
+    pub pan_env    : Envelope,
+    pub pitch_env  : Envelope,
+    pub filter_env : bool,            // pitch envelope is used as filter envelope
+}
+
+impl Default for ItInstrument {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ItInstrument {
+    pub fn new() -> Self {
+        let mut keyboard = [(0, 0); 120];
+        for (i, k) in keyboard.iter_mut().enumerate() {
+            k.0 = i as u8;
+        }
+
+        ItInstrument {
+            num        : 0,
+            name       : "".to_owned(),
+            filename   : "".to_owned(),
+            nna        : NoteAction::Cut,
+            dct        : DuplicateCheck::Off,
+            dca        : NoteAction::Cut,
+            fadeout    : 0,
+            pps        : 0,
+            ppc        : 60,
+            global_vol : 128,
+            default_pan: None,
+            rand_vol   : 0,
+            rand_pan   : 0,
+            cutoff     : None,
+            resonance  : None,
+            keyboard,
+            vol_env    : Envelope::new(),
+            pan_env    : Envelope::new(),
+            pitch_env  : Envelope::new(),
+            filter_env : false,
+        }
+    }
+}
+
+
+/// ItSample defines the Impulse Tracker sample parameters. The sample data
+/// is stored in the module sample list.
+#[derive(Debug,Default)]
+pub struct ItSample {
+    pub num        : usize,
+    pub name       : String,
+    pub filename   : String,
+    pub global_vol : u8,           // 0-64
+    pub flags      : u8,
+    pub volume     : u8,           // 0-64
+    pub cvt        : u8,
+    pub default_pan: Option<u8>,   // 0-64
+    pub c5speed    : u32,
+    pub vib_speed  : u8,
+    pub vib_depth  : u8,
+    pub vib_rate   : u8,
+    pub vib_type   : u8,
+}
+
+impl ItSample {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+
+/// ItEvent defines the event format used in Impulse Tracker patterns.
+#[derive(Clone,Debug,Default)]
+pub struct ItEvent {
+    pub note  : Option<u8>,   // 0-119 are notes, 255 is note off, 254 is note cut, others are note fade
+    pub ins   : u8,
+    pub volpan: Option<u8>,
+    pub cmd   : u8,
+    pub info  : u8,
+}
+
+impl ItEvent {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl fmt::Display for ItEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let note = match self.note {
+            None              => "...".to_owned(),
+            Some(IT_NOTE_OFF) => "===".to_owned(),
+            Some(IT_NOTE_CUT) => "^^^".to_owned(),
+            Some(n) if n > 119 => "~~~".to_owned(),
+            Some(n)           => format!("{}{}", NOTES[n as usize % 12], n / 12),
+        };
+
+        let ins = if self.ins == 0 {
+            "..".to_owned()
+        } else {
+            format!("{:02}", self.ins)
+        };
+
+        let vol = match self.volpan {
+            None    => "...".to_owned(),
+            Some(v) => format!("{:03}", v),
+        };
+
+        let cmd = if self.cmd == 0 {
+            '.'
+        } else {
+            (64_u8 + self.cmd) as char
+        };
+
+        write!(f, "{} {} {} {}{:02X}", note, ins, vol, cmd, self.info)
+    }
+}
+
+
+pub struct ItPattern {
+    pub rows: usize,
+    data    : Vec<ItEvent>,
+}
+
+impl ItPattern {
+    /// Unpack pattern data. Each event starts with a channel variable byte,
+    /// followed by an optional mask byte and the values selected by the mask.
+    /// Values not present in the event may be copied from the last values
+    /// used in the channel.
+    fn from_packed(rows: usize, b: &[u8]) -> Self {
+        let mut pat = ItPattern {
+            rows,
+            data: vec![ItEvent::new(); rows * 64],
+        };
+
+        let mut mask = [0_u8; 64];
+        let mut last = vec![ItEvent::new(); 64];
+
+        let mut i = 0;
+        let mut row = 0;
+        let next = |i: &mut usize| {
+            let val = if *i < b.len() { b[*i] } else { 0 };
+            *i += 1;
+            val
+        };
+
+        while row < rows {
+            let chnvar = next(&mut i);
+            if chnvar == 0 {
+                row += 1;
+                continue;
+            }
+
+            let chn = ((chnvar - 1) & 63) as usize;
+            if chnvar & 0x80 != 0 {
+                mask[chn] = next(&mut i);
+            }
+
+            let m = mask[chn];
+            let mut e = ItEvent::new();
+            if m & 0x01 != 0 {
+                last[chn].note = Some(next(&mut i));
+            }
+            if m & 0x02 != 0 {
+                last[chn].ins = next(&mut i);
+            }
+            if m & 0x04 != 0 {
+                last[chn].volpan = Some(next(&mut i));
+            }
+            if m & 0x08 != 0 {
+                last[chn].cmd = next(&mut i);
+                last[chn].info = next(&mut i);
+            }
+            if m & 0x11 != 0 {
+                e.note = last[chn].note;
+            }
+            if m & 0x22 != 0 {
+                e.ins = last[chn].ins;
+            }
+            if m & 0x44 != 0 {
+                e.volpan = last[chn].volpan;
+            }
+            if m & 0x88 != 0 {
+                e.cmd = last[chn].cmd;
+                e.info = last[chn].info;
+            }
+
+            pat.data[row * 64 + chn] = e;
+
+            // Stop at truncated pattern data
+            if i > b.len() {
+                break;
+            }
+        }
+
+        pat
+    }
+
+    pub fn event(&self, row: usize, chn: usize) -> &ItEvent {
+        &self.data[row * 64 + chn]
+    }
+
+    /// Return the highest channel number used in the pattern, plus one.
+    fn channels_used(&self) -> usize {
+        match self.data.iter().enumerate()
+                  .filter(|&(_, e)| e.note.is_some() || e.ins != 0 || e.volpan.is_some() || e.cmd != 0)
+                  .map(|(i, _)| i % 64)
+                  .max() {
+            Some(val) => val + 1,
+            None      => 0,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_packed() {
+        let pat = ItPattern::from_packed(3, &[
+            0x81, 0x0f, 0x3c, 0x01, 0x40, 0x01, 0x06,   // chn 0: C-5 01 064 A06
+            0x83, 0x08, 0x04, 0x20,                     // chn 2: D20
+            0x00,
+            0x81, 0xf0,                                 // chn 0: last values
+            0x00,
+            0x01,                                       // chn 0: same mask
+            0x00,
+        ]);
+
+        assert_eq!(format!("{}", pat.event(0, 0)), "C 5 01 064 A06");
+        assert_eq!(format!("{}", pat.event(0, 2)), "... .. ... D20");
+        assert_eq!(format!("{}", pat.event(1, 0)), "C 5 01 064 A06");
+        assert_eq!(format!("{}", pat.event(2, 0)), "C 5 01 064 A06");
+        assert_eq!(pat.channels_used(), 3);
+    }
+}
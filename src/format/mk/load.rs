@@ -9,6 +9,40 @@ use ::*;
 /// Protracker module loader
 pub struct ModLoader;
 
+/// Recognize the module magic at offset 1080 and return the number of
+/// channels and the tracker description for it.
+fn magic_info(magic: &[u8]) -> Option<(usize, &'static str)> {
+    let digit = |c: u8| if c.is_ascii_digit() { Some((c - b'0') as usize) } else { None };
+
+    match magic {
+        b"M.K." => Some((4, "Protracker M.K.")),
+        b"M!K!" => Some((4, "Protracker M!K!")),
+        b"M&K!" => Some((4, "Noisetracker M&K!")),
+        b"N.T." => Some((4, "Noisetracker N.T.")),
+        b"FLT4" => Some((4, "Startrekker FLT4")),
+        b"FLT8" => Some((8, "Startrekker FLT8")),
+        b"CD81" => Some((8, "Falcon CD81")),
+        b"OKTA" |
+        b"OCTA" => Some((8, "Oktalyzer OKTA")),
+        _       => {
+            // xCHN (2 to 9 channels) or xxCH (10 to 32 channels)
+            if &magic[1..] == b"CHN" {
+                match digit(magic[0]) {
+                    Some(n) if n >= 2 => Some((n, "Fasttracker xCHN")),
+                    _                 => None,
+                }
+            } else if &magic[2..] == b"CH" {
+                match (digit(magic[0]), digit(magic[1])) {
+                    (Some(h), Some(l)) if h * 10 + l >= 10 && h * 10 + l <= 32 => Some((h * 10 + l, "Fasttracker xxCH")),
+                    _                                                          => None,
+                }
+            } else {
+                None
+            }
+        },
+    }
+}
+
 impl ModLoader {
     fn load_instrument(&self, b: &[u8], i: usize) -> Result<(ModInstrument, Sample), Error> {
         let mut ins = ModInstrument::new();
@@ -54,10 +88,9 @@ impl Loader for ModLoader {
             return Err(Error::Format("file too short"));
         }
 
-        if b.read32b(1080)? == 0x4d2e4b2e {
-            Ok(())
-        } else {
-            Err(Error::Format("bad magic"))
+        match magic_info(b.slice(1080, 4)?) {
+            Some(_) => Ok(()),
+            None    => Err(Error::Format("bad magic")),
         }
     }
 
@@ -68,7 +101,7 @@ impl Loader for ModLoader {
         let mut instruments: Vec<ModInstrument> = Vec::new();
         let mut samples: Vec<Sample> = Vec::new();
         for i in 0..31 {
-            let (ins, smp) = try!(self.load_instrument(b, i));
+            let (ins, smp) = self.load_instrument(b, i)?;
             instruments.push(ins);
             samples.push(smp);
         }
@@ -79,25 +112,39 @@ impl Loader for ModLoader {
         let orders = b.slice(952, 128)?;
         let magic = b.slice(1080, 4)?;
 
+        let (chn, description) = match magic_info(magic) {
+            Some(val) => val,
+            None      => return Err(Error::Format("bad magic")),
+        };
+
         let mut pat = 0_usize;
         orders[..song_length].iter().for_each(|x| { pat = cmp::max(pat, *x as usize); } );
         pat += 1;
 
+        // Startrekker 8-channel patterns are pairs of 4-channel patterns, and
+        // orders refer to the 4-channel pattern numbers
+        let flt8 = magic == b"FLT8";
+        if flt8 {
+            pat = pat.div_ceil(2);
+        }
+
         // Load patterns
-        let patterns = ModPatterns::from_slice(pat, b.slice(1084, 1024*pat)?)?;
+        let pat_size = 256 * chn;
+        let patterns = ModPatterns::from_slice(pat, chn, flt8, b.slice(1084, pat_size*pat)?)?;
 
         // Load samples (sample size is set when loading instruments)
-        let mut ofs = 1084 + 1024*pat;
+        let mut ofs = 1084 + pat_size*pat;
         for i in 0..31 {
-            let size = samples[i].size as usize;
+            let size = samples[i].size;
             if size > 0 {
-                samples = try!(self.load_sample(b.slice(ofs, size)?, samples, i));
+                samples = self.load_sample(b.slice(ofs, size)?, samples, i)?;
                 ofs += size;
             }
         }
 
         let mut data = ModData{
             song_name,
+            channels: chn,
             instruments,
             song_length,
             restart,
@@ -110,9 +157,13 @@ impl Loader for ModLoader {
         data.orders.copy_from_slice(orders);
         data.magic.copy_from_slice(magic);
 
+        if flt8 {
+            data.orders.iter_mut().for_each(|x| *x >>= 1);
+        }
+
         let m = Module {
             format     : "mod",
-            description: "Protracker M.K.",
+            description,
             player     : "pt21",
             data       : Box::new(data),
         };
@@ -121,3 +172,22 @@ impl Loader for ModLoader {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_magic_info() {
+        assert_eq!(magic_info(b"M.K.").unwrap().0, 4);
+        assert_eq!(magic_info(b"6CHN").unwrap().0, 6);
+        assert_eq!(magic_info(b"8CHN").unwrap().0, 8);
+        assert_eq!(magic_info(b"16CH").unwrap().0, 16);
+        assert_eq!(magic_info(b"32CH").unwrap().0, 32);
+        assert_eq!(magic_info(b"FLT8").unwrap().0, 8);
+        assert_eq!(magic_info(b"CD81").unwrap().0, 8);
+        assert!(magic_info(b"1CHN").is_none());
+        assert!(magic_info(b"33CH").is_none());
+        assert!(magic_info(b"XXXX").is_none());
+    }
+}
@@ -4,16 +4,17 @@ pub use self::load::*;
 
 use std::any::Any;
 use std::fmt;
-use module::{ModuleData, Event, Sample};
+use module::{ModuleData, Event, Instrument, Sample};
 use util::{NOTES, BinaryRead};
 use ::*;
 
 
 pub struct ModData {
     pub song_name: String,
+    pub channels: usize,
     pub instruments: Vec<ModInstrument>,
     pub song_length: usize,
-    pub restart: u8,  // Noisetracker restart
+    pub restart: u8,  // Noisetracker restart, Ultimate Soundtracker tempo
     pub orders: [u8; 128],
     pub magic: [u8; 4],
     pub patterns: ModPatterns,
@@ -21,7 +22,7 @@ pub struct ModData {
 }
 
 impl ModuleData for ModData {
-    fn as_any(&self) -> &Any {
+    fn as_any(&self) -> &dyn Any {
         self
     }
 
@@ -30,7 +31,7 @@ impl ModuleData for ModData {
     }
 
     fn channels(&self) -> usize {
-        4
+        self.channels
     }
 
     fn patterns(&self) -> usize {
@@ -57,15 +58,18 @@ impl ModuleData for ModData {
         0
     }
 
-    fn instruments(&self) -> Vec<String> {
-        self.instruments.iter().map(|x| x.name.to_owned()).collect::<Vec<String>>()
+    fn instruments(&self) -> Vec<Instrument> {
+        self.instruments.iter().enumerate().map(|(i, x)| {
+            let smp = if self.samples[i].size > 0 { Some(i) } else { None };
+            Instrument::with_sample(i + 1, &x.name, x.volume, smp)
+        }).collect::<Vec<Instrument>>()
     }
 
     fn event(&self, num: usize, row: usize, chn: usize) -> Option<Event> {
-        if num >= self.patterns.num() || row >= 64 || chn >= 4 {
-           return None
+        if num >= self.patterns.num() || row >= 64 || chn >= self.channels {
+           None
         } else {
-           let p = &self.patterns.data[num*256 + row*4 + chn];
+           let p = self.patterns.event(num, row as u8, chn);
            Some(Event{
                note: p.note,
                ins : p.ins,
@@ -144,27 +148,34 @@ impl fmt::Display for ModEvent {
 
 
 pub struct ModPatterns {
-    num : usize,
-    data: Vec<ModEvent>,
+    num     : usize,
+    channels: usize,
+    data    : Vec<ModEvent>,
 }
 
 impl ModPatterns {
-    fn from_slice(num: usize, b: &[u8]) -> Result<Self, Error> {
+    /// Load `num` patterns with `chn` channels each. If `split` is set, each
+    /// pattern is stored as a sequence of 4-channel patterns (as in Startrekker
+    /// FLT8 modules) instead of having all channels in the same row.
+    pub fn from_slice(num: usize, chn: usize, split: bool, b: &[u8]) -> Result<Self, Error> {
         let mut pat = ModPatterns{
             num,
+            channels: chn,
             data: Vec::new(),
         };
 
+        let (row_chn, pat_size) = if split { (4, 1024) } else { (chn, 256 * chn) };
+
         for p in 0..num {
             for r in 0..64 {
-                for c in 0..4 {
-                    let ofs = p * 1024 + r * 16 + c * 4;
+                for c in 0..chn {
+                    let ofs = p * 256 * chn + (c / row_chn) * pat_size + r * 4 * row_chn + (c % row_chn) * 4;
                     let e = ModEvent::from_slice(b.slice(ofs, 4)?);
                     pat.data.push(e);
                 }
             }
         }
-        
+
         Ok(pat)
     }
 
@@ -172,15 +183,18 @@ impl ModPatterns {
         self.num
     }
 
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
     pub fn event(&self, pat: usize, row: u8, chn: usize) -> &ModEvent {
-        &self.data[pat * 256 + row as usize * 4 + chn]
+        &self.data[(pat * 64 + row as usize) * self.channels + chn]
     }
 }
 
 
 /// Protracker period table.
-
-static MT_PERIOD_TABLE: &'static [u16; 16*12*3] = &[
+static MT_PERIOD_TABLE: &[u16; 16*12*3] = &[
 // Tuning 0, Normal
     856, 808, 762, 720, 678, 640, 604, 570, 538, 508, 480, 453,
     428, 404, 381, 360, 339, 320, 302, 285, 269, 254, 240, 226,
@@ -296,3 +310,33 @@ impl PeriodTable {
         48 + (note % 36)
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patterns_split() {
+        // Two 4-channel patterns, each event has its instrument set to the
+        // channel number
+        let mut b = vec![0_u8; 2048];
+        for p in 0..2 {
+            for r in 0..64 {
+                for c in 0..4 {
+                    b[p * 1024 + r * 16 + c * 4 + 2] = ((p * 4 + c) as u8) << 4;
+                }
+            }
+        }
+
+        let pat = ModPatterns::from_slice(1, 8, true, &b).unwrap();
+        for c in 0..8 {
+            assert_eq!(pat.event(0, 0, c).ins as usize, c);
+            assert_eq!(pat.event(0, 63, c).ins as usize, c);
+        }
+
+        let pat = ModPatterns::from_slice(1, 8, false, &b).unwrap();
+        assert_eq!(pat.event(0, 0, 4).ins, 0);
+        assert_eq!(pat.event(0, 32, 4).ins, 4);
+    }
+}
@@ -1,22 +1,30 @@
 use module::Module;
 use ::*;
 
+pub mod it;
 pub mod mk;
+pub mod s3m;
+pub mod st;
 pub mod stm;
+pub mod xm;
 
 // Trait for module loader
 
 pub trait Loader {
     fn name(&self) -> &'static str;
-    fn probe(&self, &[u8]) -> Result<(), Error>;
-    fn load(self: Box<Self>, &[u8]) -> Result<Module, Error>;
+    fn probe(&self, _: &[u8]) -> Result<(), Error>;
+    fn load(self: Box<Self>, _: &[u8]) -> Result<Module, Error>;
 }
 
 
-pub fn list() -> Vec<Box<Loader>> {
+pub fn list() -> Vec<Box<dyn Loader>> {
     vec![
         Box::new(mk::ModLoader),
+        Box::new(st::StLoader),
         Box::new(stm::StmLoader),
+        Box::new(s3m::S3mLoader),
+        Box::new(xm::XmLoader),
+        Box::new(it::ItLoader),
     ]
 }
 
@@ -0,0 +1,223 @@
+use format::Loader;
+use format::s3m::{S3mData, S3mPatterns, S3mInstrument};
+use module::{Module, Sample};
+use module::sample::SampleType;
+use util::BinaryRead;
+use ::*;
+
+/// Scream Tracker 3 module loader
+pub struct S3mLoader;
+
+impl S3mLoader {
+    fn load_instrument(&self, b: &[u8], i: usize, ofs: usize, signed: bool) -> Result<(S3mInstrument, Sample), Error> {
+        let mut ins = S3mInstrument::new();
+        let mut smp = Sample::new();
+
+        ins.num = i + 1;
+        smp.num = i + 1;
+
+        if ofs == 0 {
+            return Ok((ins, smp))
+        }
+
+        ins.typ = b.read8(ofs)?;
+        ins.filename = b.read_string(ofs + 1, 12)?;
+        ins.name = b.read_string(ofs + 48, 28)?;
+        smp.name = ins.name.to_owned();
+
+        // Only sampled instruments have sample data
+        if ins.typ != 1 {
+            return Ok((ins, smp))
+        }
+
+        let memseg = ((b.read8(ofs + 13)? as usize) << 16) | b.read16l(ofs + 14)? as usize;
+        smp.size = b.read32l(ofs + 16)? as usize;
+        smp.loop_start = b.read32l(ofs + 20)? as usize;
+        smp.loop_end = b.read32l(ofs + 24)? as usize;
+        ins.volume = b.read8(ofs + 28)?;
+        let pack = b.read8(ofs + 30)?;
+        ins.flags = b.read8(ofs + 31)?;
+        ins.c2spd = b.read32l(ofs + 32)?;
+        smp.rate = ins.c2spd as f64;
+
+        // Packed (ADPCM) samples are not supported
+        if smp.size == 0 || pack != 0 {
+            smp.size = 0;
+            return Ok((ins, smp))
+        }
+
+        // Stereo samples store the left channel first, we play only that
+        let is_16bit = ins.flags & 0x04 != 0;
+        let bytes = if is_16bit { smp.size * 2 } else { smp.size };
+
+        let start = memseg * 16;
+        if start >= b.len() {
+            smp.size = 0;
+            return Ok((ins, smp))
+        }
+
+        // Allow truncated sample data in the last sample
+        let avail = b.len() - start;
+        let bytes = if bytes > avail { avail & !(is_16bit as usize) } else { bytes };
+        let mut data = b.slice(start, bytes)?.to_vec();
+
+        if is_16bit {
+            smp.size = bytes / 2;
+            smp.sample_type = SampleType::Sample16;
+            if !signed {
+                data.iter_mut().skip(1).step_by(2).for_each(|x| *x ^= 0x80);
+            }
+        } else {
+            smp.size = bytes;
+            smp.sample_type = SampleType::Sample8;
+            if !signed {
+                data.iter_mut().for_each(|x| *x ^= 0x80);
+            }
+        }
+
+        if smp.loop_end > smp.size {
+            smp.loop_end = smp.size;
+        }
+        smp.has_loop = ins.flags & 0x01 != 0 && smp.loop_start < smp.loop_end;
+
+        if smp.size > 0 {
+            smp.store(&data);
+        } else {
+            smp.sample_type = SampleType::Empty;
+        }
+
+        Ok((ins, smp))
+    }
+}
+
+impl Loader for S3mLoader {
+    fn name(&self) -> &'static str {
+        "Scream Tracker 3 S3M"
+    }
+
+    fn probe(&self, b: &[u8]) -> Result<(), Error> {
+        if b.len() < 0x60 {
+            return Err(Error::Format("file too short"));
+        }
+
+        if b.read32b(44)? == 0x5343524d && b.read8(29)? == 16 {  // SCRM
+            Ok(())
+        } else {
+            Err(Error::Format("bad magic"))
+        }
+    }
+
+    fn load(self: Box<Self>, b: &[u8]) -> Result<Module, Error> {
+        let song_name = b.read_string(0, 28)?;
+        let ord_num = b.read16l(32)? as usize;
+        let ins_num = b.read16l(34)? as usize;
+        let pat_num = b.read16l(36)? as usize;
+        let flags = b.read16l(38)?;
+        let cwt_v = b.read16l(40)?;
+        let ffi = b.read16l(42)?;
+        let global_vol = b.read8(48)?;
+        let initial_speed = b.read8(49)?;
+        let initial_tempo = b.read8(50)?;
+        let master_vol = b.read8(51)?;
+        let default_pan = b.read8(53)?;
+
+        let mut ch_settings = [0_u8; 32];
+        ch_settings.copy_from_slice(b.slice(64, 32)?);
+
+        // Number of channels is the last enabled channel
+        let channels = match ch_settings.iter().rposition(|&x| x < 16) {
+            Some(val) => val + 1,
+            None      => return Err(Error::Load("no channels enabled")),
+        };
+
+        // Channel panning: left channels are 0-7, right channels are 8-15
+        let stereo = master_vol & 0x80 != 0;
+        let mut ch_pan = [0x07_u8; 32];
+        for i in 0..32 {
+            if stereo && ch_settings[i] < 16 {
+                ch_pan[i] = if ch_settings[i] < 8 { 0x03 } else { 0x0c };
+            }
+        }
+
+        // Load orders
+        let orders = b.slice(96, ord_num)?.to_vec();
+
+        let mut ofs = 96 + ord_num;
+        let mut ins_pp = Vec::<usize>::new();
+        for _ in 0..ins_num {
+            ins_pp.push(b.read16l(ofs)? as usize * 16);
+            ofs += 2;
+        }
+
+        let mut pat_pp = Vec::<usize>::new();
+        for _ in 0..pat_num {
+            pat_pp.push(b.read16l(ofs)? as usize * 16);
+            ofs += 2;
+        }
+
+        // Default panning
+        if default_pan == 0xfc {
+            let pan = b.slice(ofs, 32)?;
+            for i in 0..32 {
+                if pan[i] & 0x20 != 0 {
+                    ch_pan[i] = pan[i] & 0x0f;
+                }
+            }
+        }
+
+        // Load instruments and samples
+        let mut instruments = Vec::<S3mInstrument>::new();
+        let mut samples = Vec::<Sample>::new();
+        for (i, &pp) in ins_pp.iter().enumerate() {
+            let (ins, smp) = self.load_instrument(b, i, pp, ffi != 2)?;
+            instruments.push(ins);
+            samples.push(smp);
+        }
+
+        // Load patterns
+        let mut patterns = S3mPatterns{
+            channels,
+            data: Vec::new(),
+        };
+
+        for &pp in &pat_pp {
+            if pp == 0 {
+                patterns.push_packed(&[]);
+                continue;
+            }
+            let size = b.read16l(pp)? as usize;
+            let start = pp + 2;
+            let size = if start + size > b.len() { b.len().saturating_sub(start) } else { size };
+            patterns.push_packed(b.slice(start, size)?);
+        }
+
+        let data = S3mData{
+            song_name,
+            ord_num,
+            ins_num,
+            pat_num,
+            flags,
+            cwt_v,
+            global_vol,
+            initial_speed,
+            initial_tempo,
+            master_vol,
+            channels,
+            ch_settings,
+            ch_pan,
+            orders,
+            instruments,
+            patterns,
+            samples,
+        };
+
+        let m = Module {
+            format     : "s3m",
+            description: "Scream Tracker 3 S3M",
+            player     : "st3",
+            data       : Box::new(data),
+        };
+
+        Ok(m)
+    }
+}
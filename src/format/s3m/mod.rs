@@ -0,0 +1,276 @@
+
+pub mod load;
+
+pub use self::load::*;
+
+use std::any::Any;
+use std::fmt;
+use module::{ModuleData, Event, Instrument, Sample};
+use module::event::KEY_CUT;
+use util::NOTES;
+
+
+pub struct S3mData {
+    pub song_name    : String,
+    pub ord_num      : usize,
+    pub ins_num      : usize,
+    pub pat_num      : usize,
+    pub flags        : u16,
+    pub cwt_v        : u16,
+    pub global_vol   : u8,
+    pub initial_speed: u8,
+    pub initial_tempo: u8,
+    pub master_vol   : u8,
+    pub channels     : usize,
+    pub ch_settings  : [u8; 32],
+    pub ch_pan       : [u8; 32],
+    pub orders       : Vec<u8>,
+    pub instruments  : Vec<S3mInstrument>,
+    pub patterns     : S3mPatterns,
+    pub samples      : Vec<Sample>,
+}
+
+impl S3mData {
+    /// Fast volume slides are used if the flag is set or if the module was
+    /// created with Scream Tracker 3.00.
+    pub fn fast_volslides(&self) -> bool {
+        self.flags & 0x40 != 0 || self.cwt_v == 0x1300
+    }
+
+    /// Check if the channel is enabled in the channel settings.
+    pub fn channel_enabled(&self, chn: usize) -> bool {
+        chn < 32 && self.ch_settings[chn] < 16
+    }
+}
+
+impl ModuleData for S3mData {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn title(&self) -> &str {
+        &self.song_name
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn patterns(&self) -> usize {
+        self.pat_num
+    }
+
+    fn len(&self) -> usize {
+        self.orders.iter().position(|&x| x == 255).unwrap_or(self.orders.len())
+    }
+
+    fn pattern_in_position(&self, pos: usize) -> Option<usize> {
+        if pos >= self.orders.len() {
+            None
+        } else {
+            Some(self.orders[pos] as usize)
+        }
+    }
+
+    fn next_position(&self, _pos: usize) -> usize {
+        0
+    }
+
+    fn prev_position(&self, _pos: usize) -> usize {
+        0
+    }
+
+    fn instruments(&self) -> Vec<Instrument> {
+        self.instruments.iter().enumerate().map(|(i, x)| {
+            let smp = if self.samples[i].size > 0 { Some(i) } else { None };
+            Instrument::with_sample(i + 1, &x.name, x.volume as usize, smp)
+        }).collect::<Vec<Instrument>>()
+    }
+
+    fn event(&self, num: usize, row: usize, chn: usize) -> Option<Event> {
+        if num >= self.pat_num || row >= 64 || chn >= self.channels {
+           None
+        } else {
+           let e = self.patterns.event(num, row, chn);
+           Some(Event{
+               note: match e.note { 255 => 0, 254 => KEY_CUT, _ => e.key() },
+               ins : e.ins,
+               vol : if e.vol == 255 { 0 } else { e.vol + 1 },
+               fxt : e.cmd,
+               fxp : e.info,
+           })
+        }
+    }
+
+    fn rows(&self, pat: usize) -> usize {
+        if pat >= self.pat_num {
+            0
+        } else {
+            64
+        }
+    }
+
+    fn samples(&self) -> &Vec<Sample> {
+        &self.samples
+    }
+}
+
+
+/// S3mInstrument defines extra instrument fields used in Scream Tracker 3 instruments.
+#[derive(Debug,Default)]
+pub struct S3mInstrument {
+    pub num     : usize,
+    pub typ     : u8,
+    pub filename: String,
+    pub name    : String,
+    pub volume  : u8,
+    pub flags   : u8,
+    pub c2spd   : u32,
+}
+
+impl S3mInstrument {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+
+/// S3mEvent defines the event format used in Scream Tracker 3 patterns.
+#[derive(Clone,Debug)]
+pub struct S3mEvent {
+    pub note: u8,   // high nibble is octave, low nibble is note; 254 is note off, 255 is empty
+    pub ins : u8,
+    pub vol : u8,   // 255 is empty
+    pub cmd : u8,
+    pub info: u8,
+}
+
+impl Default for S3mEvent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl S3mEvent {
+    pub fn new() -> Self {
+        S3mEvent {
+            note: 255,
+            ins : 0,
+            vol : 255,
+            cmd : 0,
+            info: 0,
+        }
+    }
+
+    /// Return the note number with octave 0 note C mapped to 12.
+    pub fn key(&self) -> u8 {
+        12 * ((self.note >> 4) + 1) + (self.note & 0x0f)
+    }
+}
+
+impl fmt::Display for S3mEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let note = match self.note {
+            255 => "...".to_owned(),
+            254 => "^^.".to_owned(),
+            _   => format!("{}{}", NOTES[(self.note & 0x0f) as usize % 12], self.note >> 4),
+        };
+
+        let ins = if self.ins == 0 {
+            "..".to_owned()
+        } else {
+            format!("{:02}", self.ins)
+        };
+
+        let vol = if self.vol == 255 {
+            "..".to_owned()
+        } else {
+            format!("{:02}", self.vol)
+        };
+
+        let cmd = if self.cmd == 0 {
+            '.'
+        } else {
+            (64_u8 + self.cmd) as char
+        };
+
+        write!(f, "{} {} {} {}{:02X}", note, ins, vol, cmd, self.info)
+    }
+}
+
+
+pub struct S3mPatterns {
+    channels: usize,
+    data    : Vec<S3mEvent>,
+}
+
+impl S3mPatterns {
+    /// Unpack a pattern from its packed representation (without the length
+    /// header) and append it to the pattern list.
+    fn push_packed(&mut self, b: &[u8]) {
+        let ofs = self.data.len();
+        self.data.extend(vec![S3mEvent::new(); 64 * self.channels]);
+
+        let mut i = 0;
+        let mut row = 0;
+        while row < 64 && i < b.len() {
+            let what = b[i];
+            i += 1;
+
+            if what == 0 {
+                row += 1;
+                continue;
+            }
+
+            let mut e = S3mEvent::new();
+            if what & 0x20 != 0 && i + 2 <= b.len() {
+                e.note = b[i];
+                e.ins = b[i + 1];
+                i += 2;
+            }
+            if what & 0x40 != 0 && i < b.len() {
+                e.vol = b[i];
+                i += 1;
+            }
+            if what & 0x80 != 0 && i + 2 <= b.len() {
+                e.cmd = b[i];
+                e.info = b[i + 1];
+                i += 2;
+            }
+
+            let chn = (what & 0x1f) as usize;
+            if chn < self.channels {
+                self.data[ofs + row * self.channels + chn] = e;
+            }
+        }
+    }
+
+    pub fn event(&self, pat: usize, row: usize, chn: usize) -> &S3mEvent {
+        &self.data[(pat * 64 + row) * self.channels + chn]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_packed() {
+        let mut pat = S3mPatterns{ channels: 4, data: Vec::new() };
+        pat.push_packed(&[
+            0x21, 0x40, 0x01,        // chn 1: C-4 01
+            0xc3, 0x20, 0x04, 0x12,  // chn 3: vol 32, D12
+            0x00,
+            0x00,
+            0x82, 0x01, 0x06,        // chn 2: A06
+            0x00,
+        ]);
+
+        assert_eq!(format!("{}", pat.event(0, 0, 1)), "C 4 01 .. .00");
+        assert_eq!(format!("{}", pat.event(0, 0, 3)), "... .. 32 D12");
+        assert_eq!(format!("{}", pat.event(0, 2, 2)), "... .. .. A06");
+        assert_eq!(format!("{}", pat.event(0, 3, 2)), "... .. .. .00");
+        assert_eq!(pat.event(0, 0, 1).key(), 60);
+    }
+}
@@ -0,0 +1,255 @@
+use std::cmp;
+use format::Loader;
+use format::mk::{ModData, ModPatterns, ModInstrument, PeriodTable};
+use module::{Module, Sample};
+use module::sample::SampleType;
+use util::{self, BinaryRead};
+use ::*;
+
+/// Ultimate Soundtracker module loader
+///
+/// Loads the original 15-instrument Soundtracker modules. These modules
+/// have no magic signature, so detection relies on a set of heuristics
+/// applied to the module header and pattern data.
+pub struct StLoader;
+
+/// Check if a name contains only printable characters or padding.
+fn is_printable(b: &[u8]) -> bool {
+    b.iter().all(|&c| c == 0 || (32..=126).contains(&c))
+}
+
+impl StLoader {
+    fn load_instrument(&self, b: &[u8], i: usize) -> Result<(ModInstrument, Sample), Error> {
+        let mut ins = ModInstrument::new();
+        let mut smp = Sample::new();
+
+        let ofs = 20 + i * 30;
+        ins.name = b.read_string(ofs, 22)?;
+        smp.name = ins.name.to_owned();
+        smp.num = i + 1;
+
+        smp.size = b.read16b(ofs + 22)? as usize * 2;
+        ins.volume = b.read8(ofs + 25)? as usize;
+
+        // Loop start is in bytes, not words
+        smp.loop_start = b.read16b(ofs + 26)? as usize;
+        let loop_size = b.read16b(ofs + 28)? as usize * 2;
+        smp.loop_end = smp.loop_start + loop_size;
+        smp.has_loop = loop_size > 2;
+
+        if smp.has_loop {
+            if smp.loop_start > 0 {
+                // Only the looped part of the sample is played
+                smp.size = loop_size;
+                smp.loop_start = 0;
+                smp.loop_end = loop_size;
+            } else {
+                // Whole sample is played before entering the loop
+                smp.loop_full = true;
+            }
+        }
+
+        smp.rate = util::C4_PAL_RATE;
+        if smp.size > 0 {
+            smp.sample_type = SampleType::Sample8;
+        }
+
+        Ok((ins, smp))
+    }
+
+    // Compute the number of patterns referenced in the order list and check it
+    // against the pattern data available in the file.
+    fn pattern_count(&self, b: &[u8]) -> Result<usize, Error> {
+        let song_length = b.read8(470)? as usize;
+        if song_length == 0 || song_length > 128 {
+            return Err(Error::Format("invalid song length"));
+        }
+
+        let orders = b.slice(472, 128)?;
+        if orders.iter().any(|&x| x > 0x7f) {
+            return Err(Error::Format("invalid pattern number"));
+        }
+
+        let pat = *orders[..song_length].iter().max().unwrap() as usize + 1;
+
+        let mut smp_size = 0;
+        for i in 0..15 {
+            smp_size += b.read16b(20 + i * 30 + 22)? as usize * 2;
+        }
+
+        // File size must match the sample and pattern data size, allowing
+        // for small truncation or padding
+        let size = 600 + 1024 * pat + smp_size;
+        if b.len() < 600 + 1024 * pat || (b.len() as isize - size as isize).abs() >= 1024 {
+            return Err(Error::Format("bad file size"));
+        }
+
+        Ok(pat)
+    }
+}
+
+impl Loader for StLoader {
+    fn name(&self) -> &'static str {
+        "Ultimate Soundtracker"
+    }
+
+    fn probe(&self, b: &[u8]) -> Result<(), Error> {
+        if b.len() < 600 + 1024 {
+            return Err(Error::Format("file too short"));
+        }
+
+        if !is_printable(b.slice(0, 20)?) {
+            return Err(Error::Format("bad song name"));
+        }
+
+        for i in 0..15 {
+            let ofs = 20 + i * 30;
+            if !is_printable(b.slice(ofs, 22)?) {
+                return Err(Error::Format("bad instrument name"));
+            }
+
+            let size = b.read16b(ofs + 22)? as usize;
+            let finetune = b.read8(ofs + 24)?;
+            let volume = b.read8(ofs + 25)?;
+            let loop_start = b.read16b(ofs + 26)? as usize;
+            let loop_size = b.read16b(ofs + 28)? as usize;
+
+            if size > 0x8000 || finetune != 0 || volume > 0x40 {
+                return Err(Error::Format("bad instrument"));
+            }
+
+            if loop_size > 1 && loop_start + loop_size * 2 > size * 2 {
+                return Err(Error::Format("bad sample loop"));
+            }
+        }
+
+        let pat = self.pattern_count(b)?;
+
+        // Check pattern data for valid instruments and note periods
+        for e in b.slice(600, 1024 * pat)?.chunks(4) {
+            if e[0] & 0xf0 != 0 {
+                return Err(Error::Format("bad instrument in pattern"));
+            }
+            let period = ((e[0] as u16 & 0x0f) << 8) | e[1] as u16;
+            if period != 0 && PeriodTable::note_to_period(PeriodTable::period_to_note(period, 0), 0) != period {
+                return Err(Error::Format("bad note in pattern"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn load(self: Box<Self>, b: &[u8]) -> Result<Module, Error> {
+        let song_name = b.read_string(0, 20)?;
+
+        // Load instruments
+        let mut instruments: Vec<ModInstrument> = Vec::new();
+        let mut samples: Vec<Sample> = Vec::new();
+        for i in 0..15 {
+            let (ins, smp) = self.load_instrument(b, i)?;
+            instruments.push(ins);
+            samples.push(smp);
+        }
+
+        // Load orders
+        let song_length = b.read8(470)? as usize;
+        let tempo = b.read8(471)?;
+        let orders = b.slice(472, 128)?;
+
+        // Load patterns
+        let pat = self.pattern_count(b)?;
+        let patterns = ModPatterns::from_slice(pat, 4, false, b.slice(600, 1024*pat)?)?;
+
+        // Load samples, the last sample may be truncated
+        let mut ofs = 600 + 1024*pat;
+        for (i, smp) in samples.iter_mut().enumerate() {
+            let size = b.read16b(20 + i * 30 + 22)? as usize * 2;
+            if size == 0 {
+                continue;
+            }
+
+            let start = if smp.loop_full || !smp.has_loop {
+                ofs
+            } else {
+                ofs + b.read16b(20 + i * 30 + 26)? as usize
+            };
+
+            let end = cmp::min(start + smp.size, b.len());
+            if start < end {
+                smp.size = end - start;
+                smp.store(b.slice(start, end - start)?);
+                if smp.loop_end > smp.size {
+                    smp.loop_end = smp.size;
+                }
+            } else {
+                smp.size = 0;
+                smp.sample_type = SampleType::Empty;
+            }
+            ofs += size;
+        }
+
+        let mut data = ModData{
+            song_name,
+            channels: 4,
+            instruments,
+            song_length,
+            restart: tempo,
+            orders: [0; 128],
+            magic: [0; 4],
+            patterns,
+            samples,
+        };
+
+        data.orders.copy_from_slice(orders);
+
+        let m = Module {
+            format     : "st",
+            description: "Ultimate Soundtracker",
+            player     : "ust",
+            data       : Box::new(data),
+        };
+
+        Ok(m)
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn build_module(sample_size: u16) -> Vec<u8> {
+        let mut b = vec![0_u8; 600 + 1024];
+        b[..8].copy_from_slice(b"st song!");
+        b[20..26].copy_from_slice(b"st-01:");
+        b[42] = (sample_size >> 8) as u8;
+        b[43] = sample_size as u8;
+        b[45] = 64;
+        b[470] = 1;
+        b[471] = 0x78;
+        b[600..604].copy_from_slice(&[0x01, 0xac, 0x00, 0x00]);  // C-2
+        b.extend(vec![0; sample_size as usize * 2]);
+        b
+    }
+
+    #[test]
+    fn test_probe() {
+        let b = build_module(1000);
+        assert!(StLoader.probe(&b).is_ok());
+
+        // bad instrument name
+        let mut b = build_module(1000);
+        b[21] = 0x80;
+        assert!(StLoader.probe(&b).is_err());
+
+        // bad note period
+        let mut b = build_module(1000);
+        b[601] = 0xab;
+        assert!(StLoader.probe(&b).is_err());
+
+        // file size doesn't match the pattern count
+        let mut b = build_module(1000);
+        b.extend(vec![0; 2048]);
+        assert!(StLoader.probe(&b).is_err());
+    }
+}
@@ -0,0 +1,4 @@
+
+pub mod load;
+
+pub use self::load::*;
@@ -15,7 +15,7 @@ impl StmLoader {
 
         let ofs = 48 + i * 32;
         ins.num = i + 1;
-        smp.num = i + 1;;
+        smp.num = i + 1;
         ins.name = b.read_string(ofs, 12)?;
         smp.size = b.read16l(ofs + 16)? as usize;
         smp.loop_start = b.read16l(ofs + 18)? as usize;
@@ -81,7 +81,7 @@ impl Loader for StmLoader {
 
         // Load instruments
         for i in 0..31 {
-            let (ins, smp) = try!(self.load_instrument(b, i));
+            let (ins, smp) = self.load_instrument(b, i)?;
             instruments.push(ins);
             samples.push(smp);
         }
@@ -95,9 +95,9 @@ impl Loader for StmLoader {
         // Load samples
         let mut ofs = 1168 + 1024*num_patterns as usize;
         for i in 0..31 {
-            let size = samples[i].size as usize;
+            let size = samples[i].size;
             if size > 0 {
-                samples = try!(self.load_sample(b.slice(ofs, size)?, samples, i));
+                samples = self.load_sample(b.slice(ofs, size)?, samples, i)?;
                 ofs += size;
             }
         }
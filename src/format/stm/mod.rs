@@ -4,7 +4,7 @@ pub use self::load::*;
 
 use std::any::Any;
 use std::fmt;
-use module::{ModuleData, Event, Sample};
+use module::{ModuleData, Event, Instrument, Sample};
 use util::{NOTES, BinaryRead};
 use ::*;
 
@@ -21,7 +21,7 @@ pub struct StmData {
 }
 
 impl ModuleData for StmData {
-    fn as_any(&self) -> &Any {
+    fn as_any(&self) -> &dyn Any {
         self
     }
 
@@ -63,13 +63,16 @@ impl ModuleData for StmData {
         0
     }
 
-    fn instruments(&self) -> Vec<String> {
-        self.instruments.iter().map(|x| x.name.to_owned()).collect::<Vec<String>>()
+    fn instruments(&self) -> Vec<Instrument> {
+        self.instruments.iter().enumerate().map(|(i, x)| {
+            let smp = if self.samples[i].size > 0 { Some(i) } else { None };
+            Instrument::with_sample(i + 1, &x.name, x.volume, smp)
+        }).collect::<Vec<Instrument>>()
     }
 
     fn event(&self, num: usize, row: usize, chn: usize) -> Option<Event> {
         if num >= self.num_patterns as usize || row >= 64 || chn >= 4 {
-           return None
+           None
         } else {
            let p = &self.patterns.data[num*256 + row*4 + chn];
            Some(Event{
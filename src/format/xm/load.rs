@@ -0,0 +1,280 @@
+use format::Loader;
+use format::xm::{XmData, XmPattern, XmInstrument, XmSample};
+use module::{Module, Sample};
+use module::sample::SampleType;
+use module::instrument::{Envelope, EnvelopePoint};
+use util::BinaryRead;
+use ::*;
+
+const SAMPLE_HEADER_SIZE: usize = 40;
+
+/// Fasttracker 2 module loader
+pub struct XmLoader;
+
+impl XmLoader {
+    fn load_pattern(&self, b: &[u8], ofs: usize, channels: usize) -> Result<(XmPattern, usize), Error> {
+        let header_size = b.read32l(ofs)? as usize;
+        let rows = b.read16l(ofs + 5)? as usize;
+        let size = b.read16l(ofs + 7)? as usize;
+
+        if rows == 0 || rows > 256 {
+            return Err(Error::Load("invalid number of rows"));
+        }
+
+        let start = ofs + header_size;
+        let pat = XmPattern::from_packed(rows, channels, b.slice(start, size)?);
+
+        Ok((pat, start + size))
+    }
+
+    // Load envelope 0 (volume) or 1 (panning) from the instrument header at ofs
+    fn load_envelope(&self, b: &[u8], ofs: usize, n: usize) -> Result<Envelope, Error> {
+        let mut env = Envelope::new();
+
+        let num = b.read8(ofs + 225 + n)? as usize;
+        let sus = b.read8(ofs + 227 + n * 3)? as usize;
+        let typ = b.read8(ofs + 233 + n)?;
+
+        env.enabled = typ & 0x01 != 0;
+        env.has_sustain = typ & 0x02 != 0;
+        env.has_loop = typ & 0x04 != 0;
+        env.sus_start = sus;
+        env.sus_end = sus;
+        env.loop_start = b.read8(ofs + 228 + n * 3)? as usize;
+        env.loop_end = b.read8(ofs + 229 + n * 3)? as usize;
+
+        let points = ofs + 129 + n * 48;
+        for i in 0..num.min(12) {
+            env.points.push(EnvelopePoint{
+                x: b.read16l(points + i * 4)? as usize,
+                y: b.read16l(points + i * 4 + 2)? as isize,
+            });
+        }
+
+        if env.points.is_empty() {
+            env.enabled = false;
+        }
+
+        Ok(env)
+    }
+
+    fn load_instrument(&self, b: &[u8], ofs: usize, i: usize, samples: &mut Vec<Sample>) -> Result<(XmInstrument, usize), Error> {
+        let mut ins = XmInstrument::new();
+        ins.num = i + 1;
+
+        let ins_size = b.read32l(ofs)? as usize;
+        ins.name = b.read_string(ofs + 4, 22)?;
+        let smp_num = b.read16l(ofs + 27)? as usize;
+
+        if smp_num == 0 {
+            return Ok((ins, ofs + ins_size))
+        }
+
+        ins.keymap.copy_from_slice(b.slice(ofs + 33, 96)?);
+        ins.vol_env = self.load_envelope(b, ofs, 0)?;
+        ins.pan_env = self.load_envelope(b, ofs, 1)?;
+        ins.vib_type = b.read8(ofs + 235)?;
+        ins.vib_sweep = b.read8(ofs + 236)?;
+        ins.vib_depth = b.read8(ofs + 237)?;
+        ins.vib_rate = b.read8(ofs + 238)?;
+        ins.fadeout = b.read16l(ofs + 239)? as usize;
+
+        // Sample headers
+        let mut ofs = ofs + ins_size;
+        let mut flags = Vec::<u8>::new();
+        for _ in 0..smp_num {
+            let mut smp = Sample::new();
+            let mut sub = XmSample::new();
+
+            smp.num = samples.len() + 1;
+            smp.size = b.read32l(ofs)? as usize;
+            smp.loop_start = b.read32l(ofs + 4)? as usize;
+            let loop_size = b.read32l(ofs + 8)? as usize;
+            sub.volume = b.read8(ofs + 12)?.min(64);
+            sub.finetune = b.read8i(ofs + 13)?;
+            let typ = b.read8(ofs + 14)?;
+            sub.pan = b.read8(ofs + 15)?;
+            sub.relnote = b.read8i(ofs + 16)?;
+            sub.name = b.read_string(ofs + 18, 22)?;
+            smp.name = sub.name.to_owned();
+            smp.loop_end = smp.loop_start + loop_size;
+            smp.rate = 8363.0;
+
+            // 16-bit sample sizes are in bytes
+            if typ & 0x10 != 0 {
+                smp.size >>= 1;
+                smp.loop_start >>= 1;
+                smp.loop_end >>= 1;
+            }
+
+            sub.smp = samples.len();
+            ins.samples.push(sub);
+            samples.push(smp);
+            flags.push(typ);
+            ofs += SAMPLE_HEADER_SIZE;
+        }
+
+        // Sample data
+        let first = samples.len() - smp_num;
+        for (smp, &typ) in samples[first..].iter_mut().zip(flags.iter()) {
+            let is_16bit = typ & 0x10 != 0;
+            let bytes = if is_16bit { smp.size * 2 } else { smp.size };
+
+            // Allow truncated sample data in the last sample
+            let avail = b.len().saturating_sub(ofs);
+            let bytes = if bytes > avail { avail & !(is_16bit as usize) } else { bytes };
+            let mut data = b.slice(ofs, bytes)?.to_vec();
+            ofs += bytes;
+
+            if is_16bit {
+                smp.size = bytes / 2;
+                smp.sample_type = SampleType::Sample16;
+                delta_decode_16(&mut data);
+            } else {
+                smp.size = bytes;
+                smp.sample_type = SampleType::Sample8;
+                delta_decode_8(&mut data);
+            }
+
+            if smp.loop_end > smp.size {
+                smp.loop_end = smp.size;
+            }
+            smp.has_loop = typ & 0x03 != 0 && smp.loop_start < smp.loop_end;
+            smp.loop_bidir = smp.has_loop && typ & 0x03 == 2;
+
+            if smp.size > 0 {
+                smp.store(&data);
+            } else {
+                smp.sample_type = SampleType::Empty;
+            }
+        }
+
+        Ok((ins, ofs))
+    }
+}
+
+fn delta_decode_8(data: &mut [u8]) {
+    let mut old = 0_u8;
+    for x in data.iter_mut() {
+        old = old.wrapping_add(*x);
+        *x = old;
+    }
+}
+
+fn delta_decode_16(data: &mut [u8]) {
+    let mut old = 0_u16;
+    for x in data.chunks_mut(2) {
+        old = old.wrapping_add(x[0] as u16 | (x[1] as u16) << 8);
+        x[0] = old as u8;
+        x[1] = (old >> 8) as u8;
+    }
+}
+
+impl Loader for XmLoader {
+    fn name(&self) -> &'static str {
+        "Fasttracker 2 XM"
+    }
+
+    fn probe(&self, b: &[u8]) -> Result<(), Error> {
+        if b.len() < 80 {
+            return Err(Error::Format("file too short"));
+        }
+
+        if b.read_string(0, 17)? != "Extended Module: " {
+            return Err(Error::Format("bad magic"));
+        }
+
+        if b.read16l(58)? < 0x0104 {
+            return Err(Error::Format("unsupported XM version"));
+        }
+
+        Ok(())
+    }
+
+    fn load(self: Box<Self>, b: &[u8]) -> Result<Module, Error> {
+        let song_name = b.read_string(17, 20)?;
+        let tracker_name = b.read_string(38, 20)?;
+        let version = b.read16l(58)?;
+        let header_size = b.read32l(60)? as usize;
+        let song_length = b.read16l(64)? as usize;
+        let restart = b.read16l(66)? as usize;
+        let channels = b.read16l(68)? as usize;
+        let pat_num = b.read16l(70)? as usize;
+        let ins_num = b.read16l(72)? as usize;
+        let flags = b.read16l(74)?;
+        let default_speed = b.read16l(76)? as usize;
+        let default_tempo = b.read16l(78)? as usize;
+
+        if channels == 0 || channels > MAX_CHANNELS {
+            return Err(Error::Load("invalid number of channels"));
+        }
+
+        if song_length > 256 {
+            return Err(Error::Load("invalid song length"));
+        }
+
+        let orders = b.slice(80, song_length)?.to_vec();
+
+        // Load patterns
+        let mut ofs = 60 + header_size;
+        let mut patterns = Vec::<XmPattern>::new();
+        for _ in 0..pat_num {
+            let (pat, next) = self.load_pattern(b, ofs, channels)?;
+            patterns.push(pat);
+            ofs = next;
+        }
+
+        // Load instruments and samples
+        let mut instruments = Vec::<XmInstrument>::new();
+        let mut samples = Vec::<Sample>::new();
+        for i in 0..ins_num {
+            let (ins, next) = self.load_instrument(b, ofs, i, &mut samples)?;
+            instruments.push(ins);
+            ofs = next;
+        }
+
+        let data = XmData{
+            song_name,
+            tracker_name,
+            version,
+            song_length,
+            restart,
+            channels,
+            pat_num,
+            ins_num,
+            flags,
+            default_speed,
+            default_tempo,
+            orders,
+            patterns,
+            instruments,
+            samples,
+        };
+
+        let m = Module {
+            format     : "xm",
+            description: "Fasttracker 2 XM",
+            player     : "ft2",
+            data       : Box::new(data),
+        };
+
+        Ok(m)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_decode() {
+        let mut data = vec![0x01, 0x01, 0xfe, 0x10];
+        delta_decode_8(&mut data);
+        assert_eq!(data, vec![0x01, 0x02, 0x00, 0x10]);
+
+        let mut data = vec![0x00, 0x01, 0xff, 0xff, 0x01, 0x00];
+        delta_decode_16(&mut data);
+        assert_eq!(data, vec![0x00, 0x01, 0xff, 0x00, 0x00, 0x01]);
+    }
+}
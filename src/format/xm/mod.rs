@@ -0,0 +1,305 @@
+
+pub mod load;
+
+pub use self::load::*;
+
+use std::any::Any;
+use std::fmt;
+use module::{ModuleData, Event, Instrument, Sample};
+use module::event::KEY_OFF;
+use module::instrument::Envelope;
+use util::NOTES;
+
+pub const XM_KEY_OFF: u8 = 97;
+
+
+pub struct XmData {
+    pub song_name    : String,
+    pub tracker_name : String,
+    pub version      : u16,
+    pub song_length  : usize,
+    pub restart      : usize,
+    pub channels     : usize,
+    pub pat_num      : usize,
+    pub ins_num      : usize,
+    pub flags        : u16,
+    pub default_speed: usize,
+    pub default_tempo: usize,
+    pub orders       : Vec<u8>,
+    pub patterns     : Vec<XmPattern>,
+    pub instruments  : Vec<XmInstrument>,
+    pub samples      : Vec<Sample>,
+}
+
+impl XmData {
+    /// Check if the module uses linear frequencies.
+    pub fn linear_freq(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+}
+
+impl ModuleData for XmData {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn title(&self) -> &str {
+        &self.song_name
+    }
+
+    fn channels(&self) -> usize {
+        self.channels
+    }
+
+    fn patterns(&self) -> usize {
+        self.pat_num
+    }
+
+    fn len(&self) -> usize {
+        self.song_length
+    }
+
+    fn pattern_in_position(&self, pos: usize) -> Option<usize> {
+        if pos >= self.song_length {
+            None
+        } else {
+            Some(self.orders[pos] as usize)
+        }
+    }
+
+    fn next_position(&self, _pos: usize) -> usize {
+        0
+    }
+
+    fn prev_position(&self, _pos: usize) -> usize {
+        0
+    }
+
+    fn instruments(&self) -> Vec<Instrument> {
+        self.instruments.iter().map(|x| {
+            let mut ins = Instrument::new();
+            ins.num = x.num;
+            ins.name = x.name.to_owned();
+            ins.volume = 64;
+            for (k, &s) in x.keymap.iter().enumerate() {
+                if let Some(sub) = x.samples.get(s as usize) {
+                    // XM note 1 (C-0) is key 12
+                    ins.keymap[k + 12] = Some(sub.smp);
+                }
+            }
+            ins.vol_env = x.vol_env.clone();
+            ins.pan_env = x.pan_env.clone();
+            ins.fade_out = x.fadeout;
+            ins
+        }).collect::<Vec<Instrument>>()
+    }
+
+    fn event(&self, num: usize, row: usize, chn: usize) -> Option<Event> {
+        if num >= self.pat_num || row >= self.rows(num) || chn >= self.channels {
+           None
+        } else {
+           let e = self.patterns[num].event(row, chn);
+           Some(Event{
+               note: match e.note { 0 => 0, XM_KEY_OFF => KEY_OFF, n => n + 11 },
+               ins : e.ins,
+               vol : e.vol,
+               fxt : e.fxt,
+               fxp : e.fxp,
+           })
+        }
+    }
+
+    fn rows(&self, pat: usize) -> usize {
+        if pat >= self.pat_num {
+            0
+        } else {
+            self.patterns[pat].rows
+        }
+    }
+
+    fn samples(&self) -> &Vec<Sample> {
+        &self.samples
+    }
+}
+
+
+/// XmSample defines the sample parameters used in Fasttracker 2 instruments.
+/// The sample data is stored in the module sample list at index `smp`.
+#[derive(Clone,Debug,Default)]
+pub struct XmSample {
+    pub smp     : usize,
+    pub volume  : u8,
+    pub finetune: i8,
+    pub pan     : u8,
+    pub relnote : i8,
+    pub name    : String,
+}
+
+impl XmSample {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+
+/// XmInstrument defines the Fasttracker 2 multi-sample instrument.
+#[derive(Debug)]
+pub struct XmInstrument {
+    pub num      : usize,
+    pub name     : String,
+    pub keymap   : [u8; 96],   // sample number for each note
+    pub vol_env  : Envelope,
+    pub pan_env  : Envelope,
+    pub vib_type : u8,
+    pub vib_sweep: u8,
+    pub vib_depth: u8,
+    pub vib_rate : u8,
+    pub fadeout  : usize,
+    pub samples  : Vec<XmSample>,
+}
+
+impl Default for XmInstrument {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl XmInstrument {
+    pub fn new() -> Self {
+        XmInstrument {
+            num      : 0,
+            name     : "".to_owned(),
+            keymap   : [0; 96],
+            vol_env  : Envelope::new(),
+            pan_env  : Envelope::new(),
+            vib_type : 0,
+            vib_sweep: 0,
+            vib_depth: 0,
+            vib_rate : 0,
+            fadeout  : 0,
+            samples  : Vec::new(),
+        }
+    }
+}
+
+
+/// XmEvent defines the event format used in Fasttracker 2 patterns.
+#[derive(Clone,Debug,Default)]
+pub struct XmEvent {
+    pub note: u8,   // 1 is C-0, 97 is key off
+    pub ins : u8,
+    pub vol : u8,
+    pub fxt : u8,
+    pub fxp : u8,
+}
+
+impl XmEvent {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl fmt::Display for XmEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let note = match self.note {
+            0          => "---".to_owned(),
+            XM_KEY_OFF => "===".to_owned(),
+            n          => format!("{}{}", NOTES[(n - 1) as usize % 12], (n - 1) / 12),
+        };
+
+        let ins = if self.ins == 0 {
+            "--".to_owned()
+        } else {
+            format!("{:02X}", self.ins)
+        };
+
+        let vol = if self.vol < 0x10 {
+            "--".to_owned()
+        } else {
+            format!("{:02X}", self.vol)
+        };
+
+        let fxt = if self.fxt < 10 {
+            (b'0' + self.fxt) as char
+        } else {
+            (b'A' + self.fxt - 10) as char
+        };
+
+        write!(f, "{} {} {} {}{:02X}", note, ins, vol, fxt, self.fxp)
+    }
+}
+
+
+pub struct XmPattern {
+    pub rows: usize,
+    channels: usize,
+    data    : Vec<XmEvent>,
+}
+
+impl XmPattern {
+    /// Unpack pattern data. Each event starts with a note byte or a packing
+    /// flags byte if bit 7 is set.
+    fn from_packed(rows: usize, channels: usize, b: &[u8]) -> Self {
+        let mut pat = XmPattern {
+            rows,
+            channels,
+            data: vec![XmEvent::new(); rows * channels],
+        };
+
+        let mut i = 0;
+        let mut next = || {
+            let val = if i < b.len() { b[i] } else { 0 };
+            i += 1;
+            val
+        };
+
+        if b.is_empty() {
+            return pat;
+        }
+
+        for e in pat.data.iter_mut() {
+            let mut flags = next();
+            if flags & 0x80 == 0 {
+                e.note = flags;
+                flags = 0x1e;
+            } else if flags & 0x01 != 0 {
+                e.note = next();
+            }
+            if flags & 0x02 != 0 { e.ins = next() }
+            if flags & 0x04 != 0 { e.vol = next() }
+            if flags & 0x08 != 0 { e.fxt = next() }
+            if flags & 0x10 != 0 { e.fxp = next() }
+
+            if e.note > XM_KEY_OFF {
+                e.note = 0;
+            }
+        }
+
+        pat
+    }
+
+    pub fn event(&self, row: usize, chn: usize) -> &XmEvent {
+        &self.data[row * self.channels + chn]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_packed() {
+        let pat = XmPattern::from_packed(2, 2, &[
+            0x31, 0x01, 0x40, 0x0a, 0x0f,   // C-4 01 40 A0F
+            0x80,                           // empty
+            0x81, 0x61,                     // key off
+            0x98, 0x0f, 0x06,               // F06
+        ]);
+
+        assert_eq!(format!("{}", pat.event(0, 0)), "C 4 01 40 A0F");
+        assert_eq!(format!("{}", pat.event(0, 1)), "--- -- -- 000");
+        assert_eq!(format!("{}", pat.event(1, 0)), "=== -- -- 000");
+        assert_eq!(format!("{}", pat.event(1, 1)), "--- -- -- F06");
+    }
+}
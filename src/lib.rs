@@ -7,7 +7,7 @@ pub mod format;
 pub mod mixer;
 pub mod module;
 pub mod player;
-pub use player::FrameInfo;
+pub use player::{FrameInfo, ChannelInfo};
 
 use std::error;
 use std::fmt;
@@ -22,6 +22,8 @@ pub const MIN_BPM      : i32 = 20;
 pub const MAX_FRAMESIZE: usize = (5 * MAX_RATE / MIN_BPM) as usize;
 pub const MAX_KEYS     : usize = 128;
 pub const MAX_CHANNELS : usize = 64;
+pub const MAX_VOICES   : usize = 128;  // mixer voices for players with virtual channels
+pub const MAX_PREVIEW  : usize = 4;    // channels to play notes outside the replay
 
 
 #[derive(Debug)]
@@ -49,15 +51,7 @@ impl fmt::Display for Error {
 }
 
 impl error::Error for Error {
-    fn description(&self) -> &str {
-        match *self {
-            Error::Format(_)   => "Unsupported module format",
-            Error::Load(_)     => "Can't load module data",
-            Error::Io(ref err) => err.description(),
-        }
-    }
-
-    fn cause(&self) -> Option<&error::Error> {
+    fn cause(&self) -> Option<&dyn error::Error> {
         match *self {
             Error::Io(ref err) => Some(err),
             _                  => None,
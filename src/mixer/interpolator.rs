@@ -1,18 +1,45 @@
+use std::f64::consts::PI;
 use mixer::SMIX_SHIFT;
 
 pub trait InterpolatorBase {
+    #[allow(dead_code)]
     fn name() -> &'static str;
+
+    // Number of samples used before and after the current sample, including
+    // the current sample in the count after it
+    fn window() -> (usize, usize) {
+        (1, 2)
+    }
 }
 
 pub trait Interpolate<T> {
-    fn get_sample(&self, &[T], i32) -> i32;
+    fn get_sample(&self, _: &[T], _: i32) -> i32;
 }
 
+#[derive(Clone,Copy,Debug,PartialEq)]
 pub enum Interpolator {
     Nearest,
     Linear,
+    Cubic,
+    Sinc,
+}
+
+impl Interpolator {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "nearest" => Some(Interpolator::Nearest),
+            "linear"  => Some(Interpolator::Linear),
+            "cubic"   => Some(Interpolator::Cubic),
+            "sinc"    => Some(Interpolator::Sinc),
+            _         => None,
+        }
+    }
 }
 
+// Interpolation tables use 14-bit coefficients
+const COEF_SHIFT: usize = 14;
+const COEF_ONE  : f64 = (1 << COEF_SHIFT) as f64;
+
 // Nearest neighbor interpolator
 pub struct Nearest;
 
@@ -48,7 +75,7 @@ impl Interpolate<i8> for Linear {
     fn get_sample(&self, i: &[i8], frac: i32) -> i32 {
         let l1 = (i[1] as i32) << 8;
         let dt = ((i[2] as i32) << 8) - l1;
-        l1 as i32 + (((frac >> 1) * dt as i32) >> (SMIX_SHIFT - 1)) as i32
+        l1 + (((frac >> 1) * dt) >> (SMIX_SHIFT - 1))
     }
 }
 
@@ -56,7 +83,147 @@ impl Interpolate<i16> for Linear {
     fn get_sample(&self, i: &[i16], frac: i32) -> i32 {
         let l1 = i[1] as i32;
         let dt = i[2] as i32 - l1;
-        l1 as i32 + (((frac >> 1) * dt as i32) >> (SMIX_SHIFT - 1)) as i32
+        l1 + (((frac >> 1) * dt) >> (SMIX_SHIFT - 1))
+    }
+}
+
+
+// Catmull-Rom cubic spline interpolator
+pub struct Cubic {
+    table: Vec<i16>,
+}
+
+const CUBIC_PHASES: usize = 1024;
+const CUBIC_FRAC_SHIFT: usize = SMIX_SHIFT - 10;
+
+impl Cubic {
+    pub fn new() -> Self {
+        let mut table = Vec::with_capacity(CUBIC_PHASES * 4);
+        for p in 0..CUBIC_PHASES {
+            let t = p as f64 / CUBIC_PHASES as f64;
+            let (t2, t3) = (t * t, t * t * t);
+            table.push(((-t3 + 2.0 * t2 - t) / 2.0 * COEF_ONE).round() as i16);
+            table.push(((3.0 * t3 - 5.0 * t2 + 2.0) / 2.0 * COEF_ONE).round() as i16);
+            table.push(((-3.0 * t3 + 4.0 * t2 + t) / 2.0 * COEF_ONE).round() as i16);
+            table.push(((t3 - t2) / 2.0 * COEF_ONE).round() as i16);
+        }
+
+        Cubic { table }
+    }
+
+    fn get(&self, i: [i32; 4], frac: i32) -> i32 {
+        let c = &self.table[(frac as usize >> CUBIC_FRAC_SHIFT) * 4..];
+        (c[0] as i32 * i[0] + c[1] as i32 * i[1] + c[2] as i32 * i[2] + c[3] as i32 * i[3]) >> COEF_SHIFT
+    }
+}
+
+impl InterpolatorBase for Cubic {
+    fn name() -> &'static str {
+        "cubic spline"
+    }
+
+    fn window() -> (usize, usize) {
+        (1, 3)
+    }
+}
+
+impl Interpolate<i8> for Cubic {
+    fn get_sample(&self, i: &[i8], frac: i32) -> i32 {
+        self.get([(i[0] as i32) << 8, (i[1] as i32) << 8, (i[2] as i32) << 8, (i[3] as i32) << 8], frac)
+    }
+}
+
+impl Interpolate<i16> for Cubic {
+    fn get_sample(&self, i: &[i16], frac: i32) -> i32 {
+        self.get([i[0] as i32, i[1] as i32, i[2] as i32, i[3] as i32], frac)
+    }
+}
+
+
+// Band-limited windowed sinc interpolator. Kernels are precomputed for a
+// number of cutoff frequencies, and the cutoff is lowered when the sample
+// is played faster than the output rate to avoid aliasing in high notes.
+pub struct SincTable {
+    table: Vec<i16>,
+}
+
+pub struct Sinc<'a> {
+    coef: &'a [i16],
+}
+
+const SINC_TAPS     : usize = 16;
+const SINC_PHASES   : usize = 256;
+const SINC_BANDS    : usize = 8;
+const SINC_BAND_STEP: f64 = 1.25;     // ratio between cutoffs of consecutive bands
+const SINC_CUTOFF   : f64 = 0.95;     // relative to the Nyquist frequency
+const SINC_FRAC_SHIFT: usize = SMIX_SHIFT - 8;
+
+impl SincTable {
+    pub fn new() -> Self {
+        let mut table = Vec::with_capacity(SINC_BANDS * SINC_PHASES * SINC_TAPS);
+        let half = (SINC_TAPS / 2) as f64;
+
+        for band in 0..SINC_BANDS {
+            let cutoff = SINC_CUTOFF / SINC_BAND_STEP.powi(band as i32);
+            for p in 0..SINC_PHASES {
+                let t = p as f64 / SINC_PHASES as f64;
+                let kernel = (0..SINC_TAPS).map(|j| {
+                    // Distance from the interpolated position, tap 7 is the current sample
+                    let x = j as f64 - (half - 1.0) - t;
+                    let sinc = if x == 0.0 { 1.0 } else { (PI * cutoff * x).sin() / (PI * cutoff * x) };
+                    let w = 0.42 + 0.5 * (PI * x / half).cos() + 0.08 * (2.0 * PI * x / half).cos();
+                    cutoff * sinc * w
+                }).collect::<Vec<f64>>();
+
+                // Normalize to unity gain
+                let sum: f64 = kernel.iter().sum();
+                table.extend(kernel.iter().map(|k| (k / sum * COEF_ONE).round() as i16));
+            }
+        }
+
+        SincTable { table }
+    }
+
+    /// Select the kernels for a sample played with the given step.
+    pub fn band(&self, step: f64) -> Sinc<'_> {
+        let band = if step <= 1.0 {
+            0
+        } else {
+            ((step.ln() / SINC_BAND_STEP.ln()).ceil() as usize).min(SINC_BANDS - 1)
+        };
+        let size = SINC_PHASES * SINC_TAPS;
+        Sinc { coef: &self.table[band * size..(band + 1) * size] }
+    }
+}
+
+impl<'a> Sinc<'a> {
+    fn coef(&self, frac: i32) -> &[i16] {
+        let ofs = (frac as usize >> SINC_FRAC_SHIFT) * SINC_TAPS;
+        &self.coef[ofs..ofs + SINC_TAPS]
+    }
+}
+
+impl<'a> InterpolatorBase for Sinc<'a> {
+    fn name() -> &'static str {
+        "windowed sinc"
+    }
+
+    fn window() -> (usize, usize) {
+        (SINC_TAPS / 2 - 1, SINC_TAPS / 2 + 1)
+    }
+}
+
+impl<'a> Interpolate<i8> for Sinc<'a> {
+    fn get_sample(&self, i: &[i8], frac: i32) -> i32 {
+        let sum = self.coef(frac).iter().zip(i).fold(0, |acc, (&c, &x)| acc + c as i32 * x as i32);
+        sum >> (COEF_SHIFT - 8)
+    }
+}
+
+impl<'a> Interpolate<i16> for Sinc<'a> {
+    fn get_sample(&self, i: &[i16], frac: i32) -> i32 {
+        let sum = self.coef(frac).iter().zip(i).fold(0, |acc, (&c, &x)| acc + c as i32 * x as i32);
+        sum >> COEF_SHIFT
     }
 }
 
@@ -100,4 +267,25 @@ mod tests {
         assert_eq!(interp.get_sample(i, 32767), 0x27ff);
         assert_eq!(interp.get_sample(i, 65535), 0x3fff);
     }
+
+    #[test]
+    fn test_interpolate_cubic_i16() {
+        let interp = Cubic::new();
+        let i: &[i16] = &[0, 0x1000, 0x4000, 0x7000];
+        assert_eq!(interp.get_sample(i, 0), 0x1000);
+        assert_eq!(interp.get_sample(i, 32768), 0x2600);
+    }
+
+    #[test]
+    fn test_interpolate_sinc_i16() {
+        let table = SincTable::new();
+        let i: &[i16] = &[0x1000; 16];
+        for &step in &[0.5, 1.0, 2.0, 10.0] {
+            let interp = table.band(step);
+            for &frac in &[0, 16384, 32768, 65535] {
+                let val = interp.get_sample(i, frac);
+                assert!((val - 0x1000).abs() <= 4, "step {} frac {} val {}", step, frac, val);
+            }
+        }
+    }
 }
@@ -1,18 +1,27 @@
-use module::sample::{Sample, SampleType};
-use mixer::interpolator::{Interpolator, Interpolate};
+use std::sync::Arc;
+use module::Module;
+use module::sample::{Sample, SampleType, GUARD_SIZE};
+use mixer::interpolator::{Interpolate, InterpolatorBase};
 use util::MemOpExt;
 use util;
 use ::*;
 
 mod interpolator;
+mod paula;
+
+pub use self::interpolator::Interpolator;
+pub use self::paula::Paula;
 
-const PAL_RATE     : usize = 250;
 const C4_PERIOD    : f64 = 428.0;
 const SMIX_SHIFT   : usize = 16;
 const SMIX_MASK    : usize = 0xffff;
 const LIM16_HI     : i32 = 32767;
 const LIM16_LO     : i32 = -32768;
 const DOWNMIX_SHIFT: usize = 10;
+const FILTER_SHIFT : usize = 16;
+const GUARD        : usize = GUARD_SIZE / 2;  // guard samples before the sample data
+const RAMP_SHIFT   : usize = 16;
+const RAMP_RATE    : usize = 200;             // anti-click ramps last 1/200 s
 
 macro_rules! try_voice {
     ( $a:expr, $b: expr ) => {
@@ -28,31 +37,176 @@ macro_rules! try_voice {
 }
 
 
-pub struct Mixer<'a> {
+/// Output channel layout.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Layout {
+    Mono,
+    Stereo,
+    ReversedStereo,
+}
+
+impl Layout {
+    pub fn channels(&self) -> usize {
+        match *self {
+            Layout::Mono => 1,
+            _            => 2,
+        }
+    }
+}
+
+/// Limiter applied to the output of the floating-point mixer. `Clip` clips
+/// samples above full scale and counts them as overs, `SoftKnee` compresses
+/// peaks above the knee smoothly towards full scale.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Limiter {
+    Off,
+    Clip,
+    SoftKnee,
+}
+
+const LIMITER_KNEE : f32 = 0.8;
+
+/// How the replay tempo sets the length of each tick. `Bpm` ticks last
+/// 2.5/tempo seconds as in PC trackers, `Cia` ticks use the Amiga CIA timer
+/// value for the tempo, `CiaTimer` ticks use a fixed CIA timer value and
+/// `Vblank` ticks follow the video frame rate of the clock. The last two
+/// ignore the tempo.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Timing {
+    Bpm,
+    Cia,
+    CiaTimer(usize),  // timer value in CIA clock cycles
+    Vblank,
+}
+
+/// Amiga clock used by `Cia`, `CiaTimer` and `Vblank` timing.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Clock {
+    Pal,
+    Ntsc,
+}
+
+impl Clock {
+    // CIA clock rate in Hz, CPU clock / 10
+    fn cia_rate(&self) -> f64 {
+        match *self {
+            Clock::Pal  => 709379.0,
+            Clock::Ntsc => 715909.0,
+        }
+    }
+
+    // CIA timer value for 1 BPM, as used by Protracker
+    fn cia_tempo(&self) -> usize {
+        match *self {
+            Clock::Pal  => 1773447,
+            Clock::Ntsc => 1789773,
+        }
+    }
+
+    // Video frame rate in Hz: color clock / (lines * color clocks per line)
+    fn vblank_rate(&self) -> f64 {
+        match *self {
+            Clock::Pal  => 3546895.0 / (313.0 * 227.0),
+            Clock::Ntsc => 3579545.0 / (263.0 * 227.5),
+        }
+    }
+}
+
+/// Render voices to separate stereo buffers, one for each module channel
+/// or for each instrument, in addition to the master mix.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Stems {
+    Off,
+    Channel,
+    Instrument,
+}
+
+/// Output sample format. Float samples are not clipped and use 1.0 as full scale.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum SampleFormat {
+    I16,
+    I32,
+    F32,
+}
+
+
+pub struct Mixer {
 
     pub rate  : usize,
+    layout    : Layout,
+    format    : SampleFormat,
     mute      : bool,
+    chn_mute  : [bool; MAX_CHANNELS],
     voices    : Vec<Voice>,
+    tails     : Vec<Voice>,   // fade out the previous note played in each voice
+    anticlick : bool,
     framesize : usize,
+    timing    : Timing,
+    clock     : Clock,
+    frame_frac: f64,      // fractional samples carried to the next frame
+    paula     : Paula,
+    amiga     : paula::AudioFilter,
     buf32     : [i32; MAX_FRAMESIZE],
+    buf_f     : Vec<f32>,   // accumulation buffer for floating-point mixing
+    float_mix : bool,
+    gain      : f32,
+    limiter   : Limiter,
+    overs     : usize,
     buffer    : [i16; MAX_FRAMESIZE],
+    buffer_i32: Vec<i32>,
+    buffer_f32: Vec<f32>,
+    stems     : Stems,
+    stem32    : Vec<Vec<i32>>,  // stem accumulation buffers for each mixer
+    stem_f    : Vec<Vec<f32>>,
+    stem_out  : Vec<Vec<f32>>,  // rendered stems, interleaved stereo
+    scopes    : Vec<Vec<f32>>,  // mono samples of each voice, before panning
+    voice32   : Vec<i32>,       // voice accumulation buffers for scopes
+    voice_f   : Vec<f32>,
     pub interp: interpolator::Interpolator,
-    sample    : &'a Vec<Sample>,
+    cubic     : interpolator::Cubic,
+    sinc      : interpolator::SincTable,
+    module    : Arc<Module>,
 }
 
 
-impl<'a> Mixer<'a> {
+impl Mixer {
 
-    pub fn new(num: usize, sample: &'a Vec<Sample>) -> Self {
+    pub fn new(_num: usize, rate: usize, module: Arc<Module>) -> Self {
         Mixer {
-            rate     : 44100,
-            mute     : false,
-            voices   : Vec::new(),
-            framesize: 0,
-            buf32    : [0; MAX_FRAMESIZE],
-            buffer   : [0; MAX_FRAMESIZE],
-            interp   : Interpolator::Linear,
-            sample,
+            rate,
+            layout    : Layout::Stereo,
+            format    : SampleFormat::I16,
+            mute      : false,
+            chn_mute  : [false; MAX_CHANNELS],
+            voices    : Vec::new(),
+            tails     : Vec::new(),
+            anticlick : true,
+            framesize : 0,
+            timing    : Timing::Bpm,
+            clock     : Clock::Pal,
+            frame_frac: 0.0,
+            paula     : Paula::Off,
+            amiga     : paula::AudioFilter::new(Paula::Off, rate),
+            buf32     : [0; MAX_FRAMESIZE],
+            buf_f     : vec![0.0; MAX_FRAMESIZE],
+            float_mix : false,
+            gain      : 1.0,
+            limiter   : Limiter::Clip,
+            overs     : 0,
+            buffer    : [0; MAX_FRAMESIZE],
+            buffer_i32: Vec::new(),
+            buffer_f32: Vec::new(),
+            stems     : Stems::Off,
+            stem32    : Vec::new(),
+            stem_f    : Vec::new(),
+            stem_out  : Vec::new(),
+            scopes    : Vec::new(),
+            voice32   : Vec::new(),
+            voice_f   : Vec::new(),
+            interp    : Interpolator::Linear,
+            cubic     : interpolator::Cubic::new(),
+            sinc      : interpolator::SincTable::new(),
+            module,
         }
     }
 
@@ -62,6 +216,7 @@ impl<'a> Mixer<'a> {
 
     pub fn create_voices(&mut self, num: usize) {
         self.voices = vec![Voice::new(); num];
+        self.tails = vec![Voice::new(); num];
 
         for i in 0..self.voices.len() {
             self.voices[i].num = i;
@@ -70,17 +225,18 @@ impl<'a> Mixer<'a> {
 
     pub fn find_free_voice(&self) -> Option<usize> {
         for (i, v) in self.voices.iter().enumerate() {
-            if v.chn == None {
+            if v.chn.is_none() {
                 return Some(i);
             }
         }
 
-        return None;
+        None
     }
 
-    pub fn find_lowest_voice(&self, num_tracks: usize) -> usize {
-        let mut vol = std::usize::MAX;
-        let mut num = 0;
+    /// Find the voice with the lowest volume playing in a background channel.
+    pub fn find_lowest_voice(&self, num_tracks: usize, num_channels: usize) -> Option<usize> {
+        let mut vol = usize::MAX;
+        let mut num = None;
 
         for (i, v) in self.voices.iter().enumerate() {
 
@@ -89,10 +245,10 @@ impl<'a> Mixer<'a> {
                 None    => continue,
             };
 
-            if chn >= num_tracks {   // only background channels
+            if chn >= num_tracks && chn < num_channels {   // only background channels
                 if v.vol < vol {
                     vol = v.vol;
-                    num = i;
+                    num = Some(i);
                 }
             }
         }
@@ -100,8 +256,132 @@ impl<'a> Mixer<'a> {
         num
     }
 
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    pub fn set_format(&mut self, format: SampleFormat) {
+        self.format = format;
+        match format {
+            SampleFormat::I32 => if self.buffer_i32.is_empty() { self.buffer_i32 = vec![0; MAX_FRAMESIZE] },
+            SampleFormat::F32 => if self.buffer_f32.is_empty() { self.buffer_f32 = vec![0.0; MAX_FRAMESIZE] },
+            SampleFormat::I16 => {},
+        }
+    }
+
+    /// Select stem rendering with the given number of stems. Voices of channels
+    /// or instruments beyond the number of stems are only mixed to the master.
+    pub fn set_stems(&mut self, stems: Stems, num: usize) {
+        let num = if stems == Stems::Off { 0 } else { num };
+        self.stems = stems;
+        self.stem32 = vec![vec![0; MAX_FRAMESIZE]; num];
+        self.stem_f = vec![vec![0.0; MAX_FRAMESIZE]; num];
+        self.stem_out = vec![vec![0.0; MAX_FRAMESIZE]; num];
+    }
+
+    pub fn num_stems(&self) -> usize {
+        self.stem_out.len()
+    }
+
+    /// Interleaved stereo samples of a stem in the last frame, with the
+    /// master gain applied and 1.0 as full scale.
+    pub fn stem_buffer(&self, num: usize) -> &[f32] {
+        match self.stem_out.get(num) {
+            Some(b) => &b[..self.framesize * 2],
+            None    => &[],
+        }
+    }
+
+    /// Keep the samples produced by each voice in the last frame, before
+    /// panning, for oscilloscope displays.
+    pub fn set_scopes(&mut self, enable: bool) {
+        if enable {
+            self.scopes = vec![vec![0.0; MAX_FRAMESIZE / 2]; self.voices.len()];
+            self.voice32 = vec![0; MAX_FRAMESIZE];
+            self.voice_f = vec![0.0; MAX_FRAMESIZE];
+        } else {
+            self.scopes = Vec::new();
+            self.voice32 = Vec::new();
+            self.voice_f = Vec::new();
+        }
+    }
+
+    /// Mono samples of the voice in the last frame, with 1.0 as full scale at
+    /// full volume. Empty if scopes are disabled.
+    pub fn scope_buffer(&self, voice: usize) -> &[f32] {
+        match self.scopes.get(voice) {
+            Some(b) => &b[..self.framesize],
+            None    => &[],
+        }
+    }
+
+    /// Mix voices in floating point, or use the integer mixer with hard
+    /// clipping of the original player. The integer mixer is the default.
+    pub fn set_float_mix(&mut self, enable: bool) {
+        self.float_mix = enable;
+    }
+
+    /// Set the master gain applied to the floating-point mixer output.
+    pub fn set_master_gain(&mut self, gain: f32) {
+        self.gain = gain;
+    }
+
+    pub fn set_limiter(&mut self, limiter: Limiter) {
+        self.limiter = limiter;
+    }
+
+    /// Number of output samples clipped since the mixer was created.
+    pub fn overs(&self) -> usize {
+        self.overs
+    }
+
+    pub fn set_interpolator(&mut self, interp: Interpolator) {
+        self.interp = interp;
+    }
+
+    pub fn set_timing(&mut self, timing: Timing, clock: Clock) {
+        self.timing = timing;
+        self.clock = clock;
+    }
+
+    /// Length of a tick in seconds at the given tempo.
+    pub fn tick_time(&self, tempo: usize) -> f64 {
+        let tempo = tempo.max(MIN_BPM as usize);
+        match self.timing {
+            Timing::Bpm    => 2.5 / tempo as f64,
+            Timing::Cia    => (self.clock.cia_tempo() / tempo) as f64 / self.clock.cia_rate(),
+            Timing::CiaTimer(val) => val as f64 / self.clock.cia_rate(),
+            Timing::Vblank => 1.0 / self.clock.vblank_rate(),
+        }
+    }
+
+    /// Set the length of the next frame. Called once for each frame played,
+    /// carrying the fractional part of the tick length to the next frame.
     pub fn set_tempo(&mut self, tempo: usize) {
-        self.framesize = self.rate * PAL_RATE / tempo / 100;
+        let size = self.rate as f64 * self.tick_time(tempo) + self.frame_frac;
+        self.framesize = (size as usize).min(MAX_FRAMESIZE / 2);
+        self.frame_frac = (size - self.framesize as f64).min(1.0);
+    }
+
+    /// Emulate the audio hardware of an Amiga model, or disable emulation.
+    /// Paula applies volume changes immediately, so emulation also disables
+    /// anti-click. Enable it again after this call to ramp volume changes.
+    pub fn set_paula(&mut self, model: Paula) {
+        self.paula = model;
+        self.amiga = paula::AudioFilter::new(model, self.rate);
+        if model != Paula::Off {
+            self.set_anticlick(false);
+        }
+    }
+
+    pub fn paula(&self) -> Paula {
+        self.paula
+    }
+
+    /// Turn the Amiga LED filter on or off. The filter is only applied in
+    /// Paula mode.
+    pub fn set_led_filter(&mut self, on: bool) {
+        self.amiga.set_led(on);
     }
 
     pub fn set_voice(&mut self, num: usize, chn: usize) {
@@ -120,45 +400,167 @@ impl<'a> Mixer<'a> {
         self.voices[voice].chn
     }
 
-    pub fn reset_voice(&self, voice: usize) {
+    pub fn set_voice_chn(&mut self, num: usize, chn: usize) {
+        try_voice!(num, self.voices);
+        self.voices[num].chn = Some(chn);
     }
 
-    pub fn voicepos(&self, voice: usize) -> f64 {
-        try_voice!(voice, self.voices, 0_f64);
+    pub fn voice_ins(&self, voice: usize) -> Option<usize> {
+        try_voice!(voice, self.voices, None);
+        self.voices[voice].chn.map(|_| self.voices[voice].ins)
+    }
+
+    pub fn voice_smp(&self, voice: usize) -> Option<usize> {
+        try_voice!(voice, self.voices, None);
+        self.voices[voice].chn.map(|_| self.voices[voice].smp)
+    }
+
+    pub fn voice_vol(&self, voice: usize) -> usize {
+        try_voice!(voice, self.voices, 0);
+        self.voices[voice].vol
+    }
+
+    pub fn voice_note(&self, voice: usize) -> usize {
+        try_voice!(voice, self.voices, 0);
+        self.voices[voice].note
+    }
+
+    pub fn voice_pan(&self, voice: usize) -> isize {
+        try_voice!(voice, self.voices, 0);
+        self.voices[voice].pan
+    }
+
+    pub fn voice_period(&self, voice: usize) -> f64 {
+        try_voice!(voice, self.voices, 0.0);
+        self.voices[voice].period
+    }
 
+    /// Playback frequency of the voice in Hz.
+    pub fn voice_freq(&self, voice: usize) -> f64 {
+        try_voice!(voice, self.voices, 0.0);
         let v = &self.voices[voice];
-        let sample = &self.sample[v.smp];
+        if v.period < 1.0 {
+            return 0.0;
+        }
+        self.module.data.samples().get(v.smp).map_or(0.0, |s| C4_PERIOD * s.rate / v.period)
+    }
 
-        if sample.has_loop && sample.loop_bidir {
-            // TODO: handle bidirectional loop
+    /// True if the voice is playing a sample loop or sustain loop.
+    pub fn voice_looping(&self, voice: usize) -> bool {
+        try_voice!(voice, self.voices, false);
+        let v = &self.voices[voice];
+        match self.module.data.samples().get(v.smp) {
+            Some(sample) => !v.sample_end && v.active_loop(sample).is_some(),
+            None         => false,
         }
-        
-        v.pos
+    }
+
+    pub fn sample_end(&self, voice: usize) -> bool {
+        try_voice!(voice, self.voices, true);
+        self.voices[voice].sample_end
+    }
+
+    /// Stop all voices and their fade out tails.
+    pub fn reset(&mut self) {
+        for i in 0..self.voices.len() {
+            self.voices[i] = Voice::new();
+            self.voices[i].num = i;
+            self.tails[i] = Voice::new();
+            self.tails[i].num = i;
+        }
+        self.frame_frac = 0.0;
+        self.amiga.reset();
+    }
+
+    /// Advance voices without rendering audio. Muted frames are silent.
+    pub fn set_mute(&mut self, mute: bool) {
+        self.mute = mute;
+    }
+
+    /// Silence voices rooted in the given channel. Volume ramps to and from
+    /// zero in the next frame.
+    pub fn set_channel_mute(&mut self, chn: usize, mute: bool) {
+        if chn < MAX_CHANNELS {
+            self.chn_mute[chn] = mute;
+        }
+    }
+
+    pub fn channel_mute(&self, chn: usize) -> bool {
+        chn < MAX_CHANNELS && self.chn_mute[chn]
+    }
+
+    pub fn reset_voice(&mut self, voice: usize) {
+        try_voice!(voice, self.voices);
+        self.anticlick(voice);
+        self.voices[voice] = Voice::new();
+        self.voices[voice].num = voice;
+    }
+
+    pub fn voicepos(&self, voice: usize) -> f64 {
+        try_voice!(voice, self.voices, 0_f64);
+
+        // Voices in bidirectional loops are reflected at the loop ends, so
+        // the position is always the current sample position
+        self.voices[voice].pos
     }
 
     pub fn set_voicepos(&mut self, voice: usize, pos: f64, ac: bool) {
         try_voice!(voice, self.voices);
 
+        if ac {
+            self.anticlick(voice);
+        }
+
         let v = &mut self.voices[voice];
         v.pos = pos;
+        v.backward = false;
+
+        // Restarting DMA plays the sample latched in the voice
+        if let Some((ins, smp)) = v.next.take() {
+            v.ins = ins;
+            v.smp = smp;
+            v.has_loop = false;
+            v.sample_end = false;
+        }
 
-        let sample = &self.sample[v.smp];
+        let sample = &self.module.data.samples()[v.smp];
 
-        v.adjust_end(&sample);
+        v.adjust_end(sample);
 
         if v.pos >= v.end as f64 {
-            if sample.has_loop {
-                v.pos = sample.loop_start as f64;
+            if let Some(lp) = v.active_loop(sample) {
+                v.pos = lp.start as f64;
+                v.has_loop = true;
             } else {
                 v.pos = sample.size as f64;
             }
         }
+    }
 
-        // TODO: handle bidirectional loop
+    /// Enable or disable volume ramps and fade out of retriggered notes. With
+    /// anti-click disabled volume changes are applied immediately, as in the
+    /// original players.
+    pub fn set_anticlick(&mut self, enable: bool) {
+        self.anticlick = enable;
+        if !enable {
+            self.tails.iter_mut().for_each(|t| t.period = 0.0);
+        }
+    }
 
-        if ac {
-            v.anticlick();
+    // Move the sound currently played in the voice to its tail, so it can fade
+    // out while the voice plays a new note or sample position
+    fn anticlick(&mut self, voice: usize) {
+        let v = &mut self.voices[voice];
+        v.attack = true;
+
+        if !self.anticlick || v.period < 1.0 || (v.cur_vol_l == 0 && v.cur_vol_r == 0) || v.sample_end {
+            return;
         }
+
+        let t = &mut self.tails[voice];
+        *t = v.clone();
+        t.vol = 0;
+        t.attack = false;
     }
 
     pub fn set_note(&mut self, voice: usize, mut note: usize) {
@@ -189,6 +591,46 @@ impl<'a> Mixer<'a> {
         self.voices[voice].period = period;
     }
 
+    /// Set the resonant filter parameters. Cutoff and resonance range from 0 to 255,
+    /// and the filter is disabled with maximum cutoff and no resonance.
+    pub fn set_filter(&mut self, voice: usize, cutoff: usize, resonance: usize) {
+        try_voice!(voice, self.voices);
+        let rate = self.rate;
+        self.voices[voice].filter.setup(rate, cutoff, resonance);
+    }
+
+    /// Release the note playing in the voice. A voice playing a sample with a
+    /// sustain loop leaves the sustain loop and continues into the sample loop,
+    /// or plays to the end of the sample if it has no loop.
+    pub fn release(&mut self, voice: usize) {
+        try_voice!(voice, self.voices);
+
+        let v = &mut self.voices[voice];
+        if v.release {
+            return;
+        }
+        v.release = true;
+
+        let sample = &self.module.data.samples()[v.smp];
+        if !sample.has_sloop {
+            return;
+        }
+
+        v.backward = false;
+        v.has_loop = false;
+        v.adjust_end(sample);
+
+        if v.pos >= v.end as f64 {
+            match v.active_loop(sample) {
+                Some(lp) => {
+                    v.pos = lp.start as f64;
+                    v.has_loop = true;
+                },
+                None     => v.pos = sample.size as f64,
+            }
+        }
+    }
+
     pub fn set_patch(&mut self, voice: usize, ins: usize, smp: usize, ac: bool) {
         try_voice!(voice, self.voices);
 
@@ -200,147 +642,417 @@ impl<'a> Mixer<'a> {
         v.vol = 0;
         v.pan = 0; 
         v.has_loop = false;
-	v.sample_end = true;
+        v.backward = false;
+        v.release = false;
+        v.attack = true;
+        v.sample_end = false;
+        v.next = None;
+        v.filter.reset();
 
-        let sample = &self.sample[v.smp];
+        let sample = &self.module.data.samples()[v.smp];
 
         v.pos = 0_f64;
-        v.end = sample.size;
-        
-        // ...
+        v.adjust_end(sample);
+    }
 
+    /// Latch a sample to play in the voice without retriggering it, as Paula
+    /// does when its sample registers are written during playback. The sample
+    /// loop of the new sample plays when the current loop iteration or sample
+    /// ends, or the whole sample if the voice position is set before.
+    pub fn queue_patch(&mut self, voice: usize, ins: usize, smp: usize) {
+        try_voice!(voice, self.voices);
+        self.voices[voice].next = Some((ins, smp));
     }
 
     pub fn mix(&mut self) {
 
         let mut md = MixerData{
-            pos    : 0.0_f64,
-            buf_pos: 0,
-            step   : 0,
-            size   : 0,
-            vol_r  : 0,
-            vol_l  : 0,
+            pos       : 0.0_f64,
+            buf_pos   : 0,
+            step      : 0,
+            size      : 0,
+            vol_r     : 0,
+            vol_l     : 0,
+            delta_l   : 0,
+            delta_r   : 0,
+            target_l  : 0,
+            target_r  : 0,
+            ramp      : 0,
+            loop_start: 0,
+            loop_end  : 0,
+            wrap_start: false,
+            wrap_end  : false,
+            bidir     : false,
         };
 
-        self.buf32[..].fill(0, self.framesize);
+        let size = self.framesize * 2;
+        if self.float_mix {
+            MemOpExt::fill(&mut self.buf_f[..], 0, size);
+            self.stem_f.iter_mut().for_each(|b| MemOpExt::fill(&mut b[..], 0, size));
+        } else {
+            MemOpExt::fill(&mut self.buf32[..], 0, size);
+            self.stem32.iter_mut().for_each(|b| MemOpExt::fill(&mut b[..], 0, size));
+        }
+
+        let scope = !self.scopes.is_empty();
+        let framesize = self.framesize;
+        self.scopes.iter_mut().for_each(|b| MemOpExt::fill(&mut b[..], 0, framesize));
+
+        let ramp_len = (self.rate / RAMP_RATE).max(1);
 
-        for v in &mut self.voices {
+        for v in self.voices.iter_mut().chain(self.tails.iter_mut()) {
             if v.period < 1.0 {
                 continue
             }
 
             let mut buf_pos = 0;
 
-            let vol_r = v.vol * (0x80 - v.pan) as usize;
-            let vol_l = v.vol * (0x80 + v.pan) as usize;
+            let vol = match v.root {
+                Some(chn) if chn < MAX_CHANNELS && self.chn_mute[chn] => 0,
+                _ => v.vol,
+            };
+
+            let stem = match self.stems {
+                Stems::Off        => None,
+                Stems::Channel    => v.root,
+                Stems::Instrument => Some(v.ins),
+            };
+
+            // Paula channels 0 and 3 are wired to the left output, 1 and 2 to the right
+            let pan = match (self.paula, v.root) {
+                (Paula::Off, _) | (_, None) => v.pan,
+                (_, Some(chn))              => if chn % 4 == 0 || chn % 4 == 3 { -0x80 } else { 0x80 },
+            };
+
+            let vol_r = vol * (0x80 - pan) as usize;
+            let vol_l = vol * (0x80 + pan) as usize;
         
-            let sample = &self.sample[v.smp];
-            let step = C4_PERIOD * sample.rate / self.rate as f64 / v.period;
+            let mut sample = &self.module.data.samples()[v.smp];
+            let mut step = C4_PERIOD * sample.rate / self.rate as f64 / v.period;
             if step < 0.001 {
                 continue;
             }
 
-            //let lps = sample.loop_start;
-            //let lpe = sample.loop_end;
+            // Mix to the voice buffer and add it to the mix later
+            if scope {
+                if self.float_mix {
+                    MemOpExt::fill(&mut self.voice_f[..], 0, size);
+                } else {
+                    MemOpExt::fill(&mut self.voice32[..], 0, size);
+                }
+            }
+
+            // Ramp from the volume at the end of the previous frame
+            md.set_volume(v, (vol_l >> 8) as i32, (vol_r >> 8) as i32, if self.anticlick { ramp_len } else { 0 });
 
-            let mut usmp = 0;
             let mut size = self.framesize as isize;
-            loop {
-                if size <= 0 {
-                    break
-                }
+            while size > 0 {
+
+                // Bidirectional loops are reflected half a sample before the
+                // loop ends, so the loop end is played only once in each direction
+                let lp = v.active_loop(sample);
+                let (loop_start, end) = match lp {
+                    Some(lp) if lp.bidir && v.end == lp.end => (lp.start as f64 - 0.5, v.end as f64 - 0.5),
+                    Some(lp) => (lp.start as f64 - 0.5, v.end as f64),
+                    None     => (0.0, v.end as f64),
+                };
 
                 // How many samples we can write before the loop break or sample end...
-                let mut samples = 0;
-                if v.pos > v.end as f64 {
-                    usmp = 1;
-                } else {
-                    let mut s = ((v.end as f64 - v.pos) / step).ceil() as isize;
+                let dist = if v.backward { v.pos - loop_start } else { end - v.pos };
+                let samples = if dist > 0.0 {
                     // ...inside the tick boundaries
-                    if s > size {
-                       s = size;
-                    }
-                    samples = s;
-                    if samples > 0 {
-                        usmp = 0;
-                    }
-                }
-
-                if v.vol > 0 {
-                    let mix_size = samples * 2;
-
-                    if samples > 0 {
-                        md.pos = v.pos + 2.0;
+                    ((dist / step).ceil() as isize).min(size)
+                } else {
+                    0
+                };
+
+                if samples > 0 {
+                    if self.mute {
+                        md.skip_ramp(samples as usize);
+                    } else if vol > 0 || md.ramp > 0 {
+                        md.pos = v.pos + GUARD as f64;
                         md.buf_pos = buf_pos;
-                        md.step = (step * (1_u32 << SMIX_SHIFT) as f64) as usize;
+                        md.step = (step * (1_u32 << SMIX_SHIFT) as f64) as isize;
+                        if v.backward {
+                            md.step = -md.step;
+                        }
                         md.size = samples;
-                        md.vol_l = vol_l >> 8;
-                        md.vol_r = vol_r >> 8;
-
-                        match sample.sample_type {
-                            SampleType::Empty    => {},
-                            SampleType::Sample8  => md.mix::<i8>(&self.interp, &sample.data_8(), &mut self.buf32),
-                            SampleType::Sample16 => md.mix::<i16>(&self.interp, &sample.data_16(), &mut self.buf32),
+                        md.set_loop(v, lp);
+
+                        let sinc = self.sinc.band(step);
+                        let interp = (self.interp, &self.cubic, &sinc);
+                        let (buf32, buf_f) = if scope {
+                            (&mut self.voice32[..], &mut self.voice_f[..])
+                        } else {
+                            (mix_target(&mut self.buf32[..], &mut self.stem32, stem),
+                             mix_target(&mut self.buf_f[..], &mut self.stem_f, stem))
                         };
+                        match (&sample.sample_type, self.float_mix) {
+                            (&SampleType::Empty, _)        => {},
+                            (&SampleType::Sample8, false)  => md.mix_interp(interp, sample.data_8(), &mut v.filter, buf32),
+                            (&SampleType::Sample8, true)   => md.mix_interp(interp, sample.data_8(), &mut v.filter, buf_f),
+                            (&SampleType::Sample16, false) => md.mix_interp(interp, sample.data_16(), &mut v.filter, buf32),
+                            (&SampleType::Sample16, true)  => md.mix_interp(interp, sample.data_16(), &mut v.filter, buf_f),
+                        };
+                    }
 
-                        buf_pos += mix_size as usize;
+                    buf_pos += samples as usize * 2;
+                    size -= samples;
+                    if v.backward {
+                        v.pos -= step * samples as f64;
+                    } else {
+                        v.pos += step * samples as f64;
                     }
                 }
-                v.pos += step * samples as f64;
-                size -= samples + usmp;
-
-                // No more samples in this frame
-                if size <= 0 {
-                    if sample.has_loop {
-                        if v.pos + step >= v.end as f64 {
-                            v.pos += step;
-                            v.loop_reposition(&sample);
+
+                // Still inside the loop or sample
+                if (v.backward && v.pos >= loop_start) || (!v.backward && v.pos < end) {
+                    continue;
+                }
+
+                // Paula reloads the latched sample at the end of the loop or sample
+                if let Some((ins, smp)) = v.next.take() {
+                    let over = if v.backward { loop_start - v.pos } else { v.pos - end };
+                    v.ins = ins;
+                    v.smp = smp;
+                    v.backward = false;
+                    v.has_loop = false;
+                    sample = &self.module.data.samples()[v.smp];
+                    step = C4_PERIOD * sample.rate / self.rate as f64 / v.period;
+                    match v.active_loop(sample) {
+                        Some(lp) => {
+                            v.pos = lp.start as f64 + over.max(0.0);
+                            v.end = lp.end;
+                            v.has_loop = true;
+                            v.sample_end = false;
+                            continue;
+                        },
+                        None     => {
+                            v.pos = sample.size as f64;
+                            v.end = sample.size;
+                            v.sample_end = true;
+                            break;
                         }
                     }
-                    continue;
                 }
 
                 // First sample loop run
-                if !sample.has_loop {
+                if lp.is_none() {
                     v.sample_end = true;
-                    size = 0;
-                    continue;
+                    break;
+                }
+
+                v.loop_reposition(sample);
+            }
+
+            if scope {
+                let scp = &mut self.scopes[v.num];
+                if self.float_mix {
+                    let buf = mix_target(&mut self.buf_f[..], &mut self.stem_f, stem);
+                    let scale = 1.0 / (1_u64 << (15 + DOWNMIX_SHIFT + RAMP_SHIFT)) as f32;
+                    for i in 0..framesize {
+                        let (l, r) = (self.voice_f[i * 2], self.voice_f[i * 2 + 1]);
+                        buf[i * 2] += l;
+                        buf[i * 2 + 1] += r;
+                        scp[i] += (l + r) * scale;
+                    }
+                } else {
+                    let buf = mix_target(&mut self.buf32[..], &mut self.stem32, stem);
+                    let scale = 1.0 / (1_u64 << (15 + DOWNMIX_SHIFT)) as f32;
+                    for i in 0..framesize {
+                        let (l, r) = (self.voice32[i * 2], self.voice32[i * 2 + 1]);
+                        buf[i * 2] += l;
+                        buf[i * 2 + 1] += r;
+                        scp[i] += (l as i64 + r as i64) as f32 * scale;
+                    }
                 }
+            }
+
+            if v.sample_end {
+                md.vol_l = 0;
+                md.vol_r = 0;
+            }
+            v.cur_vol_l = md.vol_l;
+            v.cur_vol_r = md.vol_r;
+        }
 
-                v.loop_reposition(&sample);
+        // Tails are done when they fade out
+        for t in &mut self.tails {
+            if t.cur_vol_l == 0 && t.cur_vol_r == 0 {
+                t.period = 0.0;
             }
         }
 
+        if self.stems != Stems::Off {
+            self.mix_stems();
+        }
+
         // Render final frame
         self.downmix();
     }
 
+    // Add stems to the master mix and render them to float samples
+    fn mix_stems(&mut self) {
+        let (left, right) = if self.layout == Layout::ReversedStereo { (1, 0) } else { (0, 1) };
+        let size = self.framesize * 2;
+
+        if self.float_mix {
+            let scale = self.gain / (1_u64 << (15 + DOWNMIX_SHIFT + RAMP_SHIFT)) as f32;
+            for (acc, out) in self.stem_f.iter().zip(self.stem_out.iter_mut()) {
+                for (dst, src) in self.buf_f[..size].iter_mut().zip(acc) {
+                    *dst += *src;
+                }
+                for i in 0..self.framesize {
+                    out[i * 2] = acc[i * 2 + left] * scale;
+                    out[i * 2 + 1] = acc[i * 2 + right] * scale;
+                }
+            }
+        } else {
+            let scale = 1.0 / (1_u64 << (15 + DOWNMIX_SHIFT)) as f32;
+            for (acc, out) in self.stem32.iter().zip(self.stem_out.iter_mut()) {
+                for (dst, src) in self.buf32[..size].iter_mut().zip(acc) {
+                    *dst += *src;
+                }
+                for i in 0..self.framesize {
+                    out[i * 2] = acc[i * 2 + left] as f32 * scale;
+                    out[i * 2 + 1] = acc[i * 2 + right] as f32 * scale;
+                }
+            }
+        }
+    }
+
 
     fn downmix(&mut self) {
 
-        let size = self.framesize * 2;
-        let mut i = 0;
-        loop {
-            if i >= size {
-                break;
+        let (left, right) = if self.layout == Layout::ReversedStereo { (1, 0) } else { (0, 1) };
+
+        if self.float_mix {
+            // Scale to full scale 1.0 and apply the master gain
+            let scale = self.gain / (1_u64 << (15 + DOWNMIX_SHIFT + RAMP_SHIFT)) as f32;
+            for i in 0..self.framesize {
+                let mut l = self.buf_f[i * 2 + left] * scale;
+                let mut r = self.buf_f[i * 2 + right] * scale;
+                if self.paula != Paula::Off {
+                    l = self.amiga.apply(0, l);
+                    r = self.amiga.apply(1, r);
+                }
+                match self.layout {
+                    Layout::Mono => {
+                        let smp = self.limit((l + r) / 2.0);
+                        self.put_sample_f32(i, smp);
+                    },
+                    _            => {
+                        let (l, r) = (self.limit(l), self.limit(r));
+                        self.put_sample_f32(i * 2, l);
+                        self.put_sample_f32(i * 2 + 1, r);
+                    }
+                }
             }
+            return;
+        }
 
-            let smp = self.buf32[i] >> DOWNMIX_SHIFT;
-            if smp > LIM16_HI {
-                self.buffer[i] = LIM16_HI as i16;
-            } else if smp < LIM16_LO {
-                self.buffer[i] = LIM16_LO as i16;
-            } else {
-                self.buffer[i] = smp as i16;
+        for i in 0..self.framesize {
+            let mut l = self.buf32[i * 2 + left];
+            let mut r = self.buf32[i * 2 + right];
+            if self.paula != Paula::Off {
+                l = self.amiga.apply(0, l as f32).round() as i32;
+                r = self.amiga.apply(1, r as f32).round() as i32;
+            }
+            match self.layout {
+                Layout::Mono => self.put_sample(i, ((l as i64 + r as i64) / 2) as i32),
+                _            => {
+                    self.put_sample(i * 2, l);
+                    self.put_sample(i * 2 + 1, r);
+                }
             }
+        }
+    }
 
-            i += 1;
+    fn limit(&mut self, smp: f32) -> f32 {
+        match self.limiter {
+            Limiter::Off      => smp,
+            Limiter::Clip     => if smp.abs() > 1.0 {
+                self.overs += 1;
+                smp.signum()
+            } else {
+                smp
+            },
+            Limiter::SoftKnee => if smp.abs() > LIMITER_KNEE {
+                // Approach full scale with the same slope at the knee
+                let range = 1.0 - LIMITER_KNEE;
+                smp.signum() * (LIMITER_KNEE + range * ((smp.abs() - LIMITER_KNEE) / range).tanh())
+            } else {
+                smp
+            },
         }
     }
 
+    fn put_sample_f32(&mut self, i: usize, smp: f32) {
+        // Samples above full scale are only possible with the limiter off
+        if smp.abs() > 1.0 && self.format != SampleFormat::F32 {
+            self.overs += 1;
+        }
+
+        match self.format {
+            SampleFormat::I16 => {
+                let smp = (smp * 32768.0).round();
+                self.buffer[i] = smp.max(LIM16_LO as f32).min(LIM16_HI as f32) as i16;
+            },
+            SampleFormat::I32 => {
+                let smp = (smp as f64 * 2147483648.0).round();
+                self.buffer_i32[i] = smp.max(i32::MIN as f64).min(i32::MAX as f64) as i32;
+            },
+            SampleFormat::F32 => self.buffer_f32[i] = smp,
+        }
+    }
+
+    fn put_sample(&mut self, i: usize, smp: i32) {
+        match self.format {
+            SampleFormat::I16 => {
+                let smp = smp >> DOWNMIX_SHIFT;
+                self.buffer[i] = if smp > LIM16_HI {
+                    self.overs += 1;
+                    LIM16_HI as i16
+                } else if smp < LIM16_LO {
+                    self.overs += 1;
+                    LIM16_LO as i16
+                } else {
+                    smp as i16
+                }
+            },
+            SampleFormat::I32 => {
+                let smp = (smp as i64) << (16 - DOWNMIX_SHIFT);
+                self.buffer_i32[i] = if smp > i32::MAX as i64 {
+                    self.overs += 1;
+                    i32::MAX
+                } else if smp < i32::MIN as i64 {
+                    self.overs += 1;
+                    i32::MIN
+                } else {
+                    smp as i32
+                }
+            },
+            SampleFormat::F32 => {
+                self.buffer_f32[i] = smp as f32 / (1 << (15 + DOWNMIX_SHIFT)) as f32;
+            },
+        }
+    }
+
+    // Number of samples in the output buffer
+    fn buffer_size(&self) -> usize {
+        self.framesize * self.layout.channels()
+    }
+
     pub fn buffer(&self) -> &[i16] {
-        // *2 because we're stereo
-        &self.buffer[..self.framesize*2]
+        &self.buffer[..self.buffer_size()]
+    }
+
+    pub fn buffer_i32(&self) -> &[i32] {
+        &self.buffer_i32[..self.buffer_size()]
+    }
+
+    pub fn buffer_f32(&self) -> &[f32] {
+        &self.buffer_f32[..self.buffer_size()]
     }
 }
 
@@ -359,7 +1071,14 @@ struct Voice {
     smp       : usize,
     end       : usize,
     has_loop  : bool,
+    backward  : bool,   // playing backwards in a bidirectional loop
+    release   : bool,   // note released, sustain loop no longer played
+    cur_vol_l : i32,    // volume at the end of the last frame, for ramps
+    cur_vol_r : i32,
+    attack    : bool,   // new note or position, start without a volume ramp
     sample_end: bool,
+    next      : Option<(usize, usize)>,  // instrument and sample latched by Paula
+    filter    : Filter,
 }
 
 impl Voice {
@@ -368,68 +1087,558 @@ impl Voice {
         v
     }
 
+    // The loop currently played: the sustain loop while the note is held,
+    // otherwise the sample loop
+    fn active_loop(&self, sample: &Sample) -> Option<SampleLoop> {
+        if sample.has_sloop && !self.release {
+            Some(SampleLoop{ start: sample.sloop_start, end: sample.sloop_end, bidir: sample.sloop_bidir, full: false })
+        } else if sample.has_loop {
+            Some(SampleLoop{ start: sample.loop_start, end: sample.loop_end, bidir: sample.loop_bidir, full: sample.loop_full })
+        } else {
+            None
+        }
+    }
+
     pub fn adjust_end(&mut self, sample: &Sample) {
-        if sample.has_loop {
-            if sample.loop_full && !self.has_loop {
+        match self.active_loop(sample) {
+            Some(lp) => if lp.full && !self.has_loop {
                 self.end = sample.size;
             } else {
-                self.end = sample.loop_end;
-            }
-        } else {
-            self.end = sample.size;
+                self.end = lp.end;
+            },
+            None     => self.end = sample.size,
         }
     }
 
     pub fn loop_reposition(&mut self, sample: &Sample) {
-        let loop_size = sample.loop_end - sample.loop_start;
+        let lp = match self.active_loop(sample) {
+            Some(val) => val,
+            None      => return,
+        };
+        let loop_size = lp.end - lp.start;
 
         // Reposition for next loop
-        self.pos -= loop_size as f64;  // forward loop
-        self.end = sample.loop_end;
+        if lp.bidir && self.end != lp.end {
+            // Played past the loop end in the first run, restart at loop start
+            self.pos = lp.start as f64 + (self.pos - self.end as f64);
+            self.backward = false;
+        } else if lp.bidir {
+            // Reflect the position at the loop end we went past
+            let (start, end) = (lp.start as f64 - 0.5, lp.end as f64 - 0.5);
+            self.pos = if self.backward { 2.0 * start - self.pos } else { 2.0 * end - self.pos };
+            self.backward = !self.backward;
+        } else {
+            self.pos -= loop_size as f64;  // forward loop
+        }
+        self.end = lp.end;
         self.has_loop = true;
+    }
+}
+
+
+// Loop points of the sustain loop or sample loop played in a voice
+#[derive(Clone,Copy)]
+struct SampleLoop {
+    start: usize,
+    end  : usize,
+    bidir: bool,
+    full : bool,
+}
+
+
+// Resonant low-pass filter as used in Impulse Tracker
+#[derive(Clone,Debug,Default)]
+struct Filter {
+    enabled: bool,
+    a0     : i64,
+    b0     : i64,
+    b1     : i64,
+    l1     : i64,
+    l2     : i64,
+}
+
+impl Filter {
+    pub fn setup(&mut self, rate: usize, cutoff: usize, resonance: usize) {
+        if cutoff >= 254 && resonance == 0 {
+            self.enabled = false;
+            return;
+        }
+
+        let cutoff = cutoff.min(255) as f64;
+        let resonance = resonance.min(255) as f64;
+
+        let fc = 110.0 * 2.0_f64.powf(0.25 + cutoff / 48.0) * 2.0 * std::f64::consts::PI / rate as f64;
+        let dmpfac = 10.0_f64.powf(-resonance * 24.0 / (256.0 * 20.0));
+        let d = ((1.0 - 2.0 * dmpfac) * fc).min(2.0);
+        let d = (2.0 * dmpfac - d) / fc;
+        let e = 1.0 / (fc * fc);
+
+        let fg = 1.0 / (1.0 + d + e);
+        let fb0 = (d + e + e) / (1.0 + d + e);
+        let fb1 = -e / (1.0 + d + e);
+
+        if !self.enabled {
+            self.reset();
+        }
+        self.enabled = true;
+        self.a0 = (fg * (1 << FILTER_SHIFT) as f64) as i64;
+        self.b0 = (fb0 * (1 << FILTER_SHIFT) as f64) as i64;
+        self.b1 = (fb1 * (1 << FILTER_SHIFT) as f64) as i64;
+    }
+
+    pub fn reset(&mut self) {
+        self.l1 = 0;
+        self.l2 = 0;
+    }
+
+    pub fn apply(&mut self, smp: i32) -> i32 {
+        let val = (self.a0 * smp as i64 + self.b0 * self.l1 + self.b1 * self.l2) >> FILTER_SHIFT;
+        let val = val.clamp(-65536, 65535);
+        self.l2 = self.l1;
+        self.l1 = val;
+        val as i32
+    }
+}
+
+
+// Mixer accumulation buffer sample. Volumes are shifted by RAMP_SHIFT, the
+// integer mixer drops the fractional part as the original players do.
+trait MixBuffer {
+    fn add(&mut self, smp: i32, vol: i32);
+}
+
+impl MixBuffer for i32 {
+    fn add(&mut self, smp: i32, vol: i32) {
+        *self += smp * (vol >> RAMP_SHIFT);
+    }
+}
 
-        //if self.bidir_loop {
-        //}
+impl MixBuffer for f32 {
+    fn add(&mut self, smp: i32, vol: i32) {
+        *self += smp as f32 * vol as f32;
     }
+}
 
-    pub fn anticlick(&self) {
+// Mix voices to their stem accumulation buffer if rendering stems
+fn mix_target<'b, T>(master: &'b mut [T], stems: &'b mut [Vec<T>], stem: Option<usize>) -> &'b mut [T] {
+    match stem.and_then(move |s| stems.get_mut(s)) {
+        Some(buf) => &mut buf[..],
+        None      => master,
     }
 }
 
 
 struct MixerData {
-    pub pos    : f64,
-    pub buf_pos: usize,
-    pub step   : usize,
-    pub size   : isize,
-    pub vol_l  : usize,
-    pub vol_r  : usize,
+    pub pos       : f64,
+    pub buf_pos   : usize,
+    pub step      : isize,   // negative when playing backwards
+    pub size      : isize,
+    pub vol_l     : i32,     // current volume, shifted by RAMP_SHIFT
+    pub vol_r     : i32,
+    pub delta_l   : i32,     // volume change in each ramp step
+    pub delta_r   : i32,
+    pub target_l  : i32,
+    pub target_r  : i32,
+    pub ramp      : usize,   // ramp steps left
+    pub loop_start: isize,   // loop points in sample data coordinates
+    pub loop_end  : isize,
+    pub wrap_start: bool,    // samples before the loop start are read from the loop
+    pub wrap_end  : bool,    // samples after the loop end are read from the loop
+    pub bidir     : bool,
 }
 
 impl MixerData {
-    fn mix<T>(&mut self, interp: &Interpolator, data: &[T], buf32: &mut [i32])
-    where interpolator::Nearest: interpolator::Interpolate<T>,
-          interpolator::Linear : interpolator::Interpolate<T>
+    fn set_volume(&mut self, v: &mut Voice, vol_l: i32, vol_r: i32, ramp_len: usize) {
+        self.target_l = vol_l << RAMP_SHIFT;
+        self.target_r = vol_r << RAMP_SHIFT;
+
+        if v.attack || ramp_len == 0 || (v.cur_vol_l == self.target_l && v.cur_vol_r == self.target_r) {
+            v.attack = false;
+            self.vol_l = self.target_l;
+            self.vol_r = self.target_r;
+            self.ramp = 0;
+        } else {
+            self.vol_l = v.cur_vol_l;
+            self.vol_r = v.cur_vol_r;
+            self.delta_l = (self.target_l - self.vol_l) / ramp_len as i32;
+            self.delta_r = (self.target_r - self.vol_r) / ramp_len as i32;
+            self.ramp = ramp_len;
+        }
+    }
+
+    // Advance the volume ramp as if the samples were mixed
+    fn skip_ramp(&mut self, num: usize) {
+        if num >= self.ramp {
+            if self.ramp > 0 {
+                self.vol_l = self.target_l;
+                self.vol_r = self.target_r;
+            }
+            self.ramp = 0;
+        } else {
+            self.vol_l += self.delta_l * num as i32;
+            self.vol_r += self.delta_r * num as i32;
+            self.ramp -= num;
+        }
+    }
+
+    fn set_loop(&mut self, v: &Voice, lp: Option<SampleLoop>) {
+        let lp = match lp {
+            Some(val) if v.end == val.end => val,
+            _ => {
+                self.wrap_start = false;
+                self.wrap_end = false;
+                return;
+            }
+        };
+        self.loop_start = (lp.start + GUARD) as isize;
+        self.loop_end = (lp.end + GUARD) as isize;
+        self.wrap_start = v.has_loop;
+        self.wrap_end = true;
+        self.bidir = lp.bidir;
+    }
+
+    // Index of the sample data that is played at the given position, so the
+    // interpolator reads the right neighbours around loop ends
+    fn tap(&self, idx: isize) -> usize {
+        if (self.wrap_end && idx >= self.loop_end) || (self.wrap_start && idx < self.loop_start) {
+            let len = self.loop_end - self.loop_start;
+            if self.bidir {
+                let m = (idx - self.loop_start).rem_euclid(2 * len);
+                (if m < len { self.loop_start + m } else { self.loop_start + 2 * len - 1 - m }) as usize
+            } else {
+                (self.loop_start + (idx - self.loop_start).rem_euclid(len)) as usize
+            }
+        } else {
+            idx as usize
+        }
+    }
+
+    fn mix_interp<T: Copy, B: MixBuffer>(&mut self, interp: (Interpolator, &interpolator::Cubic, &interpolator::Sinc),
+                                         data: &[T], filter: &mut Filter, buf: &mut [B])
+    where interpolator::Nearest: Interpolate<T>,
+          interpolator::Linear : Interpolate<T>,
+          interpolator::Cubic  : Interpolate<T>,
+          for<'b> interpolator::Sinc<'b>: Interpolate<T>
     {
-        let mut pos = self.pos as usize;
-        let mut frac = ((1 << SMIX_SHIFT) as f64 * (self.pos - pos as f64)) as usize;
+        match interp {
+            (Interpolator::Nearest, _, _) => self.mix(&interpolator::Nearest, data, filter, buf),
+            (Interpolator::Linear, _, _)  => self.mix(&interpolator::Linear, data, filter, buf),
+            (Interpolator::Cubic, c, _)   => self.mix(c, data, filter, buf),
+            (Interpolator::Sinc, _, s)    => self.mix(s, data, filter, buf),
+        }
+    }
+
+    fn mix<T: Copy, I: Interpolate<T> + InterpolatorBase, B: MixBuffer>(&mut self, interp: &I, data: &[T], filter: &mut Filter, buf: &mut [B]) {
+        let mut fpos = ((1 << SMIX_SHIFT) as f64 * self.pos) as i64;
         let mut bpos = self.buf_pos;
 
+        let lo = if self.wrap_start { self.loop_start } else { isize::MIN };
+        let hi = if self.wrap_end { self.loop_end } else { isize::MAX };
+        let (before, after) = I::window();
+        let (before, after) = (before as isize, after as isize);
+
         for _ in 0..self.size {
-            let i = &data[pos-1..pos+2];
+            let pos = (fpos >> SMIX_SHIFT) as isize;
+            let frac = (fpos & SMIX_MASK as i64) as i32;
 
-            let smp = match interp {
-                &Interpolator::Nearest => interpolator::Nearest.get_sample(i, frac as i32),
-                &Interpolator::Linear  => interpolator::Linear.get_sample(i, frac as i32),
+            let mut tmp = [data[0]; 2 * GUARD];
+            let i = if pos - before < lo || pos + after > hi {
+                for (k, idx) in (pos - before..pos + after).enumerate() {
+                    tmp[k] = data[self.tap(idx)];
+                }
+                &tmp[..(before + after) as usize]
+            } else {
+                &data[(pos - before) as usize..(pos + after) as usize]
             };
 
-            buf32[bpos    ] += smp * self.vol_r as i32;
-            buf32[bpos + 1] += smp * self.vol_l as i32;
+            let smp = interp.get_sample(i, frac);
+
+            let smp = if filter.enabled { filter.apply(smp) } else { smp };
+
+            buf[bpos    ].add(smp, self.vol_r);
+            buf[bpos + 1].add(smp, self.vol_l);
             bpos += 2;
 
-            frac += self.step;
-            pos += frac >> SMIX_SHIFT;
-            frac &= SMIX_MASK;
+            if self.ramp > 0 {
+                self.ramp -= 1;
+                if self.ramp == 0 {
+                    self.vol_l = self.target_l;
+                    self.vol_r = self.target_r;
+                } else {
+                    self.vol_l += self.delta_l;
+                    self.vol_r += self.delta_r;
+                }
+            }
+
+            fpos += self.step as i64;
+        }
+    }
+}
+
+
+#[cfg(test)]
+pub mod tests {
+    use format::Loader;
+    use super::*;
+
+    /// Protracker module with one empty pattern and a looped 8-bit sample of
+    /// constant value for each length in words and value given.
+    pub fn test_module(samples: &[(usize, i8)]) -> Arc<Module> {
+        let size: usize = samples.iter().map(|s| s.0 * 2).sum();
+        let mut b = vec![0; 1084 + 1024 + size];
+        let mut ofs = 1084 + 1024;
+        for (i, &(len, val)) in samples.iter().enumerate() {
+            let h = 20 + 30 * i;
+            b[h + 22] = (len >> 8) as u8;  // length in words
+            b[h + 23] = len as u8;
+            b[h + 25] = 64;                // volume
+            b[h + 28] = (len >> 8) as u8;  // loop length
+            b[h + 29] = len as u8;
+            for x in &mut b[ofs..ofs + len * 2] {
+                *x = val as u8;
+            }
+            ofs += len * 2;
+        }
+        b[950] = 1;
+        b[1080..1084].copy_from_slice(b"M.K.");
+        Arc::new(format::load(&b).unwrap())
+    }
+
+    // Play the first sample at full volume in voice 0 for one frame
+    fn test_mixer(module: Arc<Module>) -> Mixer {
+        let mut mixer = Mixer::new(4, 44100, module);
+        mixer.create_voices(4);
+        mixer.set_voice(0, 0);
+        mixer.set_interpolator(Interpolator::Nearest);
+        mixer.set_anticlick(false);
+        mixer.set_tempo(125);
+        mixer.set_patch(0, 0, 0, false);
+        mixer.set_period(0, 428.0);
+        mixer.set_volume(0, 64 << 4);
+        mixer
+    }
+
+    // Impulse Tracker module with a 32-byte sample of value 32 in the first
+    // half and 96 in the second half, with a sustain loop from 8 to 16 and a
+    // sample loop from 16 to 32
+    fn sustain_module() -> Arc<Module> {
+        let mut b = vec![0; 277 + 32];
+        b[0..4].copy_from_slice(b"IMPM");
+        b[32] = 1;       // orders
+        b[36] = 1;       // samples
+        b[192] = 255;    // end of song
+        b[193] = 197;    // sample header offset
+        let h = 197;
+        b[h..h + 4].copy_from_slice(b"IMPS");
+        b[h + 18] = 0x31;  // sample data, loop and sustain loop
+        b[h + 19] = 64;    // volume
+        b[h + 46] = 0x01;  // signed samples
+        b[h + 48] = 32;    // length
+        b[h + 52] = 16;    // loop start
+        b[h + 56] = 32;    // loop end
+        b[h + 60] = 0xab;  // C5 speed 8363
+        b[h + 61] = 0x20;
+        b[h + 64] = 8;     // sustain loop start
+        b[h + 68] = 16;    // sustain loop end
+        b[h + 72] = 21;    // sample data offset 277
+        b[h + 73] = 1;
+        for (i, x) in b[277..].iter_mut().enumerate() {
+            *x = if i < 16 { 32 } else { 96 };
+        }
+        Arc::new(Box::new(format::it::ItLoader).load(&b).unwrap())
+    }
+
+    fn mixer_data(bidir: bool) -> MixerData {
+        MixerData{
+            pos       : 0.0_f64,
+            buf_pos   : 0,
+            step      : 0,
+            size      : 0,
+            vol_r     : 0,
+            vol_l     : 0,
+            delta_l   : 0,
+            delta_r   : 0,
+            target_l  : 0,
+            target_r  : 0,
+            ramp      : 0,
+            loop_start: 10,
+            loop_end  : 14,
+            wrap_start: true,
+            wrap_end  : true,
+            bidir,
+        }
+    }
+
+    #[test]
+    fn test_tap_forward_loop() {
+        let md = mixer_data(false);
+        assert_eq!(md.tap(12), 12);
+        assert_eq!(md.tap(14), 10);
+        assert_eq!(md.tap(15), 11);
+        assert_eq!(md.tap(9), 13);
+    }
+
+    #[test]
+    fn test_tap_bidir_loop() {
+        let md = mixer_data(true);
+        assert_eq!(md.tap(12), 12);
+        assert_eq!(md.tap(14), 13);
+        assert_eq!(md.tap(15), 12);
+        assert_eq!(md.tap(9), 10);
+        assert_eq!(md.tap(8), 11);
+    }
+
+    #[test]
+    fn test_sustain_loop() {
+        let mut mixer = test_mixer(sustain_module());
+
+        // The held note repeats the sustain loop
+        for _ in 0..4 {
+            mixer.mix();
+            assert!(mixer.voicepos(0) >= 8.0 && mixer.voicepos(0) < 16.0);
+            assert!(mixer.buffer().iter().all(|&x| x == 4096));
         }
+
+        // Key off continues into the sample loop
+        mixer.release(0);
+        for _ in 0..4 {
+            mixer.mix();
+            assert!(mixer.voicepos(0) >= 16.0 && mixer.voicepos(0) < 32.0);
+        }
+        assert!(mixer.buffer().iter().all(|&x| x == 12288));
+    }
+
+    #[test]
+    fn test_anticlick_ramp() {
+        let mut mixer = test_mixer(test_module(&[(32, 64)]));
+        mixer.set_float_mix(false);
+        mixer.set_anticlick(true);
+        mixer.mix();
+
+        // Volume changes ramp over 1/200 s
+        mixer.set_volume(0, 0);
+        mixer.mix();
+        let buf = mixer.buffer();
+        assert_eq!(buf[0], 8192);
+        assert!(buf.windows(2).all(|x| x[0] >= x[1]));
+        assert!(buf[219 * 2] > 0);
+        assert!(buf[220 * 2..].iter().all(|&x| x == 0));
+
+        // Paula emulation turns anti-click off
+        mixer.set_paula(Paula::A500);
+        mixer.set_paula(Paula::Off);
+        mixer.set_volume(0, 64 << 4);
+        mixer.mix();
+        mixer.set_volume(0, 0);
+        mixer.mix();
+        assert!(mixer.buffer().iter().all(|&x| x == 0));
+    }
+
+    #[test]
+    fn test_anticlick_tail() {
+        let mut mixer = test_mixer(test_module(&[(32, 64)]));
+        mixer.set_float_mix(false);
+        mixer.set_anticlick(true);
+        mixer.mix();
+
+        // The sound of a cut voice fades out in its tail
+        mixer.reset_voice(0);
+        mixer.mix();
+        let buf = mixer.buffer();
+        assert_eq!(buf[0], 8192);
+        assert!(buf[219 * 2] > 0);
+        assert!(buf[220 * 2..].iter().all(|&x| x == 0));
+
+        // and is silent in the next frame
+        mixer.mix();
+        assert!(mixer.buffer().iter().all(|&x| x == 0));
+    }
+
+    #[test]
+    fn test_frame_size() {
+        let mut mixer = Mixer::new(4, 44100, test_module(&[]));
+
+        // 861.328125 samples per tick, fractional part carried between frames
+        let mut total = 0;
+        for _ in 0..64 {
+            mixer.set_tempo(128);
+            total += mixer.framesize;
+        }
+        assert_eq!(total, 55125);
+
+        // Protracker CIA timer value 1773447 / 125 = 14187
+        mixer.set_timing(Timing::Cia, Clock::Pal);
+        assert_eq!(mixer.tick_time(125), 14187.0 / 709379.0);
+        mixer.set_timing(Timing::CiaTimer(17568), Clock::Pal);
+        assert_eq!(mixer.tick_time(125), 17568.0 / 709379.0);
+        mixer.set_timing(Timing::Vblank, Clock::Ntsc);
+        assert!(mixer.tick_time(125) > 1.0 / 60.0 && mixer.tick_time(125) < 1.0 / 59.0);
+    }
+
+    #[test]
+    fn test_paula() {
+        let mut mixer = test_mixer(test_module(&[(32, 64), (16, -64)]));
+        mixer.set_float_mix(true);
+        mixer.set_paula(Paula::A500);
+        mixer.mix();
+
+        // Channel 0 plays on the left output only, through the A500 filters
+        let mut filter = paula::AudioFilter::new(Paula::A500, 44100);
+        for (i, smp) in mixer.buffer().chunks(2).enumerate() {
+            let l = (filter.apply(0, 0.5) * 32768.0).round() as i16;
+            assert_eq!(smp, &[l, 0], "sample {}", i);
+        }
+
+        // Latched sample starts at the end of the loop
+        mixer.queue_patch(0, 1, 1);
+        assert_eq!(mixer.voice_smp(0), Some(0));
+        mixer.mix();
+        assert_eq!(mixer.voice_smp(0), Some(1));
+        assert!(mixer.voicepos(0) < 32.0);
+        let buf = mixer.buffer();
+        assert!(buf[0] > 0 && buf[buf.len() - 2] < 0);
+
+        // or when the voice position is set
+        mixer.queue_patch(0, 0, 0);
+        mixer.set_voicepos(0, 0.0, false);
+        assert_eq!(mixer.voice_smp(0), Some(0));
+    }
+
+    #[test]
+    fn test_mix() {
+        // Sample value 64 at volume 64 and center pan is a quarter of full scale
+        let mut mixer = test_mixer(test_module(&[(32, 64)]));
+        mixer.set_float_mix(false);
+        mixer.mix();
+        assert_eq!(mixer.buffer().len(), 882 * 2);
+        assert!(mixer.buffer().iter().all(|&x| x == 8192));
+
+        mixer.set_float_mix(true);
+        mixer.set_master_gain(2.0);
+        mixer.mix();
+        assert!(mixer.buffer().iter().all(|&x| x == 16384));
+
+        mixer.set_master_gain(8.0);
+        mixer.set_format(SampleFormat::F32);
+        mixer.mix();
+        assert!(mixer.buffer_f32().iter().all(|&x| x == 1.0));
+        assert_eq!(mixer.overs(), 882 * 2);
+    }
+
+    #[test]
+    fn test_limiter() {
+        let mut mixer = Mixer::new(4, 44100, test_module(&[]));
+
+        mixer.set_limiter(Limiter::Clip);
+        assert_eq!(mixer.limit(0.5), 0.5);
+        assert_eq!(mixer.limit(-1.5), -1.0);
+        assert_eq!(mixer.overs(), 1);
+
+        mixer.set_limiter(Limiter::SoftKnee);
+        assert_eq!(mixer.limit(0.5), 0.5);
+        assert!(mixer.limit(0.81) > 0.8 && mixer.limit(0.81) < 0.81);
+        assert!(mixer.limit(4.0) <= 1.0 && mixer.limit(-4.0) >= -1.0);
+        assert!(mixer.limit(0.9) < 0.9);
+        assert_eq!(mixer.overs(), 1);
     }
 }
@@ -0,0 +1,180 @@
+use std::f64::consts::PI;
+
+// Filter components of the Amiga audio output stage
+const A500_LP_R   : f64 = 360.0;       // A500 RC low-pass filter, ~4421 Hz
+const A500_LP_C   : f64 = 1.0e-7;
+const A1200_LP_R  : f64 = 680.0;       // A1200 RC low-pass filter, ~34419 Hz
+const A1200_LP_C  : f64 = 6.8e-9;
+const HP_R        : f64 = 1390.0;      // RC high-pass filter in both models, ~5.2 Hz
+const HP_C        : f64 = 2.2e-5;
+const LED_R1      : f64 = 10000.0;     // Sallen-Key LED filter, ~3091 Hz
+const LED_R2      : f64 = 10000.0;
+const LED_C1      : f64 = 6.8e-9;
+const LED_C2      : f64 = 3.9e-9;
+
+
+/// Amiga model emulated by the mixer. `A500` and `A1200` apply the output
+/// filters of the model and the LED filter when the module turns it on, pan
+/// channels hard left and right in LRRL order, and start samples set while a
+/// voice is playing only when the current loop iteration ends.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Paula {
+    Off,
+    A500,
+    A1200,
+}
+
+
+// One-pole RC filter
+#[derive(Clone,Copy,Default)]
+struct OnePole {
+    a: f64,
+    y: f64,
+}
+
+impl OnePole {
+    fn new(rate: f64, r: f64, c: f64) -> Self {
+        let fc = 1.0 / (2.0 * PI * r * c);
+        OnePole {
+            a: 1.0 - (-2.0 * PI * fc / rate).exp(),
+            y: 0.0,
+        }
+    }
+
+    fn lowpass(&mut self, x: f64) -> f64 {
+        self.y += self.a * (x - self.y);
+        self.y
+    }
+
+    fn highpass(&mut self, x: f64) -> f64 {
+        x - self.lowpass(x)
+    }
+}
+
+
+// Two-pole low-pass filter, bilinear transform of the Sallen-Key circuit
+#[derive(Clone,Copy,Default)]
+struct TwoPole {
+    b0: f64,
+    b1: f64,
+    a1: f64,
+    a2: f64,
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl TwoPole {
+    fn new(rate: f64) -> Self {
+        let rc = (LED_R1 * LED_R2 * LED_C1 * LED_C2).sqrt();
+        let fc = (1.0 / (2.0 * PI * rc)).min(rate * 0.45);
+        let q = rc / (LED_C2 * (LED_R1 + LED_R2));
+
+        let w0 = 2.0 * PI * fc / rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha;
+
+        TwoPole {
+            b0: (1.0 - w0.cos()) / 2.0 / a0,
+            b1: (1.0 - w0.cos()) / a0,
+            a1: -2.0 * w0.cos() / a0,
+            a2: (1.0 - alpha) / a0,
+            ..Default::default()
+        }
+    }
+
+    fn apply(&mut self, x: f64) -> f64 {
+        let y = self.b0 * (x + self.x2) + self.b1 * self.x1 - self.a1 * self.y1 - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+
+// Output filters of the left and right channels
+#[derive(Clone,Copy)]
+pub struct AudioFilter {
+    led_on: bool,
+    lp    : [OnePole; 2],
+    led   : [TwoPole; 2],
+    hp    : [OnePole; 2],
+}
+
+impl AudioFilter {
+    pub fn new(model: Paula, rate: usize) -> Self {
+        let rate = rate as f64;
+        let lp = match model {
+            Paula::A1200 => OnePole::new(rate, A1200_LP_R, A1200_LP_C),
+            _            => OnePole::new(rate, A500_LP_R, A500_LP_C),
+        };
+        let led = TwoPole::new(rate);
+        let hp = OnePole::new(rate, HP_R, HP_C);
+
+        AudioFilter {
+            led_on: false,
+            lp    : [lp; 2],
+            led   : [led; 2],
+            hp    : [hp; 2],
+        }
+    }
+
+    pub fn set_led(&mut self, on: bool) {
+        self.led_on = on;
+    }
+
+    pub fn reset(&mut self) {
+        self.led_on = false;
+        for i in 0..2 {
+            self.lp[i].y = 0.0;
+            self.hp[i].y = 0.0;
+            self.led[i] = TwoPole{ x1: 0.0, x2: 0.0, y1: 0.0, y2: 0.0, ..self.led[i] };
+        }
+    }
+
+    pub fn apply(&mut self, chn: usize, smp: f32) -> f32 {
+        let mut x = self.lp[chn].lowpass(smp as f64);
+        if self.led_on {
+            x = self.led[chn].apply(x);
+        }
+        self.hp[chn].highpass(x) as f32
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use super::{AudioFilter, Paula};
+
+    #[test]
+    fn test_audio_filter() {
+        // DC is removed by the high-pass filter
+        let mut f = AudioFilter::new(Paula::A500, 44100);
+        let mut y = 0.0;
+        for _ in 0..44100 {
+            y = f.apply(0, 0.5);
+        }
+        assert!(y.abs() < 0.01);
+
+        // The LED filter attenuates a 10 kHz tone
+        let peak = |led: bool| {
+            let mut f = AudioFilter::new(Paula::A1200, 44100);
+            f.set_led(led);
+            let mut peak = 0.0_f32;
+            for i in 0..4410 {
+                let x = (i as f32 * 2.0 * PI * 10000.0 / 44100.0).sin();
+                let y = f.apply(1, x);
+                if i > 2205 {
+                    peak = peak.max(y.abs());
+                }
+            }
+            peak
+        };
+        assert!(peak(false) > 0.9);
+        assert!(peak(true) < 0.2);
+    }
+}
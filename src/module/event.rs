@@ -1,6 +1,10 @@
 use fmt;
 use util::NOTES;
 
+pub const KEY_OFF : u8 = 0x81;
+pub const KEY_CUT : u8 = 0x82;
+pub const KEY_FADE: u8 = 0x83;
+
 #[derive(Debug)]
 pub struct Event {
     pub note: u8,
@@ -10,6 +14,12 @@ pub struct Event {
     pub fxp : u8,
 }
 
+impl Default for Event {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Event {
     pub fn new() -> Self {
         Event {
@@ -26,6 +36,12 @@ impl fmt::Display for Event {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let note = if self.note == 0 {
             "---".to_owned()
+        } else if self.note == KEY_OFF {
+            "===".to_owned()
+        } else if self.note == KEY_CUT {
+            "^^^".to_owned()
+        } else if self.note == KEY_FADE {
+            "~~~".to_owned()
         } else {
             format!("{}{}", NOTES[self.note as usize % 12], self.note / 12)
         };
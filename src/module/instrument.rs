@@ -0,0 +1,178 @@
+use ::*;
+
+/// New note action, what happens to the playing note when a new note is
+/// played in the same channel. Also used as duplicate check action.
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub enum NoteAction {
+    #[default]
+    Cut,
+    Continue,
+    Off,
+    Fade,
+}
+
+
+/// Duplicate check type, which notes playing in the background are checked
+/// against the new note.
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub enum DuplicateCheck {
+    #[default]
+    Off,
+    Note,
+    Sample,
+    Instrument,
+}
+
+
+/// New note action and duplicate check settings used when a note is played.
+/// The default cuts the previous note and doesn't check duplicates.
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub struct NoteActions {
+    pub nna: NoteAction,      // new note action
+    pub dct: DuplicateCheck,  // duplicate check type
+    pub dca: NoteAction,      // duplicate check action
+}
+
+
+
+/// Envelope point, `x` is the position in ticks and `y` is the value.
+#[derive(Clone,Copy,Debug,Default)]
+pub struct EnvelopePoint {
+    pub x: usize,
+    pub y: isize,
+}
+
+/// Instrument envelope. Loop and sustain boundaries are point indices.
+#[derive(Clone,Debug,Default)]
+pub struct Envelope {
+    pub enabled    : bool,
+    pub points     : Vec<EnvelopePoint>,
+    pub has_loop   : bool,
+    pub loop_start : usize,
+    pub loop_end   : usize,
+    pub has_sustain: bool,
+    pub sus_start  : usize,
+    pub sus_end    : usize,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Compute the envelope value at the given position, interpolating
+    /// between envelope points.
+    pub fn value(&self, pos: usize) -> isize {
+        let num = self.points.len();
+        if num == 0 {
+            return 0;
+        }
+
+        let i = match self.points.iter().position(|p| p.x > pos) {
+            Some(0)   => return self.points[0].y,
+            Some(val) => val,
+            None      => return self.points[num - 1].y,
+        };
+
+        let p1 = &self.points[i - 1];
+        let p2 = &self.points[i];
+        let dx = (p2.x - p1.x) as isize;
+        if dx == 0 {
+            return p2.y;
+        }
+
+        p1.y + (p2.y - p1.y) * (pos - p1.x) as isize / dx
+    }
+
+    /// Position in ticks of the given envelope point.
+    pub fn point_x(&self, num: usize) -> usize {
+        if num < self.points.len() {
+            self.points[num].x
+        } else {
+            0
+        }
+    }
+}
+
+
+/// Generic instrument description. Format-specific instrument data is
+/// kept by each format and used directly by the format players.
+#[derive(Clone,Debug)]
+pub struct Instrument {
+    pub num      : usize,
+    pub name     : String,
+    pub volume   : usize,               // default volume (0-64)
+    pub keymap   : Vec<Option<usize>>,  // sample played by each key
+    pub vol_env  : Envelope,
+    pub pan_env  : Envelope,
+    pub pitch_env: Envelope,
+    pub fade_out : usize,
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Instrument {
+    pub fn new() -> Self {
+        Instrument {
+            num      : 0,
+            name     : "".to_owned(),
+            volume   : 64,
+            keymap   : vec![None; MAX_KEYS],
+            vol_env  : Envelope::new(),
+            pan_env  : Envelope::new(),
+            pitch_env: Envelope::new(),
+            fade_out : 0,
+        }
+    }
+
+    /// Create an instrument that plays the same sample in all keys.
+    pub fn with_sample(num: usize, name: &str, volume: usize, smp: Option<usize>) -> Self {
+        let mut ins = Instrument::new();
+        ins.num = num;
+        ins.name = name.to_owned();
+        ins.volume = volume;
+        ins.keymap = vec![smp; MAX_KEYS];
+        ins
+    }
+
+    /// Return the list of samples used by this instrument.
+    pub fn samples(&self) -> Vec<usize> {
+        let mut list = Vec::<usize>::new();
+        for &k in &self.keymap {
+            if let Some(smp) = k {
+                if !list.contains(&smp) {
+                    list.push(smp);
+                }
+            }
+        }
+        list
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_value() {
+        let mut env = Envelope::new();
+        assert_eq!(env.value(10), 0);
+
+        env.points = vec![
+            EnvelopePoint{ x: 0, y: 0 },
+            EnvelopePoint{ x: 10, y: 64 },
+            EnvelopePoint{ x: 20, y: 32 },
+        ];
+        assert_eq!(env.value(0), 0);
+        assert_eq!(env.value(5), 32);
+        assert_eq!(env.value(10), 64);
+        assert_eq!(env.value(15), 48);
+        assert_eq!(env.value(20), 32);
+        assert_eq!(env.value(100), 32);
+    }
+}
@@ -1,8 +1,10 @@
 pub mod sample;
 pub mod event;
+pub mod instrument;
 
 pub use self::sample::Sample;
 pub use self::event::Event;
+pub use self::instrument::Instrument;
 
 use std::any::Any;
 use std::marker::{Sync, Send};
@@ -10,14 +12,14 @@ use std::marker::{Sync, Send};
 
 // Module
 
-pub struct Module<'a> {
+pub struct Module {
     pub format     : &'static str,       // format identifier
-    pub description: &'a str,            // format description
+    pub description: &'static str,       // format description
     pub player     : &'static str,       // primary player for this format
-    pub data       : Box<ModuleData>     //
+    pub data       : Box<dyn ModuleData>     //
 }
 
-impl<'a> Module<'a> {
+impl Module {
     pub fn title(&self) -> &str {
         self.data.title()
     }
@@ -34,6 +36,10 @@ impl<'a> Module<'a> {
         self.data.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
     pub fn pattern_in_position(&self, pos: usize) -> Option<usize> {
         self.data.pattern_in_position(pos)
     }
@@ -46,7 +52,7 @@ impl<'a> Module<'a> {
         self.data.prev_position(pos)
     }
 
-    pub fn instruments(&self) -> Vec<String> {
+    pub fn instruments(&self) -> Vec<Instrument> {
         self.data.instruments()
     }
 
@@ -64,15 +70,18 @@ impl<'a> Module<'a> {
 }
 
 pub trait ModuleData: Send + Sync {
-    fn as_any(&self) -> &Any;
+    fn as_any(&self) -> &dyn Any;
     fn title(&self) -> &str;            // module title
     fn channels(&self) -> usize;        // number of channels
     fn patterns(&self) -> usize;        // number of patterns
     fn len(&self) -> usize;             // module length
-    fn pattern_in_position(&self, usize) -> Option<usize>;
-    fn next_position(&self, usize) -> usize;
-    fn prev_position(&self, usize) -> usize;
-    fn instruments(&self) -> Vec<String>;
+    fn is_empty(&self) -> bool {        // module has no positions
+        self.len() == 0
+    }
+    fn pattern_in_position(&self, _: usize) -> Option<usize>;
+    fn next_position(&self, _: usize) -> usize;
+    fn prev_position(&self, _: usize) -> usize;
+    fn instruments(&self) -> Vec<Instrument>;
     fn event(&self, num: usize, row: usize, chn: usize) -> Option<Event>;
     fn rows(&self, pat: usize) -> usize;  // number of rows in pattern
     fn samples(&self) -> &Vec<Sample>;
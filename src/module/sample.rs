@@ -1,7 +1,7 @@
 use std::slice;
 
-// Allow 2 samples in 16-bit
-pub const GUARD_SIZE: usize = 4;
+// Allow 8 samples in 16-bit, enough for the widest interpolation kernel
+pub const GUARD_SIZE: usize = 16;
 
 
 #[derive(Debug)]
@@ -31,6 +31,12 @@ pub struct Sample {
     data            : Vec<u8>,
 }
 
+impl Default for Sample {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Sample {
     pub fn new() -> Sample {
         Sample {
@@ -60,13 +66,13 @@ impl Sample {
 
     pub fn data_8(&self) -> &[i8] {
         unsafe {
-            slice::from_raw_parts(self.data.as_ptr().offset(2) as *const i8, self.size + 2 * (GUARD_SIZE/2) as usize)
+            slice::from_raw_parts(self.data.as_ptr().add(GUARD_SIZE/2) as *const i8, self.size + 2 * (GUARD_SIZE/2))
         }
     }
 
     pub fn data_16(&self) -> &[i16] {
         unsafe {
-            slice::from_raw_parts(self.data.as_ptr() as *const i16, self.size + 2 * GUARD_SIZE as usize)
+            slice::from_raw_parts(self.data.as_ptr() as *const i16, self.size + 2 * (GUARD_SIZE/2))
         }
     }
 }
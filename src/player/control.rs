@@ -0,0 +1,224 @@
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use player::{FrameInfo, ChannelInfo, Interpolator, ScanData};
+
+// Commands waiting to be run by the player
+const QUEUE_SIZE: usize = 64;
+
+// Set in the shared state slot index when it holds a frame not read yet
+const NEW_STATE: usize = 4;
+
+
+/// Commands sent to a player running in another thread. They run before the
+/// player renders the next frame in `fill_buffer`, in constant time and
+/// without allocating memory.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Command {
+    SetPosition(usize, usize, usize, usize),  // position, row, speed, tempo
+    Mute(usize, bool),                        // module channel, mute
+    SetInterpolator(Interpolator),
+    Pause(bool),
+}
+
+impl Command {
+    /// Jump to the row played at the given time, with the speed and tempo
+    /// found in the scan data of the module. Unlike `Player::seek_time` the
+    /// module isn't replayed up to the row, so effect memory and notes still
+    /// playing are not restored.
+    pub fn seek(scan: &ScanData, millis: u32) -> Option<Command> {
+        let (pos, row) = scan.find_time(millis)?;
+        let r = scan.row(pos, row)?;
+        Some(Command::SetPosition(pos, row, r.speed as usize, r.tempo as usize))
+    }
+}
+
+
+// Single producer, single consumer ring buffer of commands.
+struct CommandQueue {
+    buf : Vec<UnsafeCell<Option<Command>>>,
+    head: AtomicUsize,  // next command to run, changed by the player
+    tail: AtomicUsize,  // next free slot, changed by the remote
+}
+
+// Only the player pops and only the remote pushes, and both need a mutable
+// reference to their end of the queue to do it.
+unsafe impl Sync for CommandQueue {}
+
+impl CommandQueue {
+    fn new() -> Self {
+        CommandQueue {
+            buf : (0..QUEUE_SIZE).map(|_| UnsafeCell::new(None)).collect(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    fn push(&self, cmd: Command) -> Result<(), Command> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) >= QUEUE_SIZE {
+            return Err(cmd);
+        }
+        unsafe { *self.buf[tail % QUEUE_SIZE].get() = Some(cmd) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    fn pop(&self) -> Option<Command> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let cmd = unsafe { (*self.buf[head % QUEUE_SIZE].get()).take() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        cmd
+    }
+}
+
+
+// Triple buffer of frame states. The player writes to its own slot and swaps
+// it with the shared slot; the remote swaps its slot with the shared slot if
+// it has a newer frame.
+struct StateBuffer {
+    slot  : [UnsafeCell<FrameInfo>; 3],
+    shared: AtomicUsize,
+}
+
+unsafe impl Sync for StateBuffer {}
+
+impl StateBuffer {
+    fn new(chn: usize) -> Self {
+        let info = || {
+            let mut info = FrameInfo::new();
+            info.channel = vec![ChannelInfo::new(); chn];
+            UnsafeCell::new(info)
+        };
+
+        StateBuffer {
+            slot  : [info(), info(), info()],
+            shared: AtomicUsize::new(1),
+        }
+    }
+}
+
+
+/// The player side of a remote control.
+pub struct Control {
+    queue: Arc<CommandQueue>,
+    state: Arc<StateBuffer>,
+    back : usize,
+}
+
+impl Control {
+    pub fn new(chn: usize) -> (Self, Remote) {
+        let queue = Arc::new(CommandQueue::new());
+        let state = Arc::new(StateBuffer::new(chn));
+
+        let remote = Remote {
+            queue: queue.clone(),
+            state: state.clone(),
+            front: 0,
+        };
+
+        (Control{ queue, state, back: 2 }, remote)
+    }
+
+    pub fn next_command(&mut self) -> Option<Command> {
+        self.queue.pop()
+    }
+
+    /// Frame state to fill before publishing.
+    pub fn state(&mut self) -> &mut FrameInfo {
+        unsafe { &mut *self.state.slot[self.back].get() }
+    }
+
+    pub fn publish(&mut self) {
+        let old = self.state.shared.swap(self.back | NEW_STATE, Ordering::AcqRel);
+        self.back = old & !NEW_STATE;
+    }
+}
+
+
+/// Controls a player from another thread. Commands and frame state are
+/// exchanged without locks, and the player doesn't allocate memory to
+/// handle them.
+pub struct Remote {
+    queue: Arc<CommandQueue>,
+    state: Arc<StateBuffer>,
+    front: usize,
+}
+
+impl Remote {
+    /// Queue a command to the player. The command is returned if the queue
+    /// is full. Commands are sent from one thread only, as the queue has a
+    /// single producer.
+    pub fn send(&mut self, cmd: Command) -> Result<(), Command> {
+        self.queue.push(cmd)
+    }
+
+    /// State of the last frame rendered by the player.
+    pub fn info(&mut self) -> &FrameInfo {
+        if self.state.shared.load(Ordering::Relaxed) & NEW_STATE != 0 {
+            let old = self.state.shared.swap(self.front, Ordering::AcqRel);
+            self.front = old & !NEW_STATE;
+        }
+        unsafe { &*self.state.slot[self.front].get() }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use player::ScanRow;
+    use super::{Control, Command, ScanData};
+
+    #[test]
+    fn test_command_queue() {
+        let (mut control, mut remote) = Control::new(4);
+        assert_eq!(control.next_command(), None);
+
+        assert_eq!(remote.send(Command::Pause(true)), Ok(()));
+        assert_eq!(remote.send(Command::SetPosition(3, 0, 6, 125)), Ok(()));
+        assert_eq!(control.next_command(), Some(Command::Pause(true)));
+        assert_eq!(control.next_command(), Some(Command::SetPosition(3, 0, 6, 125)));
+        assert_eq!(control.next_command(), None);
+
+        // Full queue returns the command
+        for i in 0..super::QUEUE_SIZE {
+            assert_eq!(remote.send(Command::Mute(i, true)), Ok(()));
+        }
+        assert_eq!(remote.send(Command::Pause(false)), Err(Command::Pause(false)));
+        assert_eq!(control.next_command(), Some(Command::Mute(0, true)));
+    }
+
+    #[test]
+    fn test_seek_command() {
+        let mut scan = ScanData::new(2);
+        let row = |millis, speed| Some(ScanRow{ millis, speed, tempo: 125, gvol: 64 });
+        scan.pos[0].row = vec![row(0, 6), row(120, 6)];
+        scan.pos[1].row = vec![row(240, 3), None];
+
+        assert_eq!(Command::seek(&scan, 100), Some(Command::SetPosition(0, 0, 6, 125)));
+        assert_eq!(Command::seek(&scan, 300), Some(Command::SetPosition(1, 0, 3, 125)));
+        assert_eq!(Command::seek(&ScanData::new(2), 100), None);
+    }
+
+    #[test]
+    fn test_state_buffer() {
+        let (mut control, mut remote) = Control::new(4);
+        assert_eq!(remote.info().pos, 0);
+
+        control.state().pos = 1;
+        control.publish();
+        control.state().pos = 2;
+        control.publish();
+        assert_eq!(remote.info().pos, 2);
+        assert_eq!(remote.info().channel.len(), 4);
+
+        // No new frame published
+        assert_eq!(remote.info().pos, 2);
+        control.state().pos = 3;
+        control.publish();
+        assert_eq!(remote.info().pos, 3);
+    }
+}
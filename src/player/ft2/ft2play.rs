@@ -0,0 +1,1079 @@
+use std::f64::consts::PI;
+use module::{Module, ModuleData};
+use module::instrument::Envelope;
+use player::{PlayerData, PlayerEvent, Virtual, FormatPlayer};
+use format::xm::{XmData, XmEvent, XM_KEY_OFF};
+use util::{note_to_period, note_to_period_mix, period_to_bend};
+use ::*;
+
+const MIN_PERIOD: f64 = 1.0;
+const MAX_PERIOD: f64 = 8000.0;
+
+const FX_ARPEGGIO         : u8 = 0x00;
+const FX_PORTA_UP         : u8 = 0x01;
+const FX_PORTA_DOWN       : u8 = 0x02;
+const FX_TONE_PORTA       : u8 = 0x03;
+const FX_VIBRATO          : u8 = 0x04;
+const FX_TONE_PORTA_VSLIDE: u8 = 0x05;
+const FX_VIBRATO_VSLIDE   : u8 = 0x06;
+const FX_TREMOLO          : u8 = 0x07;
+const FX_SET_PAN          : u8 = 0x08;
+const FX_SAMPLE_OFFSET    : u8 = 0x09;
+const FX_VOLUME_SLIDE     : u8 = 0x0a;
+const FX_POSITION_JUMP    : u8 = 0x0b;
+const FX_SET_VOLUME       : u8 = 0x0c;
+const FX_PATTERN_BREAK    : u8 = 0x0d;
+const FX_EXTENDED         : u8 = 0x0e;
+const FX_SPEED_TEMPO      : u8 = 0x0f;
+const FX_GLOBAL_VOLUME    : u8 = 0x10;  // G
+const FX_GLOBAL_VSLIDE    : u8 = 0x11;  // H
+const FX_KEY_OFF          : u8 = 0x14;  // K
+const FX_ENVELOPE_POS     : u8 = 0x15;  // L
+const FX_PAN_SLIDE        : u8 = 0x19;  // P
+const FX_MULTI_RETRIG     : u8 = 0x1b;  // R
+const FX_TREMOR           : u8 = 0x1d;  // T
+const FX_EXTRA_FINE_PORTA : u8 = 0x21;  // X
+
+static VIBRATO_TABLE: &[u8; 32] = &[
+      0,  24,  49,  74,  97, 120, 141, 161, 180, 197, 212, 224, 235, 244, 250, 253,
+    255, 253, 250, 244, 235, 224, 212, 197, 180, 161, 141, 120,  97,  74,  49,  24
+];
+
+
+/// Fasttracker 2 replayer
+///
+/// An oxdz player that reproduces the effect behaviour of the Fasttracker
+/// 2.08 replayer, using the ft2play naming conventions where possible.
+///
+/// Notes:
+/// * Periods are kept in `util::note_to_period` units, which are FT2 periods
+///   divided by 4 in both linear and Amiga modes. Effect parameters that
+///   change the period by 4*xx in FT2 change it by xx here.
+/// * Linear periods are converted to mixer periods using the bend between
+///   the note and the current period.
+/// * Mixer volumes are *16, so adjust when setting.
+pub struct Ft2Play {
+    song_pos      : usize,
+    patt_pos      : usize,
+    timer         : usize,   // current tick
+    speed         : usize,
+    tempo         : usize,
+    glob_vol      : usize,
+    pos_jump      : Option<usize>,
+    p_break_pos   : Option<usize>,
+    patt_del_time : usize,
+    patt_delaying : bool,
+    linear_freq   : bool,
+    new_order     : bool,    // moved to a new order, entered when its first row is played
+
+    channels      : Vec<Ft2Channel>,
+}
+
+impl Ft2Play {
+    pub fn new(module: &Module) -> Self {
+        Ft2Play {
+            song_pos     : 0,
+            patt_pos     : 0,
+            timer        : 0,
+            speed        : 6,
+            tempo        : 125,
+            glob_vol     : 64,
+            pos_jump     : None,
+            p_break_pos  : None,
+            patt_del_time: 0,
+            patt_delaying: false,
+            linear_freq  : true,
+            new_order    : true,
+            channels     : vec![Ft2Channel::new(); module.channels()],
+        }
+    }
+
+    // Compute period from FT2 note number (C-0 is 1) and finetune
+    fn note_period(&self, ton: usize, finetune: i8) -> f64 {
+        let ptype = if self.linear_freq { PeriodType::Linear } else { PeriodType::Amiga };
+        note_to_period(ton + 11, finetune as isize, ptype)
+    }
+
+    // Compute the fractional FT2 note number of a period
+    fn period_note(&self, period: f64, finetune: i8) -> f64 {
+        let d = if self.linear_freq {
+            240.0 - period / 16.0
+        } else {
+            12.0 * (PERIOD_BASE / period).log(2.0)
+        };
+        d - finetune as f64 / 128.0 - 11.0
+    }
+
+    // Round a period to the nearest semitone, plus an optional offset
+    fn relocate_ton(&self, period: f64, finetune: i8, add: usize) -> f64 {
+        let ton = self.period_note(period, finetune).round().max(1.0) as usize + add;
+        self.note_period(ton.min(119), finetune)
+    }
+
+    // Convert a period to a mixer period
+    fn mix_period(&self, period: f64, ton: usize) -> f64 {
+        if self.linear_freq {
+            let key = ton + 11;
+            note_to_period_mix(key, period_to_bend(period, key, PeriodType::Linear))
+        } else {
+            period
+        }
+    }
+
+    fn get_event(&self, module: &XmData, chn: usize) -> XmEvent {
+        match module.pattern_in_position(self.song_pos) {
+            Some(pat) if pat < module.pat_num => module.patterns[pat].event(self.patt_pos, chn).clone(),
+            _                                 => XmEvent::new(),
+        }
+    }
+
+    fn get_new_note(&mut self, chn: usize, module: &XmData, data: &mut PlayerData, virt: &mut Virtual) {
+        let e = self.get_event(module, chn);
+        if e.fxt | e.fxp != 0 {
+            data.event(PlayerEvent::Effect(chn, e.fxt, e.fxp));
+        }
+        {
+            let ch = &mut self.channels[chn];
+            ch.ton_typ = e.note;
+            ch.instr_nr = e.ins;
+            ch.vol_kol_vol = e.vol;
+            ch.eff_typ = e.fxt;
+            ch.eff = e.fxp;
+        }
+
+        // Note delay
+        if e.fxt == FX_EXTENDED && e.fxp >> 4 == 0xd && e.fxp & 0x0f != 0 {
+            self.channels[chn].note_delay = e.fxp & 0x0f;
+            return;
+        }
+
+        let played = self.trigger_note(chn, module, virt);
+        self.volume_column_once(chn);
+        self.cmd_once(chn, module);
+        if played {
+            self.note_event(chn, data);
+        }
+    }
+
+    fn note_event(&self, chn: usize, data: &mut PlayerData) {
+        let ch = &self.channels[chn];
+        data.event(PlayerEvent::Note(chn, ch.ton_typ as usize + 11, ch.last_ins as usize - 1, ch.real_vol as usize));
+    }
+
+    fn is_tone_porta(&self, chn: usize) -> bool {
+        let ch = &self.channels[chn];
+        ch.eff_typ == FX_TONE_PORTA || ch.eff_typ == FX_TONE_PORTA_VSLIDE || ch.vol_kol_vol >> 4 == 0xf
+    }
+
+    // Returns true if a new note started playing
+    fn trigger_note(&mut self, chn: usize, module: &XmData, virt: &mut Virtual) -> bool {
+        let mut played = false;
+        let tone_porta = self.is_tone_porta(chn);
+        let (note, ins) = {
+            let ch = &mut self.channels[chn];
+            if ch.instr_nr != 0 {
+                ch.last_ins = ch.instr_nr;
+            }
+            (ch.ton_typ, ch.instr_nr)
+        };
+
+        if note == XM_KEY_OFF {
+            self.key_off(chn, module);
+            if ins != 0 {
+                self.retrig_volume(chn, module);
+            }
+            return false;
+        }
+
+        if note != 0 {
+            // Find the sample played by this note
+            let last_ins = self.channels[chn].last_ins as usize;
+            let sub = match module.instruments.get(last_ins.wrapping_sub(1)) {
+                Some(instrument) => instrument.samples.get(instrument.keymap[note as usize - 1] as usize),
+                None             => None,
+            };
+
+            if let Some(sub) = sub {
+                let ton = note as isize + sub.relnote as isize;
+                if !(1..120).contains(&ton) {
+                    return false;
+                }
+
+                let mut finetune = sub.finetune;
+                let (eff_typ, eff) = (self.channels[chn].eff_typ, self.channels[chn].eff);
+                if eff_typ == FX_EXTENDED && eff >> 4 == 0x5 {
+                    finetune = (((eff & 0x0f) as i16 * 16) - 128) as i8;
+                }
+
+                let period = self.note_period(ton as usize, finetune);
+
+                if tone_porta && self.channels[chn].smp.is_some() {
+                    let ch = &mut self.channels[chn];
+                    ch.want_period = period;
+                } else {
+                    {
+                        let ch = &mut self.channels[chn];
+                        ch.smp = if module.samples[sub.smp].size > 0 { Some(sub.smp) } else { None };
+                        ch.ton_nr = ton as usize;
+                        ch.fine_tune = finetune;
+                        ch.real_period = period;
+                        ch.out_period = period;
+                        ch.want_period = period;
+                        ch.rel_ton_nr = sub.relnote;
+                        ch.old_vol = sub.volume;
+                        ch.old_pan = sub.pan;
+                        if ch.wave_ctrl & 0x04 == 0 {
+                            ch.vib_pos = 0;
+                        }
+                        if ch.wave_ctrl & 0x40 == 0 {
+                            ch.trem_pos = 0;
+                        }
+                        ch.retrig_cnt = 0;
+                        ch.tremor_pos = 0;
+                    }
+
+                    if let Some(smp) = self.channels[chn].smp {
+                        virt.set_patch(chn, last_ins - 1, smp, ton as usize + 11);
+                        played = true;
+
+                        if eff_typ == FX_SAMPLE_OFFSET {
+                            let ch = &mut self.channels[chn];
+                            if eff != 0 {
+                                ch.smp_offset = eff;
+                            }
+                            virt.set_voicepos(chn, ((ch.smp_offset as u32) << 8) as f64);
+                        }
+                    }
+                }
+            }
+        }
+
+        if ins != 0 {
+            self.retrig_volume(chn, module);
+            self.retrig_envelope(chn, module);
+        }
+
+        played
+    }
+
+    // Reset volume and panning to the sample defaults
+    fn retrig_volume(&mut self, chn: usize, module: &XmData) {
+        let ch = &mut self.channels[chn];
+        if module.instruments.get((ch.last_ins as usize).wrapping_sub(1)).is_some() {
+            ch.real_vol = ch.old_vol;
+            ch.out_vol = ch.old_vol;
+            ch.out_pan = ch.old_pan;
+        }
+    }
+
+    fn retrig_envelope(&mut self, chn: usize, module: &XmData) {
+        let ch = &mut self.channels[chn];
+        let ins = match module.instruments.get((ch.last_ins as usize).wrapping_sub(1)) {
+            Some(val) => val,
+            None      => return,
+        };
+
+        ch.env_v_pos = 0;
+        ch.env_p_pos = 0;
+        ch.env_sustain_active = true;
+        ch.fade_out_amp = 32768;
+        ch.fade_out_speed = ins.fadeout;
+
+        ch.e_vib_pos = 0;
+        if ins.vib_sweep != 0 {
+            ch.e_vib_amp = 0;
+            ch.e_vib_sweep = ((ins.vib_depth as usize) << 8) / ins.vib_sweep as usize;
+        } else {
+            ch.e_vib_amp = (ins.vib_depth as usize) << 8;
+            ch.e_vib_sweep = 0;
+        }
+    }
+
+    fn key_off(&mut self, chn: usize, module: &XmData) {
+        let ch = &mut self.channels[chn];
+        ch.env_sustain_active = false;
+
+        let vol_env_enabled = match module.instruments.get((ch.last_ins as usize).wrapping_sub(1)) {
+            Some(ins) => ins.vol_env.enabled,
+            None      => false,
+        };
+        if !vol_env_enabled {
+            ch.real_vol = 0;
+        }
+    }
+
+    // Volume column commands processed in the first tick of the row
+    fn volume_column_once(&mut self, chn: usize) {
+        let ch = &mut self.channels[chn];
+        let vol = ch.vol_kol_vol;
+        let val = vol & 0x0f;
+
+        match vol >> 4 {
+            0x1..=0x4       => ch.real_vol = vol - 0x10,
+            0x5             => ch.real_vol = 64,
+            0x8             => ch.real_vol = ch.real_vol.saturating_sub(val),
+            0x9             => ch.real_vol = (ch.real_vol + val).min(64),
+            0xa             => ch.vib_speed = val << 2,
+            0xb if val != 0 => ch.vib_depth = val,
+            0xc             => ch.out_pan = val << 4,
+            0xf if val != 0 => ch.porta_speed = (val as f64) * 16.0,
+            _               => {},
+        }
+    }
+
+    // Volume column commands processed in the remaining ticks of the row
+    fn volume_column_tick(&mut self, chn: usize) {
+        let vol = self.channels[chn].vol_kol_vol;
+        let val = vol & 0x0f;
+
+        match vol >> 4 {
+            0x6 => {
+                let ch = &mut self.channels[chn];
+                ch.real_vol = ch.real_vol.saturating_sub(val);
+            },
+            0x7 => {
+                let ch = &mut self.channels[chn];
+                ch.real_vol = (ch.real_vol + val).min(64);
+            },
+            0xb => {
+                self.vibrato2(chn);
+            },
+            0xd => {
+                let ch = &mut self.channels[chn];
+                ch.out_pan = ch.out_pan.saturating_sub(val);
+            },
+            0xe => {
+                let ch = &mut self.channels[chn];
+                ch.out_pan = ch.out_pan.saturating_add(val);
+            },
+            0xf => {
+                self.tone_porta(chn);
+            },
+            _   => {},
+        }
+    }
+
+    // Effects processed in the first tick of the row
+    fn cmd_once(&mut self, chn: usize, module: &XmData) {
+        let eff_typ = self.channels[chn].eff_typ;
+        let eff = self.channels[chn].eff;
+
+        match eff_typ {
+            FX_TONE_PORTA if eff != 0 => {
+                self.channels[chn].porta_speed = eff as f64;
+            },
+            FX_VIBRATO => {
+                let ch = &mut self.channels[chn];
+                if eff & 0x0f != 0 {
+                    ch.vib_depth = eff & 0x0f;
+                }
+                if eff & 0xf0 != 0 {
+                    ch.vib_speed = (eff >> 4) << 2;
+                }
+            },
+            FX_TREMOLO => {
+                let ch = &mut self.channels[chn];
+                if eff & 0x0f != 0 {
+                    ch.trem_depth = eff & 0x0f;
+                }
+                if eff & 0xf0 != 0 {
+                    ch.trem_speed = (eff >> 4) << 2;
+                }
+            },
+            FX_SET_PAN => {
+                self.channels[chn].out_pan = eff;
+            },
+            FX_POSITION_JUMP => {
+                self.pos_jump = Some(eff as usize);
+                self.p_break_pos = Some(0);
+            },
+            FX_SET_VOLUME => {
+                self.channels[chn].real_vol = eff.min(64);
+            },
+            FX_PATTERN_BREAK => {
+                let pos = (eff >> 4) as usize * 10 + (eff & 0x0f) as usize;
+                self.p_break_pos = Some(if pos > 63 { 0 } else { pos });
+            },
+            FX_EXTENDED => {
+                self.cmd_extended(chn);
+            },
+            FX_SPEED_TEMPO => {
+                if eff >= 0x20 {
+                    self.tempo = eff as usize;
+                } else if eff != 0 {
+                    self.speed = eff as usize;
+                }
+            },
+            FX_GLOBAL_VOLUME => {
+                self.glob_vol = (eff as usize).min(64);
+            },
+            FX_KEY_OFF if eff == 0 => {
+                self.key_off(chn, module);
+            },
+            FX_ENVELOPE_POS => {
+                let ch = &mut self.channels[chn];
+                if let Some(ins) = module.instruments.get((ch.last_ins as usize).wrapping_sub(1)) {
+                    ch.env_v_pos = eff as usize;
+                    // FT2 sets the panning envelope position only if the volume envelope has sustain
+                    if ins.vol_env.has_sustain {
+                        ch.env_p_pos = eff as usize;
+                    }
+                }
+            },
+            FX_MULTI_RETRIG => {
+                let ch = &mut self.channels[chn];
+                if eff & 0x0f != 0 {
+                    ch.retrig_speed = eff & 0x0f;
+                }
+                if eff & 0xf0 != 0 {
+                    ch.retrig_vol = eff >> 4;
+                }
+            },
+            FX_TREMOR if eff != 0 => {
+                self.channels[chn].tremor_param = eff;
+            },
+            FX_EXTRA_FINE_PORTA => {
+                let ch = &mut self.channels[chn];
+                let val = eff & 0x0f;
+                match eff >> 4 {
+                    0x1 => {
+                        if val != 0 {
+                            ch.ef_porta_up_speed = val;
+                        }
+                        ch.real_period = (ch.real_period - ch.ef_porta_up_speed as f64 / 4.0).max(MIN_PERIOD);
+                    },
+                    0x2 => {
+                        if val != 0 {
+                            ch.ef_porta_down_speed = val;
+                        }
+                        ch.real_period = (ch.real_period + ch.ef_porta_down_speed as f64 / 4.0).min(MAX_PERIOD);
+                    },
+                    _   => {},
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn cmd_extended(&mut self, chn: usize) {
+        let eff = self.channels[chn].eff;
+        let val = eff & 0x0f;
+
+        match eff >> 4 {
+            0x1 => {  // fine portamento up
+                let ch = &mut self.channels[chn];
+                if val != 0 {
+                    ch.f_porta_up_speed = val;
+                }
+                ch.real_period = (ch.real_period - ch.f_porta_up_speed as f64).max(MIN_PERIOD);
+            },
+            0x2 => {  // fine portamento down
+                let ch = &mut self.channels[chn];
+                if val != 0 {
+                    ch.f_porta_down_speed = val;
+                }
+                ch.real_period = (ch.real_period + ch.f_porta_down_speed as f64).min(MAX_PERIOD);
+            },
+            0x3 => {  // glissando control
+                self.channels[chn].gliss_funk = val != 0;
+            },
+            0x4 => {  // vibrato control
+                let ch = &mut self.channels[chn];
+                ch.wave_ctrl = (ch.wave_ctrl & 0xf0) | val;
+            },
+            0x6 => {  // pattern loop
+                let ch = &mut self.channels[chn];
+                if val == 0 {
+                    ch.patt_pos_loop = self.patt_pos;
+                } else {
+                    if ch.loop_cnt == 0 {
+                        ch.loop_cnt = val;
+                    } else {
+                        ch.loop_cnt -= 1;
+                    }
+                    if ch.loop_cnt != 0 {
+                        self.p_break_pos = Some(ch.patt_pos_loop);
+                        self.pos_jump = Some(self.song_pos);
+                    }
+                }
+            },
+            0x7 => {  // tremolo control
+                let ch = &mut self.channels[chn];
+                ch.wave_ctrl = (ch.wave_ctrl & 0x0f) | (val << 4);
+            },
+            0xa => {  // fine volume slide up
+                let ch = &mut self.channels[chn];
+                if val != 0 {
+                    ch.f_vol_slide_up_speed = val;
+                }
+                ch.real_vol = (ch.real_vol + ch.f_vol_slide_up_speed).min(64);
+            },
+            0xb => {  // fine volume slide down
+                let ch = &mut self.channels[chn];
+                if val != 0 {
+                    ch.f_vol_slide_down_speed = val;
+                }
+                ch.real_vol = ch.real_vol.saturating_sub(ch.f_vol_slide_down_speed);
+            },
+            0xc if val == 0 => {  // note cut
+                self.channels[chn].real_vol = 0;
+            },
+            0xe if !self.patt_delaying => {  // pattern delay
+                self.patt_del_time = val as usize;
+            },
+            _ => {},
+        }
+    }
+
+    // Effects processed in the remaining ticks of the row
+    fn cmd_tick(&mut self, chn: usize, module: &XmData, data: &mut PlayerData, virt: &mut Virtual) {
+        if self.channels[chn].note_delay != 0 {
+            let ch = &mut self.channels[chn];
+            if self.timer as u8 == ch.note_delay {
+                ch.note_delay = 0;
+                let played = self.trigger_note(chn, module, virt);
+                self.volume_column_once(chn);
+                if played {
+                    self.note_event(chn, data);
+                }
+            }
+            return;
+        }
+
+        self.volume_column_tick(chn);
+
+        let eff_typ = self.channels[chn].eff_typ;
+        let eff = self.channels[chn].eff;
+
+        match eff_typ {
+            FX_ARPEGGIO if eff != 0 => {
+                self.arpeggio(chn);
+            },
+            FX_PORTA_UP => {
+                let ch = &mut self.channels[chn];
+                if eff != 0 {
+                    ch.porta_up_speed = eff;
+                }
+                ch.real_period = (ch.real_period - ch.porta_up_speed as f64).max(MIN_PERIOD);
+                ch.out_period = ch.real_period;
+            },
+            FX_PORTA_DOWN => {
+                let ch = &mut self.channels[chn];
+                if eff != 0 {
+                    ch.porta_down_speed = eff;
+                }
+                ch.real_period = (ch.real_period + ch.porta_down_speed as f64).min(MAX_PERIOD);
+                ch.out_period = ch.real_period;
+            },
+            FX_TONE_PORTA => {
+                self.tone_porta(chn);
+            },
+            FX_VIBRATO => {
+                self.vibrato2(chn);
+            },
+            FX_TONE_PORTA_VSLIDE => {
+                self.tone_porta(chn);
+                self.volume_slide(chn);
+            },
+            FX_VIBRATO_VSLIDE => {
+                self.vibrato2(chn);
+                self.volume_slide(chn);
+            },
+            FX_TREMOLO => {
+                self.tremolo(chn);
+            },
+            FX_VOLUME_SLIDE => {
+                self.volume_slide(chn);
+            },
+            FX_EXTENDED => {
+                let val = eff & 0x0f;
+                match eff >> 4 {
+                    0x9 if val != 0 && self.timer.is_multiple_of(val as usize) => {  // retrig note
+                        virt.set_voicepos(chn, 0.0);
+                        self.retrig_envelope(chn, module);
+                    },
+                    0xc if self.timer as u8 == val => {  // note cut
+                        self.channels[chn].real_vol = 0;
+                    },
+                    _   => {},
+                }
+            },
+            FX_GLOBAL_VSLIDE => {
+                let ch = &mut self.channels[chn];
+                if eff != 0 {
+                    ch.glob_vol_slide_speed = eff;
+                }
+                let speed = ch.glob_vol_slide_speed as usize;
+                if speed & 0xf0 == 0 {
+                    self.glob_vol = self.glob_vol.saturating_sub(speed);
+                } else {
+                    self.glob_vol = (self.glob_vol + (speed >> 4)).min(64);
+                }
+            },
+            FX_KEY_OFF if self.timer as u8 == eff => {
+                self.key_off(chn, module);
+            },
+            FX_PAN_SLIDE => {
+                let ch = &mut self.channels[chn];
+                if eff != 0 {
+                    ch.pan_slide_speed = eff;
+                }
+                let speed = ch.pan_slide_speed;
+                if speed & 0xf0 == 0 {
+                    ch.out_pan = ch.out_pan.saturating_sub(speed);
+                } else {
+                    ch.out_pan = ch.out_pan.saturating_add(speed >> 4);
+                }
+            },
+            FX_MULTI_RETRIG => {
+                self.multi_retrig(chn, virt);
+            },
+            FX_TREMOR => {
+                self.tremor(chn);
+            },
+            _ => {},
+        }
+    }
+
+    fn volume_slide(&mut self, chn: usize) {
+        let ch = &mut self.channels[chn];
+        if ch.eff != 0 {
+            ch.vol_slide_speed = ch.eff;
+        }
+        let speed = ch.vol_slide_speed;
+        if speed & 0xf0 == 0 {
+            ch.real_vol = ch.real_vol.saturating_sub(speed);
+        } else {
+            ch.real_vol = (ch.real_vol + (speed >> 4)).min(64);
+        }
+        ch.out_vol = ch.real_vol;
+    }
+
+    fn tone_porta(&mut self, chn: usize) {
+        let gliss = {
+            let ch = &mut self.channels[chn];
+            if ch.real_period < ch.want_period {
+                ch.real_period = (ch.real_period + ch.porta_speed).min(ch.want_period);
+            } else if ch.real_period > ch.want_period {
+                ch.real_period = (ch.real_period - ch.porta_speed).max(ch.want_period);
+            }
+            ch.gliss_funk
+        };
+
+        let ch = &self.channels[chn];
+        let period = if gliss {
+            self.relocate_ton(ch.real_period, ch.fine_tune, 0)
+        } else {
+            ch.real_period
+        };
+        self.channels[chn].out_period = period;
+    }
+
+    fn waveform(pos: u8, typ: u8) -> u8 {
+        let idx = (pos >> 2) & 0x1f;
+        match typ & 0x03 {
+            0 => VIBRATO_TABLE[idx as usize],
+            1 => if (pos as i8) < 0 { !(idx << 3) } else { idx << 3 },
+            _ => 255,
+        }
+    }
+
+    fn vibrato2(&mut self, chn: usize) {
+        let ch = &mut self.channels[chn];
+        let val = Self::waveform(ch.vib_pos, ch.wave_ctrl) as f64 * ch.vib_depth as f64 / 128.0;
+        ch.out_period = if (ch.vib_pos as i8) < 0 {
+            ch.real_period - val
+        } else {
+            ch.real_period + val
+        };
+        ch.vib_pos = ch.vib_pos.wrapping_add(ch.vib_speed);
+    }
+
+    fn tremolo(&mut self, chn: usize) {
+        let ch = &mut self.channels[chn];
+        let val = (Self::waveform(ch.trem_pos, ch.wave_ctrl >> 4) as usize * ch.trem_depth as usize) >> 6;
+        ch.out_vol = if (ch.trem_pos as i8) < 0 {
+            ch.real_vol.saturating_sub(val as u8)
+        } else {
+            (ch.real_vol as usize + val).min(64) as u8
+        };
+        ch.trem_pos = ch.trem_pos.wrapping_add(ch.trem_speed);
+    }
+
+    fn tremor(&mut self, chn: usize) {
+        let ch = &mut self.channels[chn];
+        let mut sign = ch.tremor_pos & 0x80;
+        let mut data = (ch.tremor_pos & 0x7f) as i8 - 1;
+        if data < 0 {
+            if sign == 0x80 {
+                sign = 0;
+                data = (ch.tremor_param & 0x0f) as i8;
+            } else {
+                sign = 0x80;
+                data = (ch.tremor_param >> 4) as i8;
+            }
+        }
+        ch.tremor_pos = sign | data as u8;
+        ch.out_vol = if sign != 0 { ch.real_vol } else { 0 };
+    }
+
+    fn arpeggio(&mut self, chn: usize) {
+        let add = {
+            let ch = &self.channels[chn];
+            match self.timer % 3 {
+                1 => ch.eff >> 4,
+                2 => ch.eff & 0x0f,
+                _ => 0,
+            }
+        };
+
+        if add != 0 {
+            let ch = &self.channels[chn];
+            let period = self.relocate_ton(ch.real_period, ch.fine_tune, add as usize);
+            self.channels[chn].out_period = period;
+        }
+    }
+
+    fn multi_retrig(&mut self, chn: usize, virt: &mut Virtual) {
+        let ch = &mut self.channels[chn];
+        ch.retrig_cnt += 1;
+        if ch.retrig_cnt < ch.retrig_speed {
+            return;
+        }
+        ch.retrig_cnt = 0;
+
+        let vol = ch.real_vol as i16;
+        let vol = match ch.retrig_vol {
+            0x1 => vol - 1,
+            0x2 => vol - 2,
+            0x3 => vol - 4,
+            0x4 => vol - 8,
+            0x5 => vol - 16,
+            0x6 => (vol * 2) / 3,
+            0x7 => vol >> 1,
+            0x9 => vol + 1,
+            0xa => vol + 2,
+            0xb => vol + 4,
+            0xc => vol + 8,
+            0xd => vol + 16,
+            0xe => (vol * 3) / 2,
+            0xf => vol * 2,
+            _   => vol,
+        };
+        ch.real_vol = vol.clamp(0, 64) as u8;
+        ch.out_vol = ch.real_vol;
+
+        virt.set_voicepos(chn, 0.0);
+    }
+
+    // Advance the envelope position, honouring sustain and loop points
+    fn envelope_tick(env: &Envelope, pos: &mut usize, sustain_active: bool) -> isize {
+        let val = env.value(*pos);
+
+        if sustain_active && env.has_sustain && *pos == env.point_x(env.sus_start) {
+            return val;
+        }
+
+        *pos += 1;
+        if env.has_loop && *pos >= env.point_x(env.loop_end) {
+            *pos = env.point_x(env.loop_start);
+        }
+
+        val
+    }
+
+    // Compute final volume, panning and period with envelopes and autovibrato
+    fn fixa_envelope_vibrato(&mut self, chn: usize, module: &XmData) {
+        let glob_vol = self.glob_vol;
+        let ch = &mut self.channels[chn];
+
+        let ins = match module.instruments.get((ch.last_ins as usize).wrapping_sub(1)) {
+            Some(val) => val,
+            None      => {
+                ch.final_vol = 0;
+                return;
+            }
+        };
+
+        // Volume envelope and fadeout
+        let env_vol = if ins.vol_env.enabled {
+            if !ch.env_sustain_active {
+                ch.fade_out_amp = ch.fade_out_amp.saturating_sub(ch.fade_out_speed);
+            }
+            Self::envelope_tick(&ins.vol_env, &mut ch.env_v_pos, ch.env_sustain_active).clamp(0, 64) as usize
+        } else {
+            64
+        };
+
+        ch.final_vol = (ch.out_vol as usize * env_vol * glob_vol * ch.fade_out_amp) >> 23;
+
+        // Panning envelope
+        ch.final_pan = ch.out_pan as isize;
+        if ins.pan_env.enabled {
+            let env_pan = Self::envelope_tick(&ins.pan_env, &mut ch.env_p_pos, ch.env_sustain_active).clamp(0, 64) - 32;
+            let pan = ch.out_pan as isize;
+            let range = if pan > 128 { 255 - pan } else { pan };
+            ch.final_pan = pan + (env_pan * range) / 32;
+        }
+
+        // Autovibrato
+        ch.final_period = ch.out_period;
+        if ins.vib_depth != 0 {
+            if ch.e_vib_sweep != 0 && ch.env_sustain_active {
+                ch.e_vib_amp += ch.e_vib_sweep;
+                if ch.e_vib_amp >> 8 > ins.vib_depth as usize {
+                    ch.e_vib_amp = (ins.vib_depth as usize) << 8;
+                    ch.e_vib_sweep = 0;
+                }
+            }
+
+            let pos = ch.e_vib_pos;
+            let val = match ins.vib_type & 0x03 {
+                0 => (64.0 * (2.0 * PI * pos as f64 / 256.0).sin()).round(),
+                1 => if pos > 127 { 64.0 } else { -64.0 },
+                2 => ((((pos >> 1) as i16 + 64) & 127) - 64) as f64,
+                _ => (((-((pos >> 1) as i16) + 64) & 127) - 64) as f64,
+            };
+
+            let delta = val * ch.e_vib_amp as f64 / 16384.0 / 4.0;
+            ch.final_period = ch.out_period + delta;
+            ch.e_vib_pos = ch.e_vib_pos.wrapping_add(ins.vib_rate);
+        }
+    }
+
+    fn get_next_pos(&mut self, module: &XmData) {
+        if self.patt_del_time != 0 {
+            self.patt_del_time -= 1;
+            self.patt_delaying = true;
+            return;
+        }
+        self.patt_delaying = false;
+
+        let rows = module.rows(module.orders.get(self.song_pos).cloned().unwrap_or(0) as usize);
+
+        match (self.pos_jump.take(), self.p_break_pos.take()) {
+            (None, None) => {
+                self.patt_pos += 1;
+                if self.patt_pos >= rows {
+                    self.patt_pos = 0;
+                    self.song_pos += 1;
+                    self.new_order = true;
+                }
+            },
+            (pos, row) => {
+                self.song_pos = pos.unwrap_or(self.song_pos + 1);
+                self.patt_pos = row.unwrap_or(0);
+                self.new_order = true;
+            },
+        }
+
+        if self.song_pos >= module.song_length {
+            self.song_pos = if module.restart < module.song_length { module.restart } else { 0 };
+        }
+
+        // Pattern break to a row past the end of the next pattern
+        let rows = module.rows(module.orders.get(self.song_pos).cloned().unwrap_or(0) as usize);
+        if self.patt_pos >= rows {
+            self.patt_pos = 0;
+        }
+    }
+
+    fn process_tick(&mut self, module: &XmData, data: &mut PlayerData, virt: &mut Virtual) {
+        for ch in &mut self.channels {
+            ch.out_period = ch.real_period;
+            ch.out_vol = ch.real_vol;
+        }
+
+        if self.timer == 0 && !self.patt_delaying {
+            data.event(PlayerEvent::Row(self.song_pos, self.patt_pos));
+            for chn in 0..self.channels.len() {
+                self.get_new_note(chn, module, data, virt);
+                let ch = &mut self.channels[chn];
+                ch.out_period = ch.real_period;
+                ch.out_vol = ch.real_vol;
+            }
+        } else {
+            for chn in 0..self.channels.len() {
+                self.cmd_tick(chn, module, data, virt);
+            }
+        }
+
+        for chn in 0..self.channels.len() {
+            self.fixa_envelope_vibrato(chn, module);
+
+            let ch = &self.channels[chn];
+            if ch.smp.is_none() {
+                virt.set_volume(chn, 0);
+                continue;
+            }
+
+            let period = self.mix_period(ch.final_period.clamp(MIN_PERIOD, MAX_PERIOD), ch.ton_nr);
+            virt.set_period(chn, period);
+            virt.set_volume(chn, ch.final_vol);
+            virt.set_pan(chn, ch.final_pan.clamp(0, 255) - 0x80);
+        }
+
+        self.timer += 1;
+        if self.timer >= self.speed {
+            self.timer = 0;
+            self.get_next_pos(module);
+        }
+    }
+}
+
+
+impl FormatPlayer for Ft2Play {
+    fn start(&mut self, data: &mut PlayerData, mdata: &dyn ModuleData) {
+
+        let module = mdata.as_any().downcast_ref::<XmData>().unwrap();
+
+        data.speed = if module.default_speed != 0 { module.default_speed } else { 6 };
+        data.tempo = if module.default_tempo >= 0x20 { module.default_tempo } else { 125 };
+
+        self.linear_freq = module.linear_freq();
+        self.glob_vol = 64;
+        data.gvol = self.glob_vol;
+
+        for ch in &mut self.channels {
+            ch.out_pan = 128;
+            ch.old_pan = 128;
+        }
+    }
+
+    fn play(&mut self, data: &mut PlayerData, mdata: &dyn ModuleData, virt: &mut Virtual) {
+
+        let module = mdata.as_any().downcast_ref::<XmData>().unwrap();
+
+        if self.new_order && data.frame == 0 {
+            self.new_order = false;
+            data.enter_order(data.pos);
+        }
+
+        self.song_pos = data.pos;
+        self.patt_pos = data.row;
+        self.timer = data.frame;
+        self.speed = data.speed;
+        self.tempo = data.tempo;
+
+        self.process_tick(module, data, virt);
+
+        data.frame = self.timer;
+        data.row = self.patt_pos;
+        data.pos = self.song_pos;
+        data.speed = self.speed;
+        data.tempo = self.tempo;
+        data.gvol = self.glob_vol;
+    }
+
+    fn reset(&mut self) {
+        self.song_pos      = 0;
+        self.patt_pos      = 0;
+        self.timer         = 0;
+        self.speed         = 6;
+        self.tempo         = 125;
+        self.glob_vol      = 64;
+        self.pos_jump      = None;
+        self.p_break_pos   = None;
+        self.patt_del_time = 0;
+        self.patt_delaying = false;
+        self.linear_freq   = true;
+        self.new_order     = true;
+
+        for ch in &mut self.channels {
+            *ch = Ft2Channel::new();
+        }
+    }
+
+    fn preview_note(&self, ins: usize, note: usize, mdata: &dyn ModuleData) -> Option<(usize, f64)> {
+        let module = mdata.as_any().downcast_ref::<XmData>().unwrap();
+
+        // Note 60 is C-4, which is note 49 in FT2
+        if !(12..108).contains(&note) {
+            return None;
+        }
+        let instrument = module.instruments.get(ins)?;
+        let sub = instrument.samples.get(instrument.keymap[note - 12] as usize)?;
+        if module.samples.get(sub.smp)?.size == 0 {
+            return None;
+        }
+        let ton = note as isize - 11 + sub.relnote as isize;
+        if !(1..120).contains(&ton) {
+            return None;
+        }
+
+        // Linear and Amiga periods map to the same mixer period
+        Some((sub.smp, note_to_period(ton as usize + 11, sub.finetune as isize, PeriodType::Amiga)))
+    }
+}
+
+
+#[derive(Clone,Default)]
+struct Ft2Channel {
+    ton_typ               : u8,    // note in the pattern
+    instr_nr              : u8,
+    vol_kol_vol           : u8,    // volume column
+    eff_typ               : u8,
+    eff                   : u8,
+
+    last_ins              : u8,
+    smp                   : Option<usize>,
+    ton_nr                : usize, // note with relative note applied
+    rel_ton_nr            : i8,
+    fine_tune             : i8,
+    old_vol               : u8,
+    old_pan               : u8,
+    real_vol              : u8,
+    out_vol               : u8,
+    out_pan               : u8,
+    real_period           : f64,
+    out_period            : f64,
+    want_period           : f64,   // tone portamento target period
+    porta_speed           : f64,
+    gliss_funk            : bool,
+    porta_up_speed        : u8,
+    porta_down_speed      : u8,
+    f_porta_up_speed      : u8,
+    f_porta_down_speed    : u8,
+    ef_porta_up_speed     : u8,
+    ef_porta_down_speed   : u8,
+    vol_slide_speed       : u8,
+    f_vol_slide_up_speed  : u8,
+    f_vol_slide_down_speed: u8,
+    glob_vol_slide_speed  : u8,
+    pan_slide_speed       : u8,
+    wave_ctrl             : u8,
+    vib_pos               : u8,
+    vib_speed             : u8,
+    vib_depth             : u8,
+    trem_pos              : u8,
+    trem_speed            : u8,
+    trem_depth            : u8,
+    tremor_pos            : u8,
+    tremor_param          : u8,
+    retrig_cnt            : u8,
+    retrig_speed          : u8,
+    retrig_vol            : u8,
+    smp_offset            : u8,
+    note_delay            : u8,
+    patt_pos_loop         : usize,
+    loop_cnt              : u8,
+
+    env_sustain_active    : bool,
+    env_v_pos             : usize,
+    env_p_pos             : usize,
+    fade_out_amp          : usize,
+    fade_out_speed        : usize,
+    e_vib_pos             : u8,
+    e_vib_amp             : usize,
+    e_vib_sweep           : usize,
+
+    final_vol             : usize, // volume to send to the mixer
+    final_pan             : isize,
+    final_period          : f64,
+}
+
+impl Ft2Channel {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
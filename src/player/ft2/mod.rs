@@ -0,0 +1,23 @@
+mod ft2play;
+
+use module::Module;
+use player::{PlayerListEntry, PlayerInfo, FormatPlayer};
+
+pub struct Ft2;
+
+impl PlayerListEntry for Ft2 {
+   fn info(&self) -> PlayerInfo {
+       PlayerInfo {
+          id         : "ft2",
+          name       : "ft2play FT2.08 replayer",
+          description: "A player that reproduces the Fasttracker 2.08 replayer",
+          author     : "Claudio Matsuoka",
+          accepts    : &[ "xm" ],
+       }
+   }
+
+   fn player(&self, module: &Module) -> Box<dyn FormatPlayer> {
+       Box::new(self::ft2play::Ft2Play::new(module))
+   }
+}
+
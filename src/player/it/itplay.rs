@@ -0,0 +1,1380 @@
+use std::f64::consts::PI;
+use module::{Module, ModuleData};
+use module::instrument::{Envelope, NoteAction, NoteActions};
+use player::{PlayerData, PlayerEvent, Virtual, FormatPlayer};
+use format::it::{ItData, ItEvent, ItInstrument, IT_NOTE_OFF, IT_NOTE_CUT};
+use util::{note_to_period, note_to_period_mix, period_to_bend};
+use ::*;
+
+const MIN_PERIOD: f64 = 1.0;
+const MAX_PERIOD: f64 = 32000.0;
+
+const FX_SET_SPEED        : u8 = 0x01;  // A
+const FX_JUMP             : u8 = 0x02;  // B
+const FX_BREAK            : u8 = 0x03;  // C
+const FX_VOLUME_SLIDE     : u8 = 0x04;  // D
+const FX_PORTA_DOWN       : u8 = 0x05;  // E
+const FX_PORTA_UP         : u8 = 0x06;  // F
+const FX_TONE_PORTA       : u8 = 0x07;  // G
+const FX_VIBRATO          : u8 = 0x08;  // H
+const FX_TREMOR           : u8 = 0x09;  // I
+const FX_ARPEGGIO         : u8 = 0x0a;  // J
+const FX_VIBRATO_VSLIDE   : u8 = 0x0b;  // K
+const FX_TONE_PORTA_VSLIDE: u8 = 0x0c;  // L
+const FX_CHANNEL_VOLUME   : u8 = 0x0d;  // M
+const FX_CHANNEL_VSLIDE   : u8 = 0x0e;  // N
+const FX_SAMPLE_OFFSET    : u8 = 0x0f;  // O
+const FX_PAN_SLIDE        : u8 = 0x10;  // P
+const FX_RETRIG           : u8 = 0x11;  // Q
+const FX_TREMOLO          : u8 = 0x12;  // R
+const FX_SPECIAL          : u8 = 0x13;  // S
+const FX_TEMPO            : u8 = 0x14;  // T
+const FX_FINE_VIBRATO     : u8 = 0x15;  // U
+const FX_GLOBAL_VOLUME    : u8 = 0x16;  // V
+const FX_GLOBAL_VSLIDE    : u8 = 0x17;  // W
+const FX_SET_PAN          : u8 = 0x18;  // X
+const FX_PANBRELLO        : u8 = 0x19;  // Y
+const FX_MIDI_MACRO       : u8 = 0x1a;  // Z
+
+// Tone portamento speeds in the volume column
+static VC_PORTA_SPEED: &[u8; 10] = &[
+    0, 1, 4, 8, 16, 32, 64, 96, 128, 255
+];
+
+
+/// Impulse Tracker replayer
+///
+/// An oxdz player based on the Impulse Tracker 2.14 playback routines.
+///
+/// Notes:
+/// * Periods are kept in `util::note_to_period` units. In linear mode a
+///   period unit is 1/16 of a semitone, so effect parameters that slide
+///   4*xx in 1/64 semitone steps in IT change the period by xx here.
+/// * The channel list has one channel for each pattern track followed by
+///   background channels. When a new note action moves a note to the
+///   background, the track state is copied to the background channel and
+///   processed there until the voice is released.
+/// * Mixer volumes are *16, so adjust when setting.
+pub struct ItPlay {
+    ord          : usize,
+    row          : usize,
+    tick         : usize,
+    speed        : usize,
+    tempo        : usize,
+    global_vol   : usize,   // 0-128
+    pos_jump     : Option<usize>,
+    break_row    : Option<usize>,
+    row_delay    : usize,
+    row_delaying : bool,
+    tick_delay   : usize,   // extra ticks added by S6x
+    linear_slides: bool,
+    old_effects  : bool,
+    compat_gxx   : bool,
+    num_tracks   : usize,
+    rand_seed    : u32,
+    new_order    : bool,    // moved to a new order, entered when its first row is played
+
+    channels     : Vec<ItChannel>,
+}
+
+impl ItPlay {
+    pub fn new(module: &Module) -> Self {
+        ItPlay {
+            ord          : 0,
+            row          : 0,
+            tick         : 0,
+            speed        : 6,
+            tempo        : 125,
+            global_vol   : 128,
+            pos_jump     : None,
+            break_row    : None,
+            row_delay    : 0,
+            row_delaying : false,
+            tick_delay   : 0,
+            linear_slides: true,
+            old_effects  : false,
+            compat_gxx   : false,
+            num_tracks   : module.channels(),
+            rand_seed    : 0x1234,
+            new_order    : true,
+            channels     : vec![ItChannel::new(); module.channels() + MAX_VOICES],
+        }
+    }
+
+    fn rand(&mut self) -> u32 {
+        self.rand_seed = self.rand_seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (self.rand_seed >> 16) & 0x7fff
+    }
+
+    // Compute period from IT note number (C-0 is 0)
+    fn note_period(&self, key: usize) -> f64 {
+        let ptype = if self.linear_slides { PeriodType::Linear } else { PeriodType::Amiga };
+        note_to_period(key, 0, ptype)
+    }
+
+    // Change a period by the given amount of linear period units
+    fn pitch_bend(&self, period: f64, delta: f64) -> f64 {
+        if self.linear_slides {
+            period + delta
+        } else {
+            period * 2.0_f64.powf(delta / 192.0)
+        }
+    }
+
+    // Convert a period to a mixer period
+    fn mix_period(&self, period: f64, key: usize) -> f64 {
+        if self.linear_slides {
+            note_to_period_mix(key, period_to_bend(period, key, PeriodType::Linear))
+        } else {
+            period
+        }
+    }
+
+    fn waveform(&mut self, pos: u8, typ: u8) -> isize {
+        match typ & 0x03 {
+            0 => (64.0 * (2.0 * PI * pos as f64 / 256.0).sin()).round() as isize,
+            1 => 64 - (pos as isize >> 1),
+            2 => if pos < 128 { 64 } else { -64 },
+            _ => (self.rand() % 129) as isize - 64,
+        }
+    }
+
+    fn get_event(&self, module: &ItData, chn: usize) -> ItEvent {
+        match module.pattern_in_position(self.ord) {
+            Some(pat) if pat < module.pat_num && self.row < module.patterns[pat].rows => {
+                module.patterns[pat].event(self.row, chn).clone()
+            },
+            _ => ItEvent::new(),
+        }
+    }
+
+    fn pattern_rows(&self, module: &ItData, ord: usize) -> usize {
+        match module.pattern_in_position(ord) {
+            Some(pat) if pat < module.pat_num => module.patterns[pat].rows,
+            _                                 => 64,
+        }
+    }
+
+    fn get_new_note(&mut self, chn: usize, module: &ItData, data: &mut PlayerData, virt: &mut Virtual) {
+        let e = self.get_event(module, chn);
+        if e.cmd != 0 {
+            data.event(PlayerEvent::Effect(chn, e.cmd, e.info));
+        }
+        {
+            let ch = &mut self.channels[chn];
+            ch.note = e.note;
+            ch.ins = e.ins;
+            ch.volpan = e.volpan;
+            ch.cmd = e.cmd;
+            ch.info = e.info;
+            ch.triggered = false;
+            ch.note_cut = None;
+        }
+
+        // Note delay
+        if e.cmd == FX_SPECIAL && e.info >> 4 == 0xd {
+            self.channels[chn].note_delay = ((e.info & 0x0f) as usize).max(1);
+            return;
+        }
+
+        self.process_note(chn, module, virt);
+        self.volume_column_once(chn);
+        self.cmd_once(chn, module, virt);
+        self.note_event(chn, data);
+    }
+
+    fn note_event(&self, chn: usize, data: &mut PlayerData) {
+        let ch = &self.channels[chn];
+        if ch.triggered {
+            data.event(PlayerEvent::Note(chn, ch.note.unwrap_or(0) as usize, ch.ins_num - 1, ch.vol as usize));
+        }
+    }
+
+    fn is_tone_porta(&self, chn: usize) -> bool {
+        let ch = &self.channels[chn];
+        let vc_porta = match ch.volpan {
+            Some(v) => (193..=202).contains(&v),
+            None    => false,
+        };
+        ch.cmd == FX_TONE_PORTA || ch.cmd == FX_TONE_PORTA_VSLIDE || vc_porta
+    }
+
+    fn process_note(&mut self, chn: usize, module: &ItData, virt: &mut Virtual) {
+        let (note, ins) = (self.channels[chn].note, self.channels[chn].ins);
+
+        if ins != 0 {
+            self.channels[chn].ins_num = ins as usize;
+        }
+
+        match note {
+            None => if ins != 0 {
+                // Instrument without note resets the volume
+                let ch = &mut self.channels[chn];
+                if let Some(sub) = ch.smp.and_then(|x| module.sample_info.get(x)) {
+                    ch.vol = sub.volume;
+                }
+            },
+            Some(IT_NOTE_OFF) => {
+                self.key_off(chn, module, virt);
+            },
+            Some(IT_NOTE_CUT) => {
+                virt.reset_channel(chn);
+                self.channels[chn].active = false;
+            },
+            Some(n) if n > 119 => {
+                self.channels[chn].fade = true;
+            },
+            Some(n) => {
+                self.trigger_note(chn, n as usize, module, virt);
+            },
+        }
+    }
+
+    fn trigger_note(&mut self, chn: usize, note: usize, module: &ItData, virt: &mut Virtual) {
+        let ins_num = self.channels[chn].ins_num;
+        if ins_num == 0 {
+            return;
+        }
+
+        // Find the key and sample played by this note
+        let instrument = if module.use_instruments() {
+            match module.instruments.get(ins_num - 1) {
+                Some(val) => Some(val),
+                None      => return,
+            }
+        } else {
+            None
+        };
+
+        let (key, smp) = match instrument {
+            Some(ins) => {
+                let (k, s) = ins.keyboard[note];
+                if s == 0 {
+                    return;
+                }
+                ((k as usize).min(119), s as usize - 1)
+            },
+            None => (note, ins_num - 1),
+        };
+
+        if smp >= module.samples.len() || module.samples[smp].size == 0 {
+            return;
+        }
+
+        let sub = &module.sample_info[smp];
+        let period = self.note_period(key);
+        let new_ins = self.channels[chn].ins != 0;
+
+        // Tone portamento changes only the target period
+        if self.is_tone_porta(chn) && self.channels[chn].active {
+            let ch = &mut self.channels[chn];
+            ch.porta_target = period;
+            if new_ins {
+                ch.vol = sub.volume;
+            }
+            return;
+        }
+
+        let actions = match instrument {
+            Some(ins) => NoteActions{ nna: ins.nna, dct: ins.dct, dca: ins.dca },
+            None      => NoteActions::default(),
+        };
+
+        // The note playing in this channel keeps its new note action
+        let act = self.channels[chn].nna;
+        virt.set_nna(chn, act);
+
+        if let Some(to) = virt.set_patch_nna(chn, ins_num - 1, smp, key, note, actions) {
+            if to != chn {
+                self.channels[to] = self.channels[chn].clone();
+            }
+        }
+
+        let r1 = self.rand();
+        let r2 = self.rand();
+
+        let ch = &mut self.channels[chn];
+        ch.active = true;
+        ch.triggered = true;
+        ch.smp = Some(smp);
+        ch.key = key;
+        ch.period = period;
+        ch.porta_target = period;
+        if new_ins || ch.last_smp != Some(smp) {
+            ch.vol = sub.volume;
+        }
+        ch.last_smp = Some(smp);
+
+        ch.key_off = false;
+        ch.fade = false;
+        ch.fade_vol = 1024;
+        ch.vol_env_pos = 0;
+        ch.pan_env_pos = 0;
+        ch.pitch_env_pos = 0;
+        ch.av_pos = 0;
+        ch.av_depth = 0;
+        ch.vib_pos = 0;
+        ch.trem_pos = 0;
+        ch.retrig_count = 0;
+
+        match instrument {
+            Some(ins) => {
+                ch.nna = ins.nna;
+                ch.ins_gvol = ins.global_vol;
+                ch.vol_env_on = ins.vol_env.enabled;
+                ch.pan_env_on = ins.pan_env.enabled;
+                ch.pitch_env_on = ins.pitch_env.enabled;
+                if let Some(pan) = ins.default_pan {
+                    ch.pan = pan;
+                }
+                if let Some(val) = ins.cutoff {
+                    ch.cutoff = val;
+                }
+                if let Some(val) = ins.resonance {
+                    ch.resonance = val;
+                }
+
+                // Random volume variation, in percent of the volume
+                if ins.rand_vol != 0 {
+                    let rv = ins.rand_vol.min(100) as isize;
+                    let delta = (r1 % (2 * rv as u32 + 1)) as isize - rv;
+                    ch.vol = (ch.vol as isize + ch.vol as isize * delta / 100).clamp(0, 64) as u8;
+                }
+            },
+            None => {
+                ch.nna = NoteAction::Cut;
+                ch.ins_gvol = 128;
+                ch.vol_env_on = false;
+                ch.pan_env_on = false;
+                ch.pitch_env_on = false;
+            },
+        }
+
+        // Sample default panning overrides instrument panning
+        if let Some(pan) = sub.default_pan {
+            ch.pan = pan;
+        }
+
+        if let Some(ins) = instrument {
+            // Pitch-pan separation
+            if ins.pps != 0 {
+                let pan = ch.pan as isize + (key as isize - ins.ppc as isize) * ins.pps as isize / 8;
+                ch.pan = pan.clamp(0, 64) as u8;
+            }
+
+            // Random panning variation
+            if ins.rand_pan != 0 {
+                let rp = ins.rand_pan.min(64) as isize;
+                let delta = (r2 % (2 * rp as u32 + 1)) as isize - rp;
+                ch.pan = (ch.pan as isize + delta).clamp(0, 64) as u8;
+            }
+        }
+    }
+
+    fn key_off(&mut self, chn: usize, module: &ItData, virt: &mut Virtual) {
+        virt.release(chn);
+
+        let ch = &mut self.channels[chn];
+        ch.key_off = true;
+
+        // Fadeout starts on note off if the volume envelope is off or looped
+        if let Some(ins) = instrument(module, ch.ins_num) {
+            if !ch.vol_env_on || ins.vol_env.has_loop {
+                ch.fade = true;
+            }
+        }
+    }
+
+    // Set tone portamento speed, shared with portamento up and down
+    // unless the compatible Gxx flag is set
+    fn set_tone_porta_speed(&mut self, chn: usize, val: u8) {
+        let compat = self.compat_gxx;
+        let ch = &mut self.channels[chn];
+        ch.mem_tone_porta = val;
+        if !compat {
+            ch.mem_porta = val;
+        }
+    }
+
+    fn set_porta_speed(&mut self, chn: usize, val: u8) {
+        let compat = self.compat_gxx;
+        let ch = &mut self.channels[chn];
+        ch.mem_porta = val;
+        if !compat {
+            ch.mem_tone_porta = val;
+        }
+    }
+
+    // Volume column commands processed in the first tick of the row
+    fn volume_column_once(&mut self, chn: usize) {
+        let v = match self.channels[chn].volpan {
+            Some(val) => val,
+            None      => return,
+        };
+
+        match v {
+            0..=64 => {
+                self.channels[chn].vol = v;
+            },
+            65..=74 => {  // fine volume slide up
+                let ch = &mut self.channels[chn];
+                if v > 65 {
+                    ch.mem_vc_slide = v - 65;
+                }
+                ch.vol = (ch.vol + ch.mem_vc_slide).min(64);
+            },
+            75..=84 => {  // fine volume slide down
+                let ch = &mut self.channels[chn];
+                if v > 75 {
+                    ch.mem_vc_slide = v - 75;
+                }
+                ch.vol = ch.vol.saturating_sub(ch.mem_vc_slide);
+            },
+            85..=94 => {
+                let ch = &mut self.channels[chn];
+                if v > 85 {
+                    ch.mem_vc_slide = v - 85;
+                }
+            },
+            95..=104 => {
+                let ch = &mut self.channels[chn];
+                if v > 95 {
+                    ch.mem_vc_slide = v - 95;
+                }
+            },
+            105..=124 => {  // portamento down and up
+                let val = (v - 105) % 10;
+                if val != 0 {
+                    self.set_porta_speed(chn, val * 4);
+                }
+            },
+            128..=192 => {
+                self.channels[chn].pan = v - 128;
+            },
+            193..=202 => {
+                let val = VC_PORTA_SPEED[(v - 193) as usize];
+                if val != 0 {
+                    self.set_tone_porta_speed(chn, val);
+                }
+            },
+            203..=212 => {
+                let ch = &mut self.channels[chn];
+                if v > 203 {
+                    ch.vib_depth = (v - 203) * 4;
+                }
+                if !self.old_effects {
+                    self.vibrato(chn, false);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    // Volume column commands processed in the remaining ticks of the row
+    fn volume_column_tick(&mut self, chn: usize) {
+        let v = match self.channels[chn].volpan {
+            Some(val) => val,
+            None      => return,
+        };
+
+        match v {
+            85..=94 => {
+                let ch = &mut self.channels[chn];
+                ch.vol = (ch.vol + ch.mem_vc_slide).min(64);
+            },
+            95..=104 => {
+                let ch = &mut self.channels[chn];
+                ch.vol = ch.vol.saturating_sub(ch.mem_vc_slide);
+            },
+            105..=114 => {
+                let ch = &mut self.channels[chn];
+                ch.period = (ch.period + ch.mem_porta as f64).min(MAX_PERIOD);
+            },
+            115..=124 => {
+                let ch = &mut self.channels[chn];
+                ch.period = (ch.period - ch.mem_porta as f64).max(MIN_PERIOD);
+            },
+            193..=202 => {
+                self.tone_porta(chn);
+            },
+            203..=212 => {
+                self.vibrato(chn, false);
+            },
+            _ => {},
+        }
+    }
+
+    // Effects processed in the first tick of the row
+    fn cmd_once(&mut self, chn: usize, module: &ItData, virt: &mut Virtual) {
+        let cmd = self.channels[chn].cmd;
+        let info = self.channels[chn].info;
+
+        match cmd {
+            FX_SET_SPEED if info != 0 => {
+                self.speed = info as usize;
+            },
+            FX_JUMP => {
+                self.pos_jump = Some(info as usize);
+            },
+            FX_BREAK => {
+                self.break_row = Some(info as usize);
+            },
+            FX_VOLUME_SLIDE => {
+                self.volume_slide(chn, true);
+            },
+            FX_PORTA_DOWN | FX_PORTA_UP => {
+                if info != 0 {
+                    self.set_porta_speed(chn, info);
+                }
+                self.porta(chn, true);
+            },
+            FX_TONE_PORTA if info != 0 => {
+                self.set_tone_porta_speed(chn, info);
+            },
+            FX_VIBRATO | FX_FINE_VIBRATO => {
+                let ch = &mut self.channels[chn];
+                if info & 0x0f != 0 {
+                    ch.vib_depth = info & 0x0f;
+                }
+                if info & 0xf0 != 0 {
+                    ch.vib_speed = info >> 4;
+                }
+                if !self.old_effects {
+                    self.vibrato(chn, cmd == FX_FINE_VIBRATO);
+                }
+            },
+            FX_TREMOR if info != 0 => {
+                self.channels[chn].mem_tremor = info;
+            },
+            FX_ARPEGGIO if info != 0 => {
+                self.channels[chn].mem_arpeggio = info;
+            },
+            FX_VIBRATO_VSLIDE => {
+                if !self.old_effects {
+                    self.vibrato(chn, false);
+                }
+                self.volume_slide(chn, true);
+            },
+            FX_TONE_PORTA_VSLIDE => {
+                self.volume_slide(chn, true);
+            },
+            FX_CHANNEL_VOLUME => {
+                self.channels[chn].chn_vol = info.min(64);
+            },
+            FX_CHANNEL_VSLIDE => {
+                let ch = &mut self.channels[chn];
+                if info != 0 {
+                    ch.mem_chn_slide = info;
+                }
+                ch.chn_vol = vol_slide(ch.chn_vol as isize, ch.mem_chn_slide, true, 64) as u8;
+            },
+            FX_SAMPLE_OFFSET => {
+                let ch = &mut self.channels[chn];
+                if info != 0 {
+                    ch.mem_offset = info;
+                }
+                if ch.triggered {
+                    let pos = (ch.high_offset as usize) << 16 | (ch.mem_offset as usize) << 8;
+                    let size = ch.smp.map_or(0, |x| module.samples[x].size);
+                    if pos < size || self.old_effects {
+                        virt.set_voicepos(chn, pos as f64);
+                    }
+                }
+            },
+            FX_PAN_SLIDE => {
+                let ch = &mut self.channels[chn];
+                if info != 0 {
+                    ch.mem_pan_slide = info;
+                }
+                // Px0 slides left and P0x slides right
+                ch.pan = (64 - vol_slide(64 - ch.pan as isize, ch.mem_pan_slide, true, 64)) as u8;
+            },
+            FX_RETRIG if info != 0 => {
+                self.channels[chn].mem_retrig = info;
+            },
+            FX_TREMOLO => {
+                let ch = &mut self.channels[chn];
+                if info & 0x0f != 0 {
+                    ch.trem_depth = info & 0x0f;
+                }
+                if info & 0xf0 != 0 {
+                    ch.trem_speed = info >> 4;
+                }
+            },
+            FX_SPECIAL => {
+                self.cmd_special(chn, module, virt);
+            },
+            FX_TEMPO => {
+                if info >= 0x20 {
+                    self.tempo = info as usize;
+                } else if info != 0 {
+                    self.channels[chn].mem_tempo = info;
+                }
+            },
+            FX_GLOBAL_VOLUME if info <= 128 => {
+                self.global_vol = info as usize;
+            },
+            FX_GLOBAL_VSLIDE => {
+                let mem = {
+                    let ch = &mut self.channels[chn];
+                    if info != 0 {
+                        ch.mem_global_slide = info;
+                    }
+                    ch.mem_global_slide
+                };
+                self.global_vol = vol_slide(self.global_vol as isize, mem, true, 128) as usize;
+            },
+            FX_SET_PAN => {
+                self.channels[chn].pan = ((info as usize + 1) >> 2) as u8;
+            },
+            FX_PANBRELLO => {
+                let ch = &mut self.channels[chn];
+                if info & 0x0f != 0 {
+                    ch.panbrello_depth = info & 0x0f;
+                }
+                if info & 0xf0 != 0 {
+                    ch.panbrello_speed = info >> 4;
+                }
+            },
+            FX_MIDI_MACRO => {
+                // Default macro configuration: Z00-Z7F set the filter cutoff and
+                // Z80-Z8F set the filter resonance
+                let ch = &mut self.channels[chn];
+                if info < 0x80 {
+                    ch.cutoff = info;
+                } else if info < 0x90 {
+                    ch.resonance = (info & 0x0f) << 3;
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn cmd_special(&mut self, chn: usize, module: &ItData, virt: &mut Virtual) {
+        let info = self.channels[chn].info;
+        let val = info & 0x0f;
+
+        match info >> 4 {
+            0x3 => {  // vibrato waveform
+                self.channels[chn].vib_type = val;
+            },
+            0x4 => {  // tremolo waveform
+                self.channels[chn].trem_type = val;
+            },
+            0x5 => {  // panbrello waveform
+                self.channels[chn].panbrello_type = val;
+            },
+            0x6 => {  // fine pattern delay
+                self.tick_delay += val as usize;
+            },
+            0x7 => {  // past note actions and envelope control
+                match val {
+                    0x0 => self.past_note_action(chn, NoteAction::Cut, module, virt),
+                    0x1 => self.past_note_action(chn, NoteAction::Off, module, virt),
+                    0x2 => self.past_note_action(chn, NoteAction::Fade, module, virt),
+                    0x3..=0x6 => {
+                        let act = match val {
+                            0x3 => NoteAction::Cut,
+                            0x4 => NoteAction::Continue,
+                            0x5 => NoteAction::Off,
+                            _   => NoteAction::Fade,
+                        };
+                        self.channels[chn].nna = act;
+                        virt.set_nna(chn, act);
+                    },
+                    0x7 => self.channels[chn].vol_env_on = false,
+                    0x8 => self.channels[chn].vol_env_on = true,
+                    0x9 => self.channels[chn].pan_env_on = false,
+                    0xa => self.channels[chn].pan_env_on = true,
+                    0xb => self.channels[chn].pitch_env_on = false,
+                    0xc => self.channels[chn].pitch_env_on = true,
+                    _   => {},
+                }
+            },
+            0x8 => {  // set panning
+                self.channels[chn].pan = val << 2;
+            },
+            0x9 if val == 1 => {  // surround, played as center
+                self.channels[chn].pan = 32;
+            },
+            0xa => {  // high offset
+                self.channels[chn].high_offset = val;
+            },
+            0xb => {  // pattern loop
+                let ch = &mut self.channels[chn];
+                if val == 0 {
+                    ch.loop_row = self.row;
+                } else {
+                    if ch.loop_count == 0 {
+                        ch.loop_count = val;
+                    } else {
+                        ch.loop_count -= 1;
+                    }
+                    if ch.loop_count != 0 {
+                        self.break_row = Some(ch.loop_row);
+                        self.pos_jump = Some(self.ord);
+                    }
+                }
+            },
+            0xc => {  // note cut
+                self.channels[chn].note_cut = Some((val as usize).max(1));
+            },
+            0xe if !self.row_delaying => {  // pattern delay
+                self.row_delay = val as usize;
+            },
+            _ => {},
+        }
+    }
+
+    // Apply an action to the notes playing in the background from this channel
+    fn past_note_action(&mut self, chn: usize, act: NoteAction, module: &ItData, virt: &mut Virtual) {
+        for c in self.num_tracks..self.channels.len() {
+            if virt.root(c) != Some(chn) {
+                continue;
+            }
+            self.note_action(c, act, module, virt);
+        }
+    }
+
+    fn note_action(&mut self, chn: usize, act: NoteAction, module: &ItData, virt: &mut Virtual) {
+        match act {
+            NoteAction::Cut      => {
+                virt.reset_channel(chn);
+                self.channels[chn].active = false;
+            },
+            NoteAction::Off      => self.key_off(chn, module, virt),
+            NoteAction::Fade     => self.channels[chn].fade = true,
+            NoteAction::Continue => {},
+        }
+    }
+
+    // Effects processed in the remaining ticks of the row
+    fn cmd_tick(&mut self, chn: usize, module: &ItData, data: &mut PlayerData, virt: &mut Virtual) {
+        if self.channels[chn].note_delay != 0 {
+            let ch = &mut self.channels[chn];
+            ch.note_delay -= 1;
+            if ch.note_delay == 0 {
+                self.process_note(chn, module, virt);
+                self.volume_column_once(chn);
+                self.note_event(chn, data);
+            }
+            return;
+        }
+
+        if let Some(t) = self.channels[chn].note_cut {
+            if self.tick == t {
+                let ch = &mut self.channels[chn];
+                ch.vol = 0;
+                ch.note_cut = None;
+            }
+        }
+
+        self.volume_column_tick(chn);
+
+        let cmd = self.channels[chn].cmd;
+
+        match cmd {
+            FX_VOLUME_SLIDE => {
+                self.volume_slide(chn, false);
+            },
+            FX_PORTA_DOWN | FX_PORTA_UP => {
+                self.porta(chn, false);
+            },
+            FX_TONE_PORTA => {
+                self.tone_porta(chn);
+            },
+            FX_VIBRATO => {
+                self.vibrato(chn, false);
+            },
+            FX_FINE_VIBRATO => {
+                self.vibrato(chn, true);
+            },
+            FX_TREMOR => {
+                self.tremor(chn);
+            },
+            FX_ARPEGGIO => {
+                self.arpeggio(chn);
+            },
+            FX_VIBRATO_VSLIDE => {
+                self.vibrato(chn, false);
+                self.volume_slide(chn, false);
+            },
+            FX_TONE_PORTA_VSLIDE => {
+                self.tone_porta(chn);
+                self.volume_slide(chn, false);
+            },
+            FX_CHANNEL_VSLIDE => {
+                let ch = &mut self.channels[chn];
+                ch.chn_vol = vol_slide(ch.chn_vol as isize, ch.mem_chn_slide, false, 64) as u8;
+            },
+            FX_PAN_SLIDE => {
+                let ch = &mut self.channels[chn];
+                ch.pan = (64 - vol_slide(64 - ch.pan as isize, ch.mem_pan_slide, false, 64)) as u8;
+            },
+            FX_RETRIG => {
+                self.retrig(chn, virt);
+            },
+            FX_TREMOLO => {
+                self.tremolo(chn);
+            },
+            FX_TEMPO => {
+                let mem = self.channels[chn].mem_tempo;
+                let val = (mem & 0x0f) as usize;
+                match mem >> 4 {
+                    0 => self.tempo = self.tempo.saturating_sub(val).max(32),
+                    1 => self.tempo = (self.tempo + val).min(255),
+                    _ => {},
+                }
+            },
+            FX_GLOBAL_VSLIDE => {
+                let mem = self.channels[chn].mem_global_slide;
+                self.global_vol = vol_slide(self.global_vol as isize, mem, false, 128) as usize;
+            },
+            FX_PANBRELLO => {
+                self.panbrello(chn);
+            },
+            _ => {},
+        }
+    }
+
+    fn volume_slide(&mut self, chn: usize, first_tick: bool) {
+        let ch = &mut self.channels[chn];
+        if first_tick && ch.info != 0 {
+            ch.mem_vol_slide = ch.info;
+        }
+        ch.vol = vol_slide(ch.vol as isize, ch.mem_vol_slide, first_tick, 64) as u8;
+    }
+
+    fn porta(&mut self, chn: usize, first_tick: bool) {
+        let ch = &mut self.channels[chn];
+        let mem = ch.mem_porta;
+
+        // Portamento Fx and Ex are fine slides, applied in the first tick
+        let delta = match mem >> 4 {
+            0xf => if first_tick { (mem & 0x0f) as f64 } else { 0.0 },
+            0xe => if first_tick { (mem & 0x0f) as f64 / 4.0 } else { 0.0 },
+            _   => if first_tick { 0.0 } else { mem as f64 },
+        };
+
+        if ch.cmd == FX_PORTA_DOWN {
+            ch.period = (ch.period + delta).min(MAX_PERIOD);
+        } else {
+            ch.period = (ch.period - delta).max(MIN_PERIOD);
+        }
+    }
+
+    fn tone_porta(&mut self, chn: usize) {
+        let ch = &mut self.channels[chn];
+        let speed = ch.mem_tone_porta as f64;
+        if ch.period < ch.porta_target {
+            ch.period = (ch.period + speed).min(ch.porta_target);
+        } else if ch.period > ch.porta_target {
+            ch.period = (ch.period - speed).max(ch.porta_target);
+        }
+    }
+
+    fn vibrato(&mut self, chn: usize, fine: bool) {
+        let (pos, typ) = (self.channels[chn].vib_pos, self.channels[chn].vib_type);
+        let wave = self.waveform(pos, typ);
+
+        // Vibrato is twice as deep with old effects
+        let shift = if self.old_effects { 5 } else { 6 };
+        let ch = &mut self.channels[chn];
+        let mut delta = (wave * ch.vib_depth as isize) as f64 / (1 << shift) as f64;
+        if fine {
+            delta /= 4.0;
+        }
+
+        ch.period_delta = delta;
+        ch.vib_pos = ch.vib_pos.wrapping_add(ch.vib_speed << 2);
+    }
+
+    fn tremolo(&mut self, chn: usize) {
+        let (pos, typ) = (self.channels[chn].trem_pos, self.channels[chn].trem_type);
+        let wave = self.waveform(pos, typ);
+
+        let ch = &mut self.channels[chn];
+        ch.vol_delta = (wave * ch.trem_depth as isize) >> 5;
+        ch.trem_pos = ch.trem_pos.wrapping_add(ch.trem_speed << 2);
+    }
+
+    fn panbrello(&mut self, chn: usize) {
+        let (pos, typ) = (self.channels[chn].panbrello_pos, self.channels[chn].panbrello_type);
+        let wave = self.waveform(pos, typ);
+
+        let ch = &mut self.channels[chn];
+        ch.pan_delta = (wave * ch.panbrello_depth as isize) >> 5;
+        ch.panbrello_pos = ch.panbrello_pos.wrapping_add(ch.panbrello_speed);
+    }
+
+    fn tremor(&mut self, chn: usize) {
+        let old_effects = self.old_effects;
+        let ch = &mut self.channels[chn];
+        if ch.tremor_count == 0 {
+            ch.tremor_on = !ch.tremor_on;
+            let val = if ch.tremor_on { ch.mem_tremor >> 4 } else { ch.mem_tremor & 0x0f };
+            ch.tremor_count = val as usize + old_effects as usize;
+            if ch.tremor_count == 0 {
+                ch.tremor_count = 1;
+            }
+        }
+        ch.tremor_count -= 1;
+        ch.tremor_mute = !ch.tremor_on;
+    }
+
+    fn arpeggio(&mut self, chn: usize) {
+        let ch = &mut self.channels[chn];
+        let add = match self.tick % 3 {
+            1 => ch.mem_arpeggio >> 4,
+            2 => ch.mem_arpeggio & 0x0f,
+            _ => 0,
+        };
+
+        ch.period_delta = -16.0 * add as f64;
+    }
+
+    fn retrig(&mut self, chn: usize, virt: &mut Virtual) {
+        let ch = &mut self.channels[chn];
+        ch.retrig_count += 1;
+        if ch.retrig_count < (ch.mem_retrig & 0x0f) as usize {
+            return;
+        }
+        ch.retrig_count = 0;
+
+        let vol = ch.vol as isize;
+        let vol = match ch.mem_retrig >> 4 {
+            0x1 => vol - 1,
+            0x2 => vol - 2,
+            0x3 => vol - 4,
+            0x4 => vol - 8,
+            0x5 => vol - 16,
+            0x6 => (vol * 2) / 3,
+            0x7 => vol >> 1,
+            0x9 => vol + 1,
+            0xa => vol + 2,
+            0xb => vol + 4,
+            0xc => vol + 8,
+            0xd => vol + 16,
+            0xe => (vol * 3) / 2,
+            0xf => vol * 2,
+            _   => vol,
+        };
+        ch.vol = vol.clamp(0, 64) as u8;
+
+        virt.set_voicepos(chn, 0.0);
+    }
+
+    // Advance the envelope position, honouring sustain and loop points.
+    // Returns the envelope value and whether the envelope reached its end.
+    fn envelope_tick(env: &Envelope, pos: &mut usize, released: bool) -> (isize, bool) {
+        let val = env.value(*pos);
+        let last = match env.points.last() {
+            Some(p) => p.x,
+            None    => return (val, true),
+        };
+
+        *pos += 1;
+        if env.has_sustain && !released {
+            if *pos > env.point_x(env.sus_end) {
+                *pos = env.point_x(env.sus_start);
+            }
+        } else if env.has_loop && *pos > env.point_x(env.loop_end) {
+            *pos = env.point_x(env.loop_start);
+        }
+
+        if *pos > last {
+            *pos = last;
+            (val, true)
+        } else {
+            (val, false)
+        }
+    }
+
+    // Compute final volume, panning and period with envelopes and autovibrato
+    fn update_channel(&mut self, chn: usize, module: &ItData, virt: &mut Virtual) {
+        let smp = match self.channels[chn].smp {
+            Some(val) => val,
+            None      => return,
+        };
+
+        let sub = &module.sample_info[smp];
+        let ins = if module.use_instruments() { instrument(module, self.channels[chn].ins_num) } else { None };
+        let global_vol = self.global_vol as u64;
+
+        // Autovibrato waveform
+        let (pos, typ) = (self.channels[chn].av_pos, sub.vib_type);
+        let wave = self.waveform(pos, typ);
+
+        let mut env_vol = 64;
+        let mut env_pan = 0;
+        let mut env_pitch = 0;
+        let mut filter_env = None;
+
+        {
+            let ch = &mut self.channels[chn];
+
+            if let Some(ins) = ins {
+                if ch.vol_env_on && !ins.vol_env.points.is_empty() {
+                    let (val, end) = Self::envelope_tick(&ins.vol_env, &mut ch.vol_env_pos, ch.key_off);
+                    env_vol = val.clamp(0, 64);
+                    if end {
+                        ch.fade = true;
+                    }
+                }
+
+                if ch.pan_env_on && !ins.pan_env.points.is_empty() {
+                    env_pan = Self::envelope_tick(&ins.pan_env, &mut ch.pan_env_pos, ch.key_off).0.clamp(-32, 32);
+                }
+
+                if ch.pitch_env_on && !ins.pitch_env.points.is_empty() {
+                    let val = Self::envelope_tick(&ins.pitch_env, &mut ch.pitch_env_pos, ch.key_off).0.clamp(-32, 32);
+                    if ins.filter_env {
+                        filter_env = Some(val);
+                    } else {
+                        env_pitch = val;
+                    }
+                }
+
+                if ch.fade {
+                    ch.fade_vol = ch.fade_vol.saturating_sub(ins.fadeout);
+                }
+            }
+        }
+
+        if self.channels[chn].fade_vol == 0 {
+            virt.reset_channel(chn);
+            self.channels[chn].active = false;
+            return;
+        }
+
+        // Sample autovibrato
+        let mut av_delta = 0.0;
+        if sub.vib_depth != 0 {
+            let ch = &mut self.channels[chn];
+            ch.av_depth = (ch.av_depth + sub.vib_rate as usize).min((sub.vib_depth as usize) << 8);
+            av_delta = (wave * (ch.av_depth >> 8) as isize) as f64 / 256.0;
+            ch.av_pos = ch.av_pos.wrapping_add(sub.vib_speed);
+        }
+
+        let (period, key) = {
+            let ch = &self.channels[chn];
+            let period = self.pitch_bend(ch.period, ch.period_delta + av_delta - (env_pitch * 8) as f64);
+            (period.clamp(MIN_PERIOD, MAX_PERIOD), ch.key)
+        };
+        let period = self.mix_period(period, key);
+
+        let ch = &mut self.channels[chn];
+
+        // Final volume in 0-1024 range
+        let out_vol = if ch.tremor_mute { 0 } else { (ch.vol as isize + ch.vol_delta).clamp(0, 64) };
+        let vol = out_vol as u64 * sub.global_vol as u64 * ch.ins_gvol as u64 * ch.chn_vol as u64 *
+                  global_vol * env_vol as u64 * ch.fade_vol as u64;
+        ch.final_vol = (vol >> 38) as usize;
+
+        // Panning envelope, scaled to the distance from the closest edge
+        let pan = (ch.pan as isize + ch.pan_delta).clamp(0, 64);
+        let pan = pan + env_pan * (32 - (pan - 32).abs()) / 32;
+        ch.final_pan = if module.stereo() {
+            ((pan - 32) * 4 * module.sep.min(128) as isize / 128).clamp(-128, 127)
+        } else {
+            0
+        };
+
+        let cutoff = match filter_env {
+            Some(val) => (ch.cutoff as isize * (val + 32) / 64) as usize,
+            None      => ch.cutoff as usize,
+        };
+
+        virt.set_period(chn, period);
+        virt.set_pan(chn, ch.final_pan);
+        virt.set_filter(chn, cutoff * 2, ch.resonance as usize * 2);
+        virt.set_volume(chn, ch.final_vol);
+    }
+
+    fn get_next_pos(&mut self, module: &ItData) {
+        if self.row_delay != 0 {
+            self.row_delay -= 1;
+            self.row_delaying = true;
+            return;
+        }
+        self.row_delaying = false;
+
+        let rows = self.pattern_rows(module, self.ord);
+
+        match (self.pos_jump.take(), self.break_row.take()) {
+            (None, None) => {
+                self.row += 1;
+                if self.row >= rows {
+                    self.row = 0;
+                    self.ord += 1;
+                    self.new_order = true;
+                }
+            },
+            (pos, row) => {
+                self.ord = pos.unwrap_or(self.ord + 1);
+                self.row = row.unwrap_or(0);
+                self.new_order = true;
+            },
+        }
+
+        if self.ord >= module.len() {
+            self.ord = 0;
+        }
+
+        // Pattern break to a row past the end of the next pattern
+        if self.row >= self.pattern_rows(module, self.ord) {
+            self.row = 0;
+        }
+    }
+
+    fn process_tick(&mut self, module: &ItData, data: &mut PlayerData, virt: &mut Virtual) {
+        for ch in &mut self.channels {
+            ch.period_delta = 0.0;
+            ch.vol_delta = 0;
+            ch.pan_delta = 0;
+            ch.tremor_mute = false;
+        }
+
+        if self.tick == 0 && !self.row_delaying {
+            self.tick_delay = 0;
+            data.event(PlayerEvent::Row(self.ord, self.row));
+            for chn in 0..self.num_tracks {
+                self.get_new_note(chn, module, data, virt);
+            }
+        } else {
+            for chn in 0..self.num_tracks {
+                self.cmd_tick(chn, module, data, virt);
+            }
+        }
+
+        // Background channels play until released by their new note action
+        for chn in self.num_tracks..self.channels.len() {
+            match virt.channel_action(chn) {
+                Some(act) => self.note_action(chn, act, module, virt),
+                None      => self.channels[chn].active = false,
+            }
+        }
+
+        for chn in 0..self.channels.len() {
+            if self.channels[chn].active {
+                self.update_channel(chn, module, virt);
+            }
+        }
+
+        self.tick += 1;
+        if self.tick >= self.speed + self.tick_delay {
+            self.tick = 0;
+            self.get_next_pos(module);
+        }
+    }
+}
+
+fn instrument(module: &ItData, num: usize) -> Option<&ItInstrument> {
+    module.instruments.get(num.wrapping_sub(1))
+}
+
+// Volume slide with fine slides in the first tick, as used by D, N and W.
+// Dx0 slides up, D0y slides down, DxF and DFy are fine slides.
+fn vol_slide(val: isize, param: u8, first_tick: bool, max: isize) -> isize {
+    let hi = (param >> 4) as isize;
+    let lo = (param & 0x0f) as isize;
+
+    let val = if lo == 0x0f && hi != 0 {
+        if first_tick { val + hi } else { val }
+    } else if hi == 0x0f && lo != 0 {
+        if first_tick { val - lo } else { val }
+    } else if first_tick {
+        val
+    } else if lo == 0 {
+        val + hi
+    } else if hi == 0 {
+        val - lo
+    } else {
+        val
+    };
+
+    val.max(0).min(max)
+}
+
+
+impl FormatPlayer for ItPlay {
+    fn start(&mut self, data: &mut PlayerData, mdata: &dyn ModuleData) {
+
+        let module = mdata.as_any().downcast_ref::<ItData>().unwrap();
+
+        data.speed = if module.initial_speed != 0 { module.initial_speed as usize } else { 6 };
+        data.tempo = if module.initial_tempo >= 0x20 { module.initial_tempo as usize } else { 125 };
+
+        self.linear_slides = module.linear_slides();
+        self.old_effects = module.old_effects();
+        self.compat_gxx = module.compatible_gxx();
+        self.global_vol = module.global_vol as usize;
+        data.gvol = self.global_vol;
+
+        for (i, ch) in self.channels.iter_mut().enumerate() {
+            ch.cutoff = 127;
+            if i >= self.num_tracks || i >= 64 {
+                ch.pan = 32;
+                ch.chn_vol = 64;
+                continue;
+            }
+
+            // Disabled channels are silent, surround is played as center
+            let pan = module.chn_pan[i];
+            ch.pan = if pan & 0x7f > 64 { 32 } else { pan & 0x7f };
+            ch.chn_vol = if pan & 0x80 != 0 { 0 } else { module.chn_vol[i].min(64) };
+        }
+    }
+
+    fn play(&mut self, data: &mut PlayerData, mdata: &dyn ModuleData, virt: &mut Virtual) {
+
+        let module = mdata.as_any().downcast_ref::<ItData>().unwrap();
+
+        if self.new_order && data.frame == 0 {
+            self.new_order = false;
+            data.enter_order(data.pos);
+        }
+
+        self.ord = data.pos;
+        self.row = data.row;
+        self.tick = data.frame;
+        self.speed = data.speed;
+        self.tempo = data.tempo;
+
+        self.process_tick(module, data, virt);
+
+        data.frame = self.tick;
+        data.row = self.row;
+        data.pos = self.ord;
+        data.speed = self.speed;
+        data.tempo = self.tempo;
+        data.gvol = self.global_vol;
+    }
+
+    fn reset(&mut self) {
+        self.ord           = 0;
+        self.row           = 0;
+        self.tick          = 0;
+        self.speed         = 6;
+        self.tempo         = 125;
+        self.global_vol    = 128;
+        self.pos_jump      = None;
+        self.break_row     = None;
+        self.row_delay     = 0;
+        self.row_delaying  = false;
+        self.tick_delay    = 0;
+        self.linear_slides = true;
+        self.old_effects   = false;
+        self.compat_gxx    = false;
+        self.rand_seed     = 0x1234;
+        self.new_order     = true;
+
+        for ch in &mut self.channels {
+            *ch = ItChannel::new();
+        }
+    }
+
+    fn preview_note(&self, ins: usize, note: usize, mdata: &dyn ModuleData) -> Option<(usize, f64)> {
+        let module = mdata.as_any().downcast_ref::<ItData>().unwrap();
+
+        let (key, smp) = if module.use_instruments() {
+            let (k, s) = *module.instruments.get(ins)?.keyboard.get(note)?;
+            if s == 0 {
+                return None;
+            }
+            ((k as usize).min(119), s as usize - 1)
+        } else {
+            (note.min(119), ins)
+        };
+
+        if module.samples.get(smp)?.size == 0 {
+            return None;
+        }
+
+        Some((smp, note_to_period_mix(key, 0)))
+    }
+}
+
+
+#[derive(Clone,Default)]
+struct ItChannel {
+    note            : Option<u8>,  // pattern event
+    ins             : u8,
+    volpan          : Option<u8>,
+    cmd             : u8,
+    info            : u8,
+
+    active          : bool,
+    triggered       : bool,        // a new note was played in this row
+    ins_num         : usize,       // last instrument, or sample in sample mode
+    smp             : Option<usize>,
+    last_smp        : Option<usize>,
+    key             : usize,
+    nna             : NoteAction,
+    vol             : u8,          // 0-64
+    chn_vol         : u8,          // 0-64
+    ins_gvol        : u8,          // 0-128
+    pan             : u8,          // 0-64
+    period          : f64,
+    porta_target    : f64,
+    period_delta    : f64,         // vibrato and arpeggio, in linear period units
+    vol_delta       : isize,       // tremolo
+    pan_delta       : isize,       // panbrello
+    tremor_mute     : bool,
+
+    mem_vol_slide   : u8,
+    mem_vc_slide    : u8,
+    mem_porta       : u8,
+    mem_tone_porta  : u8,
+    mem_chn_slide   : u8,
+    mem_offset      : u8,
+    mem_pan_slide   : u8,
+    mem_retrig      : u8,
+    mem_tempo       : u8,
+    mem_global_slide: u8,
+    mem_tremor      : u8,
+    mem_arpeggio    : u8,
+    high_offset     : u8,
+    vib_pos         : u8,
+    vib_speed       : u8,
+    vib_depth       : u8,
+    vib_type        : u8,
+    trem_pos        : u8,
+    trem_speed      : u8,
+    trem_depth      : u8,
+    trem_type       : u8,
+    panbrello_pos   : u8,
+    panbrello_speed : u8,
+    panbrello_depth : u8,
+    panbrello_type  : u8,
+    tremor_on       : bool,
+    tremor_count    : usize,
+    retrig_count    : usize,
+    note_delay      : usize,
+    note_cut        : Option<usize>,
+    loop_row        : usize,
+    loop_count      : u8,
+
+    key_off         : bool,
+    fade            : bool,
+    fade_vol        : usize,       // 0-1024
+    vol_env_on      : bool,
+    pan_env_on      : bool,
+    pitch_env_on    : bool,
+    vol_env_pos     : usize,
+    pan_env_pos     : usize,
+    pitch_env_pos   : usize,
+    av_pos          : u8,
+    av_depth        : usize,
+    cutoff          : u8,          // 0-127
+    resonance       : u8,          // 0-127
+
+    final_vol       : usize,       // volume to send to the mixer
+    final_pan       : isize,
+}
+
+impl ItChannel {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
@@ -0,0 +1,27 @@
+mod itplay;
+
+use module::Module;
+use player::{PlayerListEntry, PlayerInfo, FormatPlayer};
+
+pub struct It;
+
+impl PlayerListEntry for It {
+   fn info(&self) -> PlayerInfo {
+       PlayerInfo {
+          id         : "it",
+          name       : "Impulse Tracker 2.14 replayer",
+          description: "A player based on the Impulse Tracker 2.14 playback routines",
+          author     : "Claudio Matsuoka",
+          accepts    : &[ "it" ],
+       }
+   }
+
+   fn player(&self, module: &Module) -> Box<dyn FormatPlayer> {
+       Box::new(self::itplay::ItPlay::new(module))
+   }
+
+   fn virtual_channels(&self) -> bool {
+       true
+   }
+}
+
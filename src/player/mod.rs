@@ -1,14 +1,24 @@
 mod virt;
 mod scan;
+mod control;
 mod protracker;
+mod ft2;
+mod it;
+mod soundtracker;
 mod st2;
+mod st3;
 
 pub use player::virt::Virtual;
-pub use mixer::Mixer;
+pub use player::scan::{ScanData, ScanPos, ScanRow};
+pub use player::control::{Command, Remote};
+pub use mixer::{Mixer, Interpolator, Layout, SampleFormat, Limiter, Stems, Timing, Clock, Paula};
 
 use std::cmp;
+use std::mem;
+use std::sync::Arc;
 use module::{Module, ModuleData};
-use util::MemOpExt;
+use player::control::Control;
+use util::{MemOpExt, note_to_period_mix};
 use ::*;
 
 // For the player list
@@ -23,16 +33,44 @@ pub struct PlayerInfo {
 
 pub trait PlayerListEntry {
     fn info(&self) -> PlayerInfo;
-    fn player(&self, module: &Module) -> Box<FormatPlayer>;
+    fn player(&self, module: &Module) -> Box<dyn FormatPlayer>;
+
+    // Players that use background channels to implement new note actions
+    fn virtual_channels(&self) -> bool {
+        false
+    }
+
+    // Tick timing used by the original tracker to play the module
+    fn timing(&self, _module: &Module) -> Timing {
+        Timing::Bpm
+    }
 }
 
 
 // Trait for format-specific players
 
 pub trait FormatPlayer: Send + Sync {
-    fn start(&mut self, &mut PlayerData, &ModuleData);
-    fn play(&mut self, &mut PlayerData, &ModuleData, &mut Virtual);
+    fn start(&mut self, _: &mut PlayerData, _: &dyn ModuleData);
+    fn play(&mut self, _: &mut PlayerData, _: &dyn ModuleData, _: &mut Virtual);
     fn reset(&mut self);
+
+    /// Sample and mixer period to play a note of an instrument outside the
+    /// replay. Instruments are numbered from 0, and note 60 is the middle C
+    /// of the format, played at the sample base rate.
+    fn preview_note(&self, ins: usize, note: usize, mdata: &dyn ModuleData) -> Option<(usize, f64)> {
+        let smp = mdata.instruments().get(ins).and_then(|x| x.keymap.get(note).cloned())??;
+        Some((smp, note_to_period_mix(note, 0)))
+    }
+}
+
+/// Replay events reported by the format players. Notes use the same numbering
+/// as `Player::play_note` and instruments are numbered from 0.
+#[derive(Clone,Debug,PartialEq)]
+pub enum PlayerEvent {
+    Row(usize, usize),                  // position, row
+    Order(usize),                       // position
+    Note(usize, usize, usize, usize),   // channel, note, instrument, volume (0-64)
+    Effect(usize, u8, u8),              // channel, effect type, parameter
 }
 
 #[derive(Default)]
@@ -43,9 +81,12 @@ pub struct PlayerData {
     pub song : usize,
     pub speed: usize,
     pub tempo: usize,
+    pub gvol : usize,   // global volume, 0-64 or 0-128 in Impulse Tracker
+    pub loop_count: usize,  // number of times the song looped
 
-    initial_speed: usize,
-    initial_tempo: usize,
+    played       : Vec<bool>,   // orders played since the start or the last loop
+    events       : Vec<PlayerEvent>,
+    report       : bool,        // record events for the player callbacks
 }
 
 impl PlayerData {
@@ -53,24 +94,81 @@ impl PlayerData {
         Default::default()
     }
 
-    pub fn reset(&mut self) {
-        self.pos   = 0;
-        self.row   = 0;
-        self.frame = 0;
-        self.song  = 0;
-        self.speed = self.initial_speed;
-        self.tempo = self.initial_tempo;
+    /// Format players call this when replay moves to a new order, including
+    /// the first order played. Moving to an order already played, by a jump or
+    /// at the end of the order list, means the song looped.
+    pub fn enter_order(&mut self, ord: usize) {
+        if ord >= self.played.len() {
+            self.played.resize(ord + 1, false);
+        }
+
+        if self.played[ord] {
+            self.loop_count += 1;
+            self.played.iter_mut().for_each(|x| *x = false);
+        }
+
+        self.played[ord] = true;
+
+        // The order change comes before the rows read in the same frame
+        if self.report {
+            self.events.insert(0, PlayerEvent::Order(ord));
+        }
+    }
+
+    // Reset to the initial state, keeping buffers allocated
+    fn clear(&mut self) {
+        let mut played = mem::take(&mut self.played);
+        let mut events = mem::take(&mut self.events);
+        played.iter_mut().for_each(|x| *x = false);
+        events.clear();
+        *self = PlayerData{ played, events, ..PlayerData::new() };
     }
+
+    /// Format players report rows read, notes played and effect commands
+    /// with this. Events are only recorded if the player has callbacks.
+    pub fn event(&mut self, ev: PlayerEvent) {
+        if self.report {
+            self.events.push(ev);
+        }
+    }
+}
+
+
+type RowCallback    = Box<dyn FnMut(usize, usize) + Send>;                // position, row
+type OrderCallback  = Box<dyn FnMut(usize) + Send>;                       // position
+type NoteCallback   = Box<dyn FnMut(usize, usize, usize, usize) + Send>;  // channel, note, instrument, volume
+type EffectCallback = Box<dyn FnMut(usize, u8, u8) + Send>;               // channel, effect, parameter
+
+#[derive(Default)]
+struct Callbacks {
+    row   : Vec<RowCallback>,
+    order : Vec<OrderCallback>,
+    note  : Vec<NoteCallback>,
+    effect: Vec<EffectCallback>,
 }
 
+impl Callbacks {
+    fn is_empty(&self) -> bool {
+        self.row.is_empty() && self.order.is_empty() && self.note.is_empty() && self.effect.is_empty()
+    }
+}
 
-pub struct Player<'a> {
+
+/// The module replayer. A player shares ownership of its module, so it can
+/// be moved to an audio thread while other threads read module metadata.
+pub struct Player {
     pub data     : PlayerData,
-    module       : &'a Module<'a>,
-    format_player: Box<FormatPlayer>,
-    virt         : Virtual<'a>,
-    loop_count   : usize,
+    module       : Arc<Module>,
+    player_id    : &'static str,
+    timing       : Timing,
+    clock        : Clock,
+    format_player: Box<dyn FormatPlayer>,
+    virt         : Virtual,
+    scan_data    : ScanData,
     end          : bool,
+    paused       : bool,
+    callbacks    : Callbacks,
+    control      : Option<Control>,
 
     // for buffer fill
     consumed     : usize,
@@ -79,33 +177,61 @@ pub struct Player<'a> {
     
 }
 
-impl<'a> Player<'a> {
-    pub fn find_player(module: &'a Module, player_id: &str) -> Result<Self, Error> {
+impl Player {
+    pub fn find_player<M: Into<Arc<Module>>>(module: M, player_id: &str) -> Result<Self, Error> {
+        Self::find_player_with_rate(module, player_id, 44100)
+    }
+
+    /// Create a player that renders audio at the given sampling rate. The
+    /// player takes a module or a shared reference to it.
+    pub fn find_player_with_rate<M: Into<Arc<Module>>>(module: M, player_id: &str, rate: usize) -> Result<Self, Error> {
+        let module = module.into();
+
+        if rate < MIN_RATE as usize || rate > MAX_RATE as usize {
+            return Err(Error::Format("invalid sampling rate"))
+        }
+
+        let entry = Player::find_by_id(player_id)?;
+        let format_player = entry.player(&module);
 
-        let format_player = Player::find_by_id(player_id)?.player(&module);
+        let mut virt = Virtual::new(module.data.channels(), rate, module.clone(), entry.virtual_channels());
+        let timing = entry.timing(&module);
+        virt.set_timing(timing, Clock::Pal);
+        // Keep replay from allocating when it enters new orders
+        let mut data = PlayerData::new();
+        data.played.reserve(module.len());
 
-        let virt = Virtual::new(module.data.channels(), &module.data.samples(), false);
         Ok(Player {
-            data      : PlayerData::new(),
+            data,
             module,
+            player_id : entry.info().id,
+            timing,
+            clock     : Clock::Pal,
             format_player,
             virt,
-            loop_count: 0,
+            scan_data : ScanData::new(0),
             end       : false,
+            paused    : false,
+            callbacks : Callbacks::default(),
+            control   : None,
             consumed  : 0,
             in_pos    : 0,
             in_size   : 0,
         })
     }
 
-    pub fn list() -> Vec<Box<PlayerListEntry>> {
+    pub fn list() -> Vec<Box<dyn PlayerListEntry>> {
         vec![
             Box::new(protracker::Pt21a),
+            Box::new(soundtracker::Ust27),
             Box::new(st2::St2),
+            Box::new(st3::St3),
+            Box::new(ft2::Ft2),
+            Box::new(it::It),
         ]
     }
 
-    fn find_by_id(player_id: &str) -> Result<Box<PlayerListEntry>, Error> {
+    fn find_by_id(player_id: &str) -> Result<Box<dyn PlayerListEntry>, Error> {
         for p in Self::list() {
             if player_id == p.info().id {
                 return Ok(p)
@@ -114,85 +240,442 @@ impl<'a> Player<'a> {
         Err(Error::Format("player not found"))
     }
 
-    pub fn scan(&mut self) -> &Self {
-        self.data.reset();
+    /// Scan the module to find the time of each row and the song duration.
+    /// The scan uses a separate replayer and doesn't change the player state.
+    pub fn scan(&mut self) -> &mut Self {
+        let entry = Player::find_by_id(self.player_id).unwrap();
+        let mut format_player = entry.player(&self.module);
+        let mut virt = Virtual::new(self.module.data.channels(), 44100, self.module.clone(), entry.virtual_channels());
+        virt.set_timing(self.timing, self.clock);
+        self.scan_data = ScanData::scan(&self.module, &mut *format_player, &mut virt);
         self
     }
 
-/*
-    pub fn restart(&mut self) -> &Self {
-        self.data.pos = 0;
-        self.data.row = 0;
-        self.data.song = 0;
-        self.data.frame = 0;
-        self.data.speed = 6;
-        self.format_player.reset();
+    /// Seek to the row played at the given time in milliseconds, scanning the
+    /// module if needed. The module is replayed silently from the start up to
+    /// the tick where the row is read, so effect memory, speed, tempo and notes
+    /// still playing are the same as in a normal replay.
+    pub fn seek_time(&mut self, millis: u32) -> &mut Self {
+        if self.scan_data.pos.is_empty() {
+            self.scan();
+        }
+
+        let (pos, row) = match self.scan_data.find_time(millis) {
+            Some(val) => val,
+            None      => return self,
+        };
+
+        let target = self.scan_data.row(pos, row).cloned().unwrap();
+
+        self.restart();
+
+        // Replay without rendering or calling back the ticks before the row,
+        // timed as in the scan
+        let callbacks = mem::take(&mut self.callbacks);
+        self.virt.set_mute(true);
+        let mut time = 0.0_f64;
+        while (time as u32) < target.millis {
+            self.play_frame();
+            time += 1000.0 * self.virt.tick_time(self.data.tempo);
+        }
+        self.virt.set_mute(false);
+        self.callbacks = callbacks;
+
+        self.end = false;
         self
     }
-*/
+
+    pub fn module(&self) -> &Arc<Module> {
+        &self.module
+    }
+
+    pub fn scan_data(&self) -> &ScanData {
+        &self.scan_data
+    }
+
+    /// Song duration in milliseconds, available after scanning the module.
+    pub fn duration(&self) -> u32 {
+        self.scan_data.duration
+    }
+
+    /// Rewind to the start of the song. Channel state, voices and loop count
+    /// are reset, and the player is ready to play as a new player after `start()`.
+    pub fn restart(&mut self) -> &mut Self {
+        self.data.clear();
+        self.format_player.reset();
+        self.virt.reset();
+        self.end = false;
+        self.consumed = 0;
+        self.in_pos = 0;
+        self.in_size = 0;
+        self.start()
+    }
 
     pub fn start(&mut self) -> &mut Self {
         self.format_player.start(&mut self.data, &*self.module.data);
         self
     }
 
+    /// Select the interpolator used to resample instruments when mixing.
+    pub fn set_interpolator(&mut self, interp: Interpolator) -> &mut Self {
+        self.virt.set_interpolator(interp);
+        self
+    }
+
+    /// Set how the replay tempo sets the tick length. Players start with the
+    /// timing of the original tracker. Rescan the module to update the scan
+    /// data after changing the timing.
+    pub fn set_timing(&mut self, timing: Timing) -> &mut Self {
+        self.timing = timing;
+        self.virt.set_timing(self.timing, self.clock);
+        self
+    }
+
+    /// Select the PAL or NTSC Amiga clock for CIA and vertical blank timing.
+    pub fn set_clock(&mut self, clock: Clock) -> &mut Self {
+        self.clock = clock;
+        self.virt.set_timing(self.timing, self.clock);
+        self
+    }
+
+    /// Emulate the audio output of an Amiga model: RC and LED filters, hard
+    /// LRRL panning and sample changes at the end of the current loop. Use
+    /// with the Protracker or Soundtracker players for renders close to the
+    /// original hardware. Emulation disables anti-click, call `set_anticlick`
+    /// afterwards to enable it again.
+    pub fn set_paula(&mut self, model: Paula) -> &mut Self {
+        self.virt.set_paula(model);
+        self
+    }
+
+    /// Set the output channel layout.
+    pub fn set_layout(&mut self, layout: Layout) -> &mut Self {
+        self.virt.set_layout(layout);
+        self
+    }
+
+    /// Set the output sample format. Read the frame buffer with `buffer()`,
+    /// `buffer_i32()` or `buffer_f32()` according to the format.
+    pub fn set_format(&mut self, format: SampleFormat) -> &mut Self {
+        self.virt.set_format(format);
+        self
+    }
+
+    /// Mix in floating point, or use the integer mixer. The master gain and
+    /// limiter are only applied to the floating-point mixer output. The integer
+    /// mixer is the default.
+    pub fn set_float_mix(&mut self, enable: bool) -> &mut Self {
+        self.virt.set_float_mix(enable);
+        self
+    }
+
+    pub fn set_master_gain(&mut self, gain: f32) -> &mut Self {
+        self.virt.set_master_gain(gain);
+        self
+    }
+
+    pub fn set_limiter(&mut self, limiter: Limiter) -> &mut Self {
+        self.virt.set_limiter(limiter);
+        self
+    }
+
+    /// Number of output samples clipped so far.
+    pub fn overs(&self) -> usize {
+        self.virt.overs()
+    }
+
+    /// Render each module channel or each instrument to its own stereo buffer
+    /// in addition to the master mix. Read stems with `stem_buffer()`.
+    pub fn set_stems(&mut self, stems: Stems) -> &mut Self {
+        let num = match stems {
+            Stems::Off        => 0,
+            Stems::Channel    => self.module.channels(),
+            Stems::Instrument => self.module.instruments().len(),
+        };
+        self.virt.set_stems(stems, num);
+        self
+    }
+
+    pub fn num_stems(&self) -> usize {
+        self.virt.num_stems()
+    }
+
+    /// Interleaved stereo float samples of a stem in the last frame played.
+    pub fn stem_buffer(&self, num: usize) -> &[f32] {
+        self.virt.stem_buffer(num)
+    }
+
+    /// Keep the samples played by each channel for oscilloscope displays.
+    pub fn set_scopes(&mut self, enable: bool) -> &mut Self {
+        self.virt.set_scopes(enable);
+        self
+    }
+
+    /// Enable or disable anti-click volume ramps. Disable anti-click and use the
+    /// integer mixer to render modules exactly as played by the original replayers.
+    pub fn set_anticlick(&mut self, enable: bool) -> &mut Self {
+        self.virt.set_anticlick(enable);
+        self
+    }
+
+    /// Play a note of an instrument in a preview channel, from 0 to
+    /// `MAX_PREVIEW - 1`, while the song plays or while it is stopped. Note 60
+    /// is the middle C of the format and volume is 0-64.
+    pub fn play_note(&mut self, chn: usize, ins: usize, note: usize, vol: usize) -> &mut Self {
+        if let Some((smp, period)) = self.format_player.preview_note(ins, note, &*self.module.data) {
+            self.virt.play_note(chn, ins, smp, period, vol);
+        }
+        self
+    }
+
+    pub fn stop_note(&mut self, chn: usize) -> &mut Self {
+        self.virt.stop_note(chn);
+        self
+    }
+
+    /// Render a frame without advancing the replay, to hear previewed notes
+    /// while the song is stopped.
+    pub fn preview_frame(&mut self) -> &mut Self {
+        let tempo = if self.data.tempo > 0 { self.data.tempo } else { 125 };
+        self.virt.set_tempo(tempo);
+        self.virt.mix();
+        self
+    }
+
+    /// Silence a module channel, including notes it plays in background
+    /// channels. The change takes effect in the next frame.
+    pub fn mute_channel(&mut self, chn: usize) -> &mut Self {
+        self.virt.set_channel_mute(chn, true);
+        self
+    }
+
+    pub fn unmute_channel(&mut self, chn: usize) -> &mut Self {
+        self.virt.set_channel_mute(chn, false);
+        self
+    }
+
+    /// Mute all module channels except the given one.
+    pub fn solo_channel(&mut self, chn: usize) -> &mut Self {
+        for i in 0..self.virt.num_tracks() {
+            self.virt.set_channel_mute(i, i != chn);
+        }
+        self
+    }
+
+    pub fn unmute_all(&mut self) -> &mut Self {
+        for i in 0..self.virt.num_tracks() {
+            self.virt.set_channel_mute(i, false);
+        }
+        self
+    }
+
+    pub fn channel_muted(&self, chn: usize) -> bool {
+        self.virt.channel_mute(chn)
+    }
+
     pub fn play_frame(&mut self) -> &mut Self {
+        let loop_count = self.data.loop_count;
+        self.data.report = !self.callbacks.is_empty();
         self.format_player.play(&mut self.data, &*self.module.data, &mut self.virt);
+        self.end = self.data.loop_count != loop_count;
         self.virt.set_tempo(self.data.tempo);
         self.virt.mix();
+        self.dispatch_events();
+        self
+    }
+
+    /// Call `f(pos, row)` when the replay reads a new row.
+    pub fn on_row<F>(&mut self, f: F) -> &mut Self where F: FnMut(usize, usize) + Send + 'static {
+        self.callbacks.row.push(Box::new(f));
+        self.reserve_events();
+        self
+    }
+
+    /// Call `f(pos)` when the replay moves to a new order position.
+    pub fn on_order<F>(&mut self, f: F) -> &mut Self where F: FnMut(usize) + Send + 'static {
+        self.callbacks.order.push(Box::new(f));
+        self.reserve_events();
+        self
+    }
+
+    /// Call `f(chn, note, ins, vol)` for each note played when a row is read.
+    pub fn on_note<F>(&mut self, f: F) -> &mut Self where F: FnMut(usize, usize, usize, usize) + Send + 'static {
+        self.callbacks.note.push(Box::new(f));
+        self.reserve_events();
         self
     }
 
-    pub fn fill_buffer(&mut self, out_buffer: &mut [i16], loops: usize) {
+    /// Call `f(chn, fxt, fxp)` for each effect command in a row read, using
+    /// the effect numbering of the module format.
+    pub fn on_effect<F>(&mut self, f: F) -> &mut Self where F: FnMut(usize, u8, u8) + Send + 'static {
+        self.callbacks.effect.push(Box::new(f));
+        self.reserve_events();
+        self
+    }
+
+    /// Remove all registered callbacks.
+    pub fn clear_callbacks(&mut self) -> &mut Self {
+        self.callbacks = Callbacks::default();
+        self
+    }
+
+    // Room for a row, an order change and a note and effect in each channel,
+    // so replay doesn't allocate to report events
+    fn reserve_events(&mut self) {
+        let num = 2 + 2 * self.module.channels();
+        self.data.events.reserve(num);
+    }
+
+    fn dispatch_events(&mut self) {
+        let cb = &mut self.callbacks;
+        for ev in self.data.events.drain(..) {
+            match ev {
+                PlayerEvent::Row(pos, row)             => cb.row.iter_mut().for_each(|f| f(pos, row)),
+                PlayerEvent::Order(pos)                => cb.order.iter_mut().for_each(|f| f(pos)),
+                PlayerEvent::Note(chn, note, ins, vol) => cb.note.iter_mut().for_each(|f| f(chn, note, ins, vol)),
+                PlayerEvent::Effect(chn, fxt, fxp)     => cb.effect.iter_mut().for_each(|f| f(chn, fxt, fxp)),
+            }
+        }
+    }
+
+    /// Fill the buffer with 16-bit samples, stopping after the song looped the
+    /// given number of times, or never if `loops` is 0. Returns the number of
+    /// samples written; the rest of the buffer is cleared at the end of replay.
+    pub fn fill_buffer(&mut self, out_buffer: &mut [i16], loops: usize) -> usize {
+        self.fill(out_buffer, loops, Self::buffer)
+    }
+
+    pub fn fill_buffer_i32(&mut self, out_buffer: &mut [i32], loops: usize) -> usize {
+        self.fill(out_buffer, loops, Self::buffer_i32)
+    }
+
+    pub fn fill_buffer_f32(&mut self, out_buffer: &mut [f32], loops: usize) -> usize {
+        self.fill(out_buffer, loops, Self::buffer_f32)
+    }
+
+    fn fill<T: Copy>(&mut self, out_buffer: &mut [T], loops: usize, buffer: fn(&Self) -> &[T]) -> usize {
         let mut filled = 0;
         let size = out_buffer.len();
 
+        self.run_commands();
+
+        // Paused players output silence
+        if self.paused {
+            MemOpExt::fill(out_buffer, 0, size);
+            return size;
+        }
+
         // Fill buffer
         while filled < size {
             // Check if buffer full
             if self.consumed == self.in_size {
-                self.play_frame();
-
-                // Check end of module
-                if self.end() || (loops > 0 && self.loop_count >= loops) {
-                    // Start of frame, return end of replay
-                    if filled == 0 {
-                        self.consumed = 0;
-                        self.in_size = 0;
-                        return;
-                    } else {
-                        self.end = false;
-                    }
-
-                    // Clear rest of the buffer
-                    out_buffer[filled..].fill(0, size - filled);
+                // Check end of replay before playing the next loop
+                if loops > 0 && self.data.loop_count >= loops {
+                    self.consumed = 0;
+                    self.in_size = 0;
+                    MemOpExt::fill(&mut out_buffer[filled..], 0, size - filled);
+                    return filled;
                 }
 
+                self.play_frame();
+                self.publish_state();
+
+                // The frame where the song loops belongs to the next loop
                 self.consumed = 0;
                 self.in_pos = 0;
-                self.in_size = self.buffer().len();
+                self.in_size = if loops > 0 && self.data.loop_count >= loops { 0 } else { buffer(self).len() };
+                continue;
             }
 
             // Copy frame data to user buffer
             let copy_size = cmp::min(size - filled, self.in_size - self.consumed);
-            out_buffer[filled..filled+copy_size].copy_from_slice(&self.buffer()[self.consumed..self.consumed+copy_size]);
+            out_buffer[filled..filled+copy_size].copy_from_slice(&buffer(self)[self.consumed..self.consumed+copy_size]);
             self.consumed += copy_size;
             filled += copy_size;
         }
+
+        filled
+    }
+
+    /// Create a remote control to send commands to the player and read the
+    /// state of the frames played from another thread. Commands run and state
+    /// is published when the player fills buffers. To seek from the remote
+    /// side, keep a copy of the scan data and send `Command::seek`.
+    pub fn remote(&mut self) -> Remote {
+        let (control, remote) = Control::new(self.module.channels());
+        self.control = Some(control);
+        remote
+    }
+
+    /// Stop rendering and fill buffers with silence while paused.
+    pub fn set_paused(&mut self, paused: bool) -> &mut Self {
+        self.paused = paused;
+        self
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    fn run_commands(&mut self) {
+        let mut control = try_option!(self.control.take());
+        while let Some(cmd) = control.next_command() {
+            match cmd {
+                Command::SetPosition(pos, row, speed, tempo) => {
+                    self.set_position(pos);
+                    self.set_row(row);
+                    self.data.speed = speed;
+                    self.data.tempo = tempo;
+                    self.consumed = self.in_size;  // drop the rest of the current frame
+                },
+                Command::Mute(chn, true)       => { self.mute_channel(chn); },
+                Command::Mute(chn, false)      => { self.unmute_channel(chn); },
+                Command::SetInterpolator(intp) => { self.set_interpolator(intp); },
+                Command::Pause(pause)          => { self.paused = pause; },
+            }
+        }
+        self.control = Some(control);
+    }
+
+    fn publish_state(&mut self) {
+        let mut control = try_option!(self.control.take());
+        self.frame_info(control.state());
+        control.publish();
+        self.control = Some(control);
     }
 
+    /// True if the song reached its end and looped in the last frame played.
     pub fn end(&self) -> bool {
         self.end
     }
 
+    /// Number of times the song looped since the start of replay.
+    pub fn loop_count(&self) -> usize {
+        self.data.loop_count
+    }
+
     pub fn info(&mut self, info: &mut FrameInfo) -> &mut Self {
+        let num = self.module.channels();
+        if info.channel.len() != num {
+            info.channel.resize(num, ChannelInfo::new());
+        }
+        self.frame_info(info);
+        self
+    }
+
+    // Fill the frame state without resizing the channel list, so published
+    // states use the channels allocated with the remote control
+    fn frame_info(&self, info: &mut FrameInfo) {
         info.pos = self.data.pos;
         info.row = self.data.row;
         info.song = self.data.song;
         info.frame = self.data.frame;
         info.speed = self.data.speed;
         info.tempo = self.data.tempo;
-        self
+
+        for (chn, ci) in info.channel.iter_mut().enumerate() {
+            self.virt.channel_info(chn, ci);
+        }
     }
 
     pub fn position(&self) -> usize {
@@ -235,6 +718,20 @@ impl<'a> Player<'a> {
     pub fn buffer(&self) -> &[i16] {
         self.virt.buffer()
     }
+
+    /// Mono samples played by a channel in the last frame, before panning,
+    /// with 1.0 as full scale. Enable with `set_scopes()`.
+    pub fn scope_buffer(&self, chn: usize) -> &[f32] {
+        self.virt.scope_buffer(chn)
+    }
+
+    pub fn buffer_i32(&self) -> &[i32] {
+        self.virt.buffer_i32()
+    }
+
+    pub fn buffer_f32(&self) -> &[f32] {
+        self.virt.buffer_f32()
+    }
 }
 
 
@@ -246,6 +743,7 @@ pub struct FrameInfo {
     pub song : usize,
     pub tempo: usize,
     pub speed: usize,
+    pub channel: Vec<ChannelInfo>,  // state of each module channel
 }
 
 impl FrameInfo {
@@ -253,3 +751,331 @@ impl FrameInfo {
         Default::default()
     }
 }
+
+
+#[derive(Clone,Debug,Default)]
+pub struct ChannelInfo {
+    pub active : bool,    // a voice is playing in the channel
+    pub note   : usize,
+    pub ins    : usize,
+    pub smp    : usize,
+    pub volume : usize,   // mixer volume, 0-1024
+    pub pan    : isize,   // mixer pan, -128 to 127
+    pub period : f64,
+    pub freq   : f64,     // sample playback frequency in Hz
+    pub pos    : f64,     // position in the sample
+    pub looping: bool,
+    pub muted  : bool,
+}
+
+impl ChannelInfo {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use format;
+    use format::st::load::tests::build_module;
+    use std::sync::{Arc, Mutex};
+    use module::Module;
+    use super::{Player, PlayerData, PlayerEvent, FrameInfo, SampleFormat, Stems};
+
+    #[test]
+    fn test_enter_order() {
+        let mut data = PlayerData::new();
+        data.enter_order(0);
+        data.enter_order(1);
+        data.enter_order(2);
+        assert_eq!(data.loop_count, 0);
+
+        // Jump back to an order already played
+        data.enter_order(1);
+        assert_eq!(data.loop_count, 1);
+        data.enter_order(2);
+        assert_eq!(data.loop_count, 1);
+        data.enter_order(1);
+        assert_eq!(data.loop_count, 2);
+
+        // Same order played again, as in a single pattern song
+        data.enter_order(1);
+        assert_eq!(data.loop_count, 3);
+
+        data.clear();
+        data.enter_order(1);
+        assert_eq!(data.loop_count, 0);
+    }
+
+    #[test]
+    fn test_events() {
+        let mut data = PlayerData::new();
+        data.event(PlayerEvent::Row(0, 0));
+        data.enter_order(0);
+        assert!(data.events.is_empty());
+
+        // Order changes come before the row read in the same frame
+        data.report = true;
+        data.event(PlayerEvent::Row(1, 0));
+        data.event(PlayerEvent::Note(2, 60, 0, 64));
+        data.enter_order(1);
+        assert_eq!(data.events, vec![
+            PlayerEvent::Order(1),
+            PlayerEvent::Row(1, 0),
+            PlayerEvent::Note(2, 60, 0, 64),
+        ]);
+    }
+
+    #[test]
+    fn test_ust_timing() {
+        // Default tempo plays at the PAL vertical blank rate, 883.4 samples per frame
+        let mut b = build_module(1000);
+        let mut player = Player::find_player(format::load(&b).unwrap(), "ust").unwrap();
+        player.start();
+        let mut total = 0;
+        for _ in 0..50 {
+            player.play_frame();
+            total += player.buffer().len() / 2;
+        }
+        assert_eq!(total, 44170);
+
+        // Restart byte 0x60 sets the CIA timer to (240 - 0x60) * 122 = 17568
+        // cycles, 1092.2 samples per frame
+        b[471] = 0x60;
+        let mut player = Player::find_player(format::load(&b).unwrap(), "ust").unwrap();
+        player.start();
+        player.play_frame();
+        assert_eq!(player.buffer().len() / 2, 1092);
+        assert_eq!(player.data.tempo, 101);
+    }
+
+    #[test]
+    fn test_scan() {
+        // Rows are timed at the start of the tick where they are read
+        let b = build_module(1000);
+        let mut player = Player::find_player(format::load(&b).unwrap(), "ust").unwrap();
+        player.scan();
+        let scan = player.scan_data();
+        assert_eq!(scan.row(0, 0).unwrap().millis, 0);
+        assert_eq!(scan.row(0, 1).unwrap().millis, 120);
+        assert_eq!(scan.row(0, 1).unwrap().speed, 6);
+        assert_eq!(scan.find_time(119), Some((0, 0)));
+        assert_eq!(scan.find_time(120), Some((0, 1)));
+        assert_eq!(scan.duration, 7692);
+    }
+
+    // Soundtracker module playing instrument 1 in row 0 of the given channels
+    fn playing_module(chns: &[usize]) -> Arc<Module> {
+        let mut b = build_module(4000);
+        b[600..604].copy_from_slice(&[0; 4]);
+        for &chn in chns {
+            b[600 + chn * 4..604 + chn * 4].copy_from_slice(&[0x01, 0xac, 0x10, 0x00]);
+        }
+        for (i, x) in b[1624..].iter_mut().enumerate() {
+            *x = (i * 7) as u8;
+        }
+        Arc::new(format::load(&b).unwrap())
+    }
+
+    // Render frames of a module in a player set up by the given function
+    fn render<F: FnOnce(&mut Player)>(module: Arc<Module>, frames: usize, setup: F) -> Vec<i16> {
+        let mut player = Player::find_player(module, "ust").unwrap();
+        setup(&mut player);
+        player.start();
+        let mut out = vec![];
+        for _ in 0..frames {
+            player.play_frame();
+            out.extend_from_slice(player.buffer());
+        }
+        out
+    }
+
+    #[test]
+    fn test_restart() {
+        let module = playing_module(&[0]);
+        let mut player = Player::find_player(module.clone(), "ust").unwrap();
+        player.start();
+        for _ in 0..20 {
+            player.play_frame();
+        }
+
+        // A restarted player plays as a new player
+        player.restart();
+        let mut new = Player::find_player(module, "ust").unwrap();
+        new.start();
+        for _ in 0..20 {
+            assert_eq!((player.position(), player.row(), player.frame()),
+                       (new.position(), new.row(), new.frame()));
+            player.play_frame();
+            new.play_frame();
+            assert_eq!(player.buffer(), new.buffer());
+        }
+        assert!(player.buffer().iter().any(|&x| x != 0));
+    }
+
+    #[test]
+    fn test_seek_time() {
+        let module = playing_module(&[0]);
+
+        // Seek to the tick where row 1 is read
+        let mut player = Player::find_player(module.clone(), "ust").unwrap();
+        player.start();
+        player.seek_time(130);
+
+        let mut straight = Player::find_player(module, "ust").unwrap();
+        straight.start();
+        for _ in 0..6 {
+            straight.play_frame();
+        }
+
+        for _ in 0..12 {
+            assert_eq!((player.position(), player.row(), player.frame()),
+                       (straight.position(), straight.row(), straight.frame()));
+            player.play_frame();
+            straight.play_frame();
+            assert_eq!(player.buffer(), straight.buffer());
+        }
+        assert!(player.buffer().iter().any(|&x| x != 0));
+    }
+
+    #[test]
+    fn test_mute() {
+        // A muted channel contributes nothing to the mix
+        let module = playing_module(&[0, 1]);
+        let muted = render(module.clone(), 10, |p| { p.mute_channel(1); });
+        assert_eq!(muted, render(playing_module(&[0]), 10, |_| {}));
+        assert!(muted.iter().any(|&x| x != 0));
+
+        // Solo mutes every other channel
+        let solo = render(module, 10, |p| {
+            p.solo_channel(1);
+            assert!(p.channel_muted(0) && !p.channel_muted(1) && p.channel_muted(3));
+        });
+        assert_eq!(solo, render(playing_module(&[1]), 10, |_| {}));
+        assert!(solo.iter().any(|&x| x != 0));
+    }
+
+    #[test]
+    fn test_stems() {
+        let mut player = Player::find_player(playing_module(&[0, 1]), "ust").unwrap();
+        player.set_float_mix(true).set_format(SampleFormat::F32).set_stems(Stems::Channel);
+        player.start();
+        assert_eq!(player.num_stems(), 4);
+
+        // Channel stems add up to the master mix
+        for _ in 0..10 {
+            player.play_frame();
+            let buf = player.buffer_f32();
+            for (i, &x) in buf.iter().enumerate() {
+                let sum: f32 = (0..4).map(|n| player.stem_buffer(n)[i]).sum();
+                assert!((x - sum).abs() < 1e-6, "sample {}", i);
+            }
+        }
+        assert!(player.stem_buffer(0).iter().any(|&x| x != 0.0));
+        assert!(player.stem_buffer(1).iter().any(|&x| x != 0.0));
+        assert!(player.stem_buffer(2).iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn test_channel_info() {
+        let mut player = Player::find_player(playing_module(&[1]), "ust").unwrap();
+        let mut info = FrameInfo::new();
+        player.start();
+        player.play_frame();
+        player.info(&mut info);
+        assert_eq!(info.channel.len(), 4);
+
+        // Channel 1 plays instrument 1 at C-2, volume 64
+        let ci = &info.channel[1];
+        assert!(ci.active && !ci.looping && !ci.muted);
+        assert_eq!((ci.note, ci.ins, ci.smp, ci.volume), (60, 0, 0, 64 << 4));
+        assert_eq!(ci.period, 428.0);
+        assert!(ci.pos > 0.0);
+        assert!(!info.channel[0].active);
+    }
+
+    #[test]
+    fn test_scopes() {
+        let mut player = Player::find_player(playing_module(&[0, 1]), "ust").unwrap();
+        player.set_float_mix(true).set_format(SampleFormat::F32).set_scopes(true);
+        player.start();
+        let mut solo = Player::find_player(playing_module(&[1]), "ust").unwrap();
+        solo.set_float_mix(true).set_format(SampleFormat::F32);
+        solo.start();
+
+        // The scope of a channel holds what it adds to both outputs
+        for _ in 0..10 {
+            player.play_frame();
+            solo.play_frame();
+            let scope = player.scope_buffer(1);
+            assert_eq!(scope.len(), solo.buffer_f32().len() / 2);
+            for (x, out) in scope.iter().zip(solo.buffer_f32().chunks(2)) {
+                assert!((x - (out[0] + out[1])).abs() < 1e-6);
+            }
+        }
+        assert!(player.scope_buffer(1).iter().any(|&x| x != 0.0));
+        assert!(player.scope_buffer(2).iter().all(|&x| x == 0.0));
+    }
+
+    #[test]
+    fn test_preview() {
+        let module = playing_module(&[0]);
+        let mut player = Player::find_player(module.clone(), "ust").unwrap();
+        let mut song = Player::find_player(module, "ust").unwrap();
+        let (mut info, mut song_info) = (FrameInfo::new(), FrameInfo::new());
+        player.start();
+        song.start();
+
+        // A previewed note plays along with the song without changing its channels
+        player.play_note(0, 0, 60, 64);
+        for _ in 0..10 {
+            player.play_frame();
+            song.play_frame();
+            player.info(&mut info);
+            song.info(&mut song_info);
+            for (a, b) in info.channel.iter().zip(&song_info.channel) {
+                assert_eq!((a.active, a.note, a.ins, a.volume, a.pos), (b.active, b.note, b.ins, b.volume, b.pos));
+            }
+            assert_ne!(player.buffer(), song.buffer());
+        }
+    }
+
+    #[test]
+    fn test_callbacks() {
+        // Channel 0 plays a note and channel 1 an arpeggio in row 0
+        let mut b = build_module(1000);
+        b[602] = 0x10;
+        b[606..608].copy_from_slice(&[0x01, 0x37]);
+        let mut player = Player::find_player(format::load(&b).unwrap(), "ust").unwrap();
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (l1, l2, l3, l4) = (log.clone(), log.clone(), log.clone(), log.clone());
+        player.on_row(move |pos, row| l1.lock().unwrap().push(format!("row {} {}", pos, row)))
+              .on_order(move |pos| l2.lock().unwrap().push(format!("order {}", pos)))
+              .on_note(move |chn, note, ins, vol| l3.lock().unwrap().push(format!("note {} {} {} {}", chn, note, ins, vol)))
+              .on_effect(move |chn, fxt, fxp| l4.lock().unwrap().push(format!("effect {} {} {}", chn, fxt, fxp)));
+        player.start();
+
+        // Events are dispatched in replay order after the frame is played
+        player.play_frame();
+        assert_eq!(*log.lock().unwrap(), ["order 0", "row 0 0", "note 0 60 0 64", "effect 1 1 55"]);
+        for _ in 0..6 {
+            player.play_frame();
+        }
+        assert_eq!(log.lock().unwrap()[4..], ["row 0 1"]);
+
+        // No events are reported while seeking
+        log.lock().unwrap().clear();
+        player.seek_time(500);
+        assert!(log.lock().unwrap().is_empty());
+        player.play_frame();
+        assert_eq!(*log.lock().unwrap(), ["row 0 4"]);
+    }
+
+    #[test]
+    fn test_player_send() {
+        fn is_send<T: Send + 'static>() {}
+        is_send::<Player>();
+    }
+}
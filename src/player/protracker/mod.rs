@@ -1,7 +1,7 @@
 mod player;
 
 use module::Module;
-use player::{PlayerListEntry, PlayerInfo, FormatPlayer};
+use player::{PlayerListEntry, PlayerInfo, FormatPlayer, Timing};
 
 pub struct Pt21a;
 
@@ -16,9 +16,13 @@ impl PlayerListEntry for Pt21a {
        }
    }
 
-   fn player(&self, module: &Module) -> Box<FormatPlayer> {
+   fn player(&self, module: &Module) -> Box<dyn FormatPlayer> {
        Box::new(self::player::ModPlayer::new(module))
    }
+
+   fn timing(&self, _module: &Module) -> Timing {
+       Timing::Cia
+   }
 }
 
 
@@ -1,5 +1,5 @@
 use module::{Module, ModuleData};
-use player::{PlayerData, Virtual, FormatPlayer};
+use player::{PlayerData, PlayerEvent, Virtual, FormatPlayer};
 use format::mk::{ModData, PeriodTable};
 
 /// PT2.1A Replayer
@@ -14,7 +14,7 @@ use format::mk::{ModData, PeriodTable};
 /// * Pattern periods are decoded beforehand and stored as a note value.
 /// * Pattern instruments are decoded beforehand and stored in channel state.
 /// * CIA tempo support added to the original PT2.1A set speed command.
-
+/// * E0x switches the LED filter of the mixer Paula emulation.
 pub struct ModPlayer {
     state : Vec<ChannelData>,
 
@@ -29,6 +29,7 @@ pub struct ModPlayer {
     mt_patt_del_time_2: u8,
     mt_pattern_pos    : u8,
     cia_tempo         : u8,
+    new_order         : bool,  // moved to a new position in this tick
 }
 
 impl ModPlayer {
@@ -47,23 +48,24 @@ impl ModPlayer {
             mt_patt_del_time_2: 0,
             mt_pattern_pos    : 0,
             cia_tempo         : 125,
+            new_order         : true,
         }
     }
 
-    fn mt_music(&mut self, module: &ModData, mut virt: &mut Virtual) {
+    fn mt_music(&mut self, module: &ModData, data: &mut PlayerData, virt: &mut Virtual) {
         self.mt_counter += 1;
         if self.mt_speed > self.mt_counter {
             // mt_NoNewNote
-            self.mt_no_new_all_channels(&module, &mut virt);
-            self.mt_no_new_pos_yet(&module);
+            self.mt_no_new_all_channels(module, virt);
+            self.mt_no_new_pos_yet(module);
             return;
         }
 
         self.mt_counter = 0;
         if self.mt_patt_del_time_2 == 0 {
-            self.mt_get_new_note(&module, &mut virt);
+            self.mt_get_new_note(module, data, virt);
         } else {
-            self.mt_no_new_all_channels(&module, &mut virt);
+            self.mt_no_new_all_channels(module, virt);
         }
 
         // mt_dskip
@@ -90,30 +92,33 @@ impl ModPlayer {
 
         // mt_nnpysk
         if self.mt_pattern_pos >= 64 {
-            self.mt_next_position(&module);
+            self.mt_next_position(module);
         }
-        self.mt_no_new_pos_yet(&module);
+        self.mt_no_new_pos_yet(module);
     }
 
-    fn mt_no_new_all_channels(&mut self, module: &ModData, mut virt: &mut Virtual) {
+    fn mt_no_new_all_channels(&mut self, module: &ModData, virt: &mut Virtual) {
         for chn in 0..module.channels() {
-            self.mt_check_efx(chn, &mut virt);
+            self.mt_check_efx(chn, virt);
         }
     }
 
-    fn mt_get_new_note(&mut self, module: &ModData, mut virt: &mut Virtual) {
+    fn mt_get_new_note(&mut self, module: &ModData, data: &mut PlayerData, virt: &mut Virtual) {
         let p = match module.pattern_in_position(self.mt_song_pos as usize) {
             Some(val) => val,
             None      => return,
         };
 
+        data.event(PlayerEvent::Row(self.mt_song_pos as usize, self.mt_pattern_pos as usize));
+
         for chn in 0..module.channels() {
             let event = module.patterns.event(p, self.mt_pattern_pos, chn);
             let (note, ins, cmd, cmdlo) = (event.note, event.ins, event.cmd, event.cmdlo);
 
             // mt_PlayVoice
-            if { let e = &self.state[chn]; e.n_note | e.n_ins | e.n_cmd | e.n_cmdlo == 0 } {  // TST.L   (A6)
-                self.per_nop(chn, &mut virt);
+            let e = &self.state[chn];
+            if e.n_note | e.n_ins | e.n_cmd | e.n_cmdlo == 0 {  // TST.L   (A6)
+                self.per_nop(chn, virt);
             }
 
             {
@@ -134,7 +139,7 @@ impl ModPlayer {
                     state.n_finetune = instrument.finetune as i8;
                     //self.state[chn].n_replen = sample.loop_end - sample.loop_start;
                     state.n_volume = instrument.volume as u8;
-                    virt.set_patch(chn, ins as usize - 1, ins as usize - 1, note as usize);
+                    virt.queue_patch(chn, ins as usize - 1, ins as usize - 1, note as usize);
                     virt.set_volume(chn, instrument.volume << 4);  // MOVE.W  D0,8(A5)        ; Set volume
                 }
             }
@@ -147,31 +152,40 @@ impl ModPlayer {
                                    // mt_DoSetFinetune
                                    self.mt_set_finetune(chn);
                                }
-                               self.mt_set_period(chn, &mut virt);
+                               self.mt_set_period(chn, virt);
                            }
                     0x3 => {  // TonePortamento
                                self.mt_set_tone_porta(chn);
-                               self.mt_check_more_efx(chn, &mut virt)
+                               self.mt_check_more_efx(chn, virt)
                            },
                     0x5 => {
                                self.mt_set_tone_porta(chn);
-                               self.mt_check_more_efx(chn, &mut virt)
+                               self.mt_check_more_efx(chn, virt)
                            },
                     0x9 => {  // Sample Offset
-                               self.mt_check_more_efx(chn, &mut virt);
-                               self.mt_set_period(chn, &mut virt);
+                               self.mt_check_more_efx(chn, virt);
+                               self.mt_set_period(chn, virt);
                            },
                     _   => {
-                               self.mt_set_period(chn, &mut virt);
+                               self.mt_set_period(chn, virt);
                            },
                 }
             } else {
-                self.mt_check_more_efx(chn, &mut virt);  // If no note
+                self.mt_check_more_efx(chn, virt);  // If no note
+            }
+
+            if note != 0 && cmd != 0x3 && cmd != 0x5 {
+                if let Some(ins) = virt.channel_ins(chn) {
+                    data.event(PlayerEvent::Note(chn, note as usize, ins, self.state[chn].n_volume as usize));
+                }
+            }
+            if cmd | cmdlo != 0 {
+                data.event(PlayerEvent::Effect(chn, cmd, cmdlo));
             }
         }
     }
 
-    fn mt_set_period(&mut self, chn: usize, mut virt: &mut Virtual) {
+    fn mt_set_period(&mut self, chn: usize, virt: &mut Virtual) {
         {
             let state = &mut self.state[chn];
             let period = PeriodTable::note_to_period(state.n_note, state.n_finetune);
@@ -189,7 +203,7 @@ impl ModPlayer {
             }
         }
 
-        self.mt_check_more_efx(chn, &mut virt);
+        self.mt_check_more_efx(chn, virt);
     }
 
     fn mt_next_position(&mut self, module: &ModData) {
@@ -201,40 +215,41 @@ impl ModPlayer {
         if self.mt_song_pos as usize >= module.len() {
             self.mt_song_pos = 0;
         }
+        self.new_order = true;
     }
 
     fn mt_no_new_pos_yet(&mut self, module: &ModData) {
         if self.mt_pos_jump_flag {
-            self.mt_next_position(&module);
-            self.mt_no_new_pos_yet(&module);
+            self.mt_next_position(module);
+            self.mt_no_new_pos_yet(module);
         }
     }
 
-    fn mt_check_efx(&mut self, chn: usize, mut virt: &mut Virtual) {
+    fn mt_check_efx(&mut self, chn: usize, virt: &mut Virtual) {
 
         let cmd = self.state[chn].n_cmd;
 
         // mt_UpdateFunk()
         if cmd == 0 && self.state[chn].n_cmdlo == 0 {
-            self.per_nop(chn, &mut virt);
+            self.per_nop(chn, virt);
             return
         }
 
         match cmd {
-            0x0 => self.mt_arpeggio(chn, &mut virt),
-            0x1 => self.mt_porta_up(chn, &mut virt),
-            0x2 => self.mt_porta_down(chn, &mut virt),
-            0x3 => self.mt_tone_portamento(chn, &mut virt),
-            0x4 => self.mt_vibrato(chn, &mut virt),
-            0x5 => self.mt_tone_plus_vol_slide(chn, &mut virt),
-            0x6 => self.mt_vibrato_plus_vol_slide(chn, &mut virt),
-            0xe => self.mt_e_commands(chn, &mut virt),
+            0x0 => self.mt_arpeggio(chn, virt),
+            0x1 => self.mt_porta_up(chn, virt),
+            0x2 => self.mt_porta_down(chn, virt),
+            0x3 => self.mt_tone_portamento(chn, virt),
+            0x4 => self.mt_vibrato(chn, virt),
+            0x5 => self.mt_tone_plus_vol_slide(chn, virt),
+            0x6 => self.mt_vibrato_plus_vol_slide(chn, virt),
+            0xe => self.mt_e_commands(chn, virt),
             _   => {
                        // SetBack
                        virt.set_period(chn, self.state[chn].n_period as f64);  // MOVE.W  n_period(A6),6(A5)
                        match cmd {
-                           0x7 => self.mt_tremolo(chn, &mut virt),
-                           0xa => self.mt_volume_slide(chn, &mut virt),
+                           0x7 => self.mt_tremolo(chn, virt),
+                           0xa => self.mt_volume_slide(chn, virt),
                            _   => {},
                        }
                    }
@@ -258,7 +273,7 @@ impl ModPlayer {
             _ => {
                      state.n_cmdlo >> 4
                  },
-        } as u8;
+        };
         // Arpeggio3
         // Arpeggio4
         let note = PeriodTable::period_to_note(state.n_period, state.n_finetune);
@@ -266,12 +281,12 @@ impl ModPlayer {
         virt.set_period(chn, period as f64);  // MOVE.W  D2,6(A5)
     }
 
-    fn mt_fine_porta_up(&mut self, chn: usize, mut virt: &mut Virtual) {
+    fn mt_fine_porta_up(&mut self, chn: usize, virt: &mut Virtual) {
         if self.mt_counter != 0 {
             return
         }
         self.mt_low_mask = 0x0f;
-        self.mt_porta_up(chn, &mut virt);
+        self.mt_porta_up(chn, virt);
     }
 
     fn mt_porta_up(&mut self, chn: usize, virt: &mut Virtual) {
@@ -284,12 +299,12 @@ impl ModPlayer {
         virt.set_period(chn, state.n_period as f64);  // MOVE.W  n_period(A6),6(A5)
     }
 
-    fn mt_fine_porta_down(&mut self, chn: usize, mut virt: &mut Virtual) {
+    fn mt_fine_porta_down(&mut self, chn: usize, virt: &mut Virtual) {
         if self.mt_counter != 0 {
             return
         }
         self.mt_low_mask = 0x0f;
-        self.mt_porta_down(chn, &mut virt);
+        self.mt_porta_down(chn, virt);
     }
 
     fn mt_porta_down(&mut self, chn: usize, virt: &mut Virtual) {
@@ -308,11 +323,12 @@ impl ModPlayer {
         state.n_toneportdirec = state.n_period < state.n_wantedperiod;
     }
 
+    #[allow(dead_code)]
     fn mt_clear_tone_porta(&mut self, chn: usize) {
         self.state[chn].n_wantedperiod = 0;
     }
 
-    fn mt_tone_portamento(&mut self, chn: usize, mut virt: &mut Virtual) {
+    fn mt_tone_portamento(&mut self, chn: usize, virt: &mut Virtual) {
         {
             let state = &mut self.state[chn];
             if state.n_cmdlo != 0 {
@@ -320,7 +336,7 @@ impl ModPlayer {
                 state.n_cmdlo = 0;
             }
         }
-        self.mt_tone_port_no_change(chn, &mut virt);
+        self.mt_tone_port_no_change(chn, virt);
     }
 
     fn mt_tone_port_no_change(&mut self, chn: usize, virt: &mut Virtual) {
@@ -347,14 +363,12 @@ impl ModPlayer {
                 state.n_wantedperiod = 0;
             }
         }
-        // mt_TonePortaSetPer
-        if state.n_glissfunk & 0x0f != 0 {
-        }
+        // mt_TonePortaSetPer (glissando not implemented)
         // mt_GlissSkip
         virt.set_period(chn, state.n_period as f64);
     }
 
-    fn mt_vibrato(&mut self, chn: usize, mut virt: &mut Virtual) {
+    fn mt_vibrato(&mut self, chn: usize, virt: &mut Virtual) {
         {
             let state = &mut self.state[chn];
             if state.n_cmdlo != 0 {
@@ -368,7 +382,7 @@ impl ModPlayer {
                 // mt_vibskip2
             }
         }
-        self.mt_vibrato_2(chn, &mut virt);
+        self.mt_vibrato_2(chn, virt);
     }
 
     fn mt_vibrato_2(&mut self, chn: usize, virt: &mut Virtual) {
@@ -400,14 +414,14 @@ impl ModPlayer {
         state.n_vibratopos = state.n_vibratopos.wrapping_add((state.n_vibratocmd >> 2) & 0x3c);
     }
 
-    fn mt_tone_plus_vol_slide(&mut self, chn: usize, mut virt: &mut Virtual) {
-        self.mt_tone_port_no_change(chn, &mut virt);
-        self.mt_volume_slide(chn, &mut virt);
+    fn mt_tone_plus_vol_slide(&mut self, chn: usize, virt: &mut Virtual) {
+        self.mt_tone_port_no_change(chn, virt);
+        self.mt_volume_slide(chn, virt);
     }
 
-    fn mt_vibrato_plus_vol_slide(&mut self, chn: usize, mut virt: &mut Virtual) {
-        self.mt_vibrato_2(chn, &mut virt);
-        self.mt_volume_slide(chn, &mut virt);
+    fn mt_vibrato_plus_vol_slide(&mut self, chn: usize, virt: &mut Virtual) {
+        self.mt_vibrato_2(chn, virt);
+        self.mt_volume_slide(chn, virt);
     }
 
     fn mt_tremolo(&mut self, chn: usize, virt: &mut Virtual) {
@@ -444,14 +458,8 @@ impl ModPlayer {
         } else {
             volume -= amt;
         }
-        // mt_Tremolo3
-        if volume < 0 {
-            volume = 0;
-        }
-        // mt_TremoloSkip
-        if volume > 0x40 {
-           volume = 0x40;
-        }
+        // mt_Tremolo3, mt_TremoloSkip
+        volume = volume.clamp(0, 0x40);
 
         // mt_TremoloOk
         virt.set_volume(chn, (volume as usize) << 4);  // MOVE.W  D0,8(A5)
@@ -466,11 +474,11 @@ impl ModPlayer {
         virt.set_voicepos(chn, ((state.n_sampleoffset as u32) << 8) as f64);
     }
 
-    fn mt_volume_slide(&mut self, chn: usize, mut virt: &mut Virtual) {
+    fn mt_volume_slide(&mut self, chn: usize, virt: &mut Virtual) {
         if self.state[chn].n_cmdlo >> 4 == 0 {
-            self.mt_vol_slide_down(chn, &mut virt);
+            self.mt_vol_slide_down(chn, virt);
         } else {
-            self.mt_vol_slide_up(chn, &mut virt);
+            self.mt_vol_slide_up(chn, virt);
         }
     }
 
@@ -534,46 +542,47 @@ impl ModPlayer {
         }
     }
 
-    fn mt_check_more_efx(&mut self, chn: usize, mut virt: &mut Virtual) {
+    fn mt_check_more_efx(&mut self, chn: usize, virt: &mut Virtual) {
         // mt_UpdateFunk()
 
         match self.state[chn].n_cmd {
-            0x9 => self.mt_sample_offset(chn, &mut virt),
+            0x9 => self.mt_sample_offset(chn, virt),
             0xb => self.mt_position_jump(chn),
             0xd => self.mt_pattern_break(chn),
-            0xe => self.mt_e_commands(chn, &mut virt),
+            0xe => self.mt_e_commands(chn, virt),
             0xf => self.mt_set_speed(chn),
-            0xc => self.mt_volume_change(chn, &mut virt),
+            0xc => self.mt_volume_change(chn, virt),
             _   => {},
         }
 
         // per_nop
-        self.per_nop(chn, &mut virt)
+        self.per_nop(chn, virt)
     }
 
-    fn mt_e_commands(&mut self, chn: usize, mut virt: &mut Virtual) {
+    fn mt_e_commands(&mut self, chn: usize, virt: &mut Virtual) {
 
         match self.state[chn].n_cmdlo >> 4 {
-           0x0 => self.mt_filter_on_off(chn, &mut virt),
-           0x1 => self.mt_fine_porta_up(chn, &mut virt),
-           0x2 => self.mt_fine_porta_down(chn, &mut virt),
+           0x0 => self.mt_filter_on_off(chn, virt),
+           0x1 => self.mt_fine_porta_up(chn, virt),
+           0x2 => self.mt_fine_porta_down(chn, virt),
            0x3 => self.mt_set_gliss_control(chn),
            0x4 => self.mt_set_vibrato_control(chn),
            0x5 => self.mt_set_finetune(chn),
            0x6 => self.mt_jump_loop(chn),
            0x7 => self.mt_set_tremolo_control(chn),
-           0x9 => self.mt_retrig_note(chn, &mut virt),
-           0xa => self.mt_volume_fine_up(chn, &mut virt),
-           0xb => self.mt_volume_fine_down(chn, &mut virt),
-           0xc => self.mt_note_cut(chn, &mut virt),
-           0xd => self.mt_note_delay(chn, &mut virt),
+           0x9 => self.mt_retrig_note(chn, virt),
+           0xa => self.mt_volume_fine_up(chn, virt),
+           0xb => self.mt_volume_fine_down(chn, virt),
+           0xc => self.mt_note_cut(chn, virt),
+           0xd => self.mt_note_delay(chn, virt),
            0xe => self.mt_pattern_delay(chn),
-           0xf => self.mt_funk_it(chn, &mut virt),
+           0xf => self.mt_funk_it(chn, virt),
            _   => {},
         }
     }
 
-    fn mt_filter_on_off(&self, _chn: usize, mut _virt: &mut Virtual) {
+    fn mt_filter_on_off(&self, chn: usize, virt: &mut Virtual) {
+        virt.set_led_filter(self.state[chn].n_cmdlo & 0x01 == 0);  // AND.B #$FD,$BFE001
     }
 
     fn mt_set_gliss_control(&mut self, chn: usize) {
@@ -603,7 +612,7 @@ impl ModPlayer {
 
         if cmdlo == 0 {
             // mt_SetLoop
-            state.n_pattpos = self.mt_pattern_pos as u8;
+            state.n_pattpos = self.mt_pattern_pos;
         } else {
             if state.n_loopcount == 0 {
                 // mt_jmpcnt
@@ -632,13 +641,11 @@ impl ModPlayer {
         if cmdlo == 0 {
             return;
         }
-        if self.mt_counter == 0 {
-            if state.n_note != 0 {
-                return;
-            }
+        if self.mt_counter == 0 && state.n_note != 0 {
+            return;
         }
         // mt_rtnskp
-        if self.mt_counter % cmdlo != 0 {
+        if !self.mt_counter.is_multiple_of(cmdlo) {
             return;
         }
         
@@ -646,18 +653,18 @@ impl ModPlayer {
         virt.set_voicepos(chn, 0.0);
     }
 
-    fn mt_volume_fine_up(&mut self, chn: usize, mut virt: &mut Virtual) {
+    fn mt_volume_fine_up(&mut self, chn: usize, virt: &mut Virtual) {
         if self.mt_counter != 0 {
             return;
         }
-        self.mt_vol_slide_up(chn, &mut virt);
+        self.mt_vol_slide_up(chn, virt);
     }
 
-    fn mt_volume_fine_down(&mut self, chn: usize, mut virt: &mut Virtual) {
+    fn mt_volume_fine_down(&mut self, chn: usize, virt: &mut Virtual) {
         if self.mt_counter != 0 {
             return;
         }
-        self.mt_vol_slide_down(chn, &mut virt)
+        self.mt_vol_slide_down(chn, virt)
     }
 
     fn mt_note_cut(&mut self, chn: usize, virt: &mut Virtual) {
@@ -689,7 +696,7 @@ impl ModPlayer {
         if self.mt_patt_del_time_2 != 0 {
             return;
         }
-        self.mt_patt_del_time = state.n_cmdlo & 0x0f + 1;
+        self.mt_patt_del_time = (state.n_cmdlo & 0x0f) + 1;
     }
 
     fn mt_funk_it(&self, _chn: usize, _virt: &mut Virtual) {
@@ -697,12 +704,13 @@ impl ModPlayer {
 }
 
 impl FormatPlayer for ModPlayer {
-    fn start(&mut self, data: &mut PlayerData, _mdata: &ModuleData) {
+    fn start(&mut self, data: &mut PlayerData, _mdata: &dyn ModuleData) {
         data.speed = 6;
         data.tempo = 125;
+        data.gvol = 64;
     }
 
-    fn play(&mut self, data: &mut PlayerData, mdata: &ModuleData, mut virt: &mut Virtual) {
+    fn play(&mut self, data: &mut PlayerData, mdata: &dyn ModuleData, virt: &mut Virtual) {
 
         let module = mdata.as_any().downcast_ref::<ModData>().unwrap();
 
@@ -712,7 +720,19 @@ impl FormatPlayer for ModPlayer {
         self.mt_pattern_pos = data.row as u8;
         self.mt_counter = data.frame as u8;
 
-        self.mt_music(&module, &mut virt);
+        // Replay enters a new position when its first row is read
+        let new_order = self.new_order;
+        self.new_order = false;
+
+        self.mt_music(module, data, virt);
+
+        if new_order {
+            if self.mt_counter == 0 {
+                data.enter_order(data.pos);
+            } else {
+                self.new_order = true;
+            }
+        }
 
         data.frame = self.mt_counter as usize;
         data.row = self.mt_pattern_pos as usize;
@@ -732,6 +752,22 @@ impl FormatPlayer for ModPlayer {
         self.mt_patt_del_time   = 0;
         self.mt_patt_del_time_2 = 0;
         self.mt_pattern_pos     = 0;
+        self.cia_tempo          = 125;
+        self.new_order          = true;
+
+        for ch in &mut self.state {
+            *ch = ChannelData::new();
+        }
+    }
+
+    fn preview_note(&self, ins: usize, note: usize, mdata: &dyn ModuleData) -> Option<(usize, f64)> {
+        let module = mdata.as_any().downcast_ref::<ModData>().unwrap();
+        let instrument = module.instruments.get(ins)?;
+        if module.samples.get(ins)?.size == 0 {
+            return None;
+        }
+        let period = PeriodTable::note_to_period(note.min(83) as u8, instrument.finetune as i8);
+        Some((ins, period as f64))
     }
 }
 
@@ -757,7 +793,9 @@ struct ChannelData {
     n_sampleoffset : u8,
     n_pattpos      : u8,
     n_loopcount    : u8,
+    #[allow(dead_code)]
     n_funkoffset   : u8,
+    #[allow(dead_code)]
     n_wavestart    : u32,
 }
 
@@ -768,11 +806,12 @@ impl ChannelData {
 }
 
 
-static MT_FUNK_TABLE: &'static [u8] = &[
+#[allow(dead_code)]
+static MT_FUNK_TABLE: &[u8] = &[
     0, 5, 6, 7, 8, 10, 11, 13, 16, 19, 22, 26, 32, 43, 64, 128
 ];
 
-static MT_VIBRATO_TABLE: &'static [u8] = &[
+static MT_VIBRATO_TABLE: &[u8] = &[
       0,  24,  49,  74,  97, 120, 141, 161,
     180, 197, 212, 224, 235, 244, 250, 253,
     255, 253, 250, 244, 235, 224, 212, 197,
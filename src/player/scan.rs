@@ -1,26 +1,157 @@
+use module::Module;
+use player::{PlayerData, PlayerEvent, FormatPlayer, Virtual};
 
-#[derive(Default)]
+// Give up scanning songs that don't loop after this time
+const MAX_SCAN_TIME: f64 = 4.0 * 3600.0 * 1000.0;
+
+// Minimum number of rows replayed without finding a new row to decide that
+// the song looped. Pattern loops replay rows without ending the song.
+const MIN_LOOP_ROWS: usize = 1024;
+
+
+#[derive(Clone,Debug,Default)]
 pub struct ScanRow {
-    millis: u32,  // milliseconds since start of replay
-    speed : u8,   // current replay speed
-    tempo : u8,   // current replay tempo
-    gvol  : u8,   // current global volume
+    pub millis: u32,  // milliseconds since start of replay
+    pub speed : u8,   // current replay speed
+    pub tempo : u8,   // current replay tempo
+    pub gvol  : u8,   // current global volume
 }
 
 
+#[derive(Clone,Debug,Default)]
 pub struct ScanPos {
-    row: Vec<ScanRow>,
+    pub row: Vec<Option<ScanRow>>,  // rows not played in the song are None
 }
 
+#[derive(Clone,Debug,Default)]
 pub struct ScanData {
-    pos: Vec<ScanPos>,
+    pub pos     : Vec<ScanPos>,
+    pub duration: u32,    // song duration in milliseconds
+    pub loop_pos: usize,  // position and row played after the end of the song
+    pub loop_row: usize,
 }
 
 impl ScanData {
     pub fn new(size: usize) -> Self {
         ScanData {
-            pos: Vec::new(),
+            pos     : vec![ScanPos::default(); size],
+            duration: 0,
+            loop_pos: 0,
+            loop_row: 0,
         }
     }
-}
 
+    pub fn row(&self, pos: usize, row: usize) -> Option<&ScanRow> {
+        match self.pos.get(pos) {
+            Some(p) => p.row.get(row).and_then(|x| x.as_ref()),
+            None    => None,
+        }
+    }
+
+    /// Find the last row played at or before the given time. Times after the
+    /// end of the song return the last row played.
+    pub fn find_time(&self, millis: u32) -> Option<(usize, usize)> {
+        let mut found: Option<(u32, usize, usize)> = None;
+        for (pos, p) in self.pos.iter().enumerate() {
+            for (row, r) in p.row.iter().enumerate() {
+                let r = match *r {
+                    Some(ref val) => val,
+                    None          => continue,
+                };
+                if r.millis > millis {
+                    continue;
+                }
+                if found.is_none_or(|(t, _, _)| r.millis > t) {
+                    found = Some((r.millis, pos, row));
+                }
+            }
+        }
+        found.map(|(_, pos, row)| (pos, row))
+    }
+
+    /// Play the module without mixing to find the time, speed, tempo and global
+    /// volume of each row, and the song duration. Rows are timed at the start
+    /// of the tick where they are read. The song ends when it jumps back to
+    /// rows already played.
+    pub fn scan(module: &Module, player: &mut dyn FormatPlayer, virt: &mut Virtual) -> Self {
+        let mut scan = ScanData::new(module.len());
+        let mut data = PlayerData::new();
+        player.start(&mut data, &*module.data);
+        data.report = true;
+
+        let mut time = 0.0_f64;
+        let mut last = None;
+        let mut num_rows = 0;     // number of rows played
+        let mut old_rows = 0;     // rows played again since the last new row
+        let mut end = None;       // time and position of the first row played again
+
+        'replay: loop {
+            player.play(&mut data, &*module.data, virt);
+
+            for ev in data.events.drain(..) {
+                let (pos, row) = match ev {
+                    PlayerEvent::Row(pos, row) => (pos, row),
+                    _                          => continue,
+                };
+                if last == Some((pos, row)) {
+                    continue;
+                }
+                last = Some((pos, row));
+
+                // Row outside the song, replay stopped
+                if pos >= scan.pos.len() {
+                    end = None;
+                    break 'replay;
+                }
+
+                let p = &mut scan.pos[pos];
+                if row >= p.row.len() {
+                    let rows = module.pattern_in_position(pos).map_or(0, |pat| module.rows(pat));
+                    p.row.resize(rows.max(row + 1), None);
+                }
+
+                if p.row[row].is_some() {
+                    if end.is_none() {
+                        end = Some((time, pos, row));
+                    }
+                    old_rows += 1;
+                    if old_rows > num_rows.max(MIN_LOOP_ROWS) {
+                        break 'replay;
+                    }
+                } else {
+                    // Speed and tempo set by effects in the row are already in effect
+                    p.row[row] = Some(ScanRow {
+                        millis: time as u32,
+                        speed : data.speed as u8,
+                        tempo : data.tempo as u8,
+                        gvol  : data.gvol as u8,
+                    });
+                    num_rows += 1;
+                    old_rows = 0;
+                    end = None;
+                }
+            }
+
+            time += 1000.0 * virt.tick_time(data.tempo);
+
+            // Position out of the song, replay stopped
+            if data.pos >= scan.pos.len() || time > MAX_SCAN_TIME {
+                end = None;
+                break;
+            }
+        }
+
+        match end {
+            Some((t, pos, row)) => {
+                scan.duration = t as u32;
+                scan.loop_pos = pos;
+                scan.loop_row = row;
+            },
+            None => {
+                scan.duration = time as u32;
+            }
+        }
+
+        scan
+    }
+}
@@ -0,0 +1,32 @@
+mod player;
+
+use module::Module;
+use format::mk::ModData;
+use player::{PlayerListEntry, PlayerInfo, FormatPlayer, Timing};
+
+pub struct Ust27;
+
+impl PlayerListEntry for Ust27 {
+   fn info(&self) -> PlayerInfo {
+       PlayerInfo {
+          id         : "ust",
+          name       : "Ultimate Soundtracker V27 replayer",
+          description: "A player based on the original Ultimate Soundtracker replayer",
+          author     : "Claudio Matsuoka",
+          accepts    : &[ "st" ],
+       }
+   }
+
+   fn player(&self, module: &Module) -> Box<dyn FormatPlayer> {
+       Box::new(self::player::StPlayer::new(module))
+   }
+
+   // Modules with the default tempo play at the vertical blank rate
+   fn timing(&self, module: &Module) -> Timing {
+       match module.data.as_any().downcast_ref::<ModData>().and_then(self::player::cia_timer) {
+           Some(val) => Timing::CiaTimer(val),
+           None      => Timing::Vblank,
+       }
+   }
+}
+
@@ -0,0 +1,256 @@
+use module::{Module, ModuleData};
+use player::{PlayerData, PlayerEvent, Virtual, FormatPlayer};
+use format::mk::{ModData, PeriodTable};
+
+const SPEED: u8 = 6;
+const DEFAULT_TEMPO: u8 = 0x78;
+
+/// Ultimate Soundtracker replayer
+///
+/// An oxdz player based on the Ultimate Soundtracker V27 play routine written
+/// by Karsten Obarski in 1987. Original names are used whenever possible.
+///
+/// Notes:
+/// * Only the original effects are supported: 1xy is arpeggio and 2xy is
+///   pitchbend (x slides the period up, y slides it down).
+/// * Speed is fixed at 6 ticks per row.
+/// * The song restart byte sets the CIA tempo. The default value of 0x78
+///   plays at the 50Hz vertical blank rate.
+/// * Mixer volumes are *16, so adjust when setting.
+pub struct StPlayer {
+    state  : Vec<ChannelData>,
+
+    timpos : u8,
+    trkpos : u8,
+    pattpos: u8,
+    new_order: bool,  // moved to a new position in this tick
+}
+
+/// CIA timer value set by the song restart byte, or `None` if the module
+/// plays at the vertical blank rate.
+pub fn cia_timer(module: &ModData) -> Option<usize> {
+    let tempo = module.restart;
+    if tempo == 0 || tempo == DEFAULT_TEMPO || tempo >= 240 {
+        None
+    } else {
+        Some((240 - tempo as usize) * 122)
+    }
+}
+
+// Nearest tempo in BPM for the CIA timer value, reported to the player.
+// Ticks are timed with the exact timer value.
+fn cia_tempo(module: &ModData) -> Option<usize> {
+    cia_timer(module).map(|val| (709379.0 * 125.0 / 50.0 / val as f64).round() as usize)
+}
+
+impl StPlayer {
+    pub fn new(module: &Module) -> Self {
+        StPlayer {
+            state  : vec![ChannelData::new(); module.data.channels()],
+
+            timpos : 0,
+            trkpos : 0,
+            pattpos: 0,
+            new_order: true,
+        }
+    }
+
+    fn replay_muzak(&mut self, module: &ModData, data: &mut PlayerData, virt: &mut Virtual) {
+        self.timpos += 1;
+        if self.timpos < SPEED {
+            self.chaneleffects(module, virt);
+            return;
+        }
+
+        self.timpos = 0;
+        self.replaystep(module, data, virt);
+    }
+
+    fn chaneleffects(&mut self, module: &ModData, virt: &mut Virtual) {
+        for chn in 0..module.channels() {
+            if self.state[chn].n_cmdlo == 0 {
+                continue;
+            }
+
+            // ceff5
+            match self.state[chn].n_cmd {
+                1 => self.arpreggiato(chn, virt),
+                2 => self.pitchbend(chn, virt),
+                _ => {},
+            }
+        }
+    }
+
+    fn arpreggiato(&mut self, chn: usize, virt: &mut Virtual) {
+        let state = &self.state[chn];
+        let val = match self.timpos {
+            1 | 4 => state.n_cmdlo >> 4,    // loop2
+            2 | 5 => state.n_cmdlo & 0x0f,  // loop3
+            3     => 0,                     // loop4
+            _     => return,
+        };
+
+        // cont
+        let period = if val == 0 {
+            state.n_period
+        } else {
+            PeriodTable::note_to_period(PeriodTable::period_to_note(state.n_period, 0) + val, 0)
+        };
+
+        // endpart
+        virt.set_period(chn, period as f64);  // move.w  d2,6(a5)
+    }
+
+    fn pitchbend(&mut self, chn: usize, virt: &mut Virtual) {
+        let state = &mut self.state[chn];
+        let up = (state.n_cmdlo >> 4) as u16;
+        if up != 0 {
+            state.n_pitchbend = state.n_pitchbend.wrapping_add(up);
+        } else {
+            // pit2
+            let down = (state.n_cmdlo & 0x0f) as u16;
+            if down == 0 {
+                return;
+            }
+            state.n_pitchbend = state.n_pitchbend.wrapping_sub(down);
+        }
+        virt.set_period(chn, state.n_pitchbend as f64);  // move.w  22(a6),6(a5)
+    }
+
+    fn replaystep(&mut self, module: &ModData, data: &mut PlayerData, virt: &mut Virtual) {
+        let pat = match module.pattern_in_position(self.trkpos as usize) {
+            Some(val) => val,
+            None      => return,
+        };
+
+        data.event(PlayerEvent::Row(self.trkpos as usize, self.pattpos as usize));
+
+        for chn in 0..module.channels() {
+            let event = module.patterns.event(pat, self.pattpos, chn);
+            let state = &mut self.state[chn];
+
+            state.n_note = event.note;
+            state.n_cmd = event.cmd;
+            state.n_cmdlo = event.cmdlo;
+
+            if event.cmd | event.cmdlo != 0 {
+                data.event(PlayerEvent::Effect(chn, event.cmd, event.cmdlo));
+            }
+
+            // chan2
+            if event.ins != 0 && event.ins as usize <= module.instruments.len() {
+                let instrument = &module.instruments[event.ins as usize - 1];
+                state.n_ins = event.ins;
+                state.n_volume = instrument.volume as u8;
+                virt.set_volume(chn, (state.n_volume as usize) << 4);  // move.w  18(a6),8(a5)
+            }
+
+            // chan3
+            if state.n_note == 0 {
+                continue;
+            }
+
+            state.n_period = PeriodTable::note_to_period(state.n_note, 0);
+            state.n_pitchbend = state.n_period;
+
+            if state.n_ins != 0 {
+                let ins = state.n_ins as usize - 1;
+                virt.set_patch(chn, ins, ins, state.n_note as usize);
+                virt.set_volume(chn, (state.n_volume as usize) << 4);
+                data.event(PlayerEvent::Note(chn, state.n_note as usize, ins, state.n_volume as usize));
+            }
+            virt.set_period(chn, state.n_period as f64);
+        }
+
+        // pattern position
+        self.pattpos += 1;
+        if self.pattpos >= 64 {
+            self.pattpos = 0;
+            self.trkpos += 1;
+            if self.trkpos as usize >= module.len() {
+                self.trkpos = 0;
+            }
+            self.new_order = true;
+        }
+    }
+}
+
+impl FormatPlayer for StPlayer {
+    fn start(&mut self, data: &mut PlayerData, mdata: &dyn ModuleData) {
+
+        let module = mdata.as_any().downcast_ref::<ModData>().unwrap();
+
+        data.speed = SPEED as usize;
+        data.tempo = cia_tempo(module).unwrap_or(125);
+
+        data.gvol = 64;
+
+        // Make the first frame play the first row
+        data.frame = SPEED as usize - 1;
+    }
+
+    fn play(&mut self, data: &mut PlayerData, mdata: &dyn ModuleData, virt: &mut Virtual) {
+
+        let module = mdata.as_any().downcast_ref::<ModData>().unwrap();
+
+        self.trkpos = data.pos as u8;
+        self.pattpos = data.row as u8;
+        self.timpos = data.frame as u8;
+
+        // Replay enters a new position when its first row is read
+        let new_order = self.new_order;
+        self.new_order = false;
+
+        self.replay_muzak(module, data, virt);
+
+        if new_order {
+            if self.timpos == 0 {
+                data.enter_order(data.pos);
+            } else {
+                self.new_order = true;
+            }
+        }
+
+        data.frame = self.timpos as usize;
+        data.row = self.pattpos as usize;
+        data.pos = self.trkpos as usize;
+    }
+
+    fn reset(&mut self) {
+        self.timpos    = 0;
+        self.trkpos    = 0;
+        self.pattpos   = 0;
+        self.new_order = true;
+
+        for ch in &mut self.state {
+            *ch = ChannelData::new();
+        }
+    }
+
+    fn preview_note(&self, ins: usize, note: usize, mdata: &dyn ModuleData) -> Option<(usize, f64)> {
+        let module = mdata.as_any().downcast_ref::<ModData>().unwrap();
+        if module.samples.get(ins)?.size == 0 {
+            return None;
+        }
+        let period = PeriodTable::note_to_period(note.min(83) as u8, 0);
+        Some((ins, period as f64))
+    }
+}
+
+
+#[derive(Clone,Default)]
+struct ChannelData {
+    n_note     : u8,
+    n_ins      : u8,
+    n_cmd      : u8,
+    n_cmdlo    : u8,
+    n_period   : u16,  // 16(a6)
+    n_pitchbend: u16,  // 22(a6)
+    n_volume   : u8,   // 18(a6)
+}
+
+impl ChannelData {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
@@ -16,7 +16,7 @@ impl PlayerListEntry for St2 {
        }
    }
 
-   fn player(&self, module: &Module) -> Box<FormatPlayer> {
+   fn player(&self, module: &Module) -> Box<dyn FormatPlayer> {
        Box::new(self::st2play::St2Play::new(module))
    }
 }
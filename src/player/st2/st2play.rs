@@ -1,13 +1,8 @@
 use module::{Module, ModuleData};
-use player::{PlayerData, Virtual, FormatPlayer};
+use player::{PlayerData, PlayerEvent, Virtual, FormatPlayer};
 use format::stm::StmData;
 
-/// Scream Tracker 2 replayer
-///
-/// An oxdz player based on st2play written by Sergei "x0r" Kolzun, ported
-/// to Rust by Claudio Matsuoka.
-
-
+#[allow(dead_code)]
 const ST2BASEFREQ      : u32 = 36072500;  // 2.21
 //const ST2BASEFREQ      : u32 = 35468950;  // 2.3
 
@@ -42,9 +37,9 @@ const FX_TREMOR        : u16 = 0x09;
 //const FX_VIBRA_VSLIDE  : u16 = 0x0b;
 //const FX_TONE_VSLIDE   : u16 = 0x0f;
 
-static TEMPO_MUL: &'static [u16; 18] = &[ 140, 50, 25, 15, 10, 7, 6, 4, 3, 3, 2, 2, 2, 2, 1, 1, 1, 1 ];
+static TEMPO_MUL: &[u16; 18] = &[ 140, 50, 25, 15, 10, 7, 6, 4, 3, 3, 2, 2, 2, 2, 1, 1, 1, 1 ];
 
-static PERIOD_TABLE: &'static [i16; 16*5] = &[
+static PERIOD_TABLE: &[i16; 16*5] = &[
     17080, 16012, 15184, 14236, 13664, 12808, 12008, 11388, 10676, 10248,  9608,  9108, 0, 0, 0, 0,
      8540,  8006,  7592,  7118,  6832,  6404,  6004,  5694,  5338,  5124,  4804,  4554, 0, 0, 0, 0,
      4270,  4003,  3796,  3559,  3416,  3202,  3002,  2847,  2669,  2562,  2402,  2277, 0, 0, 0, 0,
@@ -52,7 +47,7 @@ static PERIOD_TABLE: &'static [i16; 16*5] = &[
      1067,  1000,   949,   889,   854,   800,   750,   711,   667,   640,   600,   569, 0, 0, 0, 0 
 ];
 
-static LFO_TABLE: &'static [i16; 65] = &[
+static LFO_TABLE: &[i16; 65] = &[
        0,   24,   49,   74,   97,  120,  141,  161,  180,  197,  212,  224,  235,  244,  250,  253,
      255,  253,  250,  244,  235,  224,  212,  197,  180,  161,  141,  120,   97,   74,   49,   24,
        0,  -24,  -49,  -74,  -97, -120, -141, -161, -180, -197, -212, -224, -235, -244, -250, -253,
@@ -61,6 +56,10 @@ static LFO_TABLE: &'static [i16; 65] = &[
 ];
 
 
+/// Scream Tracker 2 replayer
+///
+/// An oxdz player based on st2play written by Sergei "x0r" Kolzun, ported
+/// to Rust by Claudio Matsuoka.
 pub struct St2Play {
     sample_rate     : u16,
     pattern_current : u16,
@@ -73,6 +72,7 @@ pub struct St2Play {
     order_first     : u16,
     order_next      : u16,
     order_current   : u16,
+    new_order       : bool,   // order changed in this tick
     tempo           : u8,
     global_volume   : u8,
     //play_single_note: u8,
@@ -98,6 +98,7 @@ impl St2Play {
             order_first     : 0,
             order_next      : 0,
             order_current   : 0,
+            new_order       : false,
             tempo           : 0x60,
             global_volume   : 64,
             //play_single_note: 0,
@@ -130,7 +131,7 @@ impl St2Play {
         let infobyte = self.channels[chn].event_infobyte;
 
         match cmd {
-            FX_SPEED => if infobyte != 0 {
+            FX_SPEED if infobyte != 0 => {
                 self.set_tempo(infobyte);
             },
             FX_POSITIONJUMP => {
@@ -175,11 +176,11 @@ impl St2Play {
                     if ch.tremor_state == 1 {
                         ch.tremor_state = 0;
                         ch.volume_current = 0;
-                        ch.tremor_counter = infobyte as u16 & 0x0f;
+                        ch.tremor_counter = infobyte & 0x0f;
                     } else {
                         ch.tremor_state = 1;
                         ch.volume_current = ch.volume_initial;
-                        ch.tremor_counter = infobyte as u16 >> 4;
+                        ch.tremor_counter = infobyte >> 4;
                     }
                 } else {
                     ch.tremor_counter -= 1;
@@ -294,7 +295,7 @@ impl St2Play {
                 ch.event_cmd      = event.cmd as u16;
                 ch.event_infobyte = event.infobyte as u16;
             }
-            self.trigger_note(chn, &module);
+            self.trigger_note(chn, module);
 
             if self.channels[chn].event_cmd == FX_TREMOR {
                 self.cmd_tick(chn);
@@ -313,6 +314,7 @@ impl St2Play {
 //      self.order_list_ptr[self.order_next] = 99;
         self.order_current = self.order_next;
         self.order_next += 1;
+        self.new_order = true;
 
         for ch in &mut self.channels {
             ch.row = 0;
@@ -329,11 +331,11 @@ impl St2Play {
             //if !ctx->play_single_note {
                 if self.change_pattern {
                     self.change_pattern = false;
-                    self.change_pattern(&module);
+                    self.change_pattern(module);
                 }
 
                 for i in 0..4 {
-                    self.process_row(i, &module);
+                    self.process_row(i, module);
                 }
 
                 self.current_tick = if self.ticks_per_row != 0 { self.ticks_per_row - 1 } else { 0 };
@@ -348,20 +350,21 @@ impl St2Play {
 
 
 impl FormatPlayer for St2Play {
-    fn start(&mut self, data: &mut PlayerData, mdata: &ModuleData) {
+    fn start(&mut self, data: &mut PlayerData, mdata: &dyn ModuleData) {
 
         let module = mdata.as_any().downcast_ref::<StmData>().unwrap();
 
         data.speed = module.speed as usize;
         data.tempo = 125;
+        data.gvol = self.global_volume as usize;
 
         let tempo = self.tempo as u16;
         self.set_tempo(tempo);
         self.current_frame = self.frames_per_tick;
-        self.change_pattern(&module);
+        self.change_pattern(module);
     }
 
-    fn play(&mut self, data: &mut PlayerData, mdata: &ModuleData, virt: &mut Virtual) {
+    fn play(&mut self, data: &mut PlayerData, mdata: &dyn ModuleData, virt: &mut Virtual) {
 
         let module = mdata.as_any().downcast_ref::<StmData>().unwrap();
 
@@ -374,7 +377,12 @@ impl FormatPlayer for St2Play {
         self.channels[3].row = data.row as u16;
         self.current_tick = (self.ticks_per_row - data.frame as u16) % self.ticks_per_row;
 
-        self.process_tick(&module);
+        let new_row = self.current_tick == 0;
+        self.process_tick(module);
+        if new_row {
+            data.event(PlayerEvent::Row(self.order_current as usize, self.channels[0].row as usize - 1));
+        }
+
         for chn in 0..4 {
             let ch = &mut self.channels[chn];
             if ch.trigger_note {
@@ -382,6 +390,13 @@ impl FormatPlayer for St2Play {
                 let note = ch.event_note as usize;
                 virt.set_patch(chn, smp - 1, smp - 1, note - 1);
                 ch.trigger_note = false;
+                if note < 250 {
+                    let key = (note & 0x0f) + 12 * (3 + (note >> 4));
+                    data.event(PlayerEvent::Note(chn, key, smp - 1, ch.volume_current as usize));
+                }
+            }
+            if new_row && ch.event_cmd | ch.event_infobyte != 0 {
+                data.event(PlayerEvent::Effect(chn, ch.event_cmd as u8, ch.event_infobyte as u8));
             }
             virt.set_period(chn, (ch.period_current / FXMULT as i16) as f64);
             if ch.volume_current != 65 {
@@ -394,24 +409,50 @@ impl FormatPlayer for St2Play {
         data.pos = self.order_next as usize;
         data.speed = self.ticks_per_row as usize;
         data.tempo = self.tempo as usize;
+        data.gvol = self.global_volume as usize;
 
+        if self.new_order {
+            self.new_order = false;
+            data.enter_order(self.order_current as usize);
+        }
     }
 
     fn reset(&mut self) {
+        self.sample_rate     = 15909;
+        self.pattern_current = 0;
+        self.change_pattern  = false;
+        self.current_tick    = 0;
+        self.ticks_per_row   = 0;
+        self.current_frame   = 1;
+        self.frames_per_tick = 1;
+        self.loop_count      = 0;
+        self.order_first     = 0;
+        self.order_next      = 0;
+        self.order_current   = 0;
+        self.new_order       = false;
+        self.tempo           = 0x60;
+        self.global_volume   = 64;
+
+        for ch in &mut self.channels {
+            *ch = St2Channel::new();
+        }
     }
 }
 
 #[derive(Default,Clone)]
 struct St2Channel {
     //on               : bool,
+    #[allow(dead_code)]
     empty            : bool,
     row              : u16,
+    #[allow(dead_code)]
     pattern_data_offs: usize,
     event_note       : u16,
     event_volume     : u8,
     event_smp        : u16,
     event_cmd        : u16,
     event_infobyte   : u16,
+    #[allow(dead_code)]
     last_note        : u16,
     period_current   : i16,
     period_target    : i16,
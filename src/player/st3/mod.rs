@@ -0,0 +1,23 @@
+mod st3play;
+
+use module::Module;
+use player::{PlayerListEntry, PlayerInfo, FormatPlayer};
+
+pub struct St3;
+
+impl PlayerListEntry for St3 {
+   fn info(&self) -> PlayerInfo {
+       PlayerInfo {
+          id         : "st3",
+          name       : "st3play ST3.21 replayer",
+          description: "A player that reproduces the Scream Tracker 3.21 replayer",
+          author     : "Claudio Matsuoka",
+          accepts    : &[ "s3m" ],
+       }
+   }
+
+   fn player(&self, module: &Module) -> Box<dyn FormatPlayer> {
+       Box::new(self::st3play::St3Play::new(module))
+   }
+}
+
@@ -0,0 +1,787 @@
+use module::{Module, ModuleData};
+use player::{PlayerData, PlayerEvent, Virtual, FormatPlayer};
+use format::s3m::{S3mData, S3mEvent};
+
+const ST3_CLOCK: f64 = 14317056.0;  // value used by ST3, not 8363 * 1712 = 14317456
+const C4_PERIOD: f64 = 428.0;
+
+const FX_SPEED         : u8 = 1;   // A
+const FX_POSITIONJUMP  : u8 = 2;   // B
+const FX_PATTERNBREAK  : u8 = 3;   // C
+const FX_VOLUMESLIDE   : u8 = 4;   // D
+const FX_PORTAMENTODOWN: u8 = 5;   // E
+const FX_PORTAMENTOUP  : u8 = 6;   // F
+const FX_TONEPORTAMENTO: u8 = 7;   // G
+const FX_VIBRATO       : u8 = 8;   // H
+const FX_TREMOR        : u8 = 9;   // I
+const FX_ARPEGGIO      : u8 = 10;  // J
+const FX_VIBRA_VSLIDE  : u8 = 11;  // K
+const FX_TONE_VSLIDE   : u8 = 12;  // L
+const FX_SAMPLEOFFSET  : u8 = 15;  // O
+const FX_RETRIG        : u8 = 17;  // Q
+const FX_TREMOLO       : u8 = 18;  // R
+const FX_SPECIAL       : u8 = 19;  // S
+const FX_TEMPO         : u8 = 20;  // T
+const FX_FINEVIBRATO   : u8 = 21;  // U
+const FX_GLOBALVOLUME  : u8 = 22;  // V
+const FX_SETPAN        : u8 = 24;  // X
+
+static NOTE_PERIOD: &[u32; 12] = &[
+    1712, 1616, 1524, 1440, 1356, 1280, 1208, 1140, 1076, 1016, 960, 907
+];
+
+static FINETUNE_C2SPD: &[u32; 16] = &[
+    7895, 7941, 7985, 8046, 8107, 8169, 8232, 8280,
+    8363, 8413, 8463, 8529, 8581, 8651, 8723, 8757
+];
+
+static VIB_SINE: &[i16; 64] = &[
+       0,   24,   49,   74,   97,  120,  141,  161,  180,  197,  212,  224,  235,  244,  250,  253,
+     255,  253,  250,  244,  235,  224,  212,  197,  180,  161,  141,  120,   97,   74,   49,   24,
+       0,  -24,  -49,  -74,  -97, -120, -141, -161, -180, -197, -212, -224, -235, -244, -250, -253,
+    -255, -253, -250, -244, -235, -224, -212, -197, -180, -161, -141, -120,  -97,  -74,  -49,  -24
+];
+
+// Volume change in Qxy retrig
+static RETRIG_VOL_ADD: &[i16; 16] = &[
+    0, -1, -2, -4, -8, -16, 0, 0, 0, 1, 2, 4, 8, 16, 0, 0
+];
+
+
+/// Scream Tracker 3 replayer
+///
+/// An oxdz player that reproduces the effect behaviour of the Scream Tracker
+/// 3.21 replayer, using the st3play naming conventions where possible.
+///
+/// Notes:
+/// * Periods are in ST3 units (4 times the Amiga period).
+/// * Effects D, E, F, I, J, K, L, Q and R share the same effect memory.
+///   G, H and U have separate memories.
+/// * Mixer volumes are *16, so adjust when setting.
+pub struct St3Play {
+    np_ord       : u16,   // current order
+    np_row       : u16,   // current row
+    musiccount   : u8,    // current tick
+    musicmax     : u8,    // ticks per row
+    tempo        : u8,
+    globalvol    : u8,
+    jumptoord    : Option<u16>,
+    jumptorow    : Option<u16>,
+    patterndelay : u8,
+    patdelaying  : bool,
+    patloopstart : u16,
+    patloopcount : u8,
+    fastvolslide : bool,
+    rand_seed    : u32,
+    new_order    : bool,  // moved to a new order, entered when its first row is played
+
+    channels     : Vec<St3Channel>,
+}
+
+impl St3Play {
+    pub fn new(module: &Module) -> Self {
+        St3Play {
+            np_ord      : 0,
+            np_row      : 0,
+            musiccount  : 0,
+            musicmax    : 6,
+            tempo       : 125,
+            globalvol   : 64,
+            jumptoord   : None,
+            jumptorow   : None,
+            patterndelay: 0,
+            patdelaying : false,
+            patloopstart: 0,
+            patloopcount: 0,
+            fastvolslide: false,
+            rand_seed   : 0x1234,
+            new_order   : true,
+            channels    : vec![St3Channel::new(); module.channels()],
+        }
+    }
+
+    // Compute ST3 period from note number (octave * 12 + note)
+    fn note_to_period(key: u16, c2spd: u32) -> u16 {
+        let c2spd = if c2spd == 0 { 8363 } else { c2spd };
+        let period = (8363 * 16 * (NOTE_PERIOD[key as usize % 12] >> (key / 12))) / c2spd;
+        period as u16
+    }
+
+    // Convert ST3 period to mixer period for the sample rate
+    fn mix_period(period: u16, c2spd: u32) -> f64 {
+        period as f64 * C4_PERIOD * c2spd as f64 / ST3_CLOCK
+    }
+
+    fn uses_shared_memory(cmd: u8) -> bool {
+        matches!(cmd, FX_VOLUMESLIDE | FX_PORTAMENTODOWN | FX_PORTAMENTOUP | FX_TREMOR | FX_ARPEGGIO |
+                      FX_VIBRA_VSLIDE | FX_TONE_VSLIDE | FX_RETRIG | FX_TREMOLO)
+    }
+
+    fn next_order(&self, module: &S3mData, mut ord: u16) -> u16 {
+        for _ in 0..module.orders.len() {
+            if ord as usize >= module.orders.len() || module.orders[ord as usize] == 255 {
+                ord = 0;
+            }
+            if module.orders[ord as usize] != 254 {
+                break;
+            }
+            ord += 1;
+        }
+        ord
+    }
+
+    fn get_event(&self, module: &S3mData, chn: usize) -> S3mEvent {
+        match module.pattern_in_position(self.np_ord as usize) {
+            Some(pat) if pat < module.pat_num => module.patterns.event(pat, self.np_row as usize, chn).clone(),
+            _                                 => S3mEvent::new(),
+        }
+    }
+
+    fn random(&mut self) -> i16 {
+        self.rand_seed = self.rand_seed.wrapping_mul(1103515245).wrapping_add(12345);
+        ((self.rand_seed >> 16) & 0x1ff) as i16 - 0x100
+    }
+
+    fn waveform(&mut self, pos: u8, typ: u8) -> i16 {
+        match typ & 0x03 {
+            0 => VIB_SINE[pos as usize & 0x3f],
+            1 => 255 - ((pos as i16 & 0x3f) << 3),
+            2 => if pos & 0x20 == 0 { 255 } else { -255 },
+            _ => self.random(),
+        }
+    }
+
+    fn process_row(&mut self, module: &S3mData, data: &mut PlayerData, virt: &mut Virtual) {
+        data.event(PlayerEvent::Row(self.np_ord as usize, self.np_row as usize));
+
+        for chn in 0..self.channels.len() {
+            if !module.channel_enabled(chn) {
+                continue;
+            }
+
+            let e = self.get_event(module, chn);
+            if e.cmd != 0 {
+                data.event(PlayerEvent::Effect(chn, e.cmd, e.info));
+            }
+            {
+                let ch = &mut self.channels[chn];
+                ch.note = e.note;
+                ch.ins = e.ins;
+                ch.vol = e.vol;
+                ch.cmd = e.cmd;
+                ch.info = e.info;
+
+                // Shared effect memory
+                if Self::uses_shared_memory(ch.cmd) {
+                    if ch.info != 0 {
+                        ch.alastnfo = ch.info;
+                    } else {
+                        ch.info = ch.alastnfo;
+                    }
+                }
+
+                if ch.cmd != FX_RETRIG {
+                    ch.atrigcnt = 0;
+                }
+            }
+
+            let ch_cmd = self.channels[chn].cmd;
+            let ch_info = self.channels[chn].info;
+            if ch_cmd == FX_SPECIAL && ch_info >> 4 == 0x0d && ch_info & 0x0f != 0 {
+                // Note delay
+                self.channels[chn].anotedelaycnt = ch_info & 0x0f;
+            } else {
+                self.trigger_note(chn, module, data, virt);
+            }
+
+            self.cmd_once(chn);
+        }
+    }
+
+    fn trigger_note(&mut self, chn: usize, module: &S3mData, data: &mut PlayerData, virt: &mut Virtual) {
+        let ch = &mut self.channels[chn];
+        let mut played = None;
+
+        if ch.ins != 0 && ch.ins as usize <= module.instruments.len() {
+            let instrument = &module.instruments[ch.ins as usize - 1];
+            ch.lastins = ch.ins;
+            ch.aorgvol = instrument.volume.min(64) as i16;
+            ch.avol = ch.aorgvol;
+            if instrument.c2spd != 0 {
+                ch.ac2spd = instrument.c2spd;
+            }
+        }
+
+        if ch.note == 254 {
+            // Note cut
+            ch.avol = 0;
+            ch.aspd = 0;
+        } else if ch.note < 254 {
+            let key = (ch.note >> 4) as u16 * 12 + (ch.note & 0x0f) as u16;
+            let period = Self::note_to_period(key, ch.ac2spd);
+
+            if (ch.cmd == FX_TONEPORTAMENTO || ch.cmd == FX_TONE_VSLIDE) && ch.aspd != 0 {
+                ch.asldspd = period;
+            } else {
+                ch.anote = key;
+                ch.aspd = period;
+                ch.aorgspd = period;
+                ch.asldspd = period;
+                if ch.avibtretype & 0x04 == 0 {
+                    ch.avibcnt = 0;
+                }
+                if ch.avibtretype & 0x40 == 0 {
+                    ch.atrecnt = 0;
+                }
+                ch.atremor = 0;
+                ch.atreon = true;
+
+                if ch.lastins != 0 {
+                    let ins = ch.lastins as usize - 1;
+                    virt.set_patch(chn, ins, ins, ch.anote as usize + 12);
+                    played = Some(ins);
+
+                    if ch.cmd == FX_SAMPLEOFFSET {
+                        if ch.info != 0 {
+                            ch.astartoffset = ch.info;
+                        }
+                        virt.set_voicepos(chn, ((ch.astartoffset as u32) << 8) as f64);
+                    }
+                }
+            }
+        }
+
+        if ch.vol != 255 {
+            ch.avol = ch.vol.min(64) as i16;
+        }
+
+        if let Some(ins) = played {
+            data.event(PlayerEvent::Note(chn, ch.anote as usize + 12, ins, ch.avol as usize));
+        }
+    }
+
+    // Effects processed in the first tick of the row
+    fn cmd_once(&mut self, chn: usize) {
+        let cmd = self.channels[chn].cmd;
+        let info = self.channels[chn].info;
+
+        match cmd {
+            FX_SPEED if info != 0 => {
+                self.musicmax = info;
+            },
+            FX_POSITIONJUMP => {
+                self.jumptoord = Some(info as u16);
+            },
+            FX_PATTERNBREAK => {
+                let row = (info >> 4) as u16 * 10 + (info & 0x0f) as u16;
+                self.jumptorow = Some(if row > 63 { 0 } else { row });
+                if self.jumptoord.is_none() {
+                    self.jumptoord = Some(self.np_ord + 1);
+                }
+            },
+            FX_VOLUMESLIDE => {
+                self.volslide(chn);
+            },
+            FX_PORTAMENTODOWN => {
+                let ch = &mut self.channels[chn];
+                if info >= 0xf0 {
+                    ch.aspd = ch.aspd.saturating_add(((info & 0x0f) as u16) << 2).min(0x7fff);
+                } else if info >= 0xe0 {
+                    ch.aspd = ch.aspd.saturating_add((info & 0x0f) as u16).min(0x7fff);
+                }
+            },
+            FX_PORTAMENTOUP => {
+                let ch = &mut self.channels[chn];
+                if info >= 0xf0 {
+                    ch.aspd = ch.aspd.saturating_sub(((info & 0x0f) as u16) << 2).max(64);
+                } else if info >= 0xe0 {
+                    ch.aspd = ch.aspd.saturating_sub((info & 0x0f) as u16).max(64);
+                }
+            },
+            FX_TONEPORTAMENTO if info != 0 => {
+                self.channels[chn].aglis = info;
+            },
+            FX_VIBRATO | FX_FINEVIBRATO => {
+                let ch = &mut self.channels[chn];
+                if info & 0x0f != 0 {
+                    ch.avibdepth = info & 0x0f;
+                }
+                if info & 0xf0 != 0 {
+                    ch.avibspd = info >> 4;
+                }
+            },
+            FX_TREMOR => {
+                self.tremor(chn);
+            },
+            FX_VIBRA_VSLIDE | FX_TONE_VSLIDE => {
+                self.volslide(chn);
+            },
+            FX_SPECIAL => {
+                self.cmd_special(chn);
+            },
+            FX_TEMPO if info > 0x20 => {
+                self.tempo = info;
+            },
+            FX_GLOBALVOLUME if info <= 64 => {
+                self.globalvol = info;
+            },
+            FX_SETPAN => {
+                let ch = &mut self.channels[chn];
+                ch.apanpos = if info >= 0x80 { 0xff } else { info << 1 };
+            },
+            _ => {},
+        }
+    }
+
+    fn cmd_special(&mut self, chn: usize) {
+        let info = self.channels[chn].info;
+        let val = info & 0x0f;
+
+        match info >> 4 {
+            0x1 => {  // glissando control
+                self.channels[chn].aglison = val != 0;
+            },
+            0x2 => {  // set finetune
+                let ch = &mut self.channels[chn];
+                ch.ac2spd = FINETUNE_C2SPD[val as usize];
+                if ch.note < 254 {
+                    ch.aspd = Self::note_to_period(ch.anote, ch.ac2spd);
+                    ch.aorgspd = ch.aspd;
+                }
+            },
+            0x3 => {  // vibrato waveform
+                let ch = &mut self.channels[chn];
+                ch.avibtretype = (ch.avibtretype & 0xf0) | val;
+            },
+            0x4 => {  // tremolo waveform
+                let ch = &mut self.channels[chn];
+                ch.avibtretype = (ch.avibtretype & 0x0f) | (val << 4);
+            },
+            0x8 => {  // set panning
+                self.channels[chn].apanpos = (val << 4) | val;
+            },
+            0xb => {  // pattern loop
+                if val == 0 {
+                    self.patloopstart = self.np_row;
+                } else {
+                    if self.patloopcount == 0 {
+                        self.patloopcount = val;
+                    } else {
+                        self.patloopcount -= 1;
+                    }
+                    if self.patloopcount != 0 {
+                        self.jumptorow = Some(self.patloopstart);
+                        self.jumptoord = Some(self.np_ord);
+                    } else {
+                        self.patloopstart = self.np_row + 1;
+                    }
+                }
+            },
+            0xe if !self.patdelaying => {  // pattern delay
+                self.patterndelay = val;
+            },
+            _ => {},
+        }
+    }
+
+    // Effects processed in the remaining ticks of the row
+    fn cmd_tick(&mut self, chn: usize, module: &S3mData, data: &mut PlayerData, virt: &mut Virtual) {
+        let cmd = self.channels[chn].cmd;
+        let info = self.channels[chn].info;
+
+        match cmd {
+            FX_VOLUMESLIDE => {
+                self.volslide(chn);
+            },
+            FX_PORTAMENTODOWN if info < 0xe0 => {
+                let ch = &mut self.channels[chn];
+                ch.aspd = ch.aspd.saturating_add((info as u16) << 2).min(0x7fff);
+            },
+            FX_PORTAMENTOUP if info < 0xe0 => {
+                let ch = &mut self.channels[chn];
+                ch.aspd = ch.aspd.saturating_sub((info as u16) << 2).max(64);
+            },
+            FX_TONEPORTAMENTO => {
+                self.toneslide(chn);
+            },
+            FX_VIBRATO => {
+                self.vibrato(chn, false);
+            },
+            FX_TREMOR => {
+                self.tremor(chn);
+            },
+            FX_ARPEGGIO => {
+                self.arpeggio(chn);
+            },
+            FX_VIBRA_VSLIDE => {
+                self.vibrato(chn, false);
+                self.volslide(chn);
+            },
+            FX_TONE_VSLIDE => {
+                self.toneslide(chn);
+                self.volslide(chn);
+            },
+            FX_RETRIG => {
+                self.retrig(chn, virt);
+            },
+            FX_TREMOLO => {
+                self.tremolo(chn);
+            },
+            FX_SPECIAL => {
+                let val = info & 0x0f;
+                match info >> 4 {
+                    0xc if self.musiccount == val => {  // note cut
+                        self.channels[chn].avol = 0;
+                    },
+                    0xd if self.musiccount == val && self.channels[chn].anotedelaycnt != 0 => {  // note delay
+                        self.channels[chn].anotedelaycnt = 0;
+                        self.trigger_note(chn, module, data, virt);
+                    },
+                    _ => {},
+                }
+            },
+            FX_FINEVIBRATO => {
+                self.vibrato(chn, true);
+            },
+            _ => {},
+        }
+    }
+
+    fn volslide(&mut self, chn: usize) {
+        let first_tick = self.musiccount == 0 && !self.patdelaying;
+        let fast = self.fastvolslide;
+        let ch = &mut self.channels[chn];
+        let infohi = (ch.info >> 4) as i16;
+        let infolo = (ch.info & 0x0f) as i16;
+
+        if infolo == 0x0f {
+            if infohi == 0 {
+                ch.avol -= infolo;
+            } else if first_tick {
+                ch.avol += infohi;
+            }
+        } else if infohi == 0x0f {
+            if infolo == 0 {
+                ch.avol += infohi;
+            } else if first_tick {
+                ch.avol -= infolo;
+            }
+        } else if fast || !first_tick {
+            if infolo == 0 {
+                ch.avol += infohi;
+            } else {
+                ch.avol -= infolo;
+            }
+        } else {
+            return;
+        }
+
+        ch.avol = ch.avol.clamp(0, 63);
+    }
+
+    fn toneslide(&mut self, chn: usize) {
+        let ch = &mut self.channels[chn];
+        let speed = (ch.aglis as u16) << 2;
+
+        if ch.aspd < ch.asldspd {
+            ch.aspd = ch.aspd.saturating_add(speed);
+            if ch.aspd > ch.asldspd {
+                ch.aspd = ch.asldspd;
+            }
+        } else if ch.aspd > ch.asldspd {
+            ch.aspd = ch.aspd.saturating_sub(speed);
+            if ch.aspd < ch.asldspd {
+                ch.aspd = ch.asldspd;
+            }
+        }
+
+        if ch.aglison {
+            // Round to the nearest semitone
+            let mut key = 0;
+            while key < 119 && Self::note_to_period(key, ch.ac2spd) > ch.aspd {
+                key += 1;
+            }
+            ch.out_spd = Self::note_to_period(key, ch.ac2spd);
+        } else {
+            ch.out_spd = ch.aspd;
+        }
+    }
+
+    fn vibrato(&mut self, chn: usize, fine: bool) {
+        let (pos, typ) = {
+            let ch = &self.channels[chn];
+            (ch.avibcnt, ch.avibtretype)
+        };
+        let val = self.waveform(pos, typ) as i32;
+
+        let ch = &mut self.channels[chn];
+        let depth = ch.avibdepth as i32;
+        let delta = if fine { (val * depth) >> 7 } else { (val * depth) >> 5 };
+        ch.out_spd = (ch.aspd as i32 + delta).max(1) as u16;
+        ch.avibcnt = ch.avibcnt.wrapping_add(ch.avibspd) & 0x3f;
+    }
+
+    fn tremolo(&mut self, chn: usize) {
+        let (pos, typ) = {
+            let ch = &self.channels[chn];
+            (ch.atrecnt, ch.avibtretype >> 4)
+        };
+        let val = self.waveform(pos, typ);
+
+        let ch = &mut self.channels[chn];
+        let depth = (ch.info & 0x0f) as i16;
+        let speed = ch.info >> 4;
+        let vol = ch.avol + ((val * depth) >> 6);
+        ch.out_vol = vol.clamp(0, 64);
+        ch.atrecnt = ch.atrecnt.wrapping_add(speed) & 0x3f;
+    }
+
+    fn tremor(&mut self, chn: usize) {
+        let ch = &mut self.channels[chn];
+        if ch.atremor != 0 {
+            ch.atremor -= 1;
+        } else if ch.atreon {
+            ch.atreon = false;
+            ch.atremor = ch.info & 0x0f;
+        } else {
+            ch.atreon = true;
+            ch.atremor = ch.info >> 4;
+        }
+
+        if !ch.atreon {
+            ch.out_vol = 0;
+        }
+    }
+
+    fn arpeggio(&mut self, chn: usize) {
+        let ch = &mut self.channels[chn];
+        let add = match self.musiccount % 3 {
+            1 => ch.info >> 4,
+            2 => ch.info & 0x0f,
+            _ => 0,
+        } as u16;
+
+        if add != 0 {
+            ch.out_spd = Self::note_to_period(ch.anote + add, ch.ac2spd);
+        }
+    }
+
+    fn retrig(&mut self, chn: usize, virt: &mut Virtual) {
+        let ch = &mut self.channels[chn];
+        let interval = ch.info & 0x0f;
+        if interval == 0 {
+            return;
+        }
+
+        ch.atrigcnt += 1;
+        if ch.atrigcnt < interval {
+            return;
+        }
+        ch.atrigcnt = 0;
+
+        let vol = ch.avol;
+        ch.avol = match ch.info >> 4 {
+            0x6 => (vol * 2) / 3,
+            0x7 => vol >> 1,
+            0xe => (vol * 3) / 2,
+            0xf => vol * 2,
+            x   => vol + RETRIG_VOL_ADD[x as usize],
+        };
+
+        ch.avol = ch.avol.clamp(0, 63);
+
+        virt.set_voicepos(chn, 0.0);
+    }
+
+    fn advance_row(&mut self, module: &S3mData) {
+        if self.patterndelay != 0 {
+            self.patterndelay -= 1;
+            self.patdelaying = true;
+            return;
+        }
+        self.patdelaying = false;
+
+        match (self.jumptoord.take(), self.jumptorow.take()) {
+            (None, None) => {
+                self.np_row += 1;
+                if self.np_row >= 64 {
+                    self.np_row = 0;
+                    self.np_ord = self.next_order(module, self.np_ord + 1);
+                    self.patloopstart = 0;
+                    self.new_order = true;
+                }
+            },
+            (ord, row) => {
+                let ord = ord.unwrap_or(self.np_ord + 1);
+                if ord != self.np_ord {
+                    self.patloopstart = 0;
+                }
+                self.np_ord = self.next_order(module, ord);
+                self.np_row = row.unwrap_or(0);
+                self.new_order = true;
+            },
+        }
+    }
+
+    fn process_tick(&mut self, module: &S3mData, data: &mut PlayerData, virt: &mut Virtual) {
+        for ch in &mut self.channels {
+            ch.out_spd = ch.aspd;
+            ch.out_vol = -1;
+        }
+
+        if self.musiccount == 0 && !self.patdelaying {
+            self.process_row(module, data, virt);
+        } else {
+            for chn in 0..self.channels.len() {
+                if module.channel_enabled(chn) {
+                    self.cmd_tick(chn, module, data, virt);
+                }
+            }
+        }
+
+        for chn in 0..self.channels.len() {
+            let ch = &mut self.channels[chn];
+            if ch.out_vol < 0 {
+                ch.out_vol = ch.avol;
+            }
+
+            virt.set_period(chn, Self::mix_period(ch.out_spd, ch.ac2spd));
+            virt.set_volume(chn, ((ch.out_vol as usize * self.globalvol as usize) >> 6) << 4);
+            virt.set_pan(chn, ch.apanpos as isize - 0x80);
+        }
+
+        self.musiccount += 1;
+        if self.musiccount >= self.musicmax {
+            self.musiccount = 0;
+            self.advance_row(module);
+        }
+    }
+}
+
+
+impl FormatPlayer for St3Play {
+    fn start(&mut self, data: &mut PlayerData, mdata: &dyn ModuleData) {
+
+        let module = mdata.as_any().downcast_ref::<S3mData>().unwrap();
+
+        data.speed = if module.initial_speed != 0 { module.initial_speed as usize } else { 6 };
+        data.tempo = if module.initial_tempo > 0x20 { module.initial_tempo as usize } else { 125 };
+        data.pos = self.next_order(module, 0) as usize;
+
+        self.globalvol = module.global_vol.min(64);
+        data.gvol = self.globalvol as usize;
+        self.fastvolslide = module.fast_volslides();
+
+        for (i, ch) in self.channels.iter_mut().enumerate() {
+            let pan = module.ch_pan[i];
+            ch.apanpos = (pan << 4) | pan;
+            ch.ac2spd = 8363;
+        }
+    }
+
+    fn play(&mut self, data: &mut PlayerData, mdata: &dyn ModuleData, virt: &mut Virtual) {
+
+        let module = mdata.as_any().downcast_ref::<S3mData>().unwrap();
+
+        if self.new_order && data.frame == 0 {
+            self.new_order = false;
+            data.enter_order(data.pos);
+        }
+
+        self.np_ord = data.pos as u16;
+        self.np_row = data.row as u16;
+        self.musiccount = data.frame as u8;
+        self.musicmax = data.speed as u8;
+        self.tempo = data.tempo as u8;
+
+        self.process_tick(module, data, virt);
+
+        data.frame = self.musiccount as usize;
+        data.row = self.np_row as usize;
+        data.pos = self.np_ord as usize;
+        data.speed = self.musicmax as usize;
+        data.tempo = self.tempo as usize;
+        data.gvol = self.globalvol as usize;
+    }
+
+    fn reset(&mut self) {
+        self.np_ord       = 0;
+        self.np_row       = 0;
+        self.musiccount   = 0;
+        self.musicmax     = 6;
+        self.tempo        = 125;
+        self.globalvol    = 64;
+        self.jumptoord    = None;
+        self.jumptorow    = None;
+        self.patterndelay = 0;
+        self.patdelaying  = false;
+        self.patloopstart = 0;
+        self.patloopcount = 0;
+        self.fastvolslide = false;
+        self.rand_seed    = 0x1234;
+        self.new_order    = true;
+
+        for ch in &mut self.channels {
+            *ch = St3Channel::new();
+        }
+    }
+
+    fn preview_note(&self, ins: usize, note: usize, mdata: &dyn ModuleData) -> Option<(usize, f64)> {
+        let module = mdata.as_any().downcast_ref::<S3mData>().unwrap();
+
+        // Note 60 is C-4, played at the instrument C2SPD
+        if !(12..132).contains(&note) {
+            return None;
+        }
+        let instrument = module.instruments.get(ins)?;
+        if module.samples.get(ins)?.size == 0 {
+            return None;
+        }
+        let c2spd = if instrument.c2spd != 0 { instrument.c2spd } else { 8363 };
+        let period = Self::note_to_period(note as u16 - 12, c2spd);
+
+        Some((ins, Self::mix_period(period, c2spd)))
+    }
+}
+
+
+#[derive(Clone,Default)]
+struct St3Channel {
+    note         : u8,
+    ins          : u8,
+    vol          : u8,
+    cmd          : u8,
+    info         : u8,
+
+    lastins      : u8,
+    anote        : u16,   // octave * 12 + note
+    ac2spd       : u32,
+    aorgvol      : i16,
+    avol         : i16,
+    aspd         : u16,   // current period
+    aorgspd      : u16,   // period without effects
+    asldspd      : u16,   // tone portamento target period
+    aglis        : u8,    // tone portamento speed
+    aglison      : bool,
+    alastnfo     : u8,    // shared effect memory
+    avibcnt      : u8,
+    avibspd      : u8,
+    avibdepth    : u8,
+    atrecnt      : u8,
+    avibtretype  : u8,
+    atremor      : u8,
+    atreon       : bool,
+    atrigcnt     : u8,
+    anotedelaycnt: u8,
+    astartoffset : u8,
+    apanpos      : u8,
+
+    out_spd      : u16,   // period to send to the mixer
+    out_vol      : i16,   // volume to send to the mixer, -1 if unchanged
+}
+
+impl St3Channel {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
@@ -1,5 +1,8 @@
-use mixer::Mixer;
-use module::Sample;
+use mixer::{Mixer, Interpolator, Layout, SampleFormat, Limiter, Stems, Timing, Clock, Paula};
+use std::sync::Arc;
+use module::Module;
+use player::ChannelInfo;
+use module::instrument::{NoteAction, NoteActions, DuplicateCheck};
 use ::*;
 
 
@@ -18,39 +21,49 @@ impl VirtChannel {
     }
 }
 
+// New note action and pattern note played in each voice
+#[derive(Clone,Default)]
+struct VoiceInfo {
+    act : NoteAction,
+    note: usize,
+}
+
 
-pub struct Virtual<'a> {
+pub struct Virtual {
     num_tracks   : usize,              // number of tracks
     virt_numch   : usize,              // number of virtual channels
     virt_used    : usize,              // number of voices currently in use
     virt_channel : Vec<VirtChannel>,
-    channel_mute : [bool; MAX_CHANNELS],
+    voice_info   : Vec<VoiceInfo>,
 
-    mixer        : Mixer<'a>,
+    mixer        : Mixer,
 }
 
 
-impl<'a> Virtual<'a> {
-    pub fn new(chn: usize, sample: &'a Vec<Sample>, has_virt: bool) -> Self {
+impl Virtual {
+    pub fn new(chn: usize, rate: usize, module: Arc<Module>, has_virt: bool) -> Self {
 
-        let mixer = Mixer::new(chn, &sample);
-        let num = mixer.num_voices();
+        // Players with virtual channels get a pool of voices to play notes
+        // in background channels. Preview channels come after all others.
+        let num = if has_virt { MAX_VOICES } else { chn } + MAX_PREVIEW;
+
+        let mut mixer = Mixer::new(chn, rate, module);
+        mixer.create_voices(num);
 
         let mut v = Virtual {
             num_tracks  : chn,
             virt_numch  : chn,
             virt_used   : 0,
             virt_channel: Vec::new(),
-            channel_mute: [false; MAX_CHANNELS],
+            voice_info  : vec![VoiceInfo::default(); num],
             mixer,
         };
 
         if has_virt {
-            v.virt_numch = num;
+            v.virt_numch += MAX_VOICES;
         }
 
-        v.virt_channel = vec![VirtChannel::new(); v.virt_numch];
-        v.mixer.create_voices(chn);
+        v.virt_channel = vec![VirtChannel::new(); v.virt_numch + MAX_PREVIEW];
 
         if !has_virt {
             (0..chn).for_each(|x| {v.alloc_voice(x);});
@@ -63,11 +76,118 @@ impl<'a> Virtual<'a> {
         self.mixer.set_tempo(tempo);
     }
 
+    pub fn set_timing(&mut self, timing: Timing, clock: Clock) {
+        self.mixer.set_timing(timing, clock);
+    }
+
+    pub fn tick_time(&self, tempo: usize) -> f64 {
+        self.mixer.tick_time(tempo)
+    }
+
+    pub fn set_interpolator(&mut self, interp: Interpolator) {
+        self.mixer.set_interpolator(interp);
+    }
+
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.mixer.set_layout(layout);
+    }
+
+    pub fn set_format(&mut self, format: SampleFormat) {
+        self.mixer.set_format(format);
+    }
+
+    pub fn set_float_mix(&mut self, enable: bool) {
+        self.mixer.set_float_mix(enable);
+    }
+
+    pub fn set_master_gain(&mut self, gain: f32) {
+        self.mixer.set_master_gain(gain);
+    }
+
+    pub fn set_limiter(&mut self, limiter: Limiter) {
+        self.mixer.set_limiter(limiter);
+    }
+
+    pub fn overs(&self) -> usize {
+        self.mixer.overs()
+    }
+
+    pub fn set_stems(&mut self, stems: Stems, num: usize) {
+        self.mixer.set_stems(stems, num);
+    }
+
+    pub fn num_stems(&self) -> usize {
+        self.mixer.num_stems()
+    }
+
+    pub fn stem_buffer(&self, num: usize) -> &[f32] {
+        self.mixer.stem_buffer(num)
+    }
+
+    pub fn set_scopes(&mut self, enable: bool) {
+        self.mixer.set_scopes(enable);
+    }
+
+    /// Samples of the voice playing in the channel in the last frame.
+    pub fn scope_buffer(&self, chn: usize) -> &[f32] {
+        match self.channel_to_voice(chn) {
+            Some(voice) => self.mixer.scope_buffer(voice),
+            None        => &[],
+        }
+    }
+
+    pub fn set_anticlick(&mut self, enable: bool) {
+        self.mixer.set_anticlick(enable);
+    }
+
+    pub fn set_mute(&mut self, mute: bool) {
+        self.mixer.set_mute(mute);
+    }
+
+    pub fn set_paula(&mut self, model: Paula) {
+        self.mixer.set_paula(model);
+    }
+
+    /// Turn the Amiga LED filter on or off, as set by the replayer.
+    pub fn set_led_filter(&mut self, on: bool) {
+        self.mixer.set_led_filter(on);
+    }
+
+    /// Stop all voices and release background channels.
+    pub fn reset(&mut self) {
+        self.mixer.reset();
+        self.virt_used = 0;
+        self.virt_channel.iter_mut().for_each(|x| *x = VirtChannel::new());
+        self.voice_info.iter_mut().for_each(|x| *x = VoiceInfo::default());
+
+        // Players without virtual channels keep one voice in each track
+        if self.virt_numch == self.num_tracks {
+            (0..self.num_tracks).for_each(|x| {self.alloc_voice(x);});
+        }
+    }
+
+    /// Mute or unmute the voices playing notes from a track channel. Muted
+    /// voices keep playing silently, so unmuting resumes the notes in progress.
+    pub fn set_channel_mute(&mut self, chn: usize, mute: bool) {
+        self.mixer.set_channel_mute(chn, mute);
+    }
+
+    pub fn channel_mute(&self, chn: usize) -> bool {
+        self.mixer.channel_mute(chn)
+    }
+
+    /// Number of track channels.
+    pub fn num_tracks(&self) -> usize {
+        self.num_tracks
+    }
+
+    /// Number of virtual channels, including background channels.
+    pub fn num_channels(&self) -> usize {
+        self.virt_numch
+    }
+
     pub fn root(&self, chn: usize) -> Option<usize> {
-        let voice = match self.virt_channel[chn].map {
-            Some(val) => val,
-            None      => return None,
-        };
+        let voice = self.virt_channel[chn].map?;
 
         self.mixer.voice_root(voice)
     }
@@ -83,14 +203,74 @@ impl<'a> Virtual<'a> {
         self.virt_used -= 1;
         self.virt_channel[root].count -= 1;
         self.virt_channel[chn].map = None;
+        self.voice_info[voice] = VoiceInfo::default();
         self.mixer.reset_voice(voice);
     }
 
-    pub fn alloc_voice(&mut self, chn: usize) -> usize {
+    /// Fill the state of the voice playing in the channel.
+    pub fn channel_info(&self, chn: usize, info: &mut ChannelInfo) {
+        *info = ChannelInfo::new();
+        let voice = try_option!(self.channel_to_voice(chn));
+        let m = &self.mixer;
+
+        info.active = !m.sample_end(voice) && m.voice_period(voice) >= 1.0;
+        info.note = m.voice_note(voice);
+        info.ins = m.voice_ins(voice).unwrap_or(0);
+        info.smp = m.voice_smp(voice).unwrap_or(0);
+        info.volume = m.voice_vol(voice);
+        info.pan = m.voice_pan(voice);
+        info.period = m.voice_period(voice);
+        info.freq = m.voice_freq(voice);
+        info.pos = m.voicepos(voice);
+        info.looping = info.active && m.voice_looping(voice);
+        info.muted = m.voice_root(voice).is_some_and(|root| m.channel_mute(root));
+    }
+
+    /// Play a sample in a preview channel, outside the replay channels. Volume
+    /// is 0-64 and period is a mixer period.
+    pub fn play_note(&mut self, num: usize, ins: usize, smp: usize, period: f64, vol: usize) {
+        if num >= MAX_PREVIEW {
+            return;
+        }
+
+        let chn = self.virt_numch + num;
+        let voice = match self.channel_to_voice(chn).or_else(|| self.alloc_voice(chn)) {
+            Some(v) => v,
+            None    => return,
+        };
+
+        self.mixer.set_patch(voice, ins, smp, true);
+        self.mixer.set_period(voice, period);
+        self.mixer.set_volume(voice, vol.min(64) << 4);
+    }
+
+    /// Stop the note playing in a preview channel.
+    pub fn stop_note(&mut self, num: usize) {
+        if num < MAX_PREVIEW {
+            let chn = self.virt_numch + num;
+            self.reset_channel(chn);
+        }
+    }
+
+    /// Instrument playing in the given virtual channel.
+    pub fn channel_ins(&self, chn: usize) -> Option<usize> {
+        let voice = self.channel_to_voice(chn)?;
+        self.mixer.voice_ins(voice)
+    }
+
+    /// Stop the voice playing in the given virtual channel.
+    pub fn reset_channel(&mut self, chn: usize) {
+        let voice = try_option!(self.channel_to_voice(chn));
+        self.reset_voice(voice, true);
+    }
+
+    /// Map a voice to the channel, taking the quietest background voice if no
+    /// voice is free. Returns `None` if all voices play in foreground channels.
+    pub fn alloc_voice(&mut self, chn: usize) -> Option<usize> {
         // Locate free voice
         let num = match self.mixer.find_free_voice() {
             Some(v) => v,
-            None    => self.free_voice(),
+            None    => self.free_voice()?,
         };
 
         self.virt_channel[chn].count += 1;
@@ -98,13 +278,13 @@ impl<'a> Virtual<'a> {
         self.mixer.set_voice(num, chn);
         self.virt_channel[chn].map = Some(num);
 
-        num
+        Some(num)
     }
 
-    pub fn free_voice(&mut self) -> usize {
+    pub fn free_voice(&mut self) -> Option<usize> {
 
         // Find background voice with lowest volume
-        let num = self.mixer.find_lowest_voice(self.num_tracks);
+        let num = self.mixer.find_lowest_voice(self.num_tracks, self.virt_numch)?;
 
         let root = self.mixer.voice_root(num).unwrap();
         let chn = self.mixer.voice_chn(num).unwrap();
@@ -112,23 +292,41 @@ impl<'a> Virtual<'a> {
         self.virt_channel[root].count -= 1;
         self.virt_used -= 1;
 
-        num
+        Some(num)
     }
 
     fn channel_to_voice(&self, chn: usize) -> Option<usize> {
-        if chn >= self.virt_numch {
+        if chn >= self.virt_channel.len() {
             None
         } else {
             self.virt_channel[chn].map
         }
     }
 
+    /// Return the note action of the voice playing in a background channel,
+    /// `NoteAction::Continue` for foreground channels, or `None` if no voice
+    /// is playing in the channel.
+    pub fn channel_action(&self, chn: usize) -> Option<NoteAction> {
+        let voice = self.channel_to_voice(chn)?;
+
+        if chn < self.num_tracks {
+            Some(NoteAction::Continue)
+        } else {
+            Some(self.voice_info[voice].act)
+        }
+    }
+
+    /// Set the action performed when a new note is played in the channel.
+    pub fn set_nna(&mut self, chn: usize, act: NoteAction) {
+        let voice = try_option!(self.channel_to_voice(chn));
+        self.voice_info[voice].act = act;
+    }
+
     pub fn set_volume(&mut self, chn: usize, mut vol: usize) {
         let voice = try_option!(self.channel_to_voice(chn));
 
-        match self.mixer.voice_root(voice) {
-            Some(v) => if self.channel_mute[v] { vol = 0 },
-            None    => vol = 0,
+        if self.mixer.voice_root(voice).is_none() {
+            vol = 0;
         }
 
         self.mixer.set_volume(voice, vol);
@@ -154,23 +352,178 @@ impl<'a> Virtual<'a> {
         self.mixer.set_voicepos(voice, pos, true);
     }
 
+    pub fn set_filter(&mut self, chn: usize, cutoff: usize, resonance: usize) {
+        let voice = try_option!(self.channel_to_voice(chn));
+        self.mixer.set_filter(voice, cutoff, resonance);
+    }
+
+    /// Release the note playing in the channel, leaving the sample sustain loop.
+    pub fn release(&mut self, chn: usize) {
+        let voice = try_option!(self.channel_to_voice(chn));
+        self.mixer.release(voice);
+    }
+
     pub fn set_patch(&mut self, chn: usize, ins: usize, smp: usize, note: usize) {
+        self.set_patch_nna(chn, ins, smp, note, note, NoteActions::default());
+    }
+
+    /// Set the instrument and sample of the channel as written to the Amiga
+    /// sample registers. In Paula mode the sample starts when the current loop
+    /// iteration ends or when the channel position is set, otherwise it's the
+    /// same as `set_patch`.
+    pub fn queue_patch(&mut self, chn: usize, ins: usize, smp: usize, note: usize) {
+        match self.channel_to_voice(chn) {
+            Some(voice) if self.mixer.paula() != Paula::Off => self.mixer.queue_patch(voice, ins, smp),
+            _ => self.set_patch(chn, ins, smp, note),
+        }
+    }
 
+    /// Play a note, moving the note currently playing in the channel to a
+    /// background channel according to its new note action. Voices rooted in
+    /// this channel that match the duplicate check get the duplicate check
+    /// action. The note plays at the pitch of `key`, the note after the
+    /// instrument keyboard mapping, and `note` is the pattern note used in the
+    /// duplicate check. Returns the channel where the previous note continues
+    /// playing, which is the same channel if the previous note was cut.
+    pub fn set_patch_nna(&mut self, chn: usize, ins: usize, smp: usize, key: usize, note: usize,
+                         actions: NoteActions) -> Option<usize> {
+
+        if chn >= self.virt_numch {
+            return None;
+        }
+
+        let NoteActions{ nna, dct, dca } = actions;
+        if dct != DuplicateCheck::Off {
+            for voice in 0..self.mixer.num_voices() {
+                if self.mixer.voice_root(voice) != Some(chn) || self.mixer.voice_ins(voice) != Some(ins) {
+                    continue;
+                }
+
+                let dup = match dct {
+                    DuplicateCheck::Instrument => true,
+                    DuplicateCheck::Sample     => self.mixer.voice_smp(voice) == Some(smp),
+                    DuplicateCheck::Note       => self.voice_info[voice].note == note,
+                    DuplicateCheck::Off        => false,
+                };
+
+                if dup {
+                    if dca == NoteAction::Cut {
+                        self.reset_voice(voice, true);
+                    } else if Some(voice) != self.virt_channel[chn].map || self.voice_info[voice].act != NoteAction::Cut {
+                        self.voice_info[voice].act = dca;
+                    }
+                }
+            }
+        }
+
+        let mut to = chn;
         let voice = match self.channel_to_voice(chn) {
-            Some(v) => v,  // TODO: act stuff
-            None    => self.alloc_voice(chn),
+            Some(v) => {
+                // Move the playing voice to a free background channel, or cut
+                // the previous note if there's no room in the background
+                let free = (self.num_tracks..self.virt_numch).find(|&c| self.virt_channel[c].map.is_none());
+                match free {
+                    Some(c) if self.voice_info[v].act != NoteAction::Cut => match self.alloc_voice(chn) {
+                        Some(vfree) => {
+                            to = c;
+                            self.mixer.set_voice_chn(v, to);
+                            self.virt_channel[to].map = Some(v);
+                            vfree
+                        },
+                        None        => v,
+                    },
+                    _ => v,
+                }
+            },
+            None    => self.alloc_voice(chn)?,
         };
 
         self.mixer.set_patch(voice, ins, smp, true);
-        self.mixer.set_note(voice, note);
+        self.mixer.set_note(voice, key);
+        self.voice_info[voice] = VoiceInfo{ act: nna, note };
+
+        Some(to)
     }
 
     pub fn mix(&mut self) {
-        self.mixer.mix()
+        self.mixer.mix();
+
+        // Release background voices that reached the end of the sample
+        for chn in self.num_tracks..self.virt_numch {
+            if let Some(voice) = self.virt_channel[chn].map {
+                if self.mixer.sample_end(voice) {
+                    self.reset_voice(voice, false);
+                }
+            }
+        }
     }
 
     pub fn buffer(&self) -> &[i16] {
         self.mixer.buffer()
     }
+
+    pub fn buffer_i32(&self) -> &[i32] {
+        self.mixer.buffer_i32()
+    }
+
+    pub fn buffer_f32(&self) -> &[f32] {
+        self.mixer.buffer_f32()
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use mixer::tests::test_module;
+    use module::instrument::{NoteAction, NoteActions, DuplicateCheck};
+    use super::Virtual;
+    use ::*;
+
+    #[test]
+    fn test_duplicate_check() {
+        let mut virt = Virtual::new(4, 44100, test_module(&[(16, 64)]), true);
+        virt.set_patch_nna(0, 0, 0, 60, 60, NoteActions{ nna: NoteAction::Continue, ..NoteActions::default() });
+        assert_eq!(virt.set_patch_nna(0, 0, 0, 62, 62, NoteActions{ nna: NoteAction::Continue, ..NoteActions::default() }), Some(4));
+
+        // A new note with cut action only cuts duplicates of the same note, and
+        // the note playing moves to the channel freed
+        assert_eq!(virt.set_patch_nna(0, 0, 0, 60, 60, NoteActions{ dct: DuplicateCheck::Note, ..NoteActions::default() }), Some(4));
+        assert_eq!(virt.channel_ins(4), Some(0));
+        assert_eq!(virt.channel_ins(5), None);
+        assert_eq!(virt.channel_ins(0), Some(0));
+    }
+
+    #[test]
+    fn test_preview_channel() {
+        let mut virt = Virtual::new(4, 44100, test_module(&[(16, 64), (16, 32)]), true);
+        virt.set_patch(0, 0, 0, 60);
+
+        // Preview notes play after the background channels
+        virt.play_note(1, 1, 1, 428.0, 64);
+        assert_eq!(virt.channel_ins(4 + MAX_VOICES + 1), Some(1));
+        assert!((1..4 + MAX_VOICES).all(|chn| virt.channel_ins(chn).is_none()));
+
+        // and song notes don't take their voices
+        virt.set_patch(1, 0, 0, 60);
+        assert_eq!(virt.channel_ins(1), Some(0));
+        assert_eq!(virt.channel_ins(4 + MAX_VOICES + 1), Some(1));
+
+        virt.stop_note(1);
+        assert_eq!(virt.channel_ins(4 + MAX_VOICES + 1), None);
+        assert_eq!(virt.channel_ins(0), Some(0));
+        assert_eq!(virt.channel_ins(1), Some(0));
+    }
+
+    #[test]
+    fn test_background_full() {
+        let mut virt = Virtual::new(4, 44100, test_module(&[(16, 64)]), true);
+        for _ in 0..MAX_VOICES + 1 {
+            virt.set_patch_nna(0, 0, 0, 60, 60, NoteActions{ nna: NoteAction::Continue, ..NoteActions::default() });
+        }
+
+        // No free background channel, the previous note is cut
+        assert_eq!(virt.set_patch_nna(0, 0, 0, 62, 62, NoteActions{ nna: NoteAction::Continue, ..NoteActions::default() }), Some(0));
+        assert_eq!(virt.channel_ins(0), Some(0));
+        assert_eq!(virt.channel_ins(4 + MAX_VOICES - 1), Some(0));
+    }
+}
@@ -3,11 +3,12 @@ use byteorder::{ByteOrder, BigEndian, LittleEndian};
 use Error;
 use ::*;
 
-pub const NOTES: &'static [&'static str] = &[
+pub const NOTES: &[&str] = &[
     "C ", "C#", "D ", "D#", "E ", "F ", "F#", "G ", "G#", "A ", "A#", "B "
 ];
 
 pub const C4_PAL_RATE : f64 = 8287.0;   // 7093789.2 / period (C4) * 2
+#[allow(dead_code)]
 pub const C4_NTSC_RATE: f64 = 8363.0;   // 7159090.5 / period (C4) * 2
 
 // [Amiga] PAL color carrier frequency (PCCF) = 4.43361825 MHz
@@ -37,12 +38,13 @@ macro_rules! clamp {
 
 
 pub trait MemOpExt<T> {
-    fn fill(&mut self, u8, usize);
+    fn fill(&mut self, _: u8, _: usize);
 }
 
-impl<'a, T> MemOpExt<T> for [T] {
+impl<T> MemOpExt<T> for [T] {
     fn fill(&mut self, val: u8, amt: usize) {
-        unsafe { ptr::write_bytes(self.as_mut_ptr(), val, amt * std::mem::size_of::<T>() - 1); }
+        let amt = amt.min(self.len());
+        unsafe { ptr::write_bytes(self.as_mut_ptr(), val, amt); }
     }
 }
 
@@ -58,44 +60,44 @@ pub trait BinaryRead {
     fn slice(&self, start: usize, size: usize) -> Result<&[u8], Error>;
 }
 
-impl<'a> BinaryRead for &'a [u8] {
+impl BinaryRead for &[u8] {
     fn read_string(&self, ofs: usize, size: usize) -> Result<String, Error> {
-        try!(check_buffer_size(&self, ofs + size));
+        check_buffer_size(self, ofs + size)?;
         Ok(String::from_utf8_lossy(&self[ofs..ofs+size]).to_string().replace("\x00", " "))
     }
 
     fn read32b(&self, ofs: usize) -> Result<u32, Error> {
-        try!(check_buffer_size(&self, ofs + 4));
+        check_buffer_size(self, ofs + 4)?;
         Ok(BigEndian::read_u32(&self[ofs..ofs+4]))
     }
 
     fn read16b(&self, ofs: usize) -> Result<u16, Error> {
-        try!(check_buffer_size(&self, ofs + 2));
+        check_buffer_size(self, ofs + 2)?;
         Ok(BigEndian::read_u16(&self[ofs..ofs+2]))
     }
 
     fn read32l(&self, ofs: usize) -> Result<u32, Error> {
-        try!(check_buffer_size(&self, ofs + 4));
+        check_buffer_size(self, ofs + 4)?;
         Ok(LittleEndian::read_u32(&self[ofs..ofs+4]))
     }
 
     fn read16l(&self, ofs: usize) -> Result<u16, Error> {
-        try!(check_buffer_size(&self, ofs + 2));
+        check_buffer_size(self, ofs + 2)?;
         Ok(LittleEndian::read_u16(&self[ofs..ofs+2]))
     }
 
     fn read8(&self, ofs: usize) -> Result<u8, Error> {
-        try!(check_buffer_size(&self, ofs + 1));
+        check_buffer_size(self, ofs + 1)?;
         Ok(self[ofs])
     }
 
     fn read8i(&self, ofs: usize) -> Result<i8, Error> {
-        try!(check_buffer_size(&self, ofs + 1));
+        check_buffer_size(self, ofs + 1)?;
         Ok(self[ofs] as i8)
     }
 
     fn slice(&self, start: usize, size: usize) -> Result<&[u8], Error> {
-        try!(check_buffer_size(&self, start + size));
+        check_buffer_size(self, start + size)?;
         Ok(&self[start..start + size])
     }
 }
@@ -109,6 +111,7 @@ fn check_buffer_size(b: &[u8], end: usize) -> Result<(), Error> {
 
 // Get note from Protracker period
 // This function is used only in the MOD loader
+#[allow(dead_code)]
 pub fn period_to_note(period: u32) -> usize {
     if period == 0 {
         return 0